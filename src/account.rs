@@ -0,0 +1,132 @@
+//! The accounts a service runs as: checking they exist before a unit names
+//! them, since systemd only finds out when the service fails to start.
+
+use crate::command::find_executable;
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Database {
+    Passwd,
+    Group,
+}
+
+impl Database {
+    fn name(&self) -> &'static str {
+        match self {
+            Database::Passwd => "passwd",
+            Database::Group => "group",
+        }
+    }
+}
+
+/// Whether `name` is in an /etc/passwd or /etc/group style file, by name or
+/// numeric id.
+fn in_file(content: &str, name: &str) -> bool {
+    content.lines().any(|line| {
+        let mut fields = line.split(':');
+        let entry = fields.next();
+        let id = fields.nth(1);
+        entry == Some(name) || id == Some(name)
+    })
+}
+
+/// Whether the account exists, through NSS with getent so directory users
+/// count, or the local file without it.
+pub fn exists(database: Database, name: &str) -> bool {
+    if find_executable("getent").is_some() {
+        return Command::new("getent")
+            .args([database.name(), name])
+            .stdout(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+    }
+    fs::read_to_string(format!("/etc/{}", database.name()))
+        .is_ok_and(|content| in_file(&content, name))
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    log::info!("Running {} {}", program, args.join(" "));
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Creates a system account for `user` that can't log in, in `group` if
+/// given (created too if need be), or a group of its own otherwise.
+pub fn create_system_user(user: &str, group: Option<&str>) -> Result<()> {
+    if find_executable("useradd").is_some() {
+        if let Some(group) = group.filter(|group| !exists(Database::Group, group)) {
+            run("groupadd", &["--system", group])?;
+        }
+        let shell = find_executable("nologin").map_or("/bin/false".into(), |path| {
+            path.to_string_lossy().into_owned()
+        });
+        let mut args = vec!["--system", "--no-create-home", "--shell", &shell];
+        match group {
+            Some(group) => args.extend(["--gid", group]),
+            None => args.push("--user-group"),
+        }
+        args.push(user);
+        return run("useradd", &args);
+    }
+    // Busybox, e.g. on Alpine.
+    if find_executable("adduser").is_some() {
+        let group = group.unwrap_or(user);
+        if !exists(Database::Group, group) {
+            run("addgroup", &["-S", group])?;
+        }
+        return run(
+            "adduser",
+            &["-S", "-D", "-H", "-s", "/sbin/nologin", "-G", group, user],
+        );
+    }
+    Err(anyhow!(
+        "Neither useradd nor adduser is installed to create {:?}.",
+        user
+    ))
+}
+
+/// Fails unless the accounts the service runs as exist.
+pub fn check(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let accounts = [(Database::Passwd, user), (Database::Group, group)];
+    for (database, name) in accounts {
+        let Some(name) = name else {
+            continue;
+        };
+        if !exists(database, name) {
+            let kind = match database {
+                Database::Passwd => "user",
+                Database::Group => "group",
+            };
+            return Err(anyhow!(
+                "There's no {} {:?} to run as. Create it, or pass --create-user.",
+                kind,
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_file() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+            www:x:33:33:www-data:/var/www:/usr/sbin/nologin\n";
+        assert!(in_file(passwd, "www"));
+        assert!(in_file(passwd, "33"));
+        assert!(!in_file(passwd, "ww"));
+        assert!(!in_file(passwd, "x"));
+    }
+}
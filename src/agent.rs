@@ -0,0 +1,188 @@
+//! A long-running listener so orchestration tools can manage services on a
+//! host without invoking mkservice over SSH for each change.
+
+use crate::config::{ServiceConfig, ServiceLevel};
+use crate::manifest;
+use crate::provider::{self, ServiceStatus};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a client may leave the agent waiting on it, since clients are
+/// served one at a time.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Request {
+    /// Installs a service from its manifest, as saved by the CLI.
+    Install {
+        service: Box<ServiceConfig>,
+        #[serde(default)]
+        start: bool,
+    },
+    Start {
+        name: String,
+    },
+    Stop {
+        name: String,
+    },
+    Status {
+        name: String,
+    },
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ServiceStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn default_socket(level: &ServiceLevel) -> Result<PathBuf> {
+    let runtime_dir = match level {
+        ServiceLevel::System => PathBuf::from("/run"),
+        ServiceLevel::User => PathBuf::from(env::var("XDG_RUNTIME_DIR")?),
+    };
+    Ok(runtime_dir.join("mkservice/agent.sock"))
+}
+
+/// Carries out one request for services at `level`.
+pub fn handle(request: Request, level: &ServiceLevel) -> Result<Option<ServiceStatus>> {
+    let name = match request {
+        Request::Install { mut service, start } => {
+            crate::validate_name(&service.name).map_err(|e| anyhow!(e))?;
+            crate::validate_service(&service)?;
+            service.name = manifest::qualify(&service.name);
+            if &service.level != level {
+                return Err(anyhow!(
+                    "This agent manages {} services, not {} ones.",
+                    format!("{:?}", level).to_lowercase(),
+                    format!("{:?}", service.level).to_lowercase()
+                ));
+            }
+            crate::install_service(&service, start)?;
+            return Ok(None);
+        }
+        Request::Start { ref name } | Request::Stop { ref name } | Request::Status { ref name } => {
+            name
+        }
+    };
+    let service = manifest::find(name, Some(level))?;
+    let p = provider::get_provider(service)?;
+    match request {
+        Request::Start { .. } => p.start()?,
+        Request::Stop { .. } => p.stop()?,
+        Request::Status { .. } => return Ok(Some(p.status()?)),
+        Request::Install { .. } => unreachable!("handled above"),
+    }
+    Ok(None)
+}
+
+fn respond(line: &str, level: &ServiceLevel) -> Response {
+    let result = serde_json::from_str(line)
+        .map_err(|e| anyhow!("Bad request: {}", e))
+        .and_then(|request| handle(request, level));
+    match result {
+        Ok(status) => Response {
+            ok: true,
+            status,
+            ..Default::default()
+        },
+        Err(e) => Response {
+            error: Some(format!("{:#}", e)),
+            ..Default::default()
+        },
+    }
+}
+
+/// Serves requests from one client until it hangs up, or goes quiet for
+/// `CLIENT_TIMEOUT` so it can't hold up the clients after it.
+fn serve_client(stream: UnixStream, level: &ServiceLevel) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = respond(&line, level);
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+/// Listens on `socket` and serves clients one at a time, so requests never
+/// race each other.
+pub fn serve(socket: &Path, level: &ServiceLevel) -> Result<()> {
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Clear a socket left behind by a previous run, but nothing else.
+    match fs::symlink_metadata(socket) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(socket)?,
+        Ok(_) => return Err(anyhow!("{:?} exists and isn't a socket.", socket)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    log::info!("Listening on {:?}", socket);
+    for stream in listener.incoming() {
+        if let Err(e) = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|s| serve_client(s, level))
+        {
+            log::warn!("Client error: {:#}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond_errors() {
+        let level = ServiceLevel::User;
+        let response = respond("{\"action\": \"restart\"}", &level);
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("Bad request"));
+
+        let response = respond(
+            r#"{"action": "install", "service": {"name": "web", "command": ["/usr/bin/web"]}}"#,
+            &level,
+        );
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"ok":false,"error":"This agent manages user services, not system ones."}"#
+        );
+
+        let response = respond(
+            r#"{"action": "install", "service": {"name": "web", "command": ["/usr/bin/web"], "listen": ["8080"]}}"#,
+            &level,
+        );
+        assert_eq!(
+            response.error.as_deref(),
+            Some("Bad listen: \"8080\" must be \"tcp:PORT\", \"tcp:ADDRESS:PORT\", the same with udp or sctp, \"unix:/PATH\" or \"abstract:NAME\".")
+        );
+        let response = respond(
+            r#"{"action": "install", "service": {"name": "web", "command": ["/usr/bin/web"], "env": {"A": "1\nExecStartPre=/bin/evil"}}}"#,
+            &level,
+        );
+        assert_eq!(
+            response.error.as_deref(),
+            Some("The environment variable \"A\" has control characters in it.")
+        );
+    }
+}
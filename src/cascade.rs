@@ -0,0 +1,109 @@
+//! Restarting what's bound to a service when it changes, so the units of a
+//! multi-unit app stay consistent with each other.
+
+use crate::config::ServiceConfig;
+use crate::manifest;
+use crate::provider;
+use crate::provider::systemd::Systemd;
+use crate::ServiceOperator;
+use anyhow::{Context, Result};
+
+/// The unit file names of `service`, the service unit first. PartOf= and
+/// BindsTo= are systemd's, so are the names.
+fn unit_names(service: &ServiceConfig) -> Result<Vec<String>> {
+    let systemd = Systemd {
+        service: service.clone(),
+    };
+    Ok(systemd
+        .unit_files()?
+        .into_iter()
+        .map(|(path, _)| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect())
+}
+
+/// The units of `managed` services that declare PartOf= or BindsTo= on any
+/// of `units`, including ones not loaded yet.
+fn managed_dependents(units: &[String], managed: &[ServiceConfig]) -> Result<Vec<String>> {
+    let mut dependents = Vec::new();
+    for service in managed {
+        let Some(options) = service.unit_options.get("Unit") else {
+            continue;
+        };
+        let bound = ["PartOf", "BindsTo"]
+            .iter()
+            .filter_map(|key| options.get(*key))
+            .flatten()
+            .flat_map(|value| value.split_whitespace())
+            .any(|unit| units.iter().any(|own| own == unit));
+        if bound {
+            dependents.extend(unit_names(service)?.into_iter().take(1));
+        }
+    }
+    Ok(dependents)
+}
+
+/// Restarts `service` and its dependents if installing it changed it from
+/// `previous`. A new or unchanged service is left alone.
+pub fn cascade(previous: Option<&ServiceConfig>, service: &ServiceConfig) -> Result<()> {
+    match previous {
+        None => return Ok(()),
+        Some(previous) if previous == service => {
+            log::info!("{:?} is unchanged, not restarting it.", service.name);
+            return Ok(());
+        }
+        Some(_) => {}
+    }
+    let p = provider::get_provider(service.clone())?;
+    let units = unit_names(service)?;
+    let mut dependents = p.dependents()?;
+    dependents.extend(managed_dependents(
+        &units,
+        &manifest::load_all(&service.level)?,
+    )?);
+    dependents.sort();
+    dependents.dedup();
+    p.restart_with(&dependents)
+        .with_context(|| format!("Failed restarting {:?}", service.name))?;
+    match dependents.is_empty() {
+        true => log::info!("Restarted {:?}.", service.name),
+        false => log::info!(
+            "Restarted {:?} along with {}.",
+            service.name,
+            dependents.join(", ")
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_managed_dependents() {
+        let service = |name: &str, key: &str, value: &str| ServiceConfig {
+            name: name.into(),
+            command: vec![format!("/usr/bin/{}", name)],
+            unit_options: BTreeMap::from([(
+                "Unit".into(),
+                BTreeMap::from([(key.into(), vec![value.into()])]),
+            )]),
+            ..Default::default()
+        };
+        let managed = vec![
+            service("metrics", "PartOf", "api.service"),
+            service("sidecar", "BindsTo", "db.service api.service"),
+            service("worker", "Wants", "api.service"),
+        ];
+        assert_eq!(
+            managed_dependents(&["api.service".into()], &managed).unwrap(),
+            vec!["metrics.service", "sidecar.service"]
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use crate::config::Wrapper;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The PATH systemd gives services that don't set one.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Resolves `name` against PATH, the way a shell would. Names containing a
+/// slash are checked as-is.
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return is_executable(&path).then_some(path);
+    }
+    let paths = env::var_os("PATH")?;
+    find_in(name, &paths)
+}
+
+/// mkservice as units should run it: from PATH, where it stays put across
+/// upgrades, rather than wherever this binary happens to be running from.
+pub fn mkservice_program() -> Result<String> {
+    let program = find_executable("mkservice").ok_or_else(|| {
+        anyhow!("mkservice isn't on PATH, install it there so services can run it.")
+    })?;
+    Ok(program.to_string_lossy().into_owned())
+}
+
+fn find_in(name: &str, paths: &OsStr) -> Option<PathBuf> {
+    env::split_paths(paths)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+/// Replaces the program at the head of `command` with its absolute path, as
+/// systemd wants it. Bare names are looked up in `path`, the service's PATH
+/// if it sets one, or else ours. Errors if the program isn't executable.
+pub fn resolve_program(command: &mut [String], path: Option<&str>) -> Result<()> {
+    let Some(program) = command.first_mut() else {
+        return Ok(());
+    };
+    let resolved = if program.contains('/') {
+        let path = env::current_dir()?.join(&*program);
+        if !is_executable(&path) {
+            return Err(anyhow!(
+                "{:?} doesn't exist or isn't executable, pass --no-path-check if it will.",
+                path
+            ));
+        }
+        path
+    } else {
+        let paths = match path {
+            Some(path) => path.into(),
+            None => env::var_os("PATH").unwrap_or_default(),
+        };
+        find_in(program, &paths).ok_or_else(|| {
+            anyhow!(
+                "{:?} not found in PATH, pass --no-path-check if it will be.",
+                program
+            )
+        })?
+    };
+    *program = resolved.to_string_lossy().into_owned();
+    Ok(())
+}
+
+/// Runs `f`, logging how long `what` took, shown with `-vv`.
+pub fn timed<T>(what: &str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    log::trace!("{} took {:?}", what, started.elapsed());
+    result
+}
+
+impl Wrapper {
+    fn binary(&self) -> &'static str {
+        match self {
+            Wrapper::Firejail => "firejail",
+            Wrapper::Bwrap => "bwrap",
+        }
+    }
+}
+
+/// Quotes `s` for sh, if it needs it.
+#[cfg(any(feature = "launchd", feature = "openrc", feature = "runit"))]
+pub fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=:,./@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Rewrites `command` to run under the given sandbox wrapper, e.g.
+/// `bwrap <wrapper_args> -- <command>`.
+pub fn wrap(
+    wrapper: Wrapper,
+    wrapper_args: &[String],
+    command: Vec<String>,
+) -> Result<Vec<String>> {
+    let paths = env::var_os("PATH").unwrap_or_default();
+    wrap_in(&paths, wrapper, wrapper_args, command)
+}
+
+/// `wrap`, finding the wrapper in `paths`.
+fn wrap_in(
+    paths: &OsStr,
+    wrapper: Wrapper,
+    wrapper_args: &[String],
+    command: Vec<String>,
+) -> Result<Vec<String>> {
+    let binary = find_in(wrapper.binary(), paths)
+        .ok_or_else(|| anyhow!("Sandbox wrapper {:?} not found in PATH.", wrapper.binary()))?;
+    let mut wrapped = vec![binary.to_string_lossy().into_owned()];
+    wrapped.extend(wrapper_args.iter().cloned());
+    wrapped.push("--".into());
+    wrapped.extend(command);
+    Ok(wrapped)
+}
+
+/// Puts `dir` at the front of the service's PATH, starting from systemd's
+/// default if PATH isn't set yet.
+pub fn prepend_path(env: &mut BTreeMap<String, String>, dir: &Path) {
+    let path = env.get("PATH").map(String::as_str).unwrap_or(DEFAULT_PATH);
+    let path = format!("{}:{}", dir.display(), path);
+    env.insert("PATH".into(), path);
+}
+
+/// Replaces a bare program name at the head of `command` with its absolute
+/// path if `dir` provides it.
+pub fn resolve_in(dir: &Path, command: &mut [String]) {
+    if let Some(program) = command.first_mut() {
+        if !program.contains('/') && is_executable(&dir.join(&*program)) {
+            *program = dir.join(&*program).to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Runs the service in the virtualenv at `venv`: sets VIRTUAL_ENV to its
+/// absolute path, puts its bin/ first on PATH and resolves the program there.
+pub fn use_venv(
+    venv: &Path,
+    env: &mut BTreeMap<String, String>,
+    command: &mut [String],
+) -> Result<()> {
+    let bin_dir = venv_bin_dir(venv)?;
+    let venv = bin_dir.parent().expect("bin/ has a parent");
+    env.insert("VIRTUAL_ENV".into(), venv.to_string_lossy().into_owned());
+    prepend_path(env, &bin_dir);
+    resolve_in(&bin_dir, command);
+    Ok(())
+}
+
+fn venv_bin_dir(venv: &Path) -> Result<PathBuf> {
+    let venv = venv.canonicalize()?;
+    let bin_dir = venv.join("bin");
+    if !bin_dir.is_dir() {
+        return Err(anyhow!(
+            "{:?} doesn't look like a virtualenv, missing bin/.",
+            venv
+        ));
+    }
+    Ok(bin_dir)
+}
+
+/// Finds the newest nvm-installed node matching `version`, which may be a
+/// major ("18"), major.minor, or exact version.
+pub fn node_bin_dir(version: &str) -> Result<PathBuf> {
+    let nvm_dir = match env::var_os("NVM_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var("HOME")?).join(".nvm"),
+    };
+    let versions_dir = nvm_dir.join("versions/node");
+    let wanted = version.trim_start_matches('v');
+    let best = fs::read_dir(&versions_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let installed = name.trim_start_matches('v');
+            let matches = installed == wanted || installed.starts_with(&format!("{}.", wanted));
+            matches.then(|| (parse_version(installed), entry.path()))
+        })
+        .max();
+    match best {
+        Some((_, dir)) => Ok(dir.join("bin")),
+        None => Err(anyhow!(
+            "No node version matching {:?} in {:?}.",
+            version,
+            versions_dir
+        )),
+    }
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version.split('.').filter_map(|p| p.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_program() {
+        let mut command = vec!["sh".to_string(), "-c".into(), "true".into()];
+        resolve_program(&mut command, Some("/nonexistent:/bin")).unwrap();
+        assert_eq!(command, ["/bin/sh", "-c", "true"]);
+        resolve_program(&mut command, None).unwrap();
+        assert_eq!(command[0], "/bin/sh");
+        let mut command = vec!["sh".to_string()];
+        assert!(resolve_program(&mut command, Some("/nonexistent")).is_err());
+        let mut command = vec!["/etc".to_string()];
+        assert!(resolve_program(&mut command, None).is_err());
+    }
+
+    #[test]
+    fn test_wrap() {
+        let dir = env::temp_dir().join(format!("mkservice-wrap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bwrap = dir.join("bwrap");
+        fs::write(&bwrap, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&bwrap, fs::Permissions::from_mode(0o755)).unwrap();
+        let args = ["--ro-bind".to_string(), "/".into(), "/".into()];
+        let command = vec!["/usr/bin/web".to_string(), "--port".into(), "80".into()];
+
+        let wrapped = wrap_in(dir.as_os_str(), Wrapper::Bwrap, &args, command.clone()).unwrap();
+        assert_eq!(
+            wrapped,
+            [
+                bwrap.to_str().unwrap(),
+                "--ro-bind",
+                "/",
+                "/",
+                "--",
+                "/usr/bin/web",
+                "--port",
+                "80"
+            ]
+        );
+        assert!(wrap_in(dir.as_os_str(), Wrapper::Firejail, &[], command).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_use_venv() {
+        let venv = env::temp_dir().join(format!("mkservice-venv-{}", std::process::id()));
+        let python = venv.join("bin/python");
+        fs::create_dir_all(python.parent().unwrap()).unwrap();
+        fs::write(&python, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&python, fs::Permissions::from_mode(0o755)).unwrap();
+        let venv = venv.canonicalize().unwrap();
+        // The same venv, relative to the working directory.
+        let cwd = env::current_dir().unwrap();
+        let mut relative = PathBuf::new();
+        for _ in cwd.ancestors().skip(1) {
+            relative.push("..");
+        }
+        relative.push(venv.strip_prefix("/").unwrap());
+
+        let mut env = BTreeMap::new();
+        let mut command = vec!["python".to_string(), "app.py".into()];
+        use_venv(&relative, &mut env, &mut command).unwrap();
+        assert_eq!(env["VIRTUAL_ENV"], venv.to_str().unwrap());
+        assert_eq!(
+            env["PATH"],
+            format!("{}:{}", venv.join("bin").display(), DEFAULT_PATH)
+        );
+        assert_eq!(
+            command,
+            [venv.join("bin/python").to_str().unwrap(), "app.py"]
+        );
+        assert!(use_venv(Path::new("/nonexistent"), &mut env, &mut command).is_err());
+        fs::remove_dir_all(&venv).unwrap();
+    }
+
+    #[test]
+    fn test_prepend_path() {
+        let mut env = BTreeMap::new();
+        prepend_path(&mut env, Path::new("/opt/venv/bin"));
+        assert_eq!(env["PATH"], format!("/opt/venv/bin:{}", DEFAULT_PATH));
+        prepend_path(&mut env, Path::new("/opt/node/bin"));
+        assert!(env["PATH"].starts_with("/opt/node/bin:/opt/venv/bin:"));
+    }
+}
@@ -1,21 +1,400 @@
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
-#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq)]
+#[derive(
+    clap::ValueEnum, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum ServiceLevel {
     User,
+    #[default]
     System,
 }
 
-impl Default for ServiceLevel {
-    fn default() -> Self {
-        ServiceLevel::System
+/// Third-party sandbox tools the command can be run under.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrapper {
+    Firejail,
+    Bwrap,
+}
+
+/// Where the service's stdin comes from, as systemd's `StandardInput=`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StandardInput {
+    Null,
+    /// The TTY, waiting for it to be free.
+    Tty,
+    /// The TTY, taking it over from whatever holds it.
+    TtyForce,
+    /// The TTY, failing if it's held.
+    TtyFail,
+}
+
+impl StandardInput {
+    pub fn is_tty(&self) -> bool {
+        !matches!(self, StandardInput::Null)
+    }
+}
+
+/// When the service is restarted after it exits, as systemd's `Restart=`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    No,
+    /// On a non-zero exit, a signal or a timeout.
+    OnFailure,
+    Always,
+    /// On a signal or a timeout, but not an exit of its own.
+    OnAbnormal,
+}
+
+impl RestartPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RestartPolicy::No => "no",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+            RestartPolicy::OnAbnormal => "on-abnormal",
+        }
+    }
+}
+
+/// How the service manager tells the service has started, as systemd's
+/// `Type=`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceType {
+    /// As soon as the command is launched.
+    #[default]
+    Simple,
+    /// Once the command's program has been executed.
+    Exec,
+    /// Once the command exits, leaving a daemon that forked off behind.
+    Forking,
+    /// Once the command is done, for jobs run to completion.
+    Oneshot,
+    /// Once the command says it's ready, with sd_notify.
+    Notify,
+    /// Once the command takes its D-Bus name, set by --dbus-name.
+    #[value(skip)]
+    Dbus,
+}
+
+impl ServiceType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServiceType::Simple => "simple",
+            ServiceType::Exec => "exec",
+            ServiceType::Forking => "forking",
+            ServiceType::Oneshot => "oneshot",
+            ServiceType::Notify => "notify",
+            ServiceType::Dbus => "dbus",
+        }
+    }
+}
+
+/// Which variables from the service manager's environment reach the service.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PassthroughEnv {
+    /// Scrub desktop session and locale variables.
+    None,
+    /// Scrub desktop session variables, keep the locale.
+    Minimal,
+    /// Pass these variables from the manager's own environment, scrub the
+    /// rest of the session.
+    List(Vec<String>),
+}
+
+impl PassthroughEnv {
+    /// What a service at `level` gets unless asked otherwise: system
+    /// services are kept clear of whatever a session pushed into the manager.
+    pub fn default_for(level: &ServiceLevel) -> Option<PassthroughEnv> {
+        match level {
+            ServiceLevel::System => Some(PassthroughEnv::Minimal),
+            ServiceLevel::User => None,
+        }
+    }
+}
+
+/// Baseline sandboxing that suits most daemons without further thought.
+const MODERATE_HARDENING: [(&str, &str); 7] = [
+    ("NoNewPrivileges", "yes"),
+    ("PrivateTmp", "yes"),
+    ("ProtectSystem", "full"),
+    ("ProtectKernelTunables", "yes"),
+    ("ProtectKernelModules", "yes"),
+    ("ProtectControlGroups", "yes"),
+    ("RestrictSUIDSGID", "yes"),
+];
+
+/// For services known to write only to their own directories and use no
+/// devices.
+const STRICT_HARDENING: [(&str, &str); 16] = [
+    ("NoNewPrivileges", "yes"),
+    ("PrivateTmp", "yes"),
+    ("PrivateDevices", "yes"),
+    ("ProtectSystem", "strict"),
+    ("ProtectHome", "read-only"),
+    ("ProtectKernelTunables", "yes"),
+    ("ProtectKernelModules", "yes"),
+    ("ProtectKernelLogs", "yes"),
+    ("ProtectControlGroups", "yes"),
+    ("ProtectClock", "yes"),
+    ("ProtectHostname", "yes"),
+    ("RestrictSUIDSGID", "yes"),
+    ("RestrictRealtime", "yes"),
+    ("RestrictNamespaces", "yes"),
+    ("LockPersonality", "yes"),
+    ("SystemCallArchitectures", "native"),
+];
+
+/// How much of systemd's sandboxing a service gets.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hardening {
+    /// Read-only /usr, /boot and /etc, a private /tmp and no new privileges.
+    Moderate,
+    /// A read-only file system but for the working directory, read-only
+    /// homes, no devices, and the kernel, clock and hostname left alone.
+    Strict,
+}
+
+impl Hardening {
+    /// Adds the directives to `service`, beneath anything already set.
+    pub fn apply(&self, service: &mut ServiceConfig) {
+        let options = service.unit_options.entry("Service".into()).or_default();
+        let directives: &[(&str, &str)] = match self {
+            Hardening::Moderate => &MODERATE_HARDENING,
+            Hardening::Strict => &STRICT_HARDENING,
+        };
+        for (key, value) in directives {
+            options
+                .entry(key.to_string())
+                .or_insert_with(|| vec![value.to_string()]);
+        }
+        if let (Hardening::Strict, Some(dir)) = (self, &service.working_directory) {
+            options
+                .entry("ReadWritePaths".into())
+                .or_insert_with(|| vec![dir.clone()]);
+        }
     }
 }
 
-#[derive(Clone, Default, Debug)]
+/// When a power hook runs, around the machine's sleep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerHook {
+    /// On the way into suspend or hibernation.
+    Sleep,
+    /// On the way back out.
+    Resume,
+}
+
+/// EC2 instance metadata that can be put in a service's environment.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImdsValue {
+    /// The instance role's temporary credentials, as the AWS SDKs' variables.
+    RoleCredentials,
+}
+
+impl ImdsValue {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImdsValue::RoleCredentials => "role-credentials",
+        }
+    }
+}
+
+/// The OpenRC program that runs a long-running service.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Supervisor {
+    /// Stays around to respawn the service when it dies.
+    SuperviseDaemon,
+    /// Starts the service and leaves it, finding it again by its pid file or
+    /// executable to stop it.
+    StartStopDaemon,
+}
+
+/// How OpenRC supervises the service, where supervise-daemon's defaults
+/// don't fit.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenRcConfig {
+    pub supervisor: Option<Supervisor>,
+    /// Respawns allowed within `respawn_period` before supervise-daemon
+    /// gives up.
+    pub respawn_max: Option<u32>,
+    /// Seconds over which `respawn_max` counts respawns.
+    pub respawn_period: Option<u32>,
+    /// Pid file start-stop-daemon finds the service by.
+    pub pidfile: Option<String>,
+    /// Have start-stop-daemon background a command that doesn't fork itself.
+    pub background: bool,
+}
+
+impl OpenRcConfig {
+    pub fn is_default(&self) -> bool {
+        *self == OpenRcConfig::default()
+    }
+}
+
+/// Schedule for a timer unit that triggers the service.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimerConfig {
+    pub on_calendar: Vec<String>,
+    /// Time span after boot to first run (OnBootSec=).
+    pub on_boot: Option<String>,
+    /// Time span after the last run finished to run again (OnUnitInactiveSec=).
+    pub on_unit_inactive: Option<String>,
+    pub randomized_delay: Option<String>,
+    /// Whether runs missed while the machine was off or asleep happen on
+    /// next boot (Persistent=). Left to systemd's default when unset.
+    pub persistent: Option<bool>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ServiceConfig {
     pub name: String,
     pub command: Vec<String>,
+    /// Further commands run in order after `command`, for oneshot services.
+    pub steps: Vec<Vec<String>>,
+    pub service_type: ServiceType,
     pub env: BTreeMap<String, String>,
+    /// Files of KEY=VALUE lines read into the environment at each start.
+    pub env_files: Vec<String>,
+    /// Environment variables kept encrypted with the host's credential key,
+    /// by name, as `systemd-creds encrypt` writes them in base64.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env_secrets: BTreeMap<String, String>,
+    /// Vault secrets put in the environment at each start, as PATH#FIELD,
+    /// the field naming the variable.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vault_secrets: Vec<String>,
+    /// EC2 instance metadata put in the environment at each start.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub imds_env: Vec<ImdsValue>,
+    /// GCE metadata put in the environment at each start, by variable, as
+    /// paths under computeMetadata/v1, or the instance's attribute names.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub gce_metadata_env: BTreeMap<String, String>,
+    pub working_directory: Option<String>,
+    /// `%` in the command, working directory, environment and env files
+    /// starts a systemd specifier, e.g. "%h", with "%%" a literal "%".
+    pub specifiers: bool,
     pub level: ServiceLevel,
+    pub timer: Option<TimerConfig>,
+    pub passthrough_env: Option<PassthroughEnv>,
+    /// User the service runs as, root if unset.
+    pub user: Option<String>,
+    /// Group the service runs as, the user's own if unset.
+    pub group: Option<String>,
+    /// Console the service owns, e.g. /dev/tty2.
+    pub tty: Option<String>,
+    pub standard_input: Option<StandardInput>,
+    /// Well-known D-Bus name the service owns, for bus activation.
+    pub bus_name: Option<String>,
+    /// Addresses a socket unit listens on, starting the service on the first
+    /// connection, e.g. "tcp:8080" or "unix:/run/app.sock".
+    pub listen: Vec<String>,
+    /// Run an instance of the service per connection, inetd style, with the
+    /// connection as its stdin and stdout.
+    pub accept: bool,
+    /// Networks allowed to connect to the socket, all others are refused.
+    pub socket_allow: Vec<String>,
+    /// Network interface the socket only takes connections from.
+    pub socket_bind_device: Option<String>,
+    /// Owner of the socket's file, for unix sockets.
+    pub socket_user: Option<String>,
+    /// Permissions of the socket's file, e.g. "0660".
+    pub socket_mode: Option<String>,
+    /// Name the service is passed the socket's file descriptors under.
+    pub socket_fd_name: Option<String>,
+    /// File descriptors the service can keep with the service manager, to
+    /// hand over to itself across restarts.
+    pub fd_store_max: Option<u32>,
+    /// When to restart the service, the provider's default if unset.
+    pub restart: Option<RestartPolicy>,
+    /// Seconds to wait before restarting it.
+    pub restart_sec: Option<u32>,
+    /// Memory the service can use before it's killed, as systemd's
+    /// MemoryMax=, e.g. "512M" or "20%".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<String>,
+    /// CPU time the service gets, in percent of one CPU, e.g. "50%".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<String>,
+    /// How many processes and threads the service can have, or a
+    /// percentage of the system's limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tasks_max: Option<String>,
+    #[serde(skip_serializing_if = "OpenRcConfig::is_default")]
+    pub openrc: OpenRcConfig,
+    /// Paths launchd keeps the job running while they exist, or while they
+    /// don't with a leading "!".
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keepalive_paths: Vec<String>,
+    /// Run as a login session on `tty` in place of its getty.
+    pub kiosk: bool,
+    /// Run the command around sleep rather than as a service.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_hook: Option<PowerHook>,
+    /// Start and stop with the user's graphical session, for GUI helpers.
+    pub graphical: bool,
+    /// Only run on the machine's first boot (ConditionFirstBoot=).
+    pub first_boot_only: bool,
+    /// Pid file a forking service writes, naming the daemon it leaves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pidfile: Option<String>,
+    /// Stay active after a oneshot's command exits, until stopped.
+    pub remain_after_exit: bool,
+    /// Run the command as the machine shuts down, rather than as a service.
+    pub at_shutdown: bool,
+    /// Run the command once each boot, staying active afterwards.
+    pub at_boot_once: bool,
+    /// Run this many copies, as instances of a template unit.
+    pub instances: Option<u32>,
+    /// Other managed services this one requires and starts after.
+    pub depends_on: Vec<String>,
+    /// Mount points that must be mounted before the service starts.
+    pub requires_mounts: Vec<String>,
+    /// Devices, network interfaces by name or device nodes by path, the
+    /// service needs and is stopped along with when they go.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bind_to_devices: Vec<String>,
+    /// Devices, as above, the service waits for before it starts.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after_devices: Vec<String>,
+    /// Units the service starts after, e.g. network-online.target.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    /// Units the service starts before.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<String>,
+    /// Units the service needs, and fails to start without.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    /// Units started along with the service, which starts even if they fail.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub wants: Vec<String>,
+    /// Address mailed, with mail(1), when the service fails.
+    pub failure_mail: Option<String>,
+    /// Comment written at the top of each unit.
+    pub header: Option<String>,
+    /// Labels for acting on several services at once, e.g. "web".
+    pub tags: Vec<String>,
+    /// The stack, i.e. the multi-service file, the service was imported with.
+    pub stack: Option<String>,
+    /// Extra directives by unit section ("Unit", "Service" or "Install"),
+    /// e.g. from a preset.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unit_options: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    /// Unit files whose out-of-band edits were accepted, keyed by file name.
+    /// These are installed as-is instead of being rendered.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub adopted_units: BTreeMap<String, String>,
 }
@@ -0,0 +1,194 @@
+use crate::config::ServiceConfig;
+use crate::manifest;
+use crate::provider;
+use anyhow::{anyhow, Result};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// A unit file whose content on disk differs from what its manifest renders.
+pub struct UnitDrift {
+    pub path: PathBuf,
+    pub expected: String,
+    /// None if the file is missing.
+    pub actual: Option<String>,
+}
+
+/// A unified diff from `old` to `new` content of the file at `path`, each
+/// side labelled.
+pub fn unified_diff(
+    path: &Path,
+    (old_label, old): (&str, &str),
+    (new_label, new): (&str, &str),
+) -> String {
+    let path = path.to_string_lossy();
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(
+            &format!("{} ({})", path, old_label),
+            &format!("{} ({})", path, new_label),
+        )
+        .to_string()
+}
+
+impl UnitDrift {
+    pub fn diff(&self) -> String {
+        match &self.actual {
+            Some(actual) => {
+                unified_diff(&self.path, ("manifest", &self.expected), ("live", actual))
+            }
+            None => format!("{} is missing.\n", self.path.to_string_lossy()),
+        }
+    }
+}
+
+pub struct Drift {
+    pub service: ServiceConfig,
+    pub units: Vec<UnitDrift>,
+    /// The enablement state, if the service isn't enabled as installed.
+    pub enabled: Option<String>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty() && self.enabled.is_none()
+    }
+}
+
+/// Compares a managed service's installed units and enablement to what its
+/// manifest says they should be.
+pub fn detect(service: ServiceConfig) -> Result<Drift> {
+    let p = provider::get_provider(service.clone())?;
+    let mut units = Vec::new();
+    for (path, expected) in p.unit_files()? {
+        let actual = match fs::read_to_string(&path) {
+            Ok(actual) => Some(actual),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if actual.as_ref() != Some(&expected) {
+            units.push(UnitDrift {
+                path,
+                expected,
+                actual,
+            });
+        }
+    }
+    let status = p.status()?;
+    let enabled = match status.enabled.as_str() {
+        // A timer's service is started by the timer, not enabled itself.
+        _ if service.timer.is_some() => None,
+        "enabled" => None,
+        other => Some(other.to_string()),
+    };
+    Ok(Drift {
+        service,
+        units,
+        enabled,
+    })
+}
+
+/// The files installing `service` would change, as diffs from the live file
+/// to the new one, split by whether mkservice wrote the live file as it is.
+fn replacements(service: &ServiceConfig) -> Result<(Vec<String>, Vec<String>)> {
+    let written: BTreeMap<PathBuf, String> = match manifest::load(&service.level, &service.name)? {
+        Some(previous) => provider::get_provider(previous)?
+            .unit_files()?
+            .into_iter()
+            .collect(),
+        None => BTreeMap::new(),
+    };
+    let (mut updates, mut overwrites) = (Vec::new(), Vec::new());
+    for (path, new) in provider::get_provider(service.clone())?.unit_files()? {
+        let live = match fs::read_to_string(&path) {
+            Ok(live) => live,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if live == new {
+            continue;
+        }
+        let diff = unified_diff(&path, ("live", &live), ("new", &new));
+        match written.get(&path) == Some(&live) {
+            true => updates.push(diff),
+            false => overwrites.push(diff),
+        }
+    }
+    Ok((updates, overwrites))
+}
+
+/// The files installing `service` would replace that mkservice didn't write
+/// as they are, e.g. units tuned by hand since, as diffs from the live file
+/// to the new one.
+pub fn overwrites(service: &ServiceConfig) -> Result<Vec<String>> {
+    Ok(replacements(service)?.1)
+}
+
+/// How reinstalling `service` changes the files mkservice wrote before, as
+/// diffs from the live file to the new one.
+pub fn updates(service: &ServiceConfig) -> Result<Vec<String>> {
+    Ok(replacements(service)?.0)
+}
+
+/// Whether `service` is installed exactly as it would be now: the same
+/// manifest, and every file in place with the same content.
+pub fn unchanged(service: &ServiceConfig) -> Result<bool> {
+    if manifest::load(&service.level, &service.name)?.as_ref() != Some(service) {
+        return Ok(false);
+    }
+    for (path, new) in provider::get_provider(service.clone())?.unit_files()? {
+        match fs::read_to_string(&path) {
+            Ok(live) if live == new => {}
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Records the live unit files as the accepted state of the service.
+pub fn accept(drift: &Drift) -> Result<ServiceConfig> {
+    let mut service = drift.service.clone();
+    for unit in &drift.units {
+        let actual = unit.actual.as_ref().ok_or_else(|| {
+            anyhow!(
+                "Can't accept missing unit {:?}, restore it instead.",
+                unit.path
+            )
+        })?;
+        let file_name = unit
+            .path
+            .file_name()
+            .expect("unit paths have a file name")
+            .to_string_lossy()
+            .into_owned();
+        service.adopted_units.insert(file_name, actual.clone());
+    }
+    Ok(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit_drift_diff() {
+        let unit = UnitDrift {
+            path: PathBuf::from("/etc/systemd/system/web.service"),
+            expected: "[Service]\nType=simple\n".into(),
+            actual: Some("[Service]\nType=notify\n".into()),
+        };
+        assert_eq!(
+            unit.diff(),
+            "--- /etc/systemd/system/web.service (manifest)\n\
+            +++ /etc/systemd/system/web.service (live)\n\
+            @@ -1,2 +1,2 @@\n \
+            [Service]\n\
+            -Type=simple\n\
+            +Type=notify\n",
+        );
+    }
+}
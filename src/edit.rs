@@ -0,0 +1,223 @@
+//! Drop-ins edited as `systemctl edit` does, in $VISUAL or $EDITOR on a
+//! copy, but checked before they're installed so a mistake never reaches
+//! the service manager.
+
+use crate::config::ServiceConfig;
+use crate::error::MkserviceError;
+use crate::provider::systemd::{config_dir, daemon_reload, Systemd};
+use crate::unit::parse;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The drop-in's file name, the one `systemctl edit` uses.
+const DROP_IN: &str = "override.conf";
+
+/// What a new drop-in starts as.
+fn template(unit: &str) -> String {
+    format!(
+        "# Settings for {} here override the unit's, e.g.\n\
+        #\n\
+        # [Service]\n\
+        # Environment=DEBUG=1\n\
+        #\n\
+        # Leaving no settings removes the drop-in.\n",
+        unit
+    )
+}
+
+/// The section a unit of `unit`'s type keeps its own settings in, e.g.
+/// "Service" for "web.service".
+fn type_section(unit: &str) -> Option<String> {
+    let (_, suffix) = unit.rsplit_once('.')?;
+    let mut chars = suffix.chars();
+    let first = chars.next()?;
+    Some(first.to_uppercase().chain(chars).collect())
+}
+
+/// What's wrong with `content` as a drop-in for `unit`, if anything.
+pub fn lint(unit: &str, content: &str) -> Vec<String> {
+    let parsed = match parse(content) {
+        Ok(parsed) => parsed,
+        Err(e) => return vec![e.to_string()],
+    };
+    let re_key = Regex::new(r"^[A-Za-z][A-Za-z0-9]*$").expect("Bad regex");
+    let sections: Vec<String> = std::iter::once("Unit".into())
+        .chain(type_section(unit))
+        .chain(std::iter::once("Install".into()))
+        .collect();
+    let mut problems = Vec::new();
+    for (section, options) in &parsed.sections {
+        // X- sections are for other programs, and systemd ignores them.
+        if !sections.contains(section) && !section.starts_with("X-") {
+            problems.push(format!(
+                "[{}] isn't a section of {}: {}.",
+                section,
+                unit,
+                sections.join(", ")
+            ));
+        }
+        for key in options.keys() {
+            if !re_key.is_match(key) && !key.starts_with("X-") {
+                problems.push(format!(
+                    "{:?} in [{}] isn't a directive name.",
+                    key, section
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Opens `path` in the user's editor, as git does, so the editor may come
+/// with arguments.
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.is_empty())
+        .unwrap_or_else(|| "vi".into());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .map_err(|e| MkserviceError::spawn("sh", e))?;
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}.", editor, status));
+    }
+    Ok(())
+}
+
+/// Writes `content` to a copy of the drop-in in a new directory only the
+/// user can read, so no one else can swap or read it while it's edited.
+fn write_copy(content: &str) -> Result<PathBuf> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let dir = env::temp_dir().join(format!("mkservice-edit-{}-{}", std::process::id(), nanos));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| MkserviceError::io(&dir, e))?;
+    let copy = dir.join(DROP_IN);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&copy)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| MkserviceError::io(&copy, e))?;
+    Ok(copy)
+}
+
+/// Removes the copy `write_copy` made, along with its directory.
+fn remove_copy(copy: &Path) {
+    if let Some(dir) = copy.parent() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+/// Edits the drop-in for the managed `service`'s unit, installing it if it
+/// changed and passes `lint`, or removing it if it's left without settings.
+pub fn edit(service: &ServiceConfig) -> Result<()> {
+    let level = &service.level;
+    let unit = Systemd {
+        service: service.clone(),
+    }
+    .service_file_name();
+    let path = config_dir(level)?.join(format!("{}.d", unit)).join(DROP_IN);
+    let original = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => template(&unit),
+        Err(e) => return Err(MkserviceError::io(&path, e).into()),
+    };
+    let copy = write_copy(&original)?;
+    let edited = run_editor(&copy).and_then(|_| Ok(fs::read_to_string(&copy)?));
+    let edited = match edited {
+        Ok(edited) if edited != original => edited,
+        unchanged => {
+            remove_copy(&copy);
+            log::info!("No changes to the drop-in for {}.", unit);
+            return unchanged.map(|_| ());
+        }
+    };
+
+    let problems = lint(&unit, &edited);
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "Not installing the drop-in for {}:\n  {}\nThe edited copy is kept in {:?}.",
+            unit,
+            problems.join("\n  "),
+            copy
+        ));
+    }
+    let has_settings = parse(&edited)?
+        .sections
+        .values()
+        .any(|options| !options.is_empty());
+    remove_copy(&copy);
+    if has_settings {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+        }
+        fs::write(&path, &edited).map_err(|e| MkserviceError::io(&path, e))?;
+        log::info!("Installed {:?}.", path);
+    } else if path.exists() {
+        fs::remove_file(&path).map_err(|e| MkserviceError::io(&path, e))?;
+        log::info!("Removed {:?}, it has no settings left.", path);
+    } else {
+        log::info!("No settings, not adding a drop-in for {}.", unit);
+        return Ok(());
+    }
+    Ok(daemon_reload(level)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(type_section("web.service").as_deref(), Some("Service"));
+        assert!(lint("web.service", &template("web.service")).is_empty());
+        let content = "[Service]\nEnvironment=DEBUG=1\nExecStart=\nExecStart=/usr/bin/web -v\n";
+        assert!(lint("web.service", content).is_empty());
+        assert!(lint("web.service", "[X-Deploy]\nOwner=ops\n").is_empty());
+        assert_eq!(
+            lint(
+                "web.service",
+                "[Timer]\nOnCalendar=daily\n[Service]\nMemory Max=1G\n"
+            ),
+            [
+                "\"Memory Max\" in [Service] isn't a directive name.",
+                "[Timer] isn't a section of web.service: Unit, Service, Install.",
+            ]
+        );
+        assert!(lint("backup.timer", "[Timer]\nOnCalendar=daily\n").is_empty());
+        assert_eq!(
+            lint("web.service", "Restart=always\n"),
+            ["Line 1: setting outside a section."]
+        );
+    }
+
+    #[test]
+    fn test_write_copy() {
+        use std::os::unix::fs::PermissionsExt;
+        let copy = write_copy("[Service]\n").unwrap();
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "[Service]\n");
+        let dir = copy.parent().unwrap();
+        let mode = fs::metadata(dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        remove_copy(&copy);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static JSON: OnceLock<()> = OnceLock::new();
+
+/// Reports errors as JSON for the rest of this run, see `to_json`.
+pub fn set_json() {
+    JSON.set(())
+        .expect("The error format is set once, at startup");
+}
+
+/// Whether errors are reported as JSON.
+pub fn json() -> bool {
+    JSON.get().is_some()
+}
+
+/// `error` as one line of JSON, for scripts: its message, kind, hint and
+/// exit code.
+pub fn to_json(error: &anyhow::Error) -> String {
+    let typed = error.downcast_ref::<MkserviceError>();
+    serde_json::json!({
+        "error": format!("{:#}", error),
+        "kind": typed.map_or("other", MkserviceError::kind),
+        "hint": typed.and_then(MkserviceError::hint),
+        "exit_code": typed.map_or(1, MkserviceError::exit_code),
+    })
+    .to_string()
+}
+
+/// Errors from service providers, each with a hint on how to fix it.
+#[derive(Debug)]
+pub enum MkserviceError {
+    PermissionDenied { path: PathBuf },
+    ProviderUnavailable { detected: String },
+    CommandNotFound { cmd: String },
+    CommandFailed { cmd: String, stderr: String },
+    Io(io::Error),
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = MkserviceError> = std::result::Result<T, E>;
+
+impl MkserviceError {
+    /// Classifies an I/O error on `path`.
+    pub fn io(path: &Path, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => MkserviceError::PermissionDenied {
+                path: path.to_path_buf(),
+            },
+            _ => MkserviceError::Io(error),
+        }
+    }
+
+    /// Classifies an error spawning `cmd`.
+    pub fn spawn(cmd: &str, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => MkserviceError::CommandNotFound { cmd: cmd.into() },
+            _ => MkserviceError::Io(error),
+        }
+    }
+
+    /// A stable name for the kind of error, for JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MkserviceError::PermissionDenied { .. } => "permission_denied",
+            MkserviceError::ProviderUnavailable { .. } => "provider_unavailable",
+            MkserviceError::CommandNotFound { .. } => "command_not_found",
+            MkserviceError::CommandFailed { .. } => "command_failed",
+            MkserviceError::Io(_) => "io",
+            MkserviceError::Other(_) => "other",
+        }
+    }
+
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            MkserviceError::PermissionDenied { .. } => {
+                Some("Run as root (e.g. with sudo), or install with --level user.".into())
+            }
+            MkserviceError::ProviderUnavailable { .. } => Some(
+                "mkservice supports systemd, OpenRC, runit and launchd. Check that one is running as init, or that you're not in a container without it.".into(),
+            ),
+            MkserviceError::CommandNotFound { cmd } => {
+                Some(format!("Install {} or add it to PATH.", cmd))
+            }
+            MkserviceError::CommandFailed { cmd, .. } => {
+                Some(format!("Run `{}` yourself to see the full output.", cmd))
+            }
+            MkserviceError::Io(_) | MkserviceError::Other(_) => None,
+        }
+    }
+
+    /// Process exit code for the error, following sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MkserviceError::PermissionDenied { .. } => 77,
+            MkserviceError::ProviderUnavailable { .. } => 69,
+            MkserviceError::CommandNotFound { .. } => 69,
+            MkserviceError::CommandFailed { .. } => 70,
+            MkserviceError::Io(_) => 74,
+            MkserviceError::Other(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for MkserviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MkserviceError::PermissionDenied { path } => {
+                write!(f, "Permission denied writing {:?}", path)
+            }
+            MkserviceError::ProviderUnavailable { detected } => {
+                write!(f, "Unknown service runtime ({})", detected)
+            }
+            MkserviceError::CommandNotFound { cmd } => write!(f, "{} not found", cmd),
+            MkserviceError::CommandFailed { cmd, stderr } => {
+                write!(f, "`{}` failed: {}", cmd, stderr.trim())
+            }
+            MkserviceError::Io(e) => e.fmt(f),
+            MkserviceError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MkserviceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MkserviceError::Io(e) => Some(e),
+            MkserviceError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MkserviceError {
+    fn from(error: io::Error) -> Self {
+        MkserviceError::Io(error)
+    }
+}
+
+impl From<anyhow::Error> for MkserviceError {
+    fn from(error: anyhow::Error) -> Self {
+        MkserviceError::Other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let error = anyhow::Error::new(MkserviceError::CommandFailed {
+            cmd: "systemctl enable web.service".into(),
+            stderr: "Unit web.service not found.\n".into(),
+        });
+        let json: serde_json::Value = serde_json::from_str(&to_json(&error)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": "`systemctl enable web.service` failed: Unit web.service not found.",
+                "kind": "command_failed",
+                "hint": "Run `systemctl enable web.service` yourself to see the full output.",
+                "exit_code": 70,
+            })
+        );
+
+        let error = anyhow::anyhow!("bad input").context("Reading web.yml");
+        let json: serde_json::Value = serde_json::from_str(&to_json(&error)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": "Reading web.yml: bad input",
+                "kind": "other",
+                "hint": null,
+                "exit_code": 1,
+            })
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Exporters package a managed service, its units and its programs, for
+//! deploying it some other way than installing it on this host.
+
+pub mod portable;
+pub mod sysext;
+
+use crate::command::find_executable;
+use crate::config::ServiceConfig;
+use crate::error::MkserviceError;
+use crate::provider::systemd::Systemd;
+use crate::ServiceOperator;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportTarget {
+    /// A portable service image, for `portablectl attach`.
+    PortableImage,
+    /// A system extension image, for `systemd-sysext merge`.
+    Sysext,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A directory tree.
+    Directory,
+    /// A squashfs image, built with mksquashfs.
+    Raw,
+}
+
+/// Exports `service` to `output`, by default named after the image in the
+/// current directory. Returns where it was written.
+pub fn export(
+    service: &ServiceConfig,
+    target: ExportTarget,
+    format: ImageFormat,
+    output: Option<&Path>,
+) -> Result<PathBuf> {
+    let name = image_name(service)?;
+    let output = match (output, format) {
+        (Some(output), _) => output.to_path_buf(),
+        (None, ImageFormat::Directory) => PathBuf::from(&name),
+        (None, ImageFormat::Raw) => PathBuf::from(format!("{}.raw", name)),
+    };
+    if output.symlink_metadata().is_ok() {
+        return Err(anyhow!("{:?} already exists.", output));
+    }
+    let tree = match format {
+        ImageFormat::Directory => output.clone(),
+        ImageFormat::Raw => {
+            env::temp_dir().join(format!("mkservice-export-{}", std::process::id()))
+        }
+    };
+    let built = match target {
+        ExportTarget::PortableImage => portable::build(service, &tree),
+        ExportTarget::Sysext => sysext::build(service, &tree, &name),
+    };
+    let built = built.and_then(|()| match format {
+        ImageFormat::Directory => Ok(()),
+        ImageFormat::Raw => squash(&tree, &output),
+    });
+    if built.is_err() || format == ImageFormat::Raw {
+        let _ = fs::remove_dir_all(&tree);
+    }
+    built.map(|()| output)
+}
+
+/// The image's name: the service unit's name, which portablectl requires
+/// the units' names to start with.
+pub fn image_name(service: &ServiceConfig) -> Result<String> {
+    let (path, _) = Systemd {
+        service: service.clone(),
+    }
+    .unit_files()?
+    .into_iter()
+    .next()
+    .expect("a service unit is always rendered");
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = file_name.trim_end_matches(".service").trim_end_matches('@');
+    Ok(stem.to_string())
+}
+
+/// Packs `tree` into a squashfs image at `output`, with everything owned by
+/// root as it would be installed.
+fn squash(tree: &Path, output: &Path) -> Result<()> {
+    let result = Command::new("mksquashfs")
+        .arg(tree)
+        .arg(output)
+        .args(["-noappend", "-quiet", "-all-root"])
+        .output()
+        .map_err(|e| MkserviceError::spawn("mksquashfs", e))?;
+    if !result.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: format!("mksquashfs {:?} {:?}", tree, output),
+            stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Paths of the shared libraries in `ldd` output, including the dynamic
+/// loader. The vDSO has no file and is left out.
+fn parse_ldd(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let path = line.split_once("=>").map_or(line, |(_, target)| target);
+            let path = path.split_whitespace().next()?;
+            path.starts_with('/').then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// The shared libraries `executable` loads, none if it's static or a script.
+fn libraries(executable: &Path) -> Vec<PathBuf> {
+    let output = match Command::new("ldd").arg(executable).output() {
+        Ok(output) if output.status.success() => output,
+        Ok(_) => return vec![],
+        Err(e) => {
+            log::warn!("Not copying libraries for {:?}: ldd: {}", executable, e);
+            return vec![];
+        }
+    };
+    let output = String::from_utf8_lossy(&output.stdout);
+    for line in output.lines().filter(|line| line.contains("not found")) {
+        log::warn!(
+            "{:?} needs a library ldd can't find: {}",
+            executable,
+            line.trim()
+        );
+    }
+    parse_ldd(&output)
+}
+
+/// The interpreter of a `#!` script, and the program `/usr/bin/env` would
+/// run with it.
+fn interpreters(executable: &Path) -> Vec<String> {
+    let Ok(content) = fs::read(executable) else {
+        return vec![];
+    };
+    let Some(line) = content.strip_prefix(b"#!") else {
+        return vec![];
+    };
+    let line = line.split(|b| *b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.split_whitespace();
+    let Some(interpreter) = words.next() else {
+        return vec![];
+    };
+    let mut found = vec![interpreter.to_string()];
+    if interpreter.ends_with("/env") {
+        found.extend(words.find(|word| !word.starts_with('-')).map(String::from));
+    }
+    found
+}
+
+/// Copies the programs the service runs into `root` at the same paths,
+/// along with their shared libraries and script interpreters if
+/// `dependencies`.
+pub fn copy_programs(service: &ServiceConfig, root: &Path, dependencies: bool) -> Result<()> {
+    let mut pending: Vec<String> = std::iter::once(&service.command)
+        .chain(&service.steps)
+        .filter_map(|command| command.first().cloned())
+        .collect();
+    let mut copied = BTreeSet::new();
+    while let Some(name) = pending.pop() {
+        let path = find_executable(&name)
+            .ok_or_else(|| anyhow!("{:?} not found, so it can't be exported.", name))?;
+        let path = path.canonicalize()?;
+        if !copied.insert(path.clone()) {
+            continue;
+        }
+        copy_into(root, &path)?;
+        if dependencies {
+            pending.extend(interpreters(&path));
+            for library in libraries(&path) {
+                copy_into(root, &library)?;
+            }
+        }
+        // Commands may name a symlink, e.g. /usr/bin/python3, keep it working.
+        let named = PathBuf::from(&name);
+        if named.is_absolute() && named != path {
+            copy_into(root, &named)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies `file`, following symlinks, to the same path under `root`.
+fn copy_into(root: &Path, file: &Path) -> Result<()> {
+    let target = root.join(file.strip_prefix("/").unwrap_or(file));
+    if target.exists() {
+        return Ok(());
+    }
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+    }
+    log::debug!("Copying {:?} to {:?}", file, target);
+    fs::copy(file, &target).map_err(|e| MkserviceError::io(&target, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ldd() {
+        let output = "\tlinux-vdso.so.1 (0x00007ffd4b5e9000)\n\
+            \tlibc.so.6 => /lib/x86_64-linux-gnu/libc.so.6 (0x00007f2a1c000000)\n\
+            \tlibgone.so.1 => not found\n\
+            \t/lib64/ld-linux-x86-64.so.2 (0x00007f2a1c400000)\n";
+        assert_eq!(
+            parse_ldd(output),
+            vec![
+                PathBuf::from("/lib/x86_64-linux-gnu/libc.so.6"),
+                PathBuf::from("/lib64/ld-linux-x86-64.so.2"),
+            ]
+        );
+    }
+}
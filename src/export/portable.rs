@@ -0,0 +1,120 @@
+//! Portable service images, attached with `portablectl attach`: a minimal
+//! root tree with the service's programs, its units, and an os-release.
+
+use super::{copy_programs, image_name};
+use crate::config::{ServiceConfig, ServiceLevel};
+use crate::error::MkserviceError;
+use crate::provider::systemd::Systemd;
+use crate::ServiceOperator;
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::Path;
+
+/// Mount points portablectl's profiles bind over, which must exist in the
+/// image.
+const MOUNT_POINTS: [&str; 6] = ["proc", "sys", "dev", "run", "tmp", "var/tmp"];
+
+/// The os-release portablectl needs to recognize the image.
+fn os_release(service: &ServiceConfig, image_name: &str) -> String {
+    format!(
+        "ID=mkservice\nPORTABLE_PRETTY_NAME={:?}\nPORTABLE_PREFIXES={}\n",
+        service.name, image_name
+    )
+}
+
+/// Builds the image tree for `service` at `root`.
+pub fn build(service: &ServiceConfig, root: &Path) -> Result<()> {
+    if service.level != ServiceLevel::System {
+        return Err(anyhow!("Portable services are system services."));
+    }
+    let mut service = service.clone();
+    if service.failure_mail.take().is_some() {
+        log::warn!("Leaving out the failure mail unit, mail(1) isn't in the image.");
+    }
+    if service.bus_name.take().is_some() {
+        log::warn!("Leaving out D-Bus activation, portablectl doesn't attach it.");
+    }
+    let name = image_name(&service)?;
+
+    let unit_dir = root.join("usr/lib/systemd/system");
+    for dir in MOUNT_POINTS
+        .iter()
+        .map(|dir| root.join(dir))
+        .chain([unit_dir.clone()])
+    {
+        fs::create_dir_all(&dir).map_err(|e| MkserviceError::io(&dir, e))?;
+    }
+    let etc = root.join("etc");
+    fs::create_dir_all(&etc).map_err(|e| MkserviceError::io(&etc, e))?;
+    for file in ["machine-id", "resolv.conf"] {
+        let path = etc.join(file);
+        fs::write(&path, "").map_err(|e| MkserviceError::io(&path, e))?;
+    }
+    let os_release_path = root.join("usr/lib/os-release");
+    fs::write(&os_release_path, os_release(&service, &name))
+        .map_err(|e| MkserviceError::io(&os_release_path, e))?;
+
+    copy_programs(&service, root, true)?;
+    if let Some(dir) = &service.working_directory {
+        let dir = root.join(dir.trim_start_matches('/'));
+        fs::create_dir_all(&dir).map_err(|e| MkserviceError::io(&dir, e))?;
+    }
+    for (path, content) in (Systemd { service }).unit_files()? {
+        let path = unit_dir.join(path.file_name().unwrap_or_default());
+        fs::write(&path, content).map_err(|e| MkserviceError::io(&path, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TimerConfig;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_build() {
+        let dir = env::temp_dir().join(format!("mkservice-portable-{}", std::process::id()));
+        let script = dir.join("report");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&script, "#!/bin/sh\necho ok\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let service = ServiceConfig {
+            name: "report".into(),
+            command: vec![script.to_string_lossy().into_owned()],
+            timer: Some(TimerConfig {
+                on_calendar: vec!["daily".into()],
+                ..Default::default()
+            }),
+            failure_mail: Some("ops@example.com".into()),
+            ..Default::default()
+        };
+        let root = dir.join("image");
+        let built = build(&service, &root);
+        let script = script.to_string_lossy();
+        let missing: Vec<&str> = [
+            &script[1..],
+            "bin/sh",
+            "usr/lib/systemd/system/report.service",
+            "usr/lib/systemd/system/report.timer",
+            "etc/machine-id",
+            "var/tmp",
+        ]
+        .into_iter()
+        .filter(|path| !root.join(path).exists())
+        .collect();
+        let mail_unit = root.join("usr/lib/systemd/system/report-failure-mail.service");
+        let mail_unit = mail_unit.exists();
+        let os_release = fs::read_to_string(root.join("usr/lib/os-release"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        built.unwrap();
+        assert!(missing.is_empty(), "missing {:?}", missing);
+        assert!(!mail_unit);
+        assert_eq!(
+            os_release.unwrap(),
+            "ID=mkservice\nPORTABLE_PRETTY_NAME=\"report\"\nPORTABLE_PREFIXES=report\n"
+        );
+    }
+}
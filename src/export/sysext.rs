@@ -0,0 +1,153 @@
+//! System extension images, merged over /usr with `systemd-sysext merge`,
+//! for hosts where /usr is read-only. An extension only carries /usr and
+//! /opt, and its programs run on the host's own libraries.
+
+use super::copy_programs;
+use crate::command::find_executable;
+use crate::config::{ServiceConfig, ServiceLevel};
+use crate::error::MkserviceError;
+use crate::provider::systemd::Systemd;
+use crate::ServiceOperator;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+/// Where the extension's units go, once merged.
+const UNIT_DIR: &str = "/usr/lib/systemd/system";
+
+fn in_extension(path: &Path) -> bool {
+    path.starts_with("/usr") || path.starts_with("/opt")
+}
+
+/// An extension-release for the host's `os_release`, so the extension only
+/// merges on the same OS release, by SYSEXT_LEVEL if it has one, otherwise
+/// VERSION_ID.
+fn extension_release(os_release: &str) -> String {
+    let fields: BTreeMap<&str, &str> = os_release
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+    let mut release = format!("ID={}\n", fields.get("ID").unwrap_or(&"_any"));
+    let level = ["SYSEXT_LEVEL", "VERSION_ID"]
+        .into_iter()
+        .find_map(|key| Some((key, fields.get(key)?)));
+    if let Some((key, value)) = level {
+        release += &format!("{}={}\n", key, value);
+    }
+    release
+}
+
+/// Builds the extension tree for `service` at `root`, as extension `name`.
+pub fn build(service: &ServiceConfig, root: &Path, name: &str) -> Result<()> {
+    if service.level != ServiceLevel::System {
+        return Err(anyhow!("System extensions carry system services."));
+    }
+    for command in std::iter::once(&service.command).chain(&service.steps) {
+        let Some(program) = command.first() else {
+            continue;
+        };
+        let path = find_executable(program)
+            .ok_or_else(|| anyhow!("{:?} not found, so it can't be exported.", program))?;
+        if !in_extension(&path) {
+            return Err(anyhow!(
+                "{:?} isn't under /usr or /opt, which are all a system extension carries.",
+                path
+            ));
+        }
+    }
+    copy_programs(service, root, false)?;
+
+    let systemd = Systemd {
+        service: service.clone(),
+    };
+    let files = systemd.unit_files()?;
+    let host_unit_dir = files[0].0.parent().map(Path::to_path_buf);
+    let unit_dir = root.join(UNIT_DIR.trim_start_matches('/'));
+    fs::create_dir_all(&unit_dir).map_err(|e| MkserviceError::io(&unit_dir, e))?;
+    for (path, content) in files {
+        let target = match path.parent() == host_unit_dir.as_deref() {
+            true => unit_dir.join(path.file_name().unwrap_or_default()),
+            false if in_extension(&path) => root.join(path.strip_prefix("/").unwrap_or(&path)),
+            false => {
+                log::warn!("Leaving out {:?}, outside /usr and /opt.", path);
+                continue;
+            }
+        };
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+        }
+        fs::write(&target, content).map_err(|e| MkserviceError::io(&target, e))?;
+    }
+    // /etc isn't merged, so the extension enables its own units.
+    for (link, file_name) in systemd.wants_links() {
+        let link = unit_dir.join(link);
+        if let Some(dir) = link.parent() {
+            fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+        }
+        symlink(Path::new(UNIT_DIR).join(file_name), &link)
+            .map_err(|e| MkserviceError::io(&link, e))?;
+    }
+
+    let release_dir = root.join("usr/lib/extension-release.d");
+    fs::create_dir_all(&release_dir).map_err(|e| MkserviceError::io(&release_dir, e))?;
+    let os_release = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+        .unwrap_or_default();
+    let release_path = release_dir.join(format!("extension-release.{}", name));
+    fs::write(&release_path, extension_release(&os_release))
+        .map_err(|e| MkserviceError::io(&release_path, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_extension_release() {
+        assert_eq!(
+            extension_release("NAME=\"Fedora Linux\"\nID=fedora\nVERSION_ID=40\n"),
+            "ID=fedora\nVERSION_ID=40\n"
+        );
+        assert_eq!(
+            extension_release("ID=flatcar\nVERSION_ID=3815.2.0\nSYSEXT_LEVEL=1.0\n"),
+            "ID=flatcar\nSYSEXT_LEVEL=1.0\n"
+        );
+        assert_eq!(extension_release(""), "ID=_any\n");
+    }
+
+    #[test]
+    fn test_build() {
+        let root = env::temp_dir().join(format!("mkservice-sysext-{}", std::process::id()));
+        let service = ServiceConfig {
+            name: "clock".into(),
+            command: vec!["/usr/bin/env".into(), "date".into()],
+            ..Default::default()
+        };
+        let built = build(&service, &root, "clock");
+        let link = fs::read_link(
+            root.join("usr/lib/systemd/system/multi-user.target.wants/clock.service"),
+        );
+        let copied = root.join("usr/bin/env").exists();
+        let unit = root.join("usr/lib/systemd/system/clock.service").exists();
+        let release = root
+            .join("usr/lib/extension-release.d/extension-release.clock")
+            .exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        built.unwrap();
+        assert_eq!(
+            link.unwrap(),
+            Path::new("/usr/lib/systemd/system/clock.service")
+        );
+        assert!(copied && unit && release);
+        let service = ServiceConfig {
+            command: vec!["/bin/sh".into()],
+            ..service
+        };
+        assert!(build(&service, &root, "clock").is_err());
+    }
+}
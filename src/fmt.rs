@@ -0,0 +1,375 @@
+//! `mkservice fmt`: unit files laid out one way, so units kept in git diff
+//! cleanly, and checked as `mkservice edit` checks drop-ins.
+
+use crate::drift::unified_diff;
+use crate::edit::lint;
+use crate::error::MkserviceError;
+use crate::output;
+use crate::unit::parse;
+use crate::which::unit_name;
+use anyhow::Result;
+use std::fs;
+use std::path::PathBuf;
+
+/// Sections by their usual spelling, for fixing up their case.
+const SECTIONS: [&str; 11] = [
+    "Unit",
+    "Service",
+    "Socket",
+    "Timer",
+    "Path",
+    "Mount",
+    "Automount",
+    "Swap",
+    "Slice",
+    "Scope",
+    "Install",
+];
+
+/// Common directives by their usual spelling, for fixing up their case.
+/// Others are left as they're written.
+const DIRECTIVES: &[&str] = &[
+    // [Unit]
+    "Description",
+    "Documentation",
+    "Requires",
+    "Requisite",
+    "Wants",
+    "BindsTo",
+    "PartOf",
+    "Upholds",
+    "Conflicts",
+    "Before",
+    "After",
+    "OnFailure",
+    "OnSuccess",
+    "RequiresMountsFor",
+    "StopWhenUnneeded",
+    "RefuseManualStart",
+    "RefuseManualStop",
+    "DefaultDependencies",
+    "StartLimitIntervalSec",
+    "StartLimitBurst",
+    "ConditionPathExists",
+    "ConditionFirstBoot",
+    "ConditionHost",
+    "AssertPathExists",
+    // [Install]
+    "Alias",
+    "WantedBy",
+    "RequiredBy",
+    "Also",
+    "DefaultInstance",
+    // [Service]
+    "Type",
+    "RemainAfterExit",
+    "PIDFile",
+    "BusName",
+    "ExecStart",
+    "ExecStartPre",
+    "ExecStartPost",
+    "ExecCondition",
+    "ExecReload",
+    "ExecStop",
+    "ExecStopPost",
+    "Restart",
+    "RestartSec",
+    "RestartPreventExitStatus",
+    "SuccessExitStatus",
+    "TimeoutSec",
+    "TimeoutStartSec",
+    "TimeoutStopSec",
+    "RuntimeMaxSec",
+    "WatchdogSec",
+    "NotifyAccess",
+    "Sockets",
+    "FileDescriptorStoreMax",
+    "KillMode",
+    "KillSignal",
+    "WorkingDirectory",
+    "RootDirectory",
+    "User",
+    "Group",
+    "DynamicUser",
+    "SupplementaryGroups",
+    "Environment",
+    "EnvironmentFile",
+    "PassEnvironment",
+    "UnsetEnvironment",
+    "UMask",
+    "Nice",
+    "LimitNOFILE",
+    "LimitNPROC",
+    "StandardInput",
+    "StandardOutput",
+    "StandardError",
+    "SyslogIdentifier",
+    "TTYPath",
+    "NoNewPrivileges",
+    "ProtectSystem",
+    "ProtectHome",
+    "PrivateTmp",
+    "PrivateDevices",
+    "PrivateNetwork",
+    "ProtectKernelTunables",
+    "ProtectKernelModules",
+    "ProtectControlGroups",
+    "RestrictNamespaces",
+    "RestrictRealtime",
+    "RestrictSUIDSGID",
+    "RestrictAddressFamilies",
+    "LockPersonality",
+    "MemoryDenyWriteExecute",
+    "SystemCallFilter",
+    "CapabilityBoundingSet",
+    "AmbientCapabilities",
+    "ReadWritePaths",
+    "ReadOnlyPaths",
+    "StateDirectory",
+    "CacheDirectory",
+    "LogsDirectory",
+    "RuntimeDirectory",
+    "ConfigurationDirectory",
+    "LoadCredential",
+    "SetCredentialEncrypted",
+    "MemoryMax",
+    "CPUQuota",
+    "TasksMax",
+    "Slice",
+    "IPAddressAllow",
+    "IPAddressDeny",
+    // [Timer]
+    "OnCalendar",
+    "OnActiveSec",
+    "OnBootSec",
+    "OnUnitActiveSec",
+    "OnUnitInactiveSec",
+    "AccuracySec",
+    "RandomizedDelaySec",
+    "Persistent",
+    "Unit",
+    // [Socket]
+    "ListenStream",
+    "ListenDatagram",
+    "Accept",
+    "BindToDevice",
+    "SocketUser",
+    "SocketMode",
+    "FileDescriptorName",
+    "Service",
+    // [Path]
+    "PathExists",
+    "PathChanged",
+    "PathModified",
+    "DirectoryNotEmpty",
+];
+
+/// `name` spelled as in `known`, if it's there in another case.
+fn canonical(known: &[&str], name: &str) -> String {
+    known
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(name))
+        .map_or_else(|| name.to_string(), |known| known.to_string())
+}
+
+/// Where a section goes: [Unit] first, the unit type's own, [Install],
+/// then those for other programs.
+fn rank(section: &str) -> u8 {
+    match section {
+        "Unit" => 0,
+        "Install" => 2,
+        _ if section.starts_with("X-") => 3,
+        _ => 1,
+    }
+}
+
+fn is_comment(line: &str) -> bool {
+    line.starts_with('#') || line.starts_with(';')
+}
+
+/// A section, with the comments just above its header.
+#[derive(Default)]
+struct Section {
+    name: String,
+    comments: Vec<String>,
+    lines: Vec<String>,
+}
+
+/// Pushes `lines` with leading, trailing and repeated blank lines dropped.
+fn push_lines(out: &mut Vec<String>, lines: &[String]) {
+    let start = out.len();
+    let mut blank = true;
+    for line in lines {
+        if line.is_empty() && blank {
+            continue;
+        }
+        blank = line.is_empty();
+        out.push(line.clone());
+    }
+    if out.len() > start && blank {
+        out.pop();
+    }
+}
+
+/// `content` laid out one way: sections in order, with repeats merged,
+/// sections and common directives spelled as systemd does, no space around
+/// `=` or at the ends of lines, and single blank lines.
+pub fn format(content: &str) -> String {
+    // The preamble: comments above the first section.
+    let mut sections = vec![Section::default()];
+    let mut current = 0;
+    let mut continued = false;
+    for line in content.lines() {
+        let line = line.trim_end();
+        if continued {
+            // Continuation lines keep their indentation.
+            continued = line.ends_with('\\');
+            sections[current].lines.push(line.to_string());
+            continue;
+        }
+        let line = line.trim_start();
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            let name = canonical(&SECTIONS, name.trim());
+            // Comments right above a header go along with it, unless
+            // they're all there is above the first, the file's own.
+            let lines = &mut sections[current].lines;
+            let above = lines
+                .iter()
+                .rev()
+                .take_while(|line| is_comment(line))
+                .count();
+            let comments = match current > 0 || above < lines.len() {
+                true => lines.split_off(lines.len() - above),
+                false => Vec::new(),
+            };
+            current = match sections.iter().skip(1).position(|s| s.name == name) {
+                Some(i) => {
+                    sections[i + 1]
+                        .lines
+                        .extend([String::new()].into_iter().chain(comments));
+                    i + 1
+                }
+                None => {
+                    sections.push(Section {
+                        name,
+                        comments,
+                        lines: Vec::new(),
+                    });
+                    sections.len() - 1
+                }
+            };
+            continue;
+        }
+        let line = match line.split_once('=') {
+            Some((key, value)) if !is_comment(line) => {
+                continued = value.ends_with('\\');
+                format!(
+                    "{}={}",
+                    canonical(DIRECTIVES, key.trim()),
+                    value.trim_start()
+                )
+            }
+            _ => line.to_string(),
+        };
+        sections[current].lines.push(line);
+    }
+
+    let preamble = sections.remove(0);
+    sections.sort_by_key(|section| rank(&section.name));
+    let mut out = Vec::new();
+    push_lines(&mut out, &preamble.lines);
+    for section in sections {
+        if !out.is_empty() {
+            out.push(String::new());
+        }
+        push_lines(&mut out, &section.comments);
+        out.push(format!("[{}]", section.name));
+        push_lines(&mut out, &section.lines);
+    }
+    out.into_iter().map(|line| line + "\n").collect()
+}
+
+/// Formats each of `paths` in place, or with `check` shows how it would
+/// change instead. Invalid units are reported and left alone. Returns
+/// whether all were valid and, with `check`, already formatted.
+pub fn run(paths: &[PathBuf], check: bool) -> Result<bool> {
+    let color = output::stdout_color();
+    let mut clean = true;
+    for path in paths {
+        let content = fs::read_to_string(path).map_err(|e| MkserviceError::io(path, e))?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let formatted = format(&content);
+        // Line numbers are the original's.
+        let problems = match parse(&content) {
+            Ok(_) => lint(&unit_name(&file_name), &formatted),
+            Err(e) => vec![e.to_string()],
+        };
+        if !problems.is_empty() {
+            clean = false;
+            for problem in problems {
+                let path = path.to_string_lossy();
+                println!("{}", output::paint_problem(&path, &problem, color));
+            }
+            continue;
+        }
+        if formatted == content {
+            continue;
+        }
+        if check {
+            clean = false;
+            let diff = unified_diff(path, ("original", &content), ("formatted", &formatted));
+            print!("{}", output::paint_diff(&diff, color));
+        } else {
+            fs::write(path, &formatted).map_err(|e| MkserviceError::io(path, e))?;
+            log::info!("Formatted {:?}.", path);
+        }
+    }
+    Ok(clean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let content = "# Managed by hand\n\
+            \n\
+            # How it's installed.\n\
+            [install]\n\
+            wantedby = multi-user.target  \n\
+            [Service]\n\
+            execstart=/usr/bin/web \\\n    --port 8080\n\
+            \n\
+            \n\
+            X-Owner=ops\n\
+            [Unit]\n\
+            Description=Web\n\
+            [Service]\n\
+            Restart=always\n";
+        assert_eq!(
+            format(content),
+            "# Managed by hand\n\
+            \n\
+            [Unit]\n\
+            Description=Web\n\
+            \n\
+            [Service]\n\
+            ExecStart=/usr/bin/web \\\n    --port 8080\n\
+            \n\
+            X-Owner=ops\n\
+            \n\
+            Restart=always\n\
+            \n\
+            # How it's installed.\n\
+            [Install]\n\
+            WantedBy=multi-user.target\n"
+        );
+        assert_eq!(format(&format(content)), format(content));
+        assert_eq!(
+            format("# Managed by mkservice\n[Service]\nType=simple\n[Unit]\nDescription=web\n"),
+            "# Managed by mkservice\n\n[Unit]\nDescription=web\n\n[Service]\nType=simple\n"
+        );
+        assert_eq!(format(""), "");
+    }
+}
@@ -0,0 +1,113 @@
+//! Reads a container image's invocation, for running the same program
+//! natively instead of in a container.
+
+use crate::command::find_executable;
+use crate::error::MkserviceError;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct ImageConfig {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub env: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Inspect {
+    config: ImageConfig,
+}
+
+impl ImageConfig {
+    /// The command the container would run. Like `docker run IMAGE ARGS...`,
+    /// a non-empty `args` replaces the image's CMD.
+    pub fn command(&self, args: &[String]) -> Vec<String> {
+        let cmd = match args {
+            [] => self.cmd.clone().unwrap_or_default(),
+            _ => args.to_vec(),
+        };
+        self.entrypoint
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .chain(cmd)
+            .collect()
+    }
+
+    /// The image's environment. PATH is left out, it describes the image's
+    /// filesystem rather than the host's.
+    pub fn env(&self) -> BTreeMap<String, String> {
+        self.env
+            .iter()
+            .flatten()
+            .filter_map(|var| var.split_once('='))
+            .filter(|(key, _)| *key != "PATH")
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+}
+
+/// Inspects `image` with docker, or podman if docker isn't installed. The
+/// image must have been pulled already.
+pub fn inspect(image: &str) -> Result<ImageConfig> {
+    let tool = ["docker", "podman"]
+        .into_iter()
+        .find(|tool| find_executable(tool).is_some())
+        .ok_or_else(|| MkserviceError::CommandNotFound {
+            cmd: "docker or podman".into(),
+        })?;
+    let output = Command::new(tool)
+        .args(["image", "inspect", image])
+        .output()
+        .map_err(|e| MkserviceError::spawn(tool, e))?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: format!("{} image inspect {}", tool, image),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .into());
+    }
+    let mut inspected: Vec<Inspect> = serde_json::from_slice(&output.stdout)?;
+    if inspected.is_empty() {
+        return Err(anyhow!("{} found no image {:?}.", tool, image));
+    }
+    Ok(inspected.remove(0).config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_config() {
+        let inspected: Vec<Inspect> = serde_json::from_str(
+            r#"[{"Id": "sha256:abc", "Config": {
+                "Entrypoint": ["/usr/bin/tini", "--"],
+                "Cmd": ["/app/server", "--port", "80"],
+                "Env": ["PATH=/usr/local/bin:/usr/bin", "PORT=80"],
+                "WorkingDir": "/app"
+            }}]"#,
+        )
+        .unwrap();
+        let config = &inspected[0].config;
+        assert_eq!(
+            config.command(&[]),
+            vec!["/usr/bin/tini", "--", "/app/server", "--port", "80"]
+        );
+        assert_eq!(
+            config.command(&["/app/worker".to_string()]),
+            vec!["/usr/bin/tini", "--", "/app/worker"]
+        );
+        assert_eq!(
+            config.env().into_iter().collect::<Vec<_>>(),
+            vec![("PORT".to_string(), "80".to_string())]
+        );
+        assert_eq!(config.user, None);
+    }
+}
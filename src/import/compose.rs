@@ -0,0 +1,366 @@
+//! docker-compose files: each compose service becomes a service running its
+//! container in the foreground with podman, or docker without it.
+
+use crate::command::find_executable;
+use crate::config::ServiceConfig;
+use crate::error::MkserviceError;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Words {
+    /// Split like a shell would, without running one.
+    Line(String),
+    List(Vec<String>),
+}
+
+impl Words {
+    fn into_vec(self) -> Result<Vec<String>> {
+        match self {
+            Words::Line(line) => split_words(&line),
+            Words::List(words) => Ok(words),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Environment {
+    Map(BTreeMap<String, Option<Value>>),
+    List(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DependsOn {
+    List(Vec<String>),
+    /// The long syntax, keyed by service, with conditions that are ignored.
+    Map(BTreeMap<String, Value>),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ComposeService {
+    image: Option<String>,
+    entrypoint: Option<Words>,
+    command: Option<Words>,
+    environment: Option<Environment>,
+    env_file: Option<Words>,
+    ports: Vec<Value>,
+    volumes: Vec<Value>,
+    restart: Option<String>,
+    depends_on: Option<DependsOn>,
+}
+
+#[derive(Deserialize)]
+struct Compose {
+    services: BTreeMap<String, ComposeService>,
+}
+
+/// Splits `line` into words, honoring single and double quotes and
+/// backslash escapes.
+pub fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("Unterminated ' in {:?}", line)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("Unterminated \" in {:?}", line)),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Converts compose's restart policy to systemd's.
+fn restart_policy(restart: &str) -> Result<&'static str> {
+    match restart {
+        "no" => Ok("no"),
+        "always" | "unless-stopped" => Ok("always"),
+        _ if restart.starts_with("on-failure") => Ok("on-failure"),
+        _ => Err(anyhow!("Unknown restart policy {:?}", restart)),
+    }
+}
+
+/// Orders `services` so each comes after the services it depends on.
+fn dependency_order(services: &BTreeMap<String, Vec<String>>) -> Result<Vec<String>> {
+    fn visit(
+        name: &str,
+        services: &BTreeMap<String, Vec<String>>,
+        visiting: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<()> {
+        if order.iter().any(|done| done == name) {
+            return Ok(());
+        }
+        if visiting.iter().any(|v| v == name) {
+            return Err(anyhow!("Dependency cycle through {:?}.", name));
+        }
+        let dependencies = services
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown service {:?} in depends_on.", name))?;
+        visiting.push(name.to_string());
+        for dependency in dependencies {
+            visit(dependency, services, visiting, order)?;
+        }
+        visiting.pop();
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    for name in services.keys() {
+        visit(name, services, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+fn convert(
+    name: &str,
+    service: ComposeService,
+    runtime: &str,
+    dir: &Path,
+) -> Result<ServiceConfig> {
+    let image = service.image.ok_or_else(|| {
+        anyhow!(
+            "{:?} has no image, build and tag it, then set image: in the compose file.",
+            name
+        )
+    })?;
+    let mut command = vec![runtime.to_string(), "run".into(), "--rm".into()];
+    let mut env = BTreeMap::new();
+    match service.environment {
+        Some(Environment::Map(vars)) => {
+            for (key, value) in vars {
+                env.insert(key, value.as_ref().and_then(scalar).unwrap_or_default());
+            }
+        }
+        Some(Environment::List(vars)) => {
+            for var in vars {
+                let (key, value) = var.split_once('=').unwrap_or((&var, ""));
+                env.insert(key.to_string(), value.to_string());
+            }
+        }
+        None => {}
+    }
+    // The values stay visible in the unit; the runtime passes them through.
+    for key in env.keys() {
+        command.extend(["--env".to_string(), key.clone()]);
+    }
+    let env_files = match service.env_file {
+        Some(Words::Line(path)) => vec![path],
+        Some(Words::List(paths)) => paths,
+        None => vec![],
+    };
+    for env_file in env_files {
+        command.extend(["--env-file".to_string(), resolve(dir, &env_file)]);
+    }
+    for port in &service.ports {
+        let port = scalar(port)
+            .ok_or_else(|| anyhow!("{:?}: only short port syntax is supported.", name))?;
+        command.extend(["--publish".to_string(), port]);
+    }
+    for volume in &service.volumes {
+        let volume = scalar(volume)
+            .ok_or_else(|| anyhow!("{:?}: only short volume syntax is supported.", name))?;
+        let volume = match volume.split_once(':') {
+            Some((source, rest)) => format!("{}:{}", resolve(dir, source), rest),
+            None => volume,
+        };
+        command.extend(["--volume".to_string(), volume]);
+    }
+    if let Some(entrypoint) = service.entrypoint.map(Words::into_vec).transpose()? {
+        // docker run takes the entrypoint's first word, the rest go after the image.
+        let mut entrypoint = entrypoint.into_iter();
+        if let Some(program) = entrypoint.next() {
+            command.extend(["--entrypoint".to_string(), program]);
+        }
+        command.push(image);
+        command.extend(entrypoint);
+    } else {
+        command.push(image);
+    }
+    command.extend(
+        service
+            .command
+            .map(Words::into_vec)
+            .transpose()?
+            .unwrap_or_default(),
+    );
+
+    let mut config = ServiceConfig {
+        name: name.to_string(),
+        command,
+        env,
+        depends_on: match service.depends_on {
+            Some(DependsOn::List(names)) => names,
+            Some(DependsOn::Map(names)) => names.into_keys().collect(),
+            None => vec![],
+        },
+        ..Default::default()
+    };
+    if let Some(restart) = &service.restart {
+        config
+            .unit_options
+            .entry("Service".into())
+            .or_default()
+            .insert("Restart".into(), vec![restart_policy(restart)?.into()]);
+    }
+    Ok(config)
+}
+
+/// Relative bind mount sources and env files are relative to the compose
+/// file. Named volumes are left alone.
+fn resolve(dir: &Path, path: &str) -> String {
+    if let Some(relative) = path.strip_prefix("./") {
+        dir.join(relative).to_string_lossy().into_owned()
+    } else if path.starts_with("../") || path == "." {
+        dir.join(path).to_string_lossy().into_owned()
+    } else {
+        path.to_string()
+    }
+}
+
+/// A service per compose service, running with `runtime`, in dependency
+/// order. Relative paths are relative to `dir`.
+pub fn parse(content: &str, runtime: &str, dir: &Path) -> Result<Vec<ServiceConfig>> {
+    let compose: Compose = serde_yaml::from_str(content)?;
+    let mut services = BTreeMap::new();
+    for (name, service) in compose.services {
+        services.insert(name.clone(), convert(&name, service, runtime, dir)?);
+    }
+    let dependencies = services
+        .iter()
+        .map(|(name, service)| (name.clone(), service.depends_on.clone()))
+        .collect();
+    Ok(dependency_order(&dependencies)?
+        .into_iter()
+        .filter_map(|name| services.remove(&name))
+        .collect())
+}
+
+/// A service per compose service, in dependency order.
+pub fn import(path: &Path) -> Result<Vec<ServiceConfig>> {
+    let path = path.canonicalize()?;
+    let dir = path.parent().expect("a file has a parent directory");
+    let runtime = ["podman", "docker"]
+        .into_iter()
+        .find_map(find_executable)
+        .ok_or_else(|| MkserviceError::CommandNotFound {
+            cmd: "podman or docker".into(),
+        })?;
+    if runtime.ends_with("docker") {
+        log::warn!(
+            "Using docker: stopping the service stops the client, the container may keep running. podman doesn't have this problem."
+        );
+    }
+    parse(&fs::read_to_string(&path)?, &runtime.to_string_lossy(), dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words(r#"sh -c 'echo "hi there"' a\ b "x\"y""#).unwrap(),
+            vec!["sh", "-c", "echo \"hi there\"", "a b", "x\"y"]
+        );
+        assert!(split_words("echo 'oops").is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        let compose: Compose = serde_yaml::from_str(
+            r#"
+services:
+  web:
+    image: nginx:1.25
+    command: nginx -g 'daemon off;'
+    environment:
+      TZ: UTC
+    ports: ["8080:80"]
+    volumes: ["./html:/usr/share/nginx/html:ro", "cache:/var/cache/nginx"]
+    restart: unless-stopped
+    depends_on: [db]
+  db:
+    image: postgres:16
+"#,
+        )
+        .unwrap();
+        let mut services = compose.services;
+        let web = services.remove("web").unwrap();
+        let config = convert("web", web, "/usr/bin/podman", Path::new("/srv/app")).unwrap();
+        assert_eq!(
+            config.command,
+            vec![
+                "/usr/bin/podman",
+                "run",
+                "--rm",
+                "--env",
+                "TZ",
+                "--publish",
+                "8080:80",
+                "--volume",
+                "/srv/app/html:/usr/share/nginx/html:ro",
+                "--volume",
+                "cache:/var/cache/nginx",
+                "nginx:1.25",
+                "nginx",
+                "-g",
+                "daemon off;",
+            ]
+        );
+        assert_eq!(config.env["TZ"], "UTC");
+        assert_eq!(config.depends_on, vec!["db"]);
+        assert_eq!(config.unit_options["Service"]["Restart"], vec!["always"]);
+
+        let dependencies = BTreeMap::from([
+            ("web".to_string(), vec!["db".to_string()]),
+            ("db".to_string(), vec![]),
+            ("app".to_string(), vec!["web".to_string()]),
+        ]);
+        assert_eq!(
+            dependency_order(&dependencies).unwrap(),
+            vec!["db", "web", "app"]
+        );
+    }
+}
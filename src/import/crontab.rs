@@ -0,0 +1,213 @@
+//! Crontabs: each job becomes a oneshot service triggered by a timer.
+
+use crate::config::{ServiceConfig, ServiceType, TimerConfig};
+use crate::error::MkserviceError;
+use crate::schedule;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// A crontab job, with the environment lines that came before it.
+#[derive(Debug, PartialEq)]
+pub struct CronJob {
+    pub schedule: String,
+    /// The user field of system crontabs.
+    pub user: Option<String>,
+    pub command: String,
+    pub env: BTreeMap<String, String>,
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+/// Parses a crontab. System crontabs, like /etc/crontab and /etc/cron.d/*,
+/// have a user field after the schedule.
+pub fn parse(content: &str, system: bool) -> Result<Vec<CronJob>> {
+    let re_env = Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)\s*=(.*)$").expect("Bad regex");
+    let re_job = Regex::new(r"^(@\w+|(?:\S+\s+){4}\S+)\s+(.+)$").expect("Bad regex");
+    let re_user = Regex::new(r"^(\S+)\s+(.+)$").expect("Bad regex");
+    let mut env = BTreeMap::new();
+    let mut jobs = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(captures) = re_env.captures(line) {
+            env.insert(captures[1].to_string(), unquote(&captures[2]).to_string());
+            continue;
+        }
+        let invalid = || anyhow!("Line {}: can't parse {:?}.", number + 1, line);
+        let captures = re_job.captures(line).ok_or_else(invalid)?;
+        let (user, command) = match system {
+            true => {
+                let rest = re_user.captures(&captures[2]).ok_or_else(invalid)?;
+                (Some(rest[1].to_string()), rest[2].to_string())
+            }
+            false => (None, captures[2].to_string()),
+        };
+        jobs.push(CronJob {
+            schedule: captures[1].to_string(),
+            user,
+            command,
+            env: env.clone(),
+        });
+    }
+    Ok(jobs)
+}
+
+/// A service name from the program the command runs, e.g. "backup" for
+/// "/usr/local/bin/backup.sh --full".
+fn job_name(command: &str) -> String {
+    let program = command.split_whitespace().next().unwrap_or_default();
+    let stem = Path::new(program)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name: String = stem
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                true => c,
+                false => '-',
+            },
+        )
+        .collect();
+    match name.trim_matches('-') {
+        "" => "cron".into(),
+        name => name.into(),
+    }
+}
+
+/// Converts a job. Its schedule becomes a timer, except "@reboot" jobs,
+/// which run at boot.
+pub fn convert(job: CronJob) -> Result<ServiceConfig> {
+    // In cron, an unescaped % ends the command and starts its stdin.
+    let re_percent = Regex::new(r"(^|[^\\])%").expect("Bad regex");
+    if re_percent.is_match(&job.command) {
+        return Err(anyhow!(
+            "{:?} feeds stdin with %, move that into a script.",
+            job.command
+        ));
+    }
+    let command = job.command.replace("\\%", "%");
+    let mut env = job.env;
+    let shell = env.remove("SHELL").unwrap_or_else(|| "/bin/sh".into());
+    let failure_mail = env.remove("MAILTO").filter(|address| !address.is_empty());
+    let timer = match job.schedule.as_str() {
+        "@reboot" => None,
+        schedule => Some(TimerConfig {
+            on_calendar: vec![schedule::cron_to_calendar(schedule)?],
+            ..Default::default()
+        }),
+    };
+    Ok(ServiceConfig {
+        name: job_name(&command),
+        command: vec![shell, "-c".into(), command],
+        service_type: ServiceType::Oneshot,
+        env,
+        timer,
+        user: job.user.filter(|user| user != "root"),
+        failure_mail,
+        ..Default::default()
+    })
+}
+
+/// Reads the crontab at `file`, in system format, or else `user`'s (or the
+/// current user's) with `crontab -l`.
+pub fn read(file: Option<&Path>, user: Option<&str>) -> Result<Vec<CronJob>> {
+    if let Some(file) = file {
+        return parse(&fs::read_to_string(file)?, true);
+    }
+    let mut crontab = Command::new("crontab");
+    crontab.arg("-l");
+    if let Some(user) = user {
+        crontab.args(["-u", user]);
+    }
+    let output = crontab
+        .output()
+        .map_err(|e| MkserviceError::spawn("crontab", e))?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: "crontab -l".into(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .into());
+    }
+    let mut jobs = parse(&String::from_utf8_lossy(&output.stdout), false)?;
+    for job in jobs.iter_mut() {
+        job.user = user.map(String::from);
+    }
+    Ok(jobs)
+}
+
+/// Converts every job, giving jobs running the same program distinct names.
+pub fn import(jobs: Vec<CronJob>) -> Result<Vec<ServiceConfig>> {
+    let mut services: Vec<ServiceConfig> = Vec::new();
+    for job in jobs {
+        let mut service = convert(job)?;
+        let base = service.name.clone();
+        let mut n = 1;
+        while services.iter().any(|other| other.name == service.name) {
+            n += 1;
+            service.name = format!("{}-{}", base, n);
+        }
+        services.push(service);
+    }
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_convert() {
+        let jobs = parse(
+            "# m h dom mon dow user command\n\
+            MAILTO=ops@example.com\n\
+            PATH = \"/usr/local/bin:/usr/bin:/bin\"\n\
+            30 2 * * * root /usr/local/bin/backup.sh --full > /dev/null\n\
+            @reboot www /srv/app/warm-cache\n\
+            */5 * * * * www date +\\%s >> /tmp/ticks\n",
+            true,
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[0].user.as_deref(), Some("root"));
+        assert_eq!(
+            jobs[0].command,
+            "/usr/local/bin/backup.sh --full > /dev/null"
+        );
+
+        let services = import(jobs).unwrap();
+        let backup = &services[0];
+        assert_eq!(backup.name, "backup");
+        assert_eq!(backup.user, None);
+        assert_eq!(backup.failure_mail.as_deref(), Some("ops@example.com"));
+        assert_eq!(backup.env["PATH"], "/usr/local/bin:/usr/bin:/bin");
+        assert_eq!(
+            backup.timer.as_ref().unwrap().on_calendar,
+            vec!["*-*-* 02:30:00"]
+        );
+        assert_eq!(services[1].name, "warm-cache");
+        assert!(services[1].timer.is_none());
+        assert_eq!(services[2].command[2], "date +%s >> /tmp/ticks");
+
+        let stdin_job = parse("0 * * * * mail -s hi root%body", false).unwrap();
+        assert!(import(stdin_job).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! launchd property lists, from LaunchAgents and LaunchDaemons directories.
+
+use crate::config::{ServiceConfig, ServiceType, TimerConfig};
+use crate::schedule;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeepAlive {
+    Always(bool),
+    /// Conditions: only SuccessfulExit has an equivalent.
+    Conditions(BTreeMap<String, plist::Value>),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "PascalCase")]
+struct CalendarInterval {
+    minute: Option<u32>,
+    hour: Option<u32>,
+    day: Option<u32>,
+    weekday: Option<u32>,
+    month: Option<u32>,
+}
+
+impl CalendarInterval {
+    /// The same schedule as a crontab line's; unset fields mean "every".
+    fn to_cron(&self) -> String {
+        [self.minute, self.hour, self.day, self.month, self.weekday]
+            .iter()
+            .map(|field| field.map_or("*".into(), |value| value.to_string()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CalendarIntervals {
+    One(CalendarInterval),
+    Many(Vec<CalendarInterval>),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "PascalCase")]
+struct Job {
+    label: String,
+    disabled: bool,
+    program: Option<String>,
+    program_arguments: Vec<String>,
+    environment_variables: BTreeMap<String, String>,
+    working_directory: Option<String>,
+    user_name: Option<String>,
+    group_name: Option<String>,
+    run_at_load: bool,
+    keep_alive: Option<KeepAlive>,
+    /// Seconds between runs.
+    start_interval: Option<u64>,
+    start_calendar_interval: Option<CalendarIntervals>,
+    standard_in_path: Option<String>,
+    standard_out_path: Option<String>,
+    standard_error_path: Option<String>,
+    /// Minimum seconds between starts.
+    throttle_interval: Option<u64>,
+    /// Seconds between SIGTERM and SIGKILL.
+    exit_time_out: Option<u64>,
+    nice: Option<i32>,
+    umask: Option<u32>,
+}
+
+/// Keys launchd uses to trigger jobs, which an imported service won't honor.
+const UNSUPPORTED: [&str; 5] = [
+    "Sockets",
+    "WatchPaths",
+    "QueueDirectories",
+    "StartOnMount",
+    "MachServices",
+];
+
+pub fn convert(plist: plist::Dictionary) -> Result<ServiceConfig> {
+    let job: Job = plist::from_value(&plist::Value::Dictionary(plist.clone()))?;
+    // Labels are reverse DNS, like "com.example.backup".
+    let name = job.label.rsplit('.').next().unwrap_or_default().to_string();
+    let mut command = job.program_arguments;
+    // Program is the executable to run, ProgramArguments its argv.
+    match (job.program, command.is_empty()) {
+        (Some(program), true) => command.push(program),
+        (Some(program), false) => command[0] = program,
+        (None, true) => return Err(anyhow!("{:?} has no Program.", job.label)),
+        (None, false) => (),
+    }
+    if job.disabled {
+        log::warn!(
+            "{:?} is disabled, it will be enabled now. Disable it if that's wrong.",
+            job.label
+        );
+    }
+    if job.group_name.is_some() {
+        log::warn!(
+            "{:?} runs with GroupName, which isn't imported; it will run with the user's group.",
+            job.label
+        );
+    }
+    for key in UNSUPPORTED.iter().filter(|k| plist.contains_key(k)) {
+        log::warn!("Skipping {} in {:?}, it has no equivalent.", key, job.label);
+    }
+
+    let mut on_calendar = Vec::new();
+    match job.start_calendar_interval {
+        Some(CalendarIntervals::One(interval)) => {
+            on_calendar.push(schedule::cron_to_calendar(&interval.to_cron())?)
+        }
+        Some(CalendarIntervals::Many(intervals)) => {
+            for interval in intervals {
+                on_calendar.push(schedule::cron_to_calendar(&interval.to_cron())?);
+            }
+        }
+        None => (),
+    }
+    let interval = job.start_interval.map(|seconds| format!("{}s", seconds));
+    let timer = match (on_calendar.is_empty(), &interval) {
+        (true, None) => None,
+        _ => Some(TimerConfig {
+            on_calendar,
+            // A first run after boot, then every interval after the last.
+            on_boot: interval.clone(),
+            on_unit_inactive: interval,
+            ..Default::default()
+        }),
+    };
+
+    let restart = match &job.keep_alive {
+        Some(KeepAlive::Always(true)) => Some("always"),
+        Some(KeepAlive::Always(false)) | None => None,
+        Some(KeepAlive::Conditions(conditions)) => {
+            match conditions
+                .get("SuccessfulExit")
+                .and_then(|v| v.as_boolean())
+            {
+                Some(true) => Some("on-success"),
+                Some(false) => Some("on-failure"),
+                None => {
+                    log::warn!(
+                        "{:?} is kept alive on conditions that have no equivalent, it will always be restarted.",
+                        job.label
+                    );
+                    Some("always")
+                }
+            }
+        }
+    };
+    if !job.run_at_load && restart.is_none() && timer.is_none() {
+        log::warn!(
+            "{:?} wasn't started at load, it will be now. Disable it if that's wrong.",
+            job.label
+        );
+    }
+
+    let mut service = ServiceConfig {
+        name,
+        command,
+        env: job.environment_variables,
+        working_directory: job.working_directory,
+        user: job.user_name.filter(|user| user != "root"),
+        service_type: match (&timer, restart) {
+            (Some(_), None) => ServiceType::Oneshot,
+            _ => ServiceType::Simple,
+        },
+        timer,
+        ..Default::default()
+    };
+    let options = service.unit_options.entry("Service".into()).or_default();
+    let mut set = |key: &str, value: String| {
+        options.insert(key.into(), vec![value]);
+    };
+    if let Some(restart) = restart {
+        set("Restart", restart.into());
+    }
+    if let Some(path) = job.standard_in_path {
+        set("StandardInput", format!("file:{}", path));
+    }
+    if let Some(path) = job.standard_out_path {
+        set("StandardOutput", format!("append:{}", path));
+    }
+    if let Some(path) = job.standard_error_path {
+        set("StandardError", format!("append:{}", path));
+    }
+    if let Some(seconds) = job.throttle_interval {
+        set("RestartSec", format!("{}s", seconds));
+    }
+    if let Some(seconds) = job.exit_time_out {
+        set("TimeoutStopSec", format!("{}s", seconds));
+    }
+    if let Some(nice) = job.nice {
+        set("Nice", nice.to_string());
+    }
+    if let Some(umask) = job.umask {
+        set("UMask", format!("{:04o}", umask));
+    }
+    service
+        .unit_options
+        .retain(|_, options| !options.is_empty());
+    Ok(service)
+}
+
+/// A service per property list.
+pub fn import(paths: &[impl AsRef<Path>]) -> Result<Vec<ServiceConfig>> {
+    paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            let plist =
+                plist::from_file(path).map_err(|e| anyhow!("Can't read {:?}: {}", path, e))?;
+            convert(plist)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let plist = plist::from_bytes(
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>com.example.backup</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>/usr/local/bin/backup</string>
+        <string>--full</string>
+    </array>
+    <key>EnvironmentVariables</key>
+    <dict>
+        <key>TARGET</key>
+        <string>s3://backups</string>
+    </dict>
+    <key>UserName</key>
+    <string>backup</string>
+    <key>StartCalendarInterval</key>
+    <array>
+        <dict>
+            <key>Hour</key>
+            <integer>3</integer>
+            <key>Minute</key>
+            <integer>15</integer>
+        </dict>
+        <dict>
+            <key>Weekday</key>
+            <integer>0</integer>
+            <key>Hour</key>
+            <integer>12</integer>
+            <key>Minute</key>
+            <integer>0</integer>
+        </dict>
+    </array>
+    <key>StandardErrorPath</key>
+    <string>/var/log/backup.err</string>
+    <key>Umask</key>
+    <integer>18</integer>
+    <key>WatchPaths</key>
+    <array><string>/srv/data</string></array>
+</dict>
+</plist>"#,
+        )
+        .unwrap();
+        let service = convert(plist).unwrap();
+        assert_eq!(service.name, "backup");
+        assert_eq!(service.command, vec!["/usr/local/bin/backup", "--full"]);
+        assert_eq!(service.env["TARGET"], "s3://backups");
+        assert_eq!(service.user.as_deref(), Some("backup"));
+        assert_eq!(service.service_type, ServiceType::Oneshot);
+        assert_eq!(
+            service.timer.unwrap().on_calendar,
+            vec!["*-*-* 03:15:00", "Sun *-*-* 12:00:00"]
+        );
+        let options = &service.unit_options["Service"];
+        assert_eq!(options["StandardError"], vec!["append:/var/log/backup.err"]);
+        assert_eq!(options["UMask"], vec!["0022"]);
+        assert!(!options.contains_key("Restart"));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let plist = plist::from_bytes(
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>org.example.web</string>
+    <key>Program</key>
+    <string>/opt/web/bin/serve</string>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>5</integer>
+</dict>
+</plist>"#,
+        )
+        .unwrap();
+        let service = convert(plist).unwrap();
+        assert_eq!(service.command, vec!["/opt/web/bin/serve"]);
+        assert_eq!(service.service_type, ServiceType::Simple);
+        let options = &service.unit_options["Service"];
+        assert_eq!(options["Restart"], vec!["on-failure"]);
+        assert_eq!(options["RestartSec"], vec!["5s"]);
+    }
+}
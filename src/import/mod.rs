@@ -0,0 +1,90 @@
+//! Importers turn other tools' service definitions into services mkservice
+//! manages, for migrating off those tools.
+
+pub mod compose;
+pub mod crontab;
+pub mod launchd;
+pub mod pm2;
+pub mod procfile;
+pub mod supervisor;
+pub mod systemd;
+
+use crate::config::{PassthroughEnv, ServiceConfig, ServiceLevel};
+use crate::{manifest, settings};
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+/// Options common to every importer.
+#[derive(clap::Args, Debug)]
+pub struct ImportArgs {
+    /// Prefix for the service names, e.g. "myapp-".
+    #[clap(long, default_value = "")]
+    pub prefix: String,
+    /// [default: system, or as set in config.toml]
+    #[clap(long, value_enum)]
+    pub level: Option<ServiceLevel>,
+    /// Start the services once installed.
+    #[clap(long)]
+    pub start: bool,
+    /// Install even if units with the same names exist, e.g. from a package,
+    /// overwriting or shadowing them.
+    #[clap(long, visible_alias = "shadow")]
+    pub force: bool,
+    /// Print the files that would be installed instead of installing them.
+    #[clap(long, conflicts_with = "start")]
+    pub dry_run: bool,
+    /// Label the services, e.g. to restart them together with `restart --tag`.
+    #[clap(long)]
+    pub tag: Vec<String>,
+    /// Group the services as a stack, for `stack status`. [default: the
+    /// directory of a Procfile, compose or ecosystem file]
+    #[clap(long)]
+    pub stack: Option<String>,
+    /// Write paths under your home and runtime directories as %h and %t, for
+    /// user services shared across machines.
+    #[clap(long)]
+    pub portable_paths: bool,
+    #[clap(flatten)]
+    pub annotations: settings::Annotations,
+}
+
+/// The stack for services imported from `file`: its directory's name, the
+/// way compose names projects.
+pub fn default_stack(file: &Path) -> Option<String> {
+    let dir = file.canonicalize().ok()?.parent()?.file_name()?.to_owned();
+    Some(dir.to_string_lossy().into_owned())
+}
+
+/// Names the imported services and fills in what the CLI would have, so
+/// they're installed just like services created with flags.
+pub fn finish(mut services: Vec<ServiceConfig>, args: &ImportArgs) -> Result<Vec<ServiceConfig>> {
+    if services.is_empty() {
+        return Err(anyhow!("Nothing to import."));
+    }
+    let mut settings = settings::load()?;
+    settings.annotate(&args.annotations);
+    let level = args
+        .level
+        .clone()
+        .or_else(|| settings.level.clone())
+        .unwrap_or_default();
+    for service in services.iter_mut() {
+        service.name = manifest::qualify(&format!("{}{}", args.prefix, service.name));
+        for dependency in service.depends_on.iter_mut() {
+            *dependency = manifest::qualify(&format!("{}{}", args.prefix, dependency));
+        }
+        crate::validate_name(&service.name).map_err(|e| anyhow!(e))?;
+        service.tags.extend(args.tag.iter().cloned());
+        service.stack = args.stack.clone();
+        service.level = level.clone();
+        // Imported units already say what they pass through.
+        if service.passthrough_env.is_none() {
+            service.passthrough_env = PassthroughEnv::default_for(&level);
+        }
+        if args.portable_paths {
+            crate::portable_paths(service)?;
+        }
+        settings.apply(service)?;
+    }
+    Ok(services)
+}
@@ -0,0 +1,217 @@
+//! PM2 ecosystem files, as JavaScript (evaluated with node), JSON or YAML.
+
+use crate::command::find_executable;
+use crate::config::ServiceConfig;
+use crate::error::MkserviceError;
+use crate::import::compose::split_words;
+use crate::provider::systemd::allow_specifiers;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Args {
+    Line(String),
+    List(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Instances {
+    Count(i64),
+    /// "max", one per CPU.
+    Word(String),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct App {
+    name: Option<String>,
+    script: String,
+    args: Option<Args>,
+    cwd: Option<String>,
+    interpreter: Option<String>,
+    env: BTreeMap<String, Value>,
+    instances: Option<Instances>,
+    exec_mode: Option<String>,
+    autorestart: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct Ecosystem {
+    apps: Vec<App>,
+}
+
+fn read(path: &Path) -> Result<Ecosystem> {
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    match &*extension {
+        "js" | "cjs" => {
+            let node = find_executable("node")
+                .ok_or_else(|| MkserviceError::CommandNotFound { cmd: "node".into() })?;
+            let output = Command::new(node)
+                .arg("-e")
+                .arg("process.stdout.write(JSON.stringify(require(process.argv[1])))")
+                .arg(path)
+                .output()
+                .map_err(|e| MkserviceError::spawn("node", e))?;
+            if !output.status.success() {
+                return Err(MkserviceError::CommandFailed {
+                    cmd: format!("node {:?}", path),
+                    stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                }
+                .into());
+            }
+            Ok(serde_json::from_slice(&output.stdout)?)
+        }
+        "yml" | "yaml" => Ok(serde_yaml::from_str(&fs::read_to_string(path)?)?),
+        _ => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+    }
+}
+
+fn instance_count(instances: &Instances) -> Result<u32> {
+    let cpus = || {
+        std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1)
+    };
+    match instances {
+        Instances::Word(word) if word == "max" => Ok(cpus()),
+        // Negative counts are "all CPUs but this many".
+        Instances::Count(0) => Ok(cpus()),
+        Instances::Count(n @ 1..) => Ok(*n as u32),
+        Instances::Count(n) => Ok(cpus().saturating_sub(n.unsigned_abs() as u32).max(1)),
+        Instances::Word(word) => word
+            .parse()
+            .map_err(|_| anyhow!("Bad instances value {:?}", word)),
+    }
+}
+
+fn convert(app: App, dir: &Path) -> Result<ServiceConfig> {
+    let name = app.name.clone().unwrap_or_else(|| {
+        Path::new(&app.script)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    });
+    let cwd = match &app.cwd {
+        Some(cwd) => dir.join(cwd),
+        None => dir.to_path_buf(),
+    };
+    let script = cwd.join(app.script.trim_start_matches("./"));
+    let interpreter = match app.interpreter.as_deref() {
+        Some("none") => None,
+        Some(interpreter) => Some(interpreter.to_string()),
+        // PM2 runs anything it doesn't recognize with node too.
+        None if script.extension().is_some_and(|ext| ext == "sh") => Some("bash".into()),
+        None => Some("node".into()),
+    };
+    let mut command = Vec::new();
+    if let Some(interpreter) = interpreter {
+        let resolved = match interpreter.starts_with('/') {
+            true => PathBuf::from(&interpreter),
+            false => find_executable(&interpreter).ok_or_else(|| {
+                anyhow!("{:?} runs with {}, which isn't on PATH.", name, interpreter)
+            })?,
+        };
+        command.push(resolved.to_string_lossy().into_owned());
+    }
+    command.push(script.to_string_lossy().into_owned());
+    match app.args {
+        Some(Args::Line(line)) => command.extend(split_words(&line)?),
+        Some(Args::List(args)) => command.extend(args),
+        None => {}
+    }
+
+    let env: BTreeMap<String, String> = app
+        .env
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(s) => (key, s),
+            other => (key, other.to_string()),
+        })
+        .collect();
+    let instances = app.instances.as_ref().map(instance_count).transpose()?;
+    let instances = instances.filter(|&n| n > 1);
+    if instances.is_some() && app.exec_mode.as_deref() == Some("cluster") {
+        log::warn!(
+            "{:?} ran in PM2's cluster mode, its instances are now separate processes and can't share a port unless it uses SO_REUSEPORT.",
+            name
+        );
+    }
+
+    let mut service = ServiceConfig {
+        name,
+        command,
+        env,
+        instances,
+        working_directory: Some(cwd.to_string_lossy().into_owned()),
+        ..Default::default()
+    };
+    if instances.is_some() {
+        // PM2 numbers instances from 0 in NODE_APP_INSTANCE.
+        allow_specifiers(&mut service);
+        service.env.insert("NODE_APP_INSTANCE".into(), "%i".into());
+    }
+    // PM2 restarts apps unless told not to.
+    let restart = match app.autorestart {
+        Some(false) => "no",
+        _ => "always",
+    };
+    service
+        .unit_options
+        .entry("Service".into())
+        .or_default()
+        .insert("Restart".into(), vec![restart.into()]);
+    Ok(service)
+}
+
+/// A service per app in the ecosystem file; apps with several instances
+/// become template units.
+pub fn import(path: &Path) -> Result<Vec<ServiceConfig>> {
+    let path = path.canonicalize()?;
+    let dir = path.parent().expect("a file has a parent directory");
+    read(&path)?
+        .apps
+        .into_iter()
+        .map(|app| convert(app, dir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let ecosystem: Ecosystem = serde_json::from_str(
+            r#"{"apps": [{
+                "name": "api",
+                "script": "./bin/server.sh",
+                "interpreter": "/bin/sh",
+                "args": "--port 3000",
+                "cwd": "api",
+                "instances": 4,
+                "exec_mode": "fork",
+                "autorestart": false,
+                "env": {"NODE_ENV": "production", "WORKERS": 2}
+            }]}"#,
+        )
+        .unwrap();
+        let app = ecosystem.apps.into_iter().next().unwrap();
+        let service = convert(app, Path::new("/srv")).unwrap();
+        assert_eq!(
+            service.command,
+            vec!["/bin/sh", "/srv/api/bin/server.sh", "--port", "3000"]
+        );
+        assert_eq!(service.instances, Some(4));
+        assert_eq!(service.env["WORKERS"], "2");
+        assert_eq!(service.env["NODE_APP_INSTANCE"], "%i");
+        assert_eq!(service.unit_options["Service"]["Restart"], vec!["no"]);
+    }
+}
@@ -0,0 +1,80 @@
+//! Heroku-style Procfiles: one `type: command` line per process type.
+
+use crate::config::{ServiceConfig, ServiceType};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Process types run once per deploy rather than kept running.
+const ONESHOT_TYPES: [&str; 1] = ["release"];
+
+/// Parses `type: command` lines, skipping blanks and comments.
+pub fn parse(content: &str) -> Result<Vec<(String, String)>> {
+    let re_entry = Regex::new(r"^([A-Za-z0-9_-]+):\s*(.+)$").expect("Bad regex");
+    let mut entries = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let captures = re_entry
+            .captures(line)
+            .ok_or_else(|| anyhow!("Line {}: expected \"type: command\".", number + 1))?;
+        entries.push((captures[1].to_string(), captures[2].trim().to_string()));
+    }
+    Ok(entries)
+}
+
+/// A service per process type in the Procfile at `path`, run from its
+/// directory with the `.env` file next to it, if any. The release type
+/// becomes a oneshot.
+pub fn import(path: &Path) -> Result<Vec<ServiceConfig>> {
+    let path = path.canonicalize()?;
+    let dir = path.parent().expect("a file has a parent directory");
+    let env_file = dir.join(".env");
+    let env_files = match env_file.is_file() {
+        true => vec![env_file.to_string_lossy().into_owned()],
+        false => vec![],
+    };
+    let mut services: Vec<ServiceConfig> = parse(&fs::read_to_string(&path)?)?
+        .into_iter()
+        .map(|(process_type, command)| ServiceConfig {
+            service_type: match ONESHOT_TYPES.contains(&process_type.as_str()) {
+                true => ServiceType::Oneshot,
+                false => ServiceType::Simple,
+            },
+            name: process_type,
+            // exec so the process gets the service's signals, not the shell.
+            command: vec!["/bin/sh".into(), "-c".into(), format!("exec {}", command)],
+            env_files: env_files.clone(),
+            working_directory: Some(dir.to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+        .collect();
+    // Release runs first, as it would on a deploy.
+    services.sort_by_key(|service| service.service_type != ServiceType::Oneshot);
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(
+            "# Heroku\nweb: bundle exec puma -p $PORT\n\nworker:bundle exec sidekiq\nrelease: rake db:migrate\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("web".into(), "bundle exec puma -p $PORT".into()),
+                ("worker".into(), "bundle exec sidekiq".into()),
+                ("release".into(), "rake db:migrate".into()),
+            ]
+        );
+        assert!(parse("web bundle exec puma").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! supervisord config files: each `[program:x]` section becomes a service.
+
+use crate::config::ServiceConfig;
+use crate::import::compose::split_words;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub type Section = BTreeMap<String, String>;
+
+/// Parses supervisord's INI dialect: `key = value` or `key: value`, `;` and
+/// `#` comments, and indented continuation lines.
+pub fn parse(content: &str) -> Result<Vec<(String, Section)>> {
+    let mut sections: Vec<(String, Section)> = Vec::new();
+    let mut last_key: Option<String> = None;
+    for (number, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            sections.push((name.trim().to_string(), Section::new()));
+            last_key = None;
+            continue;
+        }
+        let (_, section) = sections
+            .last_mut()
+            .ok_or_else(|| anyhow!("Line {}: setting outside a section.", number + 1))?;
+        if line.starts_with(char::is_whitespace) {
+            if let Some(value) = last_key.as_ref().and_then(|key| section.get_mut(key)) {
+                value.push(' ');
+                value.push_str(trimmed);
+                continue;
+            }
+        }
+        let split = trimmed
+            .find(['=', ':'])
+            .ok_or_else(|| anyhow!("Line {}: expected \"key = value\".", number + 1))?;
+        let key = trimmed[..split].trim().to_lowercase();
+        // Inline comments need whitespace before the semicolon.
+        let value = trimmed[split + 1..].trim();
+        let value = value.split(" ;").next().unwrap_or_default().trim();
+        section.insert(key.clone(), value.to_string());
+        last_key = Some(key);
+    }
+    Ok(sections)
+}
+
+/// Parses `KEY="value",KEY2=value2`.
+fn parse_environment(value: &str) -> Result<BTreeMap<String, String>> {
+    let mut env = BTreeMap::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let (key, after) = rest
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Bad environment {:?}", value))?;
+        let after = after.trim_start();
+        let (value, after) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = after[1..]
+                    .find(quote)
+                    .ok_or_else(|| anyhow!("Unterminated quote in environment {:?}", value))?;
+                (&after[1..end + 1], &after[end + 2..])
+            }
+            _ => after.split_at(after.find(',').unwrap_or(after.len())),
+        };
+        env.insert(key.trim().to_string(), value.to_string());
+        rest = after.trim_start().trim_start_matches(',').trim_start();
+    }
+    Ok(env)
+}
+
+/// Where a log file setting sends output, as StandardOutput=/StandardError=.
+fn log_target(logfile: &str) -> Option<String> {
+    match logfile {
+        "AUTO" => None,
+        "NONE" => Some("null".into()),
+        path => Some(format!("append:{}", path)),
+    }
+}
+
+fn is_true(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "true" | "yes" | "on" | "1")
+}
+
+pub fn convert(name: &str, program: &Section, here: &Path) -> Result<ServiceConfig> {
+    // Supervisor's own "%%" is a literal "%" to systemd too.
+    let expand = |value: &str| {
+        value
+            .replace("%(program_name)s", &name.replace('%', "%%"))
+            .replace("%(here)s", &here.to_string_lossy().replace('%', "%%"))
+            .replace("%(process_num)d", "%i")
+            .replace("%(process_num)s", "%i")
+    };
+    let command = program
+        .get("command")
+        .ok_or_else(|| anyhow!("[program:{}] has no command.", name))?;
+    let mut service = ServiceConfig {
+        name: name.to_string(),
+        command: split_words(&expand(command))?,
+        user: program.get("user").cloned(),
+        working_directory: program.get("directory").map(|dir| expand(dir)),
+        env: match program.get("environment") {
+            Some(environment) => parse_environment(&expand(environment))?,
+            None => BTreeMap::new(),
+        },
+        specifiers: true,
+        ..Default::default()
+    };
+    if let Some(numprocs) = program.get("numprocs") {
+        let numprocs: u32 = numprocs
+            .parse()
+            .map_err(|_| anyhow!("[program:{}] has a bad numprocs.", name))?;
+        if numprocs > 1 {
+            service.instances = Some(numprocs);
+        }
+    }
+    if program.get("autostart").is_some_and(|v| !is_true(v)) {
+        log::warn!(
+            "[program:{}] wasn't started automatically, it will be now. Disable it if that's wrong.",
+            name
+        );
+    }
+
+    let options = service.unit_options.entry("Service".into()).or_default();
+    let restart = match program.get("autorestart").map(String::as_str) {
+        Some("unexpected") | None => "on-failure",
+        Some(value) if is_true(value) => "always",
+        Some(_) => "no",
+    };
+    options.insert("Restart".into(), vec![restart.into()]);
+    if let Some(signal) = program.get("stopsignal") {
+        options.insert(
+            "KillSignal".into(),
+            vec![format!("SIG{}", signal.trim_start_matches("SIG"))],
+        );
+    }
+    if let Some(target) = program
+        .get("stdout_logfile")
+        .and_then(|f| log_target(&expand(f)))
+    {
+        options.insert("StandardOutput".into(), vec![target]);
+    }
+    if program.get("redirect_stderr").is_some_and(|v| is_true(v)) {
+        options.insert("StandardError".into(), vec!["inherit".into()]);
+    } else if let Some(target) = program
+        .get("stderr_logfile")
+        .and_then(|f| log_target(&expand(f)))
+    {
+        options.insert("StandardError".into(), vec![target]);
+    }
+    Ok(service)
+}
+
+/// A service per `[program:x]` section in the config files.
+pub fn import(paths: &[impl AsRef<Path>]) -> Result<Vec<ServiceConfig>> {
+    let mut services = Vec::new();
+    for path in paths {
+        let path = path.as_ref().canonicalize()?;
+        let here = path.parent().expect("a file has a parent directory");
+        for (section, program) in parse(&fs::read_to_string(&path)?)? {
+            if let Some(name) = section.strip_prefix("program:") {
+                services.push(convert(name, &program, here)?);
+            } else if section.starts_with("group:") || section.starts_with("eventlistener:") {
+                log::warn!(
+                    "Skipping [{}] in {:?}, it has no equivalent.",
+                    section,
+                    path
+                );
+            }
+        }
+    }
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::systemd::Systemd;
+
+    #[test]
+    fn test_convert() {
+        let sections = parse(
+            r#"
+; Queue workers
+[program:worker]
+command = /usr/bin/python3 %(here)s/worker.py
+    --queue default
+directory=/srv/app
+user=app
+environment=QUEUE="default,high",DEBUG=0
+autorestart=true
+stopsignal=TERM
+stdout_logfile=/var/log/worker.log ; rotated daily
+redirect_stderr=true
+numprocs=2
+process_name=%(program_name)s_%(process_num)02d
+"#,
+        )
+        .unwrap();
+        assert_eq!(sections.len(), 1);
+        let (section, program) = &sections[0];
+        assert_eq!(section, "program:worker");
+        let service = convert("worker", program, Path::new("/etc/supervisor/conf.d")).unwrap();
+        assert_eq!(
+            service.command,
+            vec![
+                "/usr/bin/python3",
+                "/etc/supervisor/conf.d/worker.py",
+                "--queue",
+                "default"
+            ]
+        );
+        assert_eq!(service.user.as_deref(), Some("app"));
+        assert_eq!(service.env["QUEUE"], "default,high");
+        assert_eq!(service.env["DEBUG"], "0");
+        assert_eq!(service.instances, Some(2));
+        let options = &service.unit_options["Service"];
+        assert_eq!(options["Restart"], vec!["always"]);
+        assert_eq!(options["KillSignal"], vec!["SIGTERM"]);
+        assert_eq!(
+            options["StandardOutput"],
+            vec!["append:/var/log/worker.log"]
+        );
+        assert_eq!(options["StandardError"], vec!["inherit"]);
+    }
+
+    #[test]
+    fn test_convert_process_num() {
+        let sections =
+            parse("[program:worker]\ncommand=/usr/bin/worker --id %(process_num)d --load 90%%\nnumprocs=2\n")
+                .unwrap();
+        let service = convert("worker", &sections[0].1, Path::new("/etc/supervisor")).unwrap();
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(
+            unit.contains("ExecStart=\"/usr/bin/worker\" \"--id\" \"%i\" \"--load\" \"90%%\"\n")
+        );
+    }
+}
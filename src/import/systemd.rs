@@ -0,0 +1,395 @@
+//! systemd service units, for bringing hand-written units under mkservice's
+//! management. Directives mkservice has a setting for become that setting,
+//! the rest are kept as they are.
+
+use crate::config::{PassthroughEnv, ServiceConfig, ServiceType, StandardInput, TimerConfig};
+use crate::import::compose::split_words;
+use crate::provider::systemd::{
+    passthrough_directives, split_exec, systemd_unescape, GRAPHICAL_SESSION,
+};
+use crate::unit::{parse, Unit};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+impl Unit {
+    fn remove(&mut self, section: &str, key: &str) -> Vec<String> {
+        self.sections
+            .get_mut(section)
+            .and_then(|options| options.remove(key))
+            .unwrap_or_default()
+    }
+
+    fn get(&self, section: &str, key: &str) -> &[String] {
+        self.sections
+            .get(section)
+            .and_then(|options| options.get(key))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Removes the single-valued `key`.
+    fn remove_one(&mut self, section: &str, key: &str) -> Option<String> {
+        match self.get(section, key) {
+            [_] => self.remove(section, key).pop(),
+            _ => None,
+        }
+    }
+
+    /// Removes `key` if its only value is `value`.
+    fn remove_if(&mut self, section: &str, key: &str, value: &str) -> bool {
+        let matches = self.get(section, key) == [value];
+        if matches {
+            self.remove(section, key);
+        }
+        matches
+    }
+
+    fn insert(&mut self, section: &str, key: &str, values: Vec<String>) {
+        if !values.is_empty() {
+            self.sections
+                .entry(section.into())
+                .or_default()
+                .insert(key.into(), values);
+        }
+    }
+}
+
+/// Values of a list directive, which may each hold several words.
+fn words(values: Vec<String>) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    for value in values {
+        words.extend(split_words(&value)?);
+    }
+    Ok(words)
+}
+
+fn convert_timer(content: &str, name: &str) -> Result<TimerConfig> {
+    let mut unit = parse(content)?;
+    unit.remove_if("Unit", "Description", &format!("Timer for {}", name));
+    unit.remove_if("Install", "WantedBy", "timers.target");
+    let timer = TimerConfig {
+        on_calendar: unit.remove("Timer", "OnCalendar"),
+        on_boot: unit.remove_one("Timer", "OnBootSec"),
+        on_unit_inactive: unit.remove_one("Timer", "OnUnitInactiveSec"),
+        randomized_delay: unit.remove_one("Timer", "RandomizedDelaySec"),
+        persistent: match unit.remove_one("Timer", "Persistent").as_deref() {
+            Some("true" | "yes" | "on" | "1") => Some(true),
+            Some(_) => Some(false),
+            None => None,
+        },
+    };
+    for (section, options) in &unit.sections {
+        if let Some(key) = options.keys().next() {
+            return Err(anyhow!(
+                "The timer for {:?} sets {}= in [{}], which isn't supported.",
+                name,
+                key,
+                section
+            ));
+        }
+    }
+    Ok(timer)
+}
+
+/// The address a failure mail unit written by mkservice mails.
+fn failure_mail_address(content: &str) -> Option<String> {
+    let re_mail = Regex::new(r"\| mail -s '[^']*' '([^']*)'$").expect("Bad regex");
+    let mut unit = parse(content).ok()?;
+    let exec_start = words(unit.remove("Service", "ExecStart")).ok()?;
+    let script = exec_start.last()?;
+    re_mail
+        .captures(script)
+        .map(|captures| captures[1].to_string())
+}
+
+/// Converts a service unit named `file_name`, with its timer and failure
+/// mail unit if it has them.
+pub fn convert(
+    file_name: &str,
+    content: &str,
+    timer: Option<&str>,
+    failure_mail: Option<&str>,
+) -> Result<ServiceConfig> {
+    let unit_name = file_name
+        .strip_suffix(".service")
+        .ok_or_else(|| anyhow!("{:?} isn't a service unit.", file_name))?;
+    if unit_name.ends_with('@') {
+        return Err(anyhow!(
+            "{:?} is a template unit, which can't be imported.",
+            file_name
+        ));
+    }
+    let name = systemd_unescape(unit_name);
+    let mut unit = parse(content)?;
+    if let Some(section) = unit
+        .sections
+        .keys()
+        .find(|s| !["Unit", "Service", "Install"].contains(&s.as_str()))
+    {
+        return Err(anyhow!("{:?} has a [{}] section.", file_name, section));
+    }
+    let mut service = ServiceConfig {
+        header: unit.header.take(),
+        ..Default::default()
+    };
+    unit.remove_if("Unit", "Description", &name);
+
+    service.service_type = match unit.remove_one("Service", "Type").as_deref() {
+        Some("simple") | None => ServiceType::Simple,
+        Some("exec") => ServiceType::Exec,
+        Some("forking") => ServiceType::Forking,
+        Some("oneshot") => ServiceType::Oneshot,
+        Some("notify") => ServiceType::Notify,
+        Some("dbus") => ServiceType::Dbus,
+        Some(other) => {
+            unit.insert("Service", "Type", vec![other.into()]);
+            ServiceType::Simple
+        }
+    };
+    let mut commands = Vec::new();
+    for exec_start in unit.remove("Service", "ExecStart") {
+        if exec_start.starts_with(['-', '@', ':', '+', '!']) {
+            return Err(anyhow!(
+                "{:?} has ExecStart={}, prefixes aren't supported.",
+                file_name,
+                exec_start
+            ));
+        }
+        commands.push(split_exec(&exec_start)?);
+    }
+    let mut commands = commands.into_iter();
+    service.command = commands
+        .next()
+        .ok_or_else(|| anyhow!("{:?} has no ExecStart=.", file_name))?;
+    service.steps = commands.collect();
+    for assignment in words(unit.remove("Service", "Environment"))? {
+        let (key, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Bad Environment={:?}", assignment))?;
+        service.env.insert(key.into(), value.into());
+    }
+    // mkservice restarts failed services unless told otherwise.
+    if service.service_type != ServiceType::Oneshot
+        && !unit.remove_if("Service", "Restart", "on-failure")
+        && unit.get("Service", "Restart").is_empty()
+    {
+        unit.insert("Service", "Restart", vec!["no".into()]);
+    }
+
+    let pass = words(unit.remove("Service", "PassEnvironment"))?;
+    let unset = words(unit.remove("Service", "UnsetEnvironment"))?;
+    if !pass.is_empty() || !unset.is_empty() {
+        let candidates = match pass.is_empty() {
+            true => vec![PassthroughEnv::None, PassthroughEnv::Minimal],
+            false => vec![PassthroughEnv::List(pass.clone())],
+        };
+        service.passthrough_env = candidates.into_iter().find(|candidate| {
+            passthrough_directives(candidate, &service.env) == (pass.clone(), unset.clone())
+        });
+        if service.passthrough_env.is_none() {
+            unit.insert("Service", "PassEnvironment", pass);
+            unit.insert("Service", "UnsetEnvironment", unset);
+        }
+    }
+
+    if service.service_type == ServiceType::Forking {
+        service.pidfile = unit.remove_one("Service", "PIDFile");
+    }
+    if service.service_type == ServiceType::Oneshot {
+        service.remain_after_exit = unit.remove_if("Service", "RemainAfterExit", "yes");
+    }
+    service.user = unit.remove_one("Service", "User");
+    service.group = unit.remove_one("Service", "Group");
+    service.tty = unit.remove_one("Service", "TTYPath");
+    service.working_directory = unit.remove_one("Service", "WorkingDirectory");
+    service.env_files = unit.remove("Service", "EnvironmentFile");
+    service.requires_mounts = unit.remove("Unit", "RequiresMountsFor");
+    let input = match unit.get("Service", "StandardInput") {
+        [input] => match input.as_str() {
+            "null" => Some(StandardInput::Null),
+            "tty" => Some(StandardInput::Tty),
+            "tty-force" => Some(StandardInput::TtyForce),
+            "tty-fail" => Some(StandardInput::TtyFail),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(input) = input {
+        unit.remove("Service", "StandardInput");
+        if input.is_tty() {
+            unit.remove_if("Service", "TTYReset", "yes");
+            unit.remove_if("Service", "TTYVHangup", "yes");
+        }
+        service.standard_input = Some(input);
+    }
+    if let Some(bus_name) = unit.remove_one("Service", "BusName") {
+        unit.remove_if("Install", "Alias", &format!("dbus-{}.service", bus_name));
+        service.bus_name = Some(bus_name);
+    }
+
+    // Units both required and ordered after are dependencies.
+    let mut requires = unit.remove("Unit", "Requires");
+    let mut after = unit.remove("Unit", "After");
+    for dependency in requires.clone() {
+        if let (Some(name), true) = (
+            dependency.strip_suffix(".service"),
+            after.contains(&dependency),
+        ) {
+            requires.retain(|r| *r != dependency);
+            after.retain(|a| *a != dependency);
+            service.depends_on.push(systemd_unescape(name));
+        }
+    }
+    let graphical_install = match timer {
+        Some(_) => true,
+        None => unit.get("Install", "WantedBy") == [GRAPHICAL_SESSION],
+    };
+    if unit.get("Unit", "PartOf") == [GRAPHICAL_SESSION]
+        && after.iter().any(|a| a == GRAPHICAL_SESSION)
+        && graphical_install
+    {
+        unit.remove("Unit", "PartOf");
+        after.retain(|a| a != GRAPHICAL_SESSION);
+        if timer.is_none() {
+            unit.remove("Install", "WantedBy");
+        }
+        service.graphical = true;
+    }
+    unit.insert("Unit", "Requires", requires);
+    unit.insert("Unit", "After", after);
+
+    let failure_unit = format!("{}-failure-mail.service", unit_name);
+    if let Some(address) = failure_mail.and_then(failure_mail_address) {
+        if unit.remove_if("Unit", "OnFailure", &failure_unit) {
+            service.failure_mail = Some(address);
+        }
+    }
+    let first_boot = "first-boot-complete.target";
+    if unit.get("Unit", "ConditionFirstBoot") == ["yes"]
+        && unit.get("Unit", "Wants") == [first_boot]
+        && unit.get("Unit", "Before") == [first_boot]
+    {
+        for key in ["ConditionFirstBoot", "Wants", "Before"] {
+            unit.remove("Unit", key);
+        }
+        service.first_boot_only = true;
+    }
+
+    match timer {
+        Some(timer) => {
+            service.timer = Some(convert_timer(timer, &name)?);
+            // A timer's service isn't enabled itself.
+            unit.sections.remove("Install");
+        }
+        None => {
+            unit.remove_if("Install", "WantedBy", "multi-user.target");
+        }
+    }
+    service.name = name;
+    service.unit_options = unit
+        .sections
+        .into_iter()
+        .filter(|(_, options)| !options.is_empty())
+        .collect();
+    Ok(service)
+}
+
+/// A service per service unit file. Timers and failure mail units next to
+/// them are picked up too.
+pub fn import(paths: &[impl AsRef<Path>]) -> Result<Vec<ServiceConfig>> {
+    let file_name = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let names: Vec<String> = paths.iter().map(|p| file_name(p.as_ref())).collect();
+    let mut services = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let name = file_name(path);
+        // Failure mail units go with the service they report on.
+        if let Some(failing) = name.strip_suffix("-failure-mail.service") {
+            if names.contains(&format!("{}.service", failing)) {
+                continue;
+            }
+        }
+        let sibling = |sibling: String| fs::read_to_string(path.with_file_name(sibling)).ok();
+        let stem = name.strip_suffix(".service").unwrap_or(&name);
+        let timer = sibling(format!("{}.timer", stem));
+        let failure_mail = sibling(format!("{}-failure-mail.service", stem));
+        services.push(convert(
+            &name,
+            &fs::read_to_string(path)?,
+            timer.as_deref(),
+            failure_mail.as_deref(),
+        )?);
+    }
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let service = convert(
+            "my\\x2dapp.service",
+            "# Managed by ops\n\
+            [Unit]\n\
+            Description=My app\n\
+            After=network-online.target db.service\n\
+            After=cache.service\n\
+            Requires=cache.service\n\
+            \n\
+            [Service]\n\
+            Type=notify\n\
+            ExecStart=/usr/bin/app \\\n\
+              --port 8080\n\
+            Environment=\"GREETING=hello world\" DEBUG=0\n\
+            User=app\n\
+            UnsetEnvironment=DISPLAY\n\
+            \n\
+            [Install]\n\
+            WantedBy=multi-user.target\n",
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(service.name, "my-app");
+        assert_eq!(service.header.as_deref(), Some("Managed by ops"));
+        assert_eq!(service.command, vec!["/usr/bin/app", "--port", "8080"]);
+        assert_eq!(service.env["GREETING"], "hello world");
+        assert_eq!(service.env["DEBUG"], "0");
+        assert_eq!(service.user.as_deref(), Some("app"));
+        assert_eq!(service.service_type, ServiceType::Notify);
+        assert_eq!(service.depends_on, vec!["cache"]);
+        assert_eq!(service.passthrough_env, None);
+        let options = &service.unit_options;
+        assert_eq!(options["Unit"]["Description"], vec!["My app"]);
+        assert_eq!(
+            options["Unit"]["After"],
+            vec!["network-online.target db.service"]
+        );
+        assert_eq!(options["Service"]["Restart"], vec!["no"]);
+        assert_eq!(options["Service"]["UnsetEnvironment"], vec!["DISPLAY"]);
+        assert!(!options.contains_key("Install"));
+
+        assert!(convert(
+            "app@.service",
+            "[Service]\nExecStart=/bin/app\n",
+            None,
+            None
+        )
+        .is_err());
+        assert!(convert(
+            "app.service",
+            "[Service]\nExecStart=-/bin/app\n",
+            None,
+            None
+        )
+        .is_err());
+    }
+}
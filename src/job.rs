@@ -0,0 +1,45 @@
+use crate::config::{ServiceConfig, ServiceType};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Lists the scripts run-parts would execute from `dir`: executable files
+/// with names made of letters, digits, underscores and hyphens, in lexical
+/// order.
+pub fn scripts(dir: &Path) -> Result<Vec<PathBuf>> {
+    let re_valid_name = Regex::new(r"^[a-zA-Z0-9_-]+$").expect("Bad regex");
+    let mut scripts = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || !re_valid_name.is_match(&name.to_string_lossy()) {
+            continue;
+        }
+        if metadata.permissions().mode() & 0o111 == 0 {
+            log::warn!("Skipping non-executable script {:?}.", entry.path());
+            continue;
+        }
+        scripts.push(entry.path());
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+/// Turns `service` into a oneshot running each script in `dir` as its own
+/// ExecStart= step. systemd runs the steps in order, stops at the first
+/// failure, and logs each step under the script's own name.
+pub fn apply(service: &mut ServiceConfig, dir: &Path) -> Result<()> {
+    let dir = dir.canonicalize()?;
+    let mut steps = scripts(&dir)?
+        .into_iter()
+        .map(|script| vec![script.to_string_lossy().into_owned()]);
+    service.command = steps
+        .next()
+        .ok_or_else(|| anyhow!("No executable scripts found in {:?}.", dir))?;
+    service.steps = steps.collect();
+    service.service_type = ServiceType::Oneshot;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use crate::config::{ServiceConfig, ServiceLevel, StandardInput};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+/// The console a kiosk takes over when none is given.
+pub const DEFAULT_TTY: &str = "/dev/tty1";
+
+/// Turns `service` into a kiosk session for `user`: it replaces the getty on
+/// its virtual terminal, runs as a logged-in session there, and is restarted
+/// whenever it exits.
+pub fn apply(service: &mut ServiceConfig, user: &str) -> Result<()> {
+    if service.level != ServiceLevel::System {
+        return Err(anyhow!(
+            "A kiosk takes over a console from its getty, it must be a system service."
+        ));
+    }
+    let tty = service.tty.get_or_insert_with(|| DEFAULT_TTY.into());
+    let re_vt = Regex::new(r"^/dev/tty[0-9]+$").expect("Bad regex");
+    if !re_vt.is_match(tty) {
+        return Err(anyhow!(
+            "{:?} isn't a virtual terminal, a kiosk needs one like {}.",
+            tty,
+            DEFAULT_TTY
+        ));
+    }
+    // A getty may still hold the console as this starts, so take it over.
+    service
+        .standard_input
+        .get_or_insert(StandardInput::TtyForce);
+    service.user = Some(user.into());
+    service.kiosk = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut service = ServiceConfig::default();
+        apply(&mut service, "kiosk").unwrap();
+        assert_eq!(service.tty.as_deref(), Some(DEFAULT_TTY));
+        assert_eq!(service.standard_input, Some(StandardInput::TtyForce));
+        assert_eq!(service.user.as_deref(), Some("kiosk"));
+
+        let mut service = ServiceConfig {
+            tty: Some("/dev/ttyS0".into()),
+            ..Default::default()
+        };
+        assert!(apply(&mut service, "kiosk").is_err());
+    }
+}
@@ -1,30 +1,802 @@
-use crate::config::{ServiceConfig, ServiceLevel};
-use anyhow::Result;
-use clap::Parser;
+use crate::config::{
+    Hardening, ImdsValue, OpenRcConfig, PassthroughEnv, PowerHook, RestartPolicy, ServiceConfig,
+    ServiceLevel, ServiceType, StandardInput, Supervisor, TimerConfig, Wrapper,
+};
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, ValueEnum};
+use error::MkserviceError;
+#[cfg(feature = "import")]
+use import::ImportArgs;
+use output::Porcelain;
 use regex::Regex;
+use report::ReportFormat;
+use std::collections::BTreeMap;
 use std::env;
-use std::process::exit;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::time::Duration;
 
+mod account;
+#[cfg(feature = "agent")]
+mod agent;
+mod cascade;
+mod command;
 mod config;
+mod drift;
+mod edit;
+mod error;
+mod export;
+mod fmt;
+mod image;
+#[cfg(feature = "import")]
+mod import;
+mod job;
+mod kiosk;
+mod manifest;
+mod metadata;
+mod output;
+mod power;
+mod preset;
 mod provider;
+mod report;
+mod restart;
+#[cfg(all(test, feature = "import"))]
+mod roundtrip;
+mod schedule;
+mod secret;
+mod settings;
+mod socket;
+mod stack;
+mod timespan;
+mod trash;
+mod unit;
+mod vault;
+mod which;
 
 #[derive(Parser, Debug)]
-#[clap(about, version, author)]
+#[clap(about, version, author, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
+    #[clap(flatten)]
+    args: Option<Args>,
+    /// Log more: -v for what's decided, -vv also for how long external
+    /// commands and detection take. RUST_LOG overrides it.
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Prefix unit names with NAMESPACE- and keep state apart, for sharing a
+    /// host with other teams. Service names given are within it.
+    #[clap(long, global = true, value_parser = validate_name)]
+    namespace: Option<String>,
+    /// Report errors as a line of JSON on stderr, with their kind, hint and
+    /// exit code, for scripts.
+    #[clap(long, global = true)]
+    json_errors: bool,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Create or update a service, the same as without a subcommand.
+    Install {
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Start managed services.
+    Start {
+        #[clap(required = true)]
+        names: Vec<String>,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Stop managed services.
+    Stop {
+        #[clap(required = true)]
+        names: Vec<String>,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Show a managed service's state and configuration. Exits 1 if it's
+    /// down.
+    Status {
+        name: String,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Show a managed service's recent output, from wherever its service
+    /// manager keeps it. Exits with the log command's status.
+    Logs {
+        name: String,
+        /// Keep showing new output as it comes, until interrupted.
+        #[clap(short, long)]
+        follow: bool,
+        /// How many of the last lines to show.
+        #[clap(short = 'n', long, default_value = "50")]
+        lines: usize,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Run a directory of scripts in order as a oneshot service, run-parts style.
+    ///
+    /// Runs never overlap: while a run is still active, further triggers of
+    /// the timer (or manual starts) join it rather than starting another.
+    Job {
+        /// Directory of executable scripts, run in lexical order.
+        #[clap(long, conflicts_with_all = ["command", "wrap"])]
+        script: PathBuf,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Run a program as an autologin session on a console, for kiosk appliances.
+    ///
+    /// The service replaces the getty on --tty (default /dev/tty1), logs in
+    /// as --user, and is restarted whenever the program exits.
+    Kiosk {
+        /// User the session logs in as.
+        #[clap(long)]
+        user: String,
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Run a command as the machine goes to sleep, suspended or hibernated.
+    OnSleep {
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Run a command as the machine resumes from sleep.
+    OnResume {
+        #[clap(flatten)]
+        args: Box<Args>,
+    },
+    /// Serve install/start/stop/status requests on a unix socket, for
+    /// orchestration tools.
+    ///
+    /// Requests are JSON, one per line, answered with one JSON line each.
+    /// Only the socket's owner can connect.
+    #[cfg(feature = "agent")]
+    Agent {
+        /// Socket path. Defaults to mkservice/agent.sock under /run, or
+        /// $XDG_RUNTIME_DIR for --level user.
+        #[clap(long)]
+        socket: Option<PathBuf>,
+        #[clap(long, value_enum, default_value = "system")]
+        level: ServiceLevel,
+    },
+    /// List managed services and their state.
+    List {
+        /// Only list services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+        /// Tab-separated output for scripts, in a format stable per version.
+        #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "v1")]
+        porcelain: Option<Porcelain>,
+    },
+    /// Create a service per process type in a Procfile, with its .env file.
+    #[cfg(feature = "import")]
+    ImportProcfile {
+        procfile: PathBuf,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Create a service per docker-compose service, running its container
+    /// with podman (or docker), in dependency order.
+    #[cfg(feature = "import")]
+    ImportCompose {
+        compose_file: PathBuf,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Create a service per app in a PM2 ecosystem file (.js, .json or .yml).
+    /// Apps with several instances become template units.
+    #[cfg(feature = "import")]
+    ImportPm2 {
+        ecosystem_file: PathBuf,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Create a service per [program:x] section of supervisord config files.
+    #[cfg(feature = "import")]
+    ImportSupervisor {
+        #[clap(required = true)]
+        config_files: Vec<PathBuf>,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Create a timer and oneshot service per crontab job, after confirming
+    /// the schedule translations.
+    #[cfg(feature = "import")]
+    ImportCrontab {
+        /// Import this user's crontab rather than your own.
+        #[clap(long, conflicts_with = "file")]
+        user: Option<String>,
+        /// Import a system crontab file, with a user field, e.g. /etc/cron.d/foo.
+        #[clap(long)]
+        file: Option<PathBuf>,
+        /// Don't ask to confirm the schedules.
+        #[clap(short, long)]
+        yes: bool,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Create a service per launchd property list, from LaunchDaemons or
+    /// LaunchAgents.
+    #[cfg(feature = "import")]
+    ImportLaunchd {
+        #[clap(required = true)]
+        plist_files: Vec<PathBuf>,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Bring existing service units under management, with their timers.
+    /// Directives without an equivalent setting are kept as they are.
+    #[cfg(feature = "import")]
+    ImportSystemd {
+        #[clap(required = true)]
+        unit_files: Vec<PathBuf>,
+        #[clap(flatten)]
+        import: ImportArgs,
+    },
+    /// Summarize all managed services for review or a change ticket.
+    Report {
+        #[clap(long, value_enum, default_value = "markdown")]
+        format: ReportFormat,
+        /// Only report services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+        /// POST the JSON inventory to this URL instead of printing a report.
+        #[clap(long, conflicts_with = "format")]
+        push: Option<String>,
+        /// Install a timer pushing the inventory on this schedule, e.g. "1h" or "daily".
+        #[clap(long, requires = "push")]
+        push_every: Option<String>,
+    },
+    /// Restart managed services, optionally one at a time.
+    Restart {
+        /// Services to restart.
+        #[clap(required_unless_present = "tag")]
+        names: Vec<String>,
+        /// Also restart the services with this tag. Repeat for several.
+        #[clap(long)]
+        tag: Vec<String>,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+        /// Restart one at a time, checking each is healthy before the next,
+        /// and stop at the first that isn't.
+        #[clap(long)]
+        rolling: bool,
+        /// How long a restarted service must stay up to count as healthy,
+        /// e.g. "10s".
+        #[clap(long, value_parser = timespan::parse, default_value = "0s")]
+        delay: Duration,
+    },
+    /// Show which files define a unit, the drop-ins applied to it, and
+    /// whether mkservice manages it.
+    Which {
+        /// Unit name; ".service" is assumed without a type suffix.
+        name: String,
+        #[clap(long, value_enum, default_value = "system")]
+        level: ServiceLevel,
+    },
+    /// Edit a drop-in for a managed service's unit in $VISUAL or $EDITOR,
+    /// as `systemctl edit` does, checking it before it's installed.
+    Edit {
+        name: String,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Lay unit files out one way: sections in order, directives spelled as
+    /// systemd does, no stray whitespace. Exits 1 if any are invalid, or
+    /// with --check, not yet formatted.
+    Fmt {
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+        /// Show what would change instead of rewriting the files.
+        #[clap(long)]
+        check: bool,
+    },
+    /// Package a managed service for deploying it elsewhere.
+    Export {
+        name: String,
+        #[clap(long, value_enum)]
+        to: export::ExportTarget,
+        #[clap(long, value_enum, default_value = "directory")]
+        format: export::ImageFormat,
+        /// Where to write it. [default: the image's name, in the current
+        /// directory]
+        #[clap(long)]
+        output: Option<PathBuf>,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Show which features each provider supports, emulates or doesn't.
+    Features {
+        /// Only show this provider, with notes on how it emulates features.
+        #[clap(long, value_enum)]
+        provider: Option<provider::ProviderKind>,
+    },
+    /// Look at the services imported together from a multi-service file.
+    Stack {
+        #[clap(subcommand)]
+        command: StackCommand,
+    },
+    /// Find out-of-band edits to managed units. Exits 1 if any remain.
+    Drift {
+        /// Only check this service, rather than all managed services.
+        name: Option<String>,
+        /// Only check services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+        /// Adopt the live unit files as the service's new configuration.
+        #[clap(long, conflicts_with = "restore")]
+        accept: bool,
+        /// Reinstall the units from the manifest, discarding the edits.
+        #[clap(long)]
+        restore: bool,
+    },
+    /// Stop and remove a managed service, moving its files to the trash.
+    Uninstall {
+        name: String,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Bring back the last uninstalled service called NAME, as it was.
+    Restore {
+        name: String,
+        /// Only look in the trash at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+        #[clap(long)]
+        start: bool,
+    },
+    /// Look at or clear out uninstalled services.
+    Trash {
+        #[clap(subcommand)]
+        command: TrashCommand,
+    },
+    /// Write a service's --vault-secret values to its environment file, run
+    /// by the unit before the service starts.
+    #[clap(hide = true)]
+    VaultEnv { secrets: Vec<String> },
+    /// Write a service's --env-from-imds and --env-from-gce-metadata values
+    /// to its environment file, run by the unit before the service starts.
+    #[clap(hide = true)]
+    MetadataEnv {
+        #[clap(long, value_enum)]
+        imds: Vec<ImdsValue>,
+        #[clap(long)]
+        gce: Vec<String>,
+    },
+    /// Manage the key --env-secret values are encrypted with.
+    Secrets {
+        #[clap(subcommand)]
+        command: SecretsCommand,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct TimerArgs {
+    /// Run on a schedule, as a systemd OnCalendar= expression.
+    #[clap(long)]
+    on_calendar: Vec<String>,
+    /// Run on a friendlier schedule, e.g. "15m", "hourly" or "monday 9am".
+    #[clap(long)]
+    every: Vec<String>,
+    /// Run on a crontab schedule, e.g. "*/5 * * * *" or "@daily".
+    #[clap(long)]
+    cron: Vec<String>,
+    /// Timezone for calendar schedules, e.g. "Europe/Berlin". Defaults to local time.
+    #[clap(long)]
+    schedule_tz: Option<String>,
+    /// Run this long after boot, e.g. "2m".
+    #[clap(long, value_parser = validate_timespan)]
+    on_boot: Option<String>,
+    /// Run again this long after the previous run finished, e.g. "1h".
+    #[clap(long, value_parser = validate_timespan)]
+    on_unit_inactive: Option<String>,
+    /// Delay each run by a random time up to this span, e.g. "5m".
+    #[clap(long, value_parser = validate_timespan)]
+    randomized_delay: Option<String>,
+    /// Run missed schedules on next boot, for machines that are often off or asleep.
+    #[clap(long, conflicts_with = "no_catch_up")]
+    catch_up: bool,
+    /// Skip schedules missed while the machine was off or asleep.
+    #[clap(long)]
+    no_catch_up: bool,
+}
+
+impl TimerArgs {
+    fn timer_config(&self) -> Result<Option<TimerConfig>> {
+        let mut on_calendar = self.on_calendar.clone();
+        for spec in &self.every {
+            on_calendar.push(schedule::every_to_calendar(spec)?);
+        }
+        for schedule in &self.cron {
+            on_calendar.push(schedule::cron_to_calendar(schedule)?);
+        }
+        if let Some(timezone) = &self.schedule_tz {
+            if on_calendar.is_empty() {
+                return Err(anyhow!("--schedule-tz only applies to calendar schedules."));
+            }
+            on_calendar = on_calendar
+                .iter()
+                .map(|expression| schedule::with_timezone(expression, timezone))
+                .collect::<Result<_>>()?;
+        }
+        if (self.catch_up || self.no_catch_up) && on_calendar.is_empty() {
+            return Err(anyhow!(
+                "Catch-up only applies to calendar schedules, see --every."
+            ));
+        }
+        if on_calendar.is_empty() && self.on_boot.is_none() && self.on_unit_inactive.is_none() {
+            if self.randomized_delay.is_some() {
+                return Err(anyhow!("Timer options need a schedule, see --every."));
+            }
+            return Ok(None);
+        }
+        if self.on_unit_inactive.is_some() && on_calendar.is_empty() && self.on_boot.is_none() {
+            log::warn!(
+                "--on-unit-inactive only counts from a previous run, add --on-boot to get the first one."
+            );
+        }
+        for expression in &on_calendar {
+            schedule::check_calendar(expression)?;
+        }
+        Ok(Some(TimerConfig {
+            on_calendar,
+            on_boot: self.on_boot.clone(),
+            on_unit_inactive: self.on_unit_inactive.clone(),
+            randomized_delay: self.randomized_delay.clone(),
+            persistent: match (self.catch_up, self.no_catch_up) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+        }))
+    }
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
-    #[clap(value_parser = validate_name)]
+    // clap leaves a group with flattened args (the annotations) empty, and
+    // `Option<Args>` is only Some when its group matched, so put the name in it.
+    #[clap(value_parser = validate_name, group = "Args")]
     name: String,
     command: Vec<String>,
     #[clap(short, long)]
     env: Vec<String>,
-    #[clap(long, value_enum, default_value = "system")]
-    level: ServiceLevel,
+    /// Scheduling makes the service a oneshot job, started by a timer.
+    #[clap(flatten)]
+    timer: TimerArgs,
+    /// File of KEY=VALUE lines read into the environment at each start, as
+    /// EnvironmentFile=. Prefix with "-" if it may be missing. Repeatable.
+    #[clap(long, value_parser = validate_env_file)]
+    env_file: Vec<String>,
+    /// Read the --env-file files now and write their values into the unit,
+    /// rather than reading them at each start.
+    #[clap(long, requires = "env_file")]
+    inline_env_files: bool,
+    /// Environment variable kept encrypted with the host's key rather than
+    /// written into the unit, as KEY=VALUE, or KEY to take the value from your
+    /// environment. systemd only. Repeatable.
+    #[clap(long, value_parser = validate_env_secret)]
+    env_secret: Vec<String>,
+    /// Vault secret to fetch into the environment at each start, as
+    /// PATH#FIELD, e.g. secret/data/app#API_KEY. The field names the
+    /// variable. Needs the vault CLI, and VAULT_ADDR and a token in the
+    /// service's environment. systemd only. Repeatable.
+    #[clap(long, value_parser = validate_vault_secret)]
+    vault_secret: Vec<String>,
+    /// EC2 instance metadata to fetch into the environment at each start.
+    /// role-credentials sets AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and
+    /// AWS_SESSION_TOKEN. Needs curl. systemd only. Repeatable.
+    #[clap(long, value_enum)]
+    env_from_imds: Vec<ImdsValue>,
+    /// GCE metadata to fetch into the environment at each start, as
+    /// KEY=ATTR, ATTR being an instance attribute's name or a path under
+    /// computeMetadata/v1. Needs curl. systemd only. Repeatable.
+    #[clap(long, value_parser = validate_gce_metadata)]
+    env_from_gce_metadata: Vec<String>,
+    /// [default: system, or as set in config.toml]
+    #[clap(long, value_enum)]
+    level: Option<ServiceLevel>,
     #[clap(long)]
     start: bool,
+    /// How long to wait after --start for the service to come up, showing
+    /// its recent log if it fails instead. "0s" doesn't wait.
+    #[clap(long, value_parser = timespan::parse, default_value = "30s")]
+    start_timeout: Duration,
+    /// Take the command and environment from a pulled container image's
+    /// ENTRYPOINT, CMD and ENV. A given COMMAND replaces CMD, as with `docker run`.
+    #[clap(long)]
+    from_docker_image: Option<String>,
+    /// Start from a preset: "webapp", "worker", "cronjob", or one of your own.
+    #[clap(long)]
+    preset: Option<String>,
+    /// Sandbox the service with systemd's directives, moderately unless
+    /// --harden=strict. Directives set otherwise win. systemd only.
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "moderate"
+    )]
+    harden: Option<Hardening>,
+    /// A unit directive mkservice doesn't otherwise set, as
+    /// Section:Key=Value, e.g. Service:LimitNOFILE=65536. Repeat a key for
+    /// several values. Wins over what mkservice generates, or merges with it
+    /// for lists like After=. systemd only. Repeatable.
+    #[clap(short = 'o', long, value_parser = validate_unit_option)]
+    unit_option: Vec<String>,
+    /// Run the command under a sandbox wrapper.
+    #[clap(long, value_enum)]
+    wrap: Option<Wrapper>,
+    /// Extra arguments for the sandbox wrapper, split on whitespace.
+    #[clap(
+        long,
+        visible_alias = "bwrap-args",
+        allow_hyphen_values = true,
+        requires = "wrap"
+    )]
+    wrap_args: Option<String>,
+    /// Python virtualenv (or pyenv version dir) whose bin/ takes precedence.
+    #[clap(long)]
+    venv: Option<PathBuf>,
+    /// Node.js version to resolve from nvm, e.g. "18" or "18.17.1".
+    #[clap(long)]
+    node_version: Option<String>,
+    /// Capture the current shell's PATH into the unit.
+    #[clap(long, conflicts_with = "path")]
+    inherit_path: bool,
+    /// Set the service's PATH explicitly.
+    #[clap(long)]
+    path: Option<String>,
+    /// Manager environment to let through: "none", "minimal" or "list:VAR1,VAR2".
+    /// Defaults to "minimal" for system services.
+    #[clap(long, value_parser = parse_passthrough_env)]
+    passthrough_env: Option<PassthroughEnv>,
+    /// Proxy URL for HTTP_PROXY/HTTPS_PROXY (and their lowercase forms).
+    #[clap(long)]
+    proxy: Option<String>,
+    /// Comma-separated hosts and networks to reach without the proxy.
+    #[clap(long, requires = "proxy")]
+    no_proxy: Option<String>,
+    /// Start the service on demand when this D-Bus name is requested.
+    #[clap(long, value_parser = validate_bus_name, conflicts_with = "first_boot_only")]
+    dbus_name: Option<String>,
+    /// Start the service on the first connection to this address, passing it
+    /// the listening socket: "tcp:PORT", "tcp:ADDRESS:PORT", the same with
+    /// udp or sctp, "unix:/PATH" or "abstract:NAME". Repeatable.
+    #[clap(long, value_parser = validate_listen, conflicts_with = "dbus_name")]
+    listen: Vec<String>,
+    /// Start an instance of the service per connection, with the connection
+    /// as its stdin and stdout, inetd style.
+    #[clap(
+        long,
+        requires = "listen",
+        conflicts_with_all = ["standard_input", "tty", "verify_socket_activation"]
+    )]
+    accept: bool,
+    /// Only take connections from this network, e.g. 10.0.0.0/8, or a
+    /// keyword like "localhost". Repeatable.
+    #[clap(long, value_parser = validate_ip_prefix, requires = "listen")]
+    socket_allow: Vec<String>,
+    /// Only take connections that come in on this network interface.
+    #[clap(long, requires = "listen")]
+    socket_bind_device: Option<String>,
+    /// Owner of a unix socket's file. Only the owner can connect unless
+    /// --socket-mode says otherwise.
+    #[clap(long, requires = "listen")]
+    socket_user: Option<String>,
+    /// Permissions of a unix socket's file, in octal, e.g. 0660.
+    #[clap(long, value_parser = validate_mode, requires = "listen")]
+    socket_mode: Option<String>,
+    /// Name the service is passed the socket under ($LISTEN_FDNAMES), to tell
+    /// it apart from file descriptors it stored itself.
+    #[clap(long, value_parser = validate_fd_name, requires = "listen")]
+    socket_fd_name: Option<String>,
+    /// Let the service keep up to N file descriptors with systemd, e.g. its
+    /// connections, to take back after a restart (FileDescriptorStoreMax=).
+    #[clap(long)]
+    fd_store_max: Option<u32>,
+    /// After installing, connect to each --listen address and fail unless the
+    /// service starts and accepts the connection from the socket it's passed.
+    #[clap(long, requires = "listen")]
+    verify_socket_activation: bool,
+    /// Account to run the service as, rather than root. It must exist, see
+    /// --create-user.
+    #[clap(long)]
+    run_as_user: Option<String>,
+    /// Group to run the service as, rather than the user's own.
+    #[clap(long)]
+    run_as_group: Option<String>,
+    /// Create --run-as-user (and --run-as-group) as a system account that
+    /// can't log in, if it doesn't exist.
+    #[clap(long, requires = "run_as_user")]
+    create_user: bool,
+    /// Directory to run the command in (WorkingDirectory=). It must exist,
+    /// see --create-workdir.
+    #[clap(long, value_parser = validate_absolute_path)]
+    workdir: Option<String>,
+    /// Create --workdir if it doesn't exist, owned by --run-as-user.
+    #[clap(long, requires = "workdir")]
+    create_workdir: bool,
+    /// When to restart the service after it exits. [default: on-failure]
+    #[clap(long, value_enum)]
+    restart: Option<RestartPolicy>,
+    /// Seconds to wait before restarting it.
+    #[clap(long, value_name = "SECS")]
+    restart_sec: Option<u32>,
+    /// Memory the service can use before it's killed, in bytes with an
+    /// optional K, M, G or T suffix, or a percentage of the machine's.
+    #[clap(long, value_name = "SIZE", value_parser = validate_memory)]
+    memory_max: Option<String>,
+    /// CPU time the service gets, as a percentage of one CPU, e.g. 50% or
+    /// 200% for two.
+    #[clap(long, value_name = "PERCENT", value_parser = validate_cpu_quota)]
+    cpu_quota: Option<String>,
+    /// How many processes and threads the service can have, or a
+    /// percentage of the system's limit.
+    #[clap(long, value_name = "N", value_parser = validate_tasks_max)]
+    tasks_max: Option<String>,
+    /// launchd only: keep the job running while PATH exists, or while it
+    /// doesn't with a leading "!". Repeatable.
+    #[clap(long, value_name = "PATH", value_parser = validate_keepalive_path)]
+    keepalive_path: Vec<String>,
+    /// OpenRC only: what runs the service. [default: supervise-daemon]
+    #[clap(long, value_enum)]
+    supervisor: Option<Supervisor>,
+    /// OpenRC only: give up respawning after this many respawns within
+    /// --respawn-period.
+    #[clap(long, value_name = "N")]
+    respawn_max: Option<u32>,
+    /// OpenRC only: seconds over which --respawn-max counts.
+    #[clap(long, value_name = "SECS", requires = "respawn_max")]
+    respawn_period: Option<u32>,
+    /// Pid file a forking daemon writes, naming the process it leaves
+    /// running. Needed by --service-type forking, and OpenRC's
+    /// start-stop-daemon finds the service by it.
+    #[clap(
+        long,
+        value_parser = validate_absolute_path,
+        required_if_eq("service_type", "forking")
+    )]
+    pidfile: Option<String>,
+    /// OpenRC only: have start-stop-daemon background a command that stays
+    /// in the foreground, writing its pid file.
+    #[clap(long)]
+    command_background: bool,
+    /// How the service manager tells the service has started. [default:
+    /// simple, or oneshot for jobs and hooks]
+    #[clap(long, value_enum, conflicts_with = "dbus_name")]
+    service_type: Option<ServiceType>,
+    /// Keep a oneshot active after its command exits, so starting it again
+    /// doesn't rerun it until it's stopped.
+    #[clap(long)]
+    remain_after_exit: bool,
+    /// Run once, on the first boot of a freshly provisioned image.
+    #[clap(long)]
+    first_boot_only: bool,
+    /// Run the command as the machine shuts down or reboots, while the
+    /// network and mounts are still up. Starts the hook right away, so the
+    /// next shutdown runs it. Stopping, restarting or uninstalling the
+    /// service runs it too.
+    #[clap(long, conflicts_with_all = ["first_boot_only", "at_boot_once"])]
+    at_shutdown: bool,
+    /// Run the command once each boot, as a oneshot that stays active
+    /// afterwards instead of being restarted.
+    #[clap(long, conflicts_with = "first_boot_only")]
+    at_boot_once: bool,
+    /// Don't start until this path's mount is up, e.g. a network or secondary disk.
+    #[clap(long, value_parser = validate_absolute_path)]
+    requires_mount: Vec<String>,
+    /// Network interface, e.g. eth1, or device node, e.g. /dev/ttyUSB0, to
+    /// wait for, stopping the service when it goes away. Repeatable.
+    #[clap(long, value_parser = validate_device)]
+    bind_to_device: Vec<String>,
+    /// Network interface or device node to wait for before starting, but
+    /// keep running without. Repeatable.
+    #[clap(long, value_parser = validate_device)]
+    after_device: Vec<String>,
+    /// Unit to start after, e.g. network-online.target or postgresql. A bare
+    /// name is a service. Repeatable.
+    #[clap(long, value_name = "UNIT", value_parser = validate_unit)]
+    after: Vec<String>,
+    /// Unit to start before. Repeatable.
+    #[clap(long, value_name = "UNIT", value_parser = validate_unit)]
+    before: Vec<String>,
+    /// Unit the service needs, starting it too and failing without it. Repeatable.
+    #[clap(long, value_name = "UNIT", value_parser = validate_unit)]
+    requires: Vec<String>,
+    /// Unit to start along with the service, which starts even if it fails. Repeatable.
+    #[clap(long, value_name = "UNIT", value_parser = validate_unit)]
+    wants: Vec<String>,
+    /// Wait for the network to be up, short for --after and --wants
+    /// network-online.target.
+    #[clap(long)]
+    network: bool,
+    /// Console for the service to own, e.g. /dev/tty2. Implies --standard-input tty.
+    #[clap(long, value_parser = validate_absolute_path)]
+    tty: Option<String>,
+    /// Where stdin comes from. The TTY modes use --tty, or /dev/console without it.
+    #[clap(long, value_enum)]
+    standard_input: Option<StandardInput>,
+    /// Fail instead of warning when another managed service runs the same command.
+    #[clap(long)]
+    strict: bool,
+    /// Write the command as given, rather than resolving it against PATH and
+    /// checking it's executable, e.g. if it isn't installed yet.
+    #[clap(long)]
+    no_path_check: bool,
+    /// Install even if units with the same name exist, e.g. from a package,
+    /// overwriting or shadowing them.
+    #[clap(long, visible_alias = "shadow")]
+    force: bool,
+    /// Overwrite unit files edited since mkservice wrote them without
+    /// asking, after showing the changes.
+    #[clap(short, long)]
+    yes: bool,
+    /// Print the files that would be installed instead of installing them.
+    #[clap(long, conflicts_with_all = ["start", "verify_socket_activation", "cascade"])]
+    dry_run: bool,
+    /// Label for acting on several services at once, e.g. with `restart --tag`.
+    #[clap(long)]
+    tag: Vec<String>,
+    /// Write paths under your home and runtime directories as %h and %t, for
+    /// user services shared across machines.
+    #[clap(long)]
+    portable_paths: bool,
+    /// Start and stop with your graphical session, for user-level GUI helpers.
+    #[clap(long)]
+    graphical: bool,
+    /// When this changes a managed service, restart it along with the units
+    /// that are part of or bound to it.
+    #[clap(long)]
+    cascade: bool,
+    #[clap(flatten)]
+    annotations: settings::Annotations,
+    /// The hook `on-sleep` and `on-resume` install the command as.
+    #[clap(skip)]
+    power_hook: Option<PowerHook>,
 }
 
 pub trait ServiceOperator {
-    fn install(&self) -> Result<()>;
-    fn start(&self) -> Result<()>;
+    fn install(&self) -> error::Result<()>;
+    fn start(&self) -> error::Result<()>;
+    fn stop(&self) -> error::Result<()>;
+    /// Stops and disables the service, and removes the files `install`
+    /// wrote.
+    fn uninstall(&self) -> error::Result<()>;
+    /// Restarts the service, failing if it doesn't come back up.
+    fn restart(&self) -> error::Result<()> {
+        self.restart_with(&[])
+    }
+    /// Restarts the service together with other `units`, in one go.
+    fn restart_with(&self, units: &[String]) -> error::Result<()>;
+    /// Units that declare they're part of or bound to the service
+    /// (PartOf=, BindsTo=), which should restart along with it.
+    fn dependents(&self) -> error::Result<Vec<String>>;
+    fn status(&self) -> error::Result<provider::ServiceStatus>;
+    /// The command showing the last `lines` lines of the service's output,
+    /// and with `follow` new ones as they come, if they're kept anywhere.
+    fn log_command(&self, _lines: usize, _follow: bool) -> Option<Command> {
+        None
+    }
+    /// Unit files already present with the names this service's units
+    /// would take, wherever the service manager looks for them.
+    fn existing_units(&self) -> error::Result<Vec<PathBuf>>;
+    /// The files `install` writes, units and any companion files, as
+    /// (path, content) pairs.
+    fn unit_files(&self) -> error::Result<Vec<(PathBuf, String)>>;
 }
 
 fn str_partition(string: &str, delimiter: &str) -> (String, String) {
@@ -48,44 +820,1658 @@ fn validate_name(v: &str) -> Result<String, String> {
     Ok(v.to_string())
 }
 
-fn main() {
-    if env::var_os("RUST_LOG").is_none() {
-        env::set_var("RUST_LOG", "mkservice=info");
+fn parse_passthrough_env(v: &str) -> Result<PassthroughEnv, String> {
+    match v {
+        "none" => Ok(PassthroughEnv::None),
+        "minimal" => Ok(PassthroughEnv::Minimal),
+        _ => match v.strip_prefix("list:") {
+            Some(vars) => Ok(PassthroughEnv::List(
+                vars.split(',')
+                    .filter(|var| !var.is_empty())
+                    .map(String::from)
+                    .collect(),
+            )),
+            None => Err(format!(
+                "{:?} must be \"none\", \"minimal\" or \"list:VAR1,VAR2\".",
+                v
+            )),
+        },
+    }
+}
+
+fn validate_listen(v: &str) -> Result<String, String> {
+    socket::Listen::parse(v).map(|_| v.to_string())
+}
+
+/// Accepts an address or network, e.g. "10.0.0.0/8" or "::1", or one of
+/// systemd's IPAddressAllow= keywords.
+fn validate_ip_prefix(v: &str) -> Result<String, String> {
+    const KEYWORDS: [&str; 4] = ["any", "localhost", "link-local", "multicast"];
+    let (address, prefix) = match v.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (v, None),
+    };
+    let valid = match (address.parse::<std::net::IpAddr>(), prefix) {
+        (Ok(_), None) => true,
+        (Ok(ip), Some(prefix)) => {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            prefix.parse::<u8>().is_ok_and(|prefix| prefix <= max)
+        }
+        (Err(_), _) => KEYWORDS.contains(&v),
+    };
+    if !valid {
+        return Err(format!(
+            "{:?} must be an address, a network like 10.0.0.0/8, or one of {}.",
+            v,
+            KEYWORDS.join(", ")
+        ));
+    }
+    Ok(v.to_string())
+}
+
+fn validate_mode(v: &str) -> Result<String, String> {
+    if v.len() > 4 || u32::from_str_radix(v, 8).is_err() {
+        return Err(format!("{:?} must be an octal mode, e.g. 0660.", v));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts a file descriptor name as systemd does: up to 255 printable
+/// ASCII characters, without colons.
+fn validate_fd_name(v: &str) -> Result<String, String> {
+    let valid =
+        !v.is_empty() && v.len() <= 255 && v.chars().all(|c| c.is_ascii_graphic() && c != ':');
+    if !valid {
+        return Err(format!(
+            "{:?} must be up to 255 printable ASCII characters, without colons or spaces.",
+            v
+        ));
+    }
+    Ok(v.to_string())
+}
+
+fn validate_bus_name(v: &str) -> Result<String, String> {
+    let re_bus_name =
+        Regex::new(r"^[A-Za-z_-][A-Za-z0-9_-]*(\.[A-Za-z_-][A-Za-z0-9_-]*)+$").expect("Bad regex");
+    if !re_bus_name.is_match(v) || v.len() > 255 {
+        return Err(format!(
+            "{:?} isn't a valid D-Bus name, e.g. \"org.example.Foo\".",
+            v
+        ));
+    }
+    Ok(v.to_string())
+}
+
+fn validate_absolute_path(v: &str) -> Result<String, String> {
+    if !v.starts_with('/') {
+        return Err(format!("{:?} must be an absolute path.", v));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts a MemoryMax= size: bytes with an optional K, M, G or T suffix, a
+/// percentage, or "infinity".
+fn validate_memory(v: &str) -> Result<String, String> {
+    let re_size = Regex::new(r"^([0-9]+[KMGT]?|[0-9]+(\.[0-9]+)?%|infinity)$").expect("Bad regex");
+    if !re_size.is_match(v) {
+        return Err(format!("{:?} isn't a size like 512M, or a percentage.", v));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts a CPUQuota= percentage above zero.
+fn validate_cpu_quota(v: &str) -> Result<String, String> {
+    match v.strip_suffix('%').map(str::parse::<f64>) {
+        Some(Ok(percent)) if percent > 0.0 && percent.is_finite() => Ok(v.to_string()),
+        _ => Err(format!("{:?} isn't a percentage like 50%.", v)),
+    }
+}
+
+/// Accepts a TasksMax= count, a percentage, or "infinity".
+fn validate_tasks_max(v: &str) -> Result<String, String> {
+    let re_tasks = Regex::new(r"^([0-9]+|[0-9]+(\.[0-9]+)?%|infinity)$").expect("Bad regex");
+    if !re_tasks.is_match(v) {
+        return Err(format!("{:?} isn't a number of tasks, or a percentage.", v));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts a device node's absolute path, or a network interface's name.
+fn validate_device(v: &str) -> Result<String, String> {
+    if v.starts_with('/') {
+        return Ok(v.to_string());
+    }
+    let re_interface = Regex::new(r"^[^/\s:]{1,15}$").expect("Bad regex");
+    if !re_interface.is_match(v) || v == "." || v == ".." {
+        return Err(format!(
+            "{:?} is neither a device's path nor a network interface.",
+            v
+        ));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts a unit's name, taking a bare name as a service's, e.g.
+/// "postgresql" as "postgresql.service".
+fn validate_unit(v: &str) -> Result<String, String> {
+    let re_unit = Regex::new(r"^[A-Za-z0-9:_\\@-][A-Za-z0-9:_.\\@-]*$").expect("Bad regex");
+    if !re_unit.is_match(v) || v.ends_with('.') {
+        return Err(format!("{:?} isn't a unit's name.", v));
+    }
+    match v.contains('.') {
+        true => Ok(v.to_string()),
+        false => Ok(format!("{}.service", v)),
+    }
+}
+
+/// Accepts an absolute path, optionally prefixed with "-" as in systemd's
+/// EnvironmentFile=.
+fn validate_env_file(v: &str) -> Result<String, String> {
+    validate_absolute_path(v.strip_prefix('-').unwrap_or(v))?;
+    Ok(v.to_string())
+}
+
+/// Accepts KEY=VALUE or KEY, where KEY is a shell variable name.
+fn validate_env_secret(v: &str) -> Result<String, String> {
+    let key = v.split_once('=').map_or(v, |(key, _)| key);
+    let re_var = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").expect("Bad regex");
+    if !re_var.is_match(key) {
+        return Err(format!("{:?} isn't a variable name.", key));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts PATH#FIELD, where FIELD is a shell variable name.
+fn validate_vault_secret(v: &str) -> Result<String, String> {
+    let (_, field) = vault::split(v).ok_or("Expected PATH#FIELD.")?;
+    validate_env_secret(field)?;
+    Ok(v.to_string())
+}
+
+/// Splits a `--unit-option` into its section, key and value.
+fn split_unit_option(v: &str) -> Option<(&str, &str, &str)> {
+    let (section, directive) = v.split_once(':')?;
+    let (key, value) = directive.split_once('=')?;
+    Some((section, key, value))
+}
+
+/// Accepts Section:Key=Value for a section of the service unit.
+fn validate_unit_option(v: &str) -> Result<String, String> {
+    let (section, key, _) = split_unit_option(v).ok_or("Expected Section:Key=Value.")?;
+    if !["Unit", "Service", "Install"].contains(&section) {
+        return Err(format!(
+            "{:?} isn't a section of the service unit: Unit, Service or Install.",
+            section
+        ));
+    }
+    let re_key = Regex::new(r"^[A-Za-z][A-Za-z0-9]*$").expect("Bad regex");
+    if !re_key.is_match(key) {
+        return Err(format!("{:?} isn't a directive name.", key));
+    }
+    Ok(v.to_string())
+}
+
+/// Accepts KEY=ATTR, where KEY is a shell variable name.
+fn validate_gce_metadata(v: &str) -> Result<String, String> {
+    match v.split_once('=') {
+        Some((key, attr)) if !attr.is_empty() => {
+            validate_env_secret(key)?;
+            Ok(v.to_string())
+        }
+        _ => Err("Expected KEY=ATTR.".into()),
+    }
+}
+
+/// Accepts an absolute path, optionally prefixed with "!" for launchd to
+/// keep the job alive while it's missing.
+fn validate_keepalive_path(v: &str) -> Result<String, String> {
+    validate_absolute_path(v.strip_prefix('!').unwrap_or(v))?;
+    Ok(v.to_string())
+}
+
+/// Parses KEY=VALUE lines as systemd reads an EnvironmentFile=: blank lines
+/// and "#" or ";" comments are skipped, and a value may be quoted.
+fn parse_env_file(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', ';']))
+        .filter(|line| line.contains('='))
+        .map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = str_partition(line, "=");
+            let value = value.trim();
+            let unquoted = ['"', '\'']
+                .iter()
+                .find_map(|&q| value.strip_prefix(q)?.strip_suffix(q))
+                .unwrap_or(value);
+            (key.trim().to_string(), unquoted.to_string())
+        })
+        .collect()
+}
+
+/// Reads the service's env files into its environment for
+/// `--inline-env-files`. The files' values win over --env ones, as
+/// EnvironmentFile= overrides Environment= in systemd.
+fn inline_env_files(service: &mut ServiceConfig) -> Result<()> {
+    for file in std::mem::take(&mut service.env_files) {
+        let (path, optional) = match file.strip_prefix('-') {
+            Some(path) => (path, true),
+            None => (file.as_str(), false),
+        };
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if optional && e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(anyhow!("Couldn't read {:?}: {}", path, e)),
+        };
+        for (key, value) in parse_env_file(&content) {
+            service.env.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Accepts systemd time spans such as "30", "5m", "1h 30min" or "2d".
+fn validate_timespan(v: &str) -> Result<String, String> {
+    timespan::parse(v).map(|_| v.to_string())
+}
+
+/// Checks a whole service, as built from flags or received by the agent:
+/// each value as its flag's validator does, and the settings that only go
+/// together some ways. Environment variables and unit options can't have
+/// control characters, which would break the unit's lines.
+fn validate_service(service: &ServiceConfig) -> Result<()> {
+    fn check<'a>(
+        field: &str,
+        values: impl IntoIterator<Item = &'a String>,
+        validate: fn(&str) -> Result<String, String>,
+    ) -> Result<()> {
+        for value in values {
+            validate(value).map_err(|e| anyhow!("Bad {}: {}", field, e))?;
+        }
+        Ok(())
     }
-    env_logger::init();
+    if service.command.is_empty() {
+        return Err(anyhow!("The service has no command."));
+    }
+    // Specifiers like %h stand for absolute paths.
+    let working_directory = service
+        .working_directory
+        .iter()
+        .filter(|dir| !(service.specifiers && dir.starts_with('%')));
+    let timespans = service.timer.iter().flat_map(|timer| {
+        [
+            &timer.on_boot,
+            &timer.on_unit_inactive,
+            &timer.randomized_delay,
+        ]
+        .into_iter()
+        .flatten()
+    });
+    let gce_metadata: Vec<String> = service
+        .gce_metadata_env
+        .iter()
+        .map(|(key, attr)| format!("{}={}", key, attr))
+        .collect();
+    let unit_options: Vec<String> = service
+        .unit_options
+        .iter()
+        .flat_map(|(section, options)| {
+            options.iter().flat_map(move |(key, values)| {
+                values
+                    .iter()
+                    .map(move |value| format!("{}:{}={}", section, key, value))
+            })
+        })
+        .collect();
+    check("env_files", service.env_files.iter(), validate_env_file)?;
+    check(
+        "env_secrets",
+        service.env_secrets.keys(),
+        validate_env_secret,
+    )?;
+    check(
+        "vault_secrets",
+        service.vault_secrets.iter(),
+        validate_vault_secret,
+    )?;
+    check(
+        "gce_metadata_env",
+        gce_metadata.iter(),
+        validate_gce_metadata,
+    )?;
+    check(
+        "working_directory",
+        working_directory,
+        validate_absolute_path,
+    )?;
+    check("bus_name", service.bus_name.iter(), validate_bus_name)?;
+    check("listen", service.listen.iter(), validate_listen)?;
+    check(
+        "socket_allow",
+        service.socket_allow.iter(),
+        validate_ip_prefix,
+    )?;
+    check("socket_mode", service.socket_mode.iter(), validate_mode)?;
+    check(
+        "socket_fd_name",
+        service.socket_fd_name.iter(),
+        validate_fd_name,
+    )?;
+    check("memory_max", service.memory_max.iter(), validate_memory)?;
+    check("cpu_quota", service.cpu_quota.iter(), validate_cpu_quota)?;
+    check("tasks_max", service.tasks_max.iter(), validate_tasks_max)?;
+    check(
+        "keepalive_paths",
+        service.keepalive_paths.iter(),
+        validate_keepalive_path,
+    )?;
+    check("pidfile", service.pidfile.iter(), validate_absolute_path)?;
+    check("tty", service.tty.iter(), validate_absolute_path)?;
+    check(
+        "requires_mounts",
+        service.requires_mounts.iter(),
+        validate_absolute_path,
+    )?;
+    let devices = service.bind_to_devices.iter().chain(&service.after_devices);
+    check("devices", devices, validate_device)?;
+    let units = [
+        &service.after,
+        &service.before,
+        &service.requires,
+        &service.wants,
+    ];
+    check("units", units.into_iter().flatten(), validate_unit)?;
+    check("timer", timespans, validate_timespan)?;
+    check("unit_options", unit_options.iter(), validate_unit_option)?;
 
-    let args = Args::parse();
+    let has_control = |s: &str| s.chars().any(char::is_control);
+    for (key, value) in &service.env {
+        if has_control(key) || has_control(value) {
+            return Err(anyhow!(
+                "The environment variable {:?} has control characters in it.",
+                key
+            ));
+        }
+    }
+    for (section, options) in &service.unit_options {
+        for (key, values) in options {
+            if has_control(key) || values.iter().any(|value| has_control(value)) {
+                return Err(anyhow!(
+                    "The unit option {}:{} has control characters in it.",
+                    section,
+                    key
+                ));
+            }
+        }
+    }
 
-    let service = ServiceConfig {
-        name: args.name,
-        command: args.command,
-        level: args.level,
-        env: args
-            .env
-            .into_iter()
-            .map(|v| str_partition(&v, "="))
+    let user = service.level == ServiceLevel::User;
+    if service.graphical && !user {
+        return Err(anyhow!(
+            "--graphical is for user services, add --level user."
+        ));
+    }
+    if !service.env_secrets.is_empty() && user {
+        return Err(anyhow!(
+            "--env-secret is for system services, systemd encrypts them with the host's key."
+        ));
+    }
+    let scheduled = service.timer.is_some();
+    let activated = service.bus_name.is_some() || !service.listen.is_empty();
+    if scheduled && activated {
+        return Err(anyhow!(
+            "A schedule can't start the service along with --dbus-name or --listen, pick one."
+        ));
+    }
+    let hook = service.at_shutdown || service.at_boot_once;
+    if hook && (scheduled || activated) {
+        return Err(anyhow!(
+            "Boot and shutdown start a hook, it can't have a schedule, --dbus-name or --listen too."
+        ));
+    }
+    let oneshot = service.first_boot_only || hook || service.power_hook.is_some() || scheduled;
+    let service_type = service.service_type;
+    if oneshot && service_type != ServiceType::Oneshot {
+        return Err(anyhow!(
+            "Schedules, --first-boot-only, boot and power hooks run oneshots, not {} services.",
+            service_type.name()
+        ));
+    }
+    if service.remain_after_exit && service_type != ServiceType::Oneshot {
+        return Err(anyhow!(
+            "--remain-after-exit is for oneshots, a {} service stays active while it runs.",
+            service_type.name()
+        ));
+    }
+    if service_type == ServiceType::Forking && service.pidfile.is_none() {
+        return Err(anyhow!(
+            "A forking service needs --pidfile, naming the daemon it leaves running."
+        ));
+    }
+    Ok(())
+}
+
+/// The service's type: the one asked for, else what its shape needs. A
+/// preset's type counts as asked for, unless a flag asked for one, and goes
+/// through the same checks in `validate_service`.
+fn resolve_service_type(
+    args: &Args,
+    preset: Option<&preset::Preset>,
+    scheduled: bool,
+) -> ServiceType {
+    let oneshot = args.first_boot_only
+        || args.at_shutdown
+        || args.at_boot_once
+        || args.power_hook.is_some()
+        || scheduled;
+    let requested_type = args.service_type.or_else(|| {
+        preset
+            .and_then(preset::Preset::service_type)
+            .filter(|_| args.dbus_name.is_none())
+    });
+    match requested_type {
+        Some(service_type) => service_type,
+        None if oneshot => ServiceType::Oneshot,
+        None if args.dbus_name.is_some() => ServiceType::Dbus,
+        None => ServiceType::Simple,
+    }
+}
+
+fn service_config(args: &Args) -> Result<ServiceConfig> {
+    let mut settings = settings::load()?;
+    settings.annotate(&args.annotations);
+    let level = args
+        .level
+        .clone()
+        .or_else(|| settings.level.clone())
+        .unwrap_or_default();
+    let mut env: BTreeMap<String, String> =
+        args.env.iter().map(|v| str_partition(v, "=")).collect();
+    let mut command = args.command.clone();
+    if let Some(image) = &args.from_docker_image {
+        let image_config = image::inspect(image)?;
+        command = image_config.command(&args.command);
+        for (key, value) in image_config.env() {
+            env.entry(key).or_insert(value);
+        }
+        if let Some(dir) = image_config.working_dir.filter(|dir| !dir.is_empty()) {
+            if args.workdir.is_none() {
+                log::warn!(
+                    "{} runs in {:?}, pass --workdir for the service to.",
+                    image,
+                    dir
+                );
+            }
+        }
+        if let Some(user) = image_config.user.filter(|user| !user.is_empty()) {
+            log::warn!("{} runs as user {:?}, the service won't.", image, user);
+        }
+        if command.is_empty() {
+            return Err(anyhow!("{} has no ENTRYPOINT or CMD.", image));
+        }
+        if !Path::new(&command[0]).exists() {
+            log::warn!(
+                "{:?} is a path in the image, install it at the same place on this host.",
+                command[0]
+            );
+        }
+    }
+
+    if let Some(path) = &args.path {
+        env.insert("PATH".into(), path.clone());
+    } else if args.inherit_path {
+        let path = env::var("PATH")?;
+        log::warn!(
+            "Baking the current PATH into the unit, it may not match on other hosts: {}",
+            path
+        );
+        env.insert("PATH".into(), path);
+    }
+    if let Some(proxy) = &args.proxy {
+        let mut vars = vec![("HTTP_PROXY", proxy), ("HTTPS_PROXY", proxy)];
+        if let Some(no_proxy) = &args.no_proxy {
+            vars.push(("NO_PROXY", no_proxy));
+        }
+        // Tools disagree on the case they read, so set both. --env wins.
+        for (key, value) in vars {
+            for key in [key.to_string(), key.to_lowercase()] {
+                env.entry(key).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    if let Some(venv) = &args.venv {
+        command::use_venv(venv, &mut env, &mut command)?;
+    }
+    if let Some(version) = &args.node_version {
+        let bin_dir = command::node_bin_dir(version)?;
+        command::prepend_path(&mut env, &bin_dir);
+        command::resolve_in(&bin_dir, &mut command);
+    }
+    // Image commands are paths inside the image, warned about above.
+    if !args.no_path_check && args.from_docker_image.is_none() {
+        command::resolve_program(&mut command, env.get("PATH").map(String::as_str))?;
+    }
+    if let Some(wrapper) = args.wrap {
+        let wrap_args: Vec<String> = args
+            .wrap_args
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        command = command::wrap(wrapper, &wrap_args, command)?;
+    }
+
+    let timer = args.timer.timer_config()?;
+    let preset = args.preset.as_deref().map(preset::load).transpose()?;
+    let service_type = resolve_service_type(args, preset.as_ref(), timer.is_some());
+    let forking = service_type == ServiceType::Forking;
+    let network = args.network.then(|| "network-online.target".to_string());
+    let name = manifest::qualify(&args.name);
+    let env_secrets = match args.env_secret.is_empty() {
+        true => BTreeMap::new(),
+        false if level == ServiceLevel::User => {
+            return Err(anyhow!(
+                "--env-secret is for system services, systemd encrypts them with the host's key."
+            ))
+        }
+        false => secret::seal(&args.env_secret, manifest::load(&level, &name)?.as_ref())?,
+    };
+    let mut service = ServiceConfig {
+        name,
+        command,
+        level: level.clone(),
+        env,
+        env_files: args.env_file.clone(),
+        env_secrets,
+        vault_secrets: args.vault_secret.clone(),
+        imds_env: args.env_from_imds.clone(),
+        gce_metadata_env: args
+            .env_from_gce_metadata
+            .iter()
+            .filter_map(|entry| entry.split_once('='))
+            .map(|(key, attr)| (key.to_string(), attr.to_string()))
             .collect(),
+        requires_mounts: args.requires_mount.clone(),
+        bind_to_devices: args.bind_to_device.clone(),
+        after_devices: args.after_device.clone(),
+        after: args.after.iter().cloned().chain(network.clone()).collect(),
+        before: args.before.clone(),
+        requires: args.requires.clone(),
+        wants: args.wants.iter().cloned().chain(network).collect(),
+        first_boot_only: args.first_boot_only,
+        at_shutdown: args.at_shutdown,
+        at_boot_once: args.at_boot_once,
+        working_directory: args.workdir.clone(),
+        user: args.run_as_user.clone(),
+        group: args.run_as_group.clone(),
+        bus_name: args.dbus_name.clone(),
+        listen: args.listen.clone(),
+        accept: args.accept,
+        socket_allow: args.socket_allow.clone(),
+        socket_bind_device: args.socket_bind_device.clone(),
+        socket_user: args.socket_user.clone(),
+        socket_mode: args.socket_mode.clone(),
+        socket_fd_name: args.socket_fd_name.clone(),
+        fd_store_max: args.fd_store_max,
+        restart: args.restart,
+        restart_sec: args.restart_sec,
+        memory_max: args.memory_max.clone(),
+        cpu_quota: args.cpu_quota.clone(),
+        tasks_max: args.tasks_max.clone(),
+        keepalive_paths: args.keepalive_path.clone(),
+        openrc: OpenRcConfig {
+            supervisor: args.supervisor,
+            respawn_max: args.respawn_max,
+            respawn_period: args.respawn_period,
+            pidfile: args.pidfile.clone().filter(|_| !forking),
+            background: args.command_background,
+        },
+        pidfile: args.pidfile.clone().filter(|_| forking),
+        remain_after_exit: args.remain_after_exit,
+        tty: args.tty.clone(),
+        standard_input: args
+            .standard_input
+            .or(args.tty.as_ref().map(|_| StandardInput::Tty)),
+        tags: args.tag.clone(),
+        graphical: args.graphical,
+        timer,
+        passthrough_env: args
+            .passthrough_env
+            .clone()
+            .or_else(|| PassthroughEnv::default_for(&level)),
+        service_type,
+        ..Default::default()
     };
+    if args.inline_env_files {
+        inline_env_files(&mut service)?;
+    }
+    if args.portable_paths {
+        portable_paths(&mut service)?;
+    }
+    for option in &args.unit_option {
+        let (section, key, value) = split_unit_option(option).expect("Validated");
+        service
+            .unit_options
+            .entry(section.into())
+            .or_default()
+            .entry(key.into())
+            .or_default()
+            .push(value.into());
+    }
+    // Before the preset, which goes beneath flags, and after the working
+    // directory's final form.
+    if let Some(hardening) = args.harden {
+        hardening.apply(&mut service);
+    }
+    if let Some(preset) = preset {
+        preset::apply(&mut service, preset);
+    }
+    if let Some(hook) = args.power_hook {
+        power::apply(&mut service, hook)?;
+    }
+    settings.apply(&mut service)?;
+    validate_service(&service)?;
+    Ok(service)
+}
+
+/// Rewrites the service's paths with specifiers for `--portable-paths`, which
+/// only user services can use.
+fn portable_paths(service: &mut ServiceConfig) -> Result<()> {
+    if service.level != ServiceLevel::User {
+        return Err(anyhow!(
+            "--portable-paths is for user services, add --level user."
+        ));
+    }
+    let home = PathBuf::from(env::var("HOME")?);
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+    provider::systemd::use_specifiers(service, &home, runtime_dir.as_deref());
+    Ok(())
+}
+
+/// Fails unless the service's working directory exists, or creates it with
+/// `create`, for the account the service runs as.
+fn check_workdir(service: &ServiceConfig, dir: &Path, create: bool) -> Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    if !create {
+        return Err(anyhow!(
+            "{:?} isn't a directory to run in. Create it, or pass --create-workdir.",
+            dir
+        ));
+    }
+    log::info!("Creating {:?}", dir);
+    std::fs::create_dir_all(dir).with_context(|| format!("Can't create {:?}", dir))?;
+    if let Some(user) = &service.user {
+        let owner = match &service.group {
+            Some(group) => format!("{}:{}", user, group),
+            None => user.clone(),
+        };
+        let status = std::process::Command::new("chown")
+            .arg(&owner)
+            .arg(dir)
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("Can't hand {:?} to {}.", dir, owner));
+        }
+    }
+    Ok(())
+}
+
+/// Warns, or fails with `--strict`, if another managed service already runs
+/// the same command.
+fn check_duplicates(args: &Args, service: &ServiceConfig) -> Result<()> {
+    let managed = manifest::load_all(&service.level)?;
+    let duplicates = manifest::same_command(service, &managed);
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<&str> = duplicates.iter().map(|s| s.name.as_str()).collect();
+    let message = format!(
+        "Managed service(s) {:?} already run the same command.",
+        names
+    );
+    if args.strict {
+        return Err(anyhow!(message));
+    }
+    log::warn!("{}", message);
+    Ok(())
+}
+
+/// Reports drift for managed services, optionally fixing it. Returns
+/// whether any drift was left in place.
+fn drift(
+    level: Option<&ServiceLevel>,
+    name: Option<&str>,
+    accept: bool,
+    restore: bool,
+) -> Result<bool> {
+    let services = match name {
+        Some(name) => vec![manifest::find(name, level)?],
+        None => {
+            let mut services = Vec::new();
+            for level in manifest::levels(level) {
+                services.extend(manifest::load_all(&level)?);
+            }
+            services
+        }
+    };
+
+    let mut drifted = false;
+    for service in services {
+        let found = drift::detect(service)?;
+        if found.is_empty() {
+            log::info!("{}: no drift.", found.service.name);
+            continue;
+        }
+        let color = output::stdout_color();
+        for unit in &found.units {
+            print!("{}", output::paint_diff(&unit.diff(), color));
+        }
+        if let Some(enabled) = &found.enabled {
+            println!("{} is {}, not enabled.", found.service.name, enabled);
+        }
+        if accept {
+            let service = drift::accept(&found)?;
+            manifest::save(&service)?;
+            log::info!("{}: accepted live units.", service.name);
+            if found.enabled.is_some() {
+                log::warn!(
+                    "{}: enablement isn't recorded, run --restore to re-enable.",
+                    service.name
+                );
+                drifted = true;
+            }
+        } else if restore {
+            let p = provider::get_provider(found.service.clone())?;
+            p.install()?;
+            log::info!("{}: restored from manifest.", found.service.name);
+        } else {
+            drifted = true;
+        }
+    }
+    Ok(drifted)
+}
+
+/// Refuses to overwrite or shadow units mkservice didn't write, unless
+/// `force` is set. Reinstalling a managed service replaces its own units.
+fn check_existing_units(service: &ServiceConfig, force: bool) -> Result<()> {
+    let p = provider::get_provider(service.clone())?;
+    let paths: Vec<PathBuf> = p.unit_files()?.into_iter().map(|(path, _)| path).collect();
+    let managed = manifest::load(&service.level, &service.name)?.is_some();
+    let (overwritten, shadowed): (Vec<PathBuf>, Vec<PathBuf>) = p
+        .existing_units()?
+        .into_iter()
+        .filter(|path| !(managed && paths.contains(path)))
+        .partition(|path| paths.contains(path));
+    let list = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut problems = Vec::new();
+    if !overwritten.is_empty() {
+        problems.push(format!(
+            "Installing {:?} would overwrite units mkservice didn't write: {}",
+            service.name,
+            list(&overwritten)
+        ));
+    }
+    if !shadowed.is_empty() {
+        problems.push(format!(
+            "Units named like {:?} already exist, the service would override them: {}",
+            service.name,
+            list(&shadowed)
+        ));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if force {
+        problems
+            .iter()
+            .for_each(|problem| log::warn!("{}", problem));
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{}. Pass --force to install anyway.",
+        problems.join(". ")
+    ))
+}
+
+/// Shows how installing `service` would change unit files edited since
+/// mkservice wrote them, or that it didn't write, and asks before going on
+/// unless `yes` is set.
+fn confirm_overwrites(service: &ServiceConfig, yes: bool) -> Result<()> {
+    let diffs = drift::overwrites(service)?;
+    if diffs.is_empty() {
+        return Ok(());
+    }
+    let color = output::stdout_color();
+    for diff in &diffs {
+        print!("{}", output::paint_diff(diff, color));
+    }
+    if yes {
+        log::warn!("Overwriting the edits to {:?}'s units.", service.name);
+        return Ok(());
+    }
+    if !output::confirm("Overwrite these edits?", "--yes")? {
+        return Err(anyhow!(
+            "Not overwriting the edits to {:?}'s units.",
+            service.name
+        ));
+    }
+    Ok(())
+}
 
+/// Logs the error and exits, for failures that end the run.
+fn exit_on_error<T>(result: Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            if error::json() {
+                eprintln!("{}", error::to_json(&e));
+                exit(
+                    e.downcast_ref::<MkserviceError>()
+                        .map_or(1, MkserviceError::exit_code),
+                );
+            }
+            log::error!("{:#}", e);
+            match e.downcast_ref::<MkserviceError>() {
+                Some(e) => {
+                    if let Some(hint) = e.hint() {
+                        log::error!("Hint: {}", hint);
+                    }
+                    exit(e.exit_code());
+                }
+                None => exit(1),
+            }
+        }
+    }
+}
+
+/// Installs the service, records its manifest and optionally starts it.
+fn install_service(service: &ServiceConfig, start: bool) -> Result<()> {
+    install_services(std::slice::from_ref(service), start)
+}
+
+/// The files installing `service` would write, each headed by a comment
+/// with its path, for `--dry-run`. Rendered for systemd on hosts without a
+/// known service manager, so units can be previewed anywhere.
+fn render(service: &ServiceConfig) -> Result<String> {
+    let kind = provider::detect().unwrap_or(provider::ProviderKind::Systemd);
+    Ok(provider::for_kind(kind, service.clone())
+        .unit_files()?
+        .iter()
+        .map(|(path, content)| format!("# {}\n{}", path.display(), content))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Installs `services` together, so the service manager reloads and
+/// enables them once rather than per service. Nothing is written unless the
+/// manager can run all of them.
+fn install_services(services: &[ServiceConfig], start: bool) -> Result<()> {
+    let kind = provider::detect()?;
+    for service in services {
+        provider::capabilities::check(kind, service)?;
+    }
+    provider::install_all(kind, services).context("Failed creating service")?;
+    for service in services {
+        manifest::save(service).context("Failed saving manifest")?;
+    }
+    if start {
+        provider::start_all(kind, services).context("Error starting service")?;
+    }
+    match services {
+        [service] => log::info!("Service {:?} installed.", service.name),
+        _ => log::info!("{} services installed.", services.len()),
+    }
+    Ok(())
+}
+
+/// Installs imported services, checking them all before writing any.
+#[cfg(feature = "import")]
+fn install_imported(import: &ImportArgs, services: Result<Vec<ServiceConfig>>) {
+    let services = exit_on_error(services.and_then(|services| import::finish(services, import)));
+    if import.dry_run {
+        let rendered: Result<Vec<String>> = services.iter().map(render).collect();
+        print!("{}", exit_on_error(rendered).join("\n"));
+        return;
+    }
+    for service in &services {
+        log::debug!("Service: {:#?}", service);
+        exit_on_error(check_existing_units(service, import.force));
+    }
+    exit_on_error(install_services(&services, import.start));
+}
+
+/// Waits for `service` to come up after `--start`, printing its recent log
+/// if it doesn't. Timers and sockets start their services later, so those
+/// aren't waited for.
+fn wait_started(service: &ServiceConfig, timeout: Duration) -> Result<()> {
+    if timeout.is_zero() || service.timer.is_some() || !service.listen.is_empty() {
+        return Ok(());
+    }
+    let p = provider::get_provider(service.clone())?;
+    let waited = restart::wait_started(service, p.as_ref(), timeout);
+    if waited.is_err() {
+        match provider::recent_logs(p.as_ref(), 20) {
+            Ok(Some(logs)) => eprint!("{}", logs),
+            Ok(None) => {}
+            Err(e) => log::warn!("Couldn't read the service's log: {}", e),
+        }
+    }
+    waited
+}
+
+/// Installs a power hook, which sleep starts rather than --start.
+fn install_power_hook(mut args: Box<Args>, hook: PowerHook) {
+    args.power_hook = Some(hook);
+    let service = exit_on_error(service_config(&args).and_then(|service| {
+        if args.start {
+            return Err(anyhow!(
+                "A power hook runs as the machine sleeps or resumes, it can't be started now."
+            ));
+        }
+        Ok(service)
+    }));
+    install(&args, service);
+}
+
+fn install(args: &Args, service: ServiceConfig) {
     log::debug!("Service: {:#?}", service);
+    if args.dry_run {
+        print!("{}", exit_on_error(render(&service)));
+        return;
+    }
 
-    match provider::get_provider(service.clone()) {
-        Some(p) => {
-            if let Err(e) = p.install() {
-                log::error!("Failed creating service: {:?}", e);
+    let check_account = || account::check(service.user.as_deref(), service.group.as_deref());
+    if !args.create_user {
+        exit_on_error(check_account());
+    }
+    // The directory as given, before --portable-paths rewrites it.
+    let workdir = args.workdir.as_deref().map(Path::new);
+    if let Some(dir) = workdir.filter(|_| !args.create_workdir) {
+        exit_on_error(check_workdir(&service, dir, false));
+    }
+    exit_on_error(check_duplicates(args, &service));
+    exit_on_error(check_existing_units(&service, args.force));
+    exit_on_error(confirm_overwrites(&service, args.yes));
+    // Only once nothing above refused the install, and the user first, to
+    // own the working directory.
+    if args.create_user {
+        if let Some(user) = service
+            .user
+            .as_deref()
+            .filter(|user| !account::exists(account::Database::Passwd, user))
+        {
+            exit_on_error(account::create_system_user(user, service.group.as_deref()));
+        }
+        exit_on_error(check_account());
+    }
+    if let Some(dir) = workdir.filter(|_| args.create_workdir) {
+        exit_on_error(check_workdir(&service, dir, true));
+    }
+    if exit_on_error(drift::unchanged(&service)) {
+        log::info!("Service {} is up to date, nothing to do.", service.name);
+        if args.start {
+            let started = provider::get_provider(service.clone()).and_then(|p| p.start());
+            exit_on_error(started.map_err(anyhow::Error::from));
+            exit_on_error(wait_started(&service, args.start_timeout));
+        }
+        return;
+    }
+    let color = output::stdout_color();
+    for diff in exit_on_error(drift::updates(&service)) {
+        print!("{}", output::paint_diff(&diff, color));
+    }
+    let previous = exit_on_error(manifest::load(&service.level, &service.name));
+    // A shutdown hook only runs if it's active by then.
+    let start = args.start || service.at_shutdown;
+    exit_on_error(install_service(&service, start));
+    if start {
+        exit_on_error(wait_started(&service, args.start_timeout));
+    }
+    if args.verify_socket_activation {
+        exit_on_error(socket::verify_activation(&service));
+    }
+    if args.cascade {
+        exit_on_error(cascade::cascade(previous.as_ref(), &service));
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum StackCommand {
+    /// Show the stack's services as a tree over what they depend on, with
+    /// their state, uptime and restarts. Exits 1 if any is down.
+    Status {
+        name: String,
+        /// Only look at services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum TrashCommand {
+    /// List the uninstalled services that can be restored.
+    List {
+        /// Only list services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+    /// Delete uninstalled services for good.
+    Empty {
+        /// Only delete those uninstalled at least this long ago, e.g. "30d".
+        #[clap(long, value_parser = timespan::parse, default_value = "0s")]
+        older_than: Duration,
+        /// Only delete services at this level, rather than both.
+        #[clap(long, value_enum)]
+        level: Option<ServiceLevel>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SecretsCommand {
+    /// Replace the host's credential key, re-encrypting every managed secret
+    /// with the new one. Credentials encrypted with it outside mkservice
+    /// can't be decrypted afterwards.
+    RotateKey,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if env::var_os("RUST_LOG").is_none() {
+        let level = match cli.verbose {
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        };
+        env::set_var("RUST_LOG", format!("mkservice={}", level));
+    }
+    let mut logger = env_logger::Builder::from_default_env();
+    if env::var_os("RUST_LOG_STYLE").is_none() {
+        logger.write_style(if output::stderr_color() {
+            env_logger::WriteStyle::Always
+        } else {
+            env_logger::WriteStyle::Never
+        });
+    }
+    logger.init();
+    if let Some(namespace) = cli.namespace {
+        manifest::set_namespace(namespace);
+    }
+    if cli.json_errors {
+        error::set_json();
+    }
+
+    match cli.subcommand {
+        Some(Subcommand::Install { args }) => {
+            let service = exit_on_error(service_config(&args));
+            install(&args, service);
+        }
+        Some(Subcommand::Start { names, level }) => {
+            for name in names {
+                let service = exit_on_error(manifest::find(&name, level.as_ref()));
+                exit_on_error(
+                    provider::get_provider(service)
+                        .and_then(|p| p.start())
+                        .map_err(anyhow::Error::from),
+                );
+                log::info!("Service {} started.", name);
+            }
+        }
+        Some(Subcommand::Stop { names, level }) => {
+            for name in names {
+                let service = exit_on_error(manifest::find(&name, level.as_ref()));
+                exit_on_error(
+                    provider::get_provider(service)
+                        .and_then(|p| p.stop())
+                        .map_err(anyhow::Error::from),
+                );
+                log::info!("Service {} stopped.", name);
+            }
+        }
+        Some(Subcommand::Status { name, level }) => {
+            let service = exit_on_error(manifest::find(&name, level.as_ref()));
+            let status = provider::get_provider(service.clone()).and_then(|p| p.status());
+            let entry = report::ReportEntry { service, status };
+            print!(
+                "{}",
+                exit_on_error(report::render_status(&entry, output::stdout_color()))
+            );
+            if stack::is_down(&entry) {
                 exit(1);
             }
-            if args.start {
-                if let Err(e) = p.start() {
-                    log::error!("Error starting service: {:?}", e);
-                    exit(1);
+        }
+        Some(Subcommand::Logs {
+            name,
+            follow,
+            lines,
+            level,
+        }) => {
+            let service = exit_on_error(manifest::find(&name, level.as_ref()));
+            let code = provider::get_provider(service)
+                .and_then(|p| provider::show_logs(p.as_ref(), lines, follow))
+                .map_err(anyhow::Error::from);
+            exit(exit_on_error(code));
+        }
+        Some(Subcommand::Job { script, args }) => {
+            let service = exit_on_error(service_config(&args).and_then(|mut service| {
+                job::apply(&mut service, &script)?;
+                Ok(service)
+            }));
+            install(&args, service);
+        }
+        Some(Subcommand::Kiosk { user, args }) => {
+            let service = exit_on_error(service_config(&args).and_then(|mut service| {
+                kiosk::apply(&mut service, &user)?;
+                Ok(service)
+            }));
+            install(&args, service);
+        }
+        Some(Subcommand::OnSleep { args }) => install_power_hook(args, PowerHook::Sleep),
+        Some(Subcommand::OnResume { args }) => install_power_hook(args, PowerHook::Resume),
+        #[cfg(feature = "agent")]
+        Some(Subcommand::Agent { socket, level }) => {
+            exit_on_error(
+                socket
+                    .map_or_else(|| agent::default_socket(&level), Ok)
+                    .and_then(|socket| agent::serve(&socket, &level)),
+            );
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportProcfile {
+            procfile,
+            mut import,
+        }) => {
+            import.stack = import.stack.or_else(|| import::default_stack(&procfile));
+            install_imported(&import, import::procfile::import(&procfile));
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportCompose {
+            compose_file,
+            mut import,
+        }) => {
+            import.stack = import
+                .stack
+                .or_else(|| import::default_stack(&compose_file));
+            install_imported(&import, import::compose::import(&compose_file));
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportPm2 {
+            ecosystem_file,
+            mut import,
+        }) => {
+            import.stack = import
+                .stack
+                .or_else(|| import::default_stack(&ecosystem_file));
+            install_imported(&import, import::pm2::import(&ecosystem_file));
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportSupervisor {
+            config_files,
+            import,
+        }) => {
+            install_imported(&import, import::supervisor::import(&config_files));
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportCrontab {
+            user,
+            file,
+            yes,
+            import,
+        }) => {
+            let jobs = exit_on_error(import::crontab::read(file.as_deref(), user.as_deref()));
+            let schedules: Vec<String> = jobs.iter().map(|job| job.schedule.clone()).collect();
+            let services = exit_on_error(import::crontab::import(jobs));
+            for (schedule, service) in schedules.iter().zip(&services) {
+                let calendar = match &service.timer {
+                    Some(timer) => timer.on_calendar.join(", "),
+                    None => "at boot".into(),
+                };
+                println!("{}: {}  ->  {}", service.name, schedule, calendar);
+                for expression in service.timer.iter().flat_map(|t| &t.on_calendar) {
+                    exit_on_error(schedule::check_calendar(expression));
                 }
             }
+            if !yes && !exit_on_error(output::confirm("Install these jobs?", "--yes")) {
+                exit(1);
+            }
+            install_imported(&import, Ok(services));
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportLaunchd {
+            plist_files,
+            import,
+        }) => {
+            install_imported(&import, import::launchd::import(&plist_files));
+        }
+        #[cfg(feature = "import")]
+        Some(Subcommand::ImportSystemd { unit_files, import }) => {
+            install_imported(&import, import::systemd::import(&unit_files));
+        }
+        Some(Subcommand::List { level, porcelain }) => {
+            let levels = manifest::levels(level.as_ref());
+            let list = report::collect(&levels).and_then(|entries| match porcelain {
+                Some(version) => report::render_porcelain(&entries, version),
+                None => report::render_list(&entries, output::stdout_color()),
+            });
+            print!("{}", exit_on_error(list));
+        }
+        Some(Subcommand::Report {
+            level,
+            push: Some(url),
+            push_every,
+            ..
+        }) => {
+            if let Some(every) = push_every {
+                let service = exit_on_error(report::push_service(&url, level.as_ref(), &every));
+                exit_on_error(install_service(&service, true));
+                return;
+            }
+            let levels = manifest::levels(level.as_ref());
+            let inventory =
+                report::collect(&levels).and_then(|entries| report::render_json(&entries));
+            exit_on_error(
+                report::push(&url, &exit_on_error(inventory)).context("Failed pushing inventory"),
+            );
+            log::info!("Pushed inventory to {}.", url);
+        }
+        Some(Subcommand::Report { format, level, .. }) => {
+            let levels = manifest::levels(level.as_ref());
+            let report = report::collect(&levels).and_then(|entries| match format {
+                ReportFormat::Markdown => report::render_markdown(&entries),
+                ReportFormat::Html => report::render_html(&entries),
+                ReportFormat::Json => report::render_json(&entries),
+            });
+            print!("{}", exit_on_error(report));
+        }
+        Some(Subcommand::Restart {
+            names,
+            tag,
+            level,
+            rolling,
+            delay,
+        }) => {
+            exit_on_error(
+                restart::select(&names, &tag, level.as_ref())
+                    .and_then(|services| restart::restart(&services, rolling, delay)),
+            );
+        }
+        Some(Subcommand::Which { name, level }) => {
+            let provenance = exit_on_error(which::which(&level, &name));
+            print!("{}", provenance.render());
+            if provenance.files.is_empty() {
+                exit(1);
+            }
+        }
+        Some(Subcommand::Edit { name, level }) => {
+            let service = exit_on_error(manifest::find(&name, level.as_ref()));
+            exit_on_error(edit::edit(&service));
+        }
+        Some(Subcommand::Fmt { files, check }) => {
+            if !exit_on_error(fmt::run(&files, check)) {
+                exit(1);
+            }
+        }
+        Some(Subcommand::Export {
+            name,
+            to,
+            format,
+            output,
+            level,
+        }) => {
+            let exported = manifest::find(&name, level.as_ref())
+                .and_then(|service| export::export(&service, to, format, output.as_deref()));
+            log::info!("Exported to {:?}.", exit_on_error(exported));
+        }
+        Some(Subcommand::Features { provider }) => {
+            let providers = match provider {
+                Some(provider) => vec![provider],
+                None => provider::ProviderKind::value_variants().to_vec(),
+            };
+            print!("{}", provider::capabilities::render_table(&providers));
+        }
+        Some(Subcommand::Stack {
+            command: StackCommand::Status { name, level },
+        }) => {
+            let entries = exit_on_error(stack::collect(&name, level.as_ref()));
+            print!(
+                "{}",
+                stack::render_status(&name, &entries, output::stdout_color())
+            );
+            if entries.iter().any(stack::is_down) {
+                exit(1);
+            }
+        }
+        Some(Subcommand::Drift {
+            name,
+            level,
+            accept,
+            restore,
+        }) => {
+            let drifted = exit_on_error(drift(level.as_ref(), name.as_deref(), accept, restore));
+            if drifted {
+                exit(1);
+            }
+        }
+        Some(Subcommand::Uninstall { name, level }) => {
+            let trashed = manifest::find(&name, level.as_ref())
+                .and_then(|service| trash::uninstall(&service));
+            log::info!(
+                "Service {} uninstalled, its files are in {:?}.",
+                name,
+                exit_on_error(trashed)
+            );
+        }
+        Some(Subcommand::Restore { name, level, start }) => {
+            exit_on_error(trash::restore(&name, level.as_ref(), start));
+            log::info!("Service {} restored.", name);
+        }
+        Some(Subcommand::Trash {
+            command: TrashCommand::List { level },
+        }) => {
+            let mut entries = Vec::new();
+            for level in manifest::levels(level.as_ref()) {
+                entries.extend(exit_on_error(trash::list(&level)));
+            }
+            print!("{}", exit_on_error(trash::render_list(&entries)));
+        }
+        Some(Subcommand::Trash {
+            command: TrashCommand::Empty { older_than, level },
+        }) => {
+            let levels = manifest::levels(level.as_ref());
+            let removed = exit_on_error(trash::empty(&levels, older_than));
+            log::info!("Deleted {} uninstalled services.", removed);
+        }
+        Some(Subcommand::VaultEnv { secrets }) => {
+            exit_on_error(vault::write_env(&secrets));
+        }
+        Some(Subcommand::MetadataEnv { imds, gce }) => {
+            exit_on_error(metadata::write_env(&imds, &gce));
+        }
+        Some(Subcommand::Secrets {
+            command: SecretsCommand::RotateKey,
+        }) => {
+            let rotated = exit_on_error(secret::rotate_key());
+            log::info!(
+                "Rotated the host key, re-encrypting the secrets of {} services.",
+                rotated
+            );
         }
         None => {
-            log::error!("Unknown service runtime, cannot add service.");
-            exit(1);
+            let args = cli.args.expect("clap requires args without a subcommand");
+            let service = exit_on_error(service_config(&args));
+            install(&args, service);
         }
     }
-    log::info!("Service {:?} installed.", service.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_args() {
+        let cli = Cli::try_parse_from(["mkservice", "web", "--owner", "ops", "/bin/true"]).unwrap();
+        let args = cli.args.expect("args without a subcommand");
+        assert_eq!(args.name, "web");
+        assert_eq!(args.annotations.owner.as_deref(), Some("ops"));
+        let cli = Cli::try_parse_from([
+            "mkservice",
+            "backup",
+            "--on-calendar",
+            "daily",
+            "--",
+            "/usr/local/bin/backup.sh",
+        ])
+        .unwrap();
+        let args = cli.args.expect("args without a subcommand");
+        assert_eq!(args.timer.on_calendar, ["daily"]);
+        assert_eq!(args.command, ["/usr/local/bin/backup.sh"]);
+        let cli = Cli::try_parse_from(["mkservice", "web", "--shadow", "/bin/true"]).unwrap();
+        assert!(cli.args.expect("args without a subcommand").force);
+        let cli = Cli::try_parse_from(["mkservice", "install", "web", "/bin/true"]).unwrap();
+        assert!(matches!(cli.subcommand, Some(Subcommand::Install { args }) if args.name == "web"));
+        let cli = Cli::try_parse_from(["mkservice", "list", "-vv"]).unwrap();
+        assert!(cli.args.is_none());
+        assert_eq!(cli.verbose, 2);
+        let cli =
+            Cli::try_parse_from(["mkservice", "status", "web", "--namespace", "team1"]).unwrap();
+        assert_eq!(cli.namespace.as_deref(), Some("team1"));
+        let cli = Cli::try_parse_from(["mkservice", "status", "web", "--json-errors"]).unwrap();
+        assert!(cli.json_errors);
+        assert!(Cli::try_parse_from(["mkservice", "--namespace", "a/b", "list"]).is_err());
+        let cli = Cli::try_parse_from(["mkservice", "web", "--env-secret", "TOKEN", "/bin/true"]);
+        assert_eq!(cli.unwrap().args.unwrap().env_secret, ["TOKEN"]);
+        assert!(
+            Cli::try_parse_from(["mkservice", "web", "--env-secret", "1X=y", "/bin/true"]).is_err()
+        );
+        let cli = Cli::try_parse_from([
+            "mkservice",
+            "web",
+            "--env-from-imds",
+            "role-credentials",
+            "--env-from-gce-metadata",
+            "DB_HOST=db-host",
+            "/bin/true",
+        ]);
+        let args = cli.unwrap().args.unwrap();
+        assert_eq!(args.env_from_imds, [ImdsValue::RoleCredentials]);
+        assert_eq!(args.env_from_gce_metadata, ["DB_HOST=db-host"]);
+        assert!(Cli::try_parse_from([
+            "mkservice",
+            "web",
+            "--env-from-gce-metadata",
+            "db-host",
+            "/bin/true"
+        ])
+        .is_err());
+        let cli = Cli::try_parse_from(["mkservice", "web", "--harden", "/bin/true"]).unwrap();
+        let args = cli.args.unwrap();
+        assert_eq!(args.harden, Some(Hardening::Moderate));
+        assert_eq!(args.command, ["/bin/true"]);
+        let cli = Cli::try_parse_from(["mkservice", "web", "--harden=strict", "/bin/true"]);
+        assert_eq!(cli.unwrap().args.unwrap().harden, Some(Hardening::Strict));
+        let cli = Cli::try_parse_from([
+            "mkservice",
+            "web",
+            "-o",
+            "Service:LimitNOFILE=65536",
+            "--unit-option",
+            "Unit:After=network-online.target",
+            "/bin/true",
+        ]);
+        assert_eq!(cli.unwrap().args.unwrap().unit_option.len(), 2);
+        for bad in [
+            "Socket:Backlog=5",
+            "Service:Limit NOFILE=1",
+            "LimitNOFILE=1",
+        ] {
+            assert!(Cli::try_parse_from(["mkservice", "web", "-o", bad, "/bin/true"]).is_err());
+        }
+        let cli = Cli::try_parse_from([
+            "mkservice",
+            "modem",
+            "--bind-to-device",
+            "eth1",
+            "--after-device",
+            "/dev/ttyUSB0",
+            "/bin/true",
+        ]);
+        let args = cli.unwrap().args.unwrap();
+        assert_eq!(args.bind_to_device, ["eth1"]);
+        assert_eq!(args.after_device, ["/dev/ttyUSB0"]);
+        for bad in ["dev/ttyUSB0", "a-very-long-interface", "eth 1"] {
+            let args = ["mkservice", "modem", "--bind-to-device", bad, "/bin/true"];
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+        let cli = Cli::try_parse_from([
+            "mkservice",
+            "web",
+            "--after",
+            "postgresql",
+            "--wants",
+            "network-online.target",
+            "--requires",
+            "getty@tty1.service",
+            "/bin/true",
+        ]);
+        let args = cli.unwrap().args.unwrap();
+        assert_eq!(args.after, ["postgresql.service"]);
+        assert_eq!(args.wants, ["network-online.target"]);
+        assert_eq!(args.requires, ["getty@tty1.service"]);
+        for bad in ["", "db.", ".service", "my db"] {
+            assert!(
+                Cli::try_parse_from(["mkservice", "web", "--after", bad, "/bin/true"]).is_err()
+            );
+        }
+        let cli = Cli::try_parse_from(["mkservice", "on-resume", "wifi", "--", "nmcli", "-v"]);
+        assert!(matches!(
+            cli.unwrap().subcommand,
+            Some(Subcommand::OnResume { args }) if args.command == ["nmcli", "-v"]
+        ));
+        let cli = Cli::try_parse_from([
+            "mkservice",
+            "web",
+            "--memory-max",
+            "512M",
+            "--cpu-quota",
+            "150%",
+            "--tasks-max",
+            "64",
+            "/bin/true",
+        ]);
+        let args = cli.unwrap().args.unwrap();
+        assert_eq!(args.memory_max.as_deref(), Some("512M"));
+        assert_eq!(args.cpu_quota.as_deref(), Some("150%"));
+        assert_eq!(args.tasks_max.as_deref(), Some("64"));
+        for (flag, bad) in [
+            ("--memory-max", "512MB"),
+            ("--cpu-quota", "50"),
+            ("--cpu-quota", "0%"),
+            ("--tasks-max", "-1"),
+        ] {
+            assert!(Cli::try_parse_from(["mkservice", "web", flag, bad, "/bin/true"]).is_err());
+        }
+        let cli =
+            Cli::try_parse_from(["mkservice", "web", "--service-type", "notify", "/bin/true"]);
+        assert_eq!(
+            cli.unwrap().args.unwrap().service_type,
+            Some(ServiceType::Notify)
+        );
+        let args = ["mkservice", "web", "--service-type", "forking", "/bin/true"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let args = ["mkservice", "web", "--service-type", "dbus", "/bin/true"];
+        assert!(Cli::try_parse_from(args).is_err());
+        let cli = Cli::try_parse_from(["mkservice", "backup", "--at-shutdown", "/bin/true"]);
+        assert!(cli.unwrap().args.unwrap().at_shutdown);
+        let args = [
+            "mkservice",
+            "a",
+            "--at-shutdown",
+            "--at-boot-once",
+            "/bin/true",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+        let cli = Cli::try_parse_from(["mkservice", "logs", "web", "-f", "-n", "100"]).unwrap();
+        assert!(matches!(
+            cli.subcommand,
+            Some(Subcommand::Logs {
+                follow: true,
+                lines: 100,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_check_workdir() {
+        let root = env::temp_dir().join(format!("mkservice-workdir-{}", std::process::id()));
+        let dir = root.join("data");
+        let service = ServiceConfig::default();
+        assert!(check_workdir(&service, &dir, false).is_err());
+        check_workdir(&service, &dir, true).unwrap();
+        assert!(dir.is_dir());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_validate_ip_prefix() {
+        for valid in ["10.0.0.0/8", "192.168.1.5", "fd00::/8", "localhost"] {
+            assert!(validate_ip_prefix(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["10.0.0.0/33", "10.0.0/8", "lan", "::1/129"] {
+            assert!(validate_ip_prefix(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_preset_type() {
+        let cronjob = preset::builtin("cronjob").unwrap();
+        let args = |extra: &[&str]| {
+            let argv = ["mkservice", "backup", "--preset", "cronjob"]
+                .iter()
+                .chain(extra)
+                .chain(&["/bin/true"]);
+            Cli::try_parse_from(argv).unwrap().args.unwrap()
+        };
+        let service = |args: &Args| ServiceConfig {
+            name: args.name.clone(),
+            command: args.command.clone(),
+            service_type: resolve_service_type(args, Some(&cronjob), false),
+            remain_after_exit: args.remain_after_exit,
+            power_hook: args.power_hook,
+            ..Default::default()
+        };
+        let remain = service(&args(&["--remain-after-exit"]));
+        assert_eq!(remain.service_type, ServiceType::Oneshot);
+        assert!(validate_service(&remain).is_ok());
+        let notify = service(&args(&["--service-type", "notify"]));
+        assert_eq!(notify.service_type, ServiceType::Notify);
+        let simple = service(&args(&["--service-type", "simple"]));
+        assert_eq!(simple.service_type, ServiceType::Simple);
+
+        let mut hook = args(&["--service-type", "notify"]);
+        hook.power_hook = Some(PowerHook::Resume);
+        assert!(validate_service(&service(&hook)).is_err());
+        hook.service_type = None;
+        let hook = service(&hook);
+        assert_eq!(hook.service_type, ServiceType::Oneshot);
+        assert!(validate_service(&hook).is_ok());
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let content = "# comment\n; another\n\nPORT=8080\nexport NAME=\"my app\"\n\
+            TOKEN='a=b'\nnot a variable\n";
+        let expected = BTreeMap::from([
+            ("NAME".to_string(), "my app".to_string()),
+            ("PORT".to_string(), "8080".to_string()),
+            ("TOKEN".to_string(), "a=b".to_string()),
+        ]);
+        assert_eq!(parse_env_file(content), expected);
+
+        let mut env = parse_env_file(content);
+        env.insert("DISCOUNT".into(), "10%".into());
+        let service = ServiceConfig {
+            name: "app".into(),
+            command: vec!["/usr/bin/app".into()],
+            env,
+            ..Default::default()
+        };
+        let unit = provider::systemd::Systemd { service }
+            .to_systemd_unit()
+            .unwrap();
+        assert!(unit.contains("Environment=\"NAME=my app\"\n"));
+        assert!(unit.contains("Environment=PORT=8080\n"));
+        assert!(unit.contains("Environment=DISCOUNT=10%%\n"));
+    }
 }
@@ -0,0 +1,308 @@
+//! Manifests record the configuration of every service mkservice installs,
+//! which is how it recognizes the services it manages.
+
+use crate::config::{ServiceConfig, ServiceLevel};
+use anyhow::{anyhow, Result};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static NAMESPACE: OnceLock<String> = OnceLock::new();
+
+/// Sets the namespace for this run, see `qualify`.
+pub fn set_namespace(namespace: String) {
+    NAMESPACE
+        .set(namespace)
+        .expect("The namespace is set once, at startup");
+}
+
+/// The name of service `name` in the namespace, if any: prefixed with it, as
+/// `team1-web`, so teams sharing a host can't collide.
+pub fn qualify(name: &str) -> String {
+    qualify_in(NAMESPACE.get().map(String::as_str), name)
+}
+
+fn qualify_in(namespace: Option<&str>, name: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{}-{}", namespace, name),
+        None => name.to_string(),
+    }
+}
+
+/// Reverses `qualify`.
+fn unqualify(name: &str) -> &str {
+    unqualify_in(NAMESPACE.get().map(String::as_str), name)
+}
+
+fn unqualify_in<'a>(namespace: Option<&str>, name: &'a str) -> &'a str {
+    namespace
+        .and_then(|namespace| name.strip_prefix(&format!("{}-", namespace)))
+        .unwrap_or(name)
+}
+
+/// The state directory for `level` outside any namespace.
+fn base_state_dir(level: &ServiceLevel) -> Result<PathBuf> {
+    Ok(match level {
+        ServiceLevel::System => PathBuf::from("/var/lib/mkservice"),
+        ServiceLevel::User => match env::var_os("XDG_STATE_HOME") {
+            Some(dir) => PathBuf::from(dir).join("mkservice"),
+            None => PathBuf::from(env::var("HOME")?).join(".local/state/mkservice"),
+        },
+    })
+}
+
+/// Where mkservice keeps what it knows about services at `level`, apart
+/// for each namespace.
+pub fn state_dir(level: &ServiceLevel) -> Result<PathBuf> {
+    let dir = base_state_dir(level)?;
+    Ok(namespaced(dir, NAMESPACE.get().map(String::as_str)))
+}
+
+fn namespaced(dir: PathBuf, namespace: Option<&str>) -> PathBuf {
+    match namespace {
+        Some(namespace) => dir.join("namespaces").join(namespace),
+        None => dir,
+    }
+}
+
+pub fn manifest_dir(level: &ServiceLevel) -> Result<PathBuf> {
+    Ok(state_dir(level)?.join("services"))
+}
+
+pub fn save(service: &ServiceConfig) -> Result<()> {
+    let dir = manifest_dir(&service.level)?;
+    fs::create_dir_all(&dir)?;
+    write(&dir.join(format!("{}.json", service.name)), service)
+}
+
+/// Writes `service`'s manifest to `path`.
+pub fn write(path: &Path, service: &ServiceConfig) -> Result<()> {
+    log::debug!("Writing manifest to {:?}", path);
+    fs::write(path, serde_json::to_string_pretty(service)?)?;
+    Ok(())
+}
+
+/// Forgets `service`, which is no longer managed.
+pub fn remove(service: &ServiceConfig) -> Result<()> {
+    let path = manifest_dir(&service.level)?.join(format!("{}.json", service.name));
+    log::debug!("Removing manifest {:?}", path);
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The levels to look at: just `level` if given, otherwise both.
+pub fn levels(level: Option<&ServiceLevel>) -> Vec<ServiceLevel> {
+    match level {
+        Some(level) => vec![level.clone()],
+        None => vec![ServiceLevel::System, ServiceLevel::User],
+    }
+}
+
+pub fn load(level: &ServiceLevel, name: &str) -> Result<Option<ServiceConfig>> {
+    let path = manifest_dir(level)?.join(format!("{}.json", name));
+    match fs::read(&path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Finds the managed service called `name` in the namespace. Without a
+/// level, a name managed at both levels is ambiguous and an error, rather
+/// than silently picking one.
+pub fn find(name: &str, level: Option<&ServiceLevel>) -> Result<ServiceConfig> {
+    let mut found = Vec::new();
+    for level in levels(level) {
+        if let Some(service) = load(&level, &qualify(name))? {
+            found.push(service);
+        }
+    }
+    match found.len() {
+        0 => {
+            let mut names = Vec::new();
+            for level in levels(level) {
+                names.extend(
+                    load_all(&level)?
+                        .iter()
+                        .map(|service| unqualify(&service.name).to_string()),
+                );
+            }
+            Err(anyhow!(
+                "{:?} isn't a managed service.{}",
+                name,
+                suggestion(name, names)
+            ))
+        }
+        1 => Ok(found.remove(0)),
+        _ => Err(anyhow!(
+            "{:?} exists as both a system and a user service, pick one with --level system or --level user.",
+            name
+        )),
+    }
+}
+
+/// Lists the managed services, pointing out the one `name` is likely a
+/// typo of.
+fn suggestion(name: &str, mut names: Vec<String>) -> String {
+    if names.is_empty() {
+        return " No services are managed yet.".into();
+    }
+    names.sort();
+    names.dedup();
+    let mut close: Vec<(usize, &String)> = names
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= (name.len() / 3).max(1))
+        .collect();
+    close.sort();
+    let managed = format!(" Managed services: {}.", names.join(", "));
+    match close.first() {
+        Some((_, candidate)) => format!(" Did you mean {:?}?{}", candidate, managed),
+        None => managed,
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Loads the manifests of every service managed at `level`.
+pub fn load_all(level: &ServiceLevel) -> Result<Vec<ServiceConfig>> {
+    let mut services: Vec<ServiceConfig> = load_dir(&manifest_dir(level)?)?
+        .into_iter()
+        .map(|(_, service)| service)
+        .collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(services)
+}
+
+/// Loads the manifests of every service managed at `level` in any
+/// namespace, or none, with their paths. For what's shared across
+/// namespaces, like the host's keys.
+pub fn load_everywhere(level: &ServiceLevel) -> Result<Vec<(PathBuf, ServiceConfig)>> {
+    let base = base_state_dir(level)?;
+    let mut services = load_dir(&base.join("services"))?;
+    let namespaces = match fs::read_dir(base.join("namespaces")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(services),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in namespaces {
+        services.extend(load_dir(&entry?.path().join("services"))?);
+    }
+    Ok(services)
+}
+
+fn load_dir(dir: &Path) -> Result<Vec<(PathBuf, ServiceConfig)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut services = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match serde_json::from_slice(&fs::read(&path)?) {
+            Ok(service) => services.push((path, service)),
+            Err(e) => log::warn!("Ignoring unreadable manifest {:?}: {}", path, e),
+        }
+    }
+    Ok(services)
+}
+
+/// Managed services, other than `service` itself, that run exactly the same
+/// commands.
+pub fn same_command<'a>(
+    service: &ServiceConfig,
+    managed: &'a [ServiceConfig],
+) -> Vec<&'a ServiceConfig> {
+    managed
+        .iter()
+        .filter(|other| other.name != service.name)
+        .filter(|other| other.command == service.command && other.steps == service.steps)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qualify() {
+        assert_eq!(qualify_in(Some("team1"), "web"), "team1-web");
+        assert_eq!(qualify_in(None, "web"), "web");
+        assert_eq!(unqualify_in(Some("team1"), "team1-web"), "web");
+        assert_eq!(unqualify_in(Some("team1"), "team1-web-api"), "web-api");
+        assert_eq!(unqualify_in(Some("team1"), "team2-web"), "team2-web");
+        assert_eq!(unqualify_in(None, "team1-web"), "team1-web");
+        for name in ["web", "team1", "team1-web"] {
+            let qualified = qualify_in(Some("team1"), name);
+            assert_eq!(unqualify_in(Some("team1"), &qualified), name);
+        }
+    }
+
+    #[test]
+    fn test_namespaced_state_dir() {
+        let dir = base_state_dir(&ServiceLevel::System).unwrap();
+        assert_eq!(dir, Path::new("/var/lib/mkservice"));
+        assert_eq!(
+            namespaced(dir.clone(), Some("team1")),
+            Path::new("/var/lib/mkservice/namespaces/team1")
+        );
+        assert_eq!(namespaced(dir, None), Path::new("/var/lib/mkservice"));
+    }
+
+    #[test]
+    fn test_same_command() {
+        let service = |name: &str, command: &str| ServiceConfig {
+            name: name.into(),
+            command: vec![command.into()],
+            ..Default::default()
+        };
+        let managed = vec![
+            service("web", "/usr/bin/web"),
+            service("web-old", "/usr/bin/web"),
+            service("worker", "/usr/bin/worker"),
+        ];
+        let duplicates = same_command(&service("web", "/usr/bin/web"), &managed);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].name, "web-old");
+    }
+
+    #[test]
+    fn test_suggestion() {
+        assert_eq!(edit_distance("wbe", "web"), 2);
+        assert_eq!(edit_distance("", "api"), 3);
+        assert_eq!(edit_distance("worker", "workers"), 1);
+        let names = || vec!["web".to_string(), "worker".into(), "api".into()];
+        assert_eq!(
+            suggestion("wob", names()),
+            " Did you mean \"web\"? Managed services: api, web, worker."
+        );
+        assert!(suggestion("wroker", names()).starts_with(" Did you mean \"worker\"?"));
+        assert_eq!(
+            suggestion("db", names()),
+            " Managed services: api, web, worker."
+        );
+        assert_eq!(suggestion("db", vec![]), " No services are managed yet.");
+    }
+}
@@ -0,0 +1,153 @@
+//! Environment variables fetched from the cloud's instance metadata service
+//! at each start. As with Vault secrets, the unit runs `mkservice
+//! metadata-env` before the service, which fetches them with curl and writes
+//! them to an environment file in the service's runtime directory.
+
+use crate::config::ImdsValue;
+use crate::error::MkserviceError;
+use crate::vault;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The environment file the values are written to, in the service's
+/// RuntimeDirectory=.
+pub const ENV_FILE: &str = "metadata.env";
+
+const IMDS: &str = "http://169.254.169.254/latest";
+const GCE: &str = "http://metadata.google.internal/computeMetadata/v1";
+
+/// The AWS SDKs' variables, by the role credentials' fields.
+const ROLE_CREDENTIALS: [(&str, &str); 3] = [
+    ("AWS_ACCESS_KEY_ID", "AccessKeyId"),
+    ("AWS_SECRET_ACCESS_KEY", "SecretAccessKey"),
+    ("AWS_SESSION_TOKEN", "Token"),
+];
+
+/// Requests `url` with curl, returning the body. The headers go through
+/// curl's stdin, keeping tokens out of the process list.
+fn fetch(method: &str, url: &str, headers: &[String]) -> Result<String> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "5"])
+        .args(["--request", method, "--header", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| MkserviceError::spawn("curl", e))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(headers.join("\n").as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: format!("curl {}", url),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// The variables for the role credentials IMDS returns.
+fn role_credentials(credentials: &Value) -> Result<Vec<(String, String)>> {
+    ROLE_CREDENTIALS
+        .iter()
+        .map(|(key, field)| {
+            let value = credentials
+                .get(field)
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("The role credentials have no {}.", field))?;
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Fetches `value` from IMDS, with a session token as IMDSv2 requires.
+fn imds(value: ImdsValue) -> Result<Vec<(String, String)>> {
+    let token = fetch(
+        "PUT",
+        &format!("{}/api/token", IMDS),
+        &["X-aws-ec2-metadata-token-ttl-seconds: 60".into()],
+    )?;
+    let headers = [format!("X-aws-ec2-metadata-token: {}", token.trim())];
+    match value {
+        ImdsValue::RoleCredentials => {
+            let url = format!("{}/meta-data/iam/security-credentials/", IMDS);
+            let roles = fetch("GET", &url, &headers)?;
+            let role = roles
+                .lines()
+                .next()
+                .ok_or_else(|| anyhow!("The instance has no IAM role."))?;
+            let credentials = fetch("GET", &format!("{}{}", url, role), &headers)?;
+            role_credentials(&serde_json::from_str(&credentials)?)
+        }
+    }
+}
+
+/// The URL of a GCE metadata path, or of the instance's attribute by a bare
+/// name.
+fn gce_url(attr: &str) -> String {
+    match attr.contains('/') {
+        true => format!("{}/{}", GCE, attr.trim_start_matches('/')),
+        false => format!("{}/instance/attributes/{}", GCE, attr),
+    }
+}
+
+/// Fetches the IMDS values and GCE metadata, given as KEY=ATTR, and writes
+/// them to the environment file.
+pub fn write_env(imds_values: &[ImdsValue], gce: &[String]) -> Result<()> {
+    let mut vars = Vec::new();
+    for value in imds_values {
+        vars.extend(imds(*value)?);
+    }
+    for entry in gce {
+        let (key, attr) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Bad metadata {:?}", entry))?;
+        let value = fetch("GET", &gce_url(attr), &["Metadata-Flavor: Google".into()])?;
+        vars.push((key.to_string(), value));
+    }
+    let content: String = vars
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, vault::quote(value)))
+        .collect();
+    vault::write_env_file(ENV_FILE, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_role_credentials() {
+        let credentials = json!({
+            "Code": "Success",
+            "AccessKeyId": "ASIA123",
+            "SecretAccessKey": "s3cr3t",
+            "Token": "tok",
+            "Expiration": "2026-10-16T12:00:00Z",
+        });
+        assert_eq!(
+            role_credentials(&credentials).unwrap(),
+            [
+                ("AWS_ACCESS_KEY_ID".to_string(), "ASIA123".to_string()),
+                ("AWS_SECRET_ACCESS_KEY".into(), "s3cr3t".into()),
+                ("AWS_SESSION_TOKEN".into(), "tok".into()),
+            ]
+        );
+        assert!(role_credentials(&json!({"Code": "Success"})).is_err());
+        assert_eq!(
+            gce_url("db-password"),
+            "http://metadata.google.internal/computeMetadata/v1/instance/attributes/db-password"
+        );
+        assert_eq!(
+            gce_url("project/project-id"),
+            "http://metadata.google.internal/computeMetadata/v1/project/project-id"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Terminal output conventions: color per NO_COLOR/CLICOLOR, and stable
+//! machine-readable formats for scripts.
+
+use anyhow::{anyhow, Result};
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Versions of the `--porcelain` format. Fields are only ever added to the
+/// end of a line within a version.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Porcelain {
+    V1,
+}
+
+fn env_set(name: &str) -> bool {
+    env::var_os(name).is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Whether to color output to a stream, going by https://no-color.org and
+/// https://bixense.com/clicolors/.
+pub fn use_color(is_terminal: bool) -> bool {
+    if env_set("NO_COLOR") {
+        false
+    } else if env_set("CLICOLOR_FORCE") {
+        true
+    } else if env::var_os("CLICOLOR").is_some_and(|v| v == "0") {
+        false
+    } else {
+        is_terminal
+    }
+}
+
+pub fn stdout_color() -> bool {
+    use_color(std::io::stdout().is_terminal())
+}
+
+pub fn stderr_color() -> bool {
+    use_color(std::io::stderr().is_terminal())
+}
+
+/// Colors a service state: green when active, red when failed.
+pub fn paint_state(state: &str, color: bool) -> String {
+    let code = match state.split(' ').next() {
+        _ if !color => None,
+        Some("active") => Some("32"),
+        Some("failed") => Some("31"),
+        _ => None,
+    };
+    match code {
+        Some(code) => format!("\x1b[{}m{}\x1b[0m", code, state),
+        None => state.to_string(),
+    }
+}
+
+/// Colors a unified diff: additions green, removals red, hunks cyan.
+pub fn paint_diff(diff: &str, color: bool) -> String {
+    if !color {
+        return diff.to_string();
+    }
+    diff.split_inclusive('\n')
+        .map(|line| {
+            let code = match line {
+                _ if line.starts_with("+++") || line.starts_with("---") => "1",
+                _ if line.starts_with('+') => "32",
+                _ if line.starts_with('-') => "31",
+                _ if line.starts_with("@@") => "36",
+                _ => return line.to_string(),
+            };
+            let (text, newline) = match line.strip_suffix('\n') {
+                Some(text) => (text, "\n"),
+                None => (line, ""),
+            };
+            format!("\x1b[{}m{}\x1b[0m{}", code, text, newline)
+        })
+        .collect()
+}
+
+/// Colors a problem found in a file: the file bold, the problem red.
+pub fn paint_problem(path: &str, problem: &str, color: bool) -> String {
+    match color {
+        true => format!("\x1b[1m{}:\x1b[0m \x1b[31m{}\x1b[0m", path, problem),
+        false => format!("{}: {}", path, problem),
+    }
+}
+
+/// Asks a yes/no question on the terminal. Without one to ask on, fails
+/// rather than assume an answer; `flag` is how to answer up front.
+pub fn confirm(question: &str, flag: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Not a terminal to confirm on, pass {} to go ahead.",
+            flag
+        ));
+    }
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Escapes a field for a tab-separated porcelain line.
+pub fn porcelain_field(field: &str) -> String {
+    if field.is_empty() {
+        return "-".into();
+    }
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_porcelain_field() {
+        assert_eq!(porcelain_field(""), "-");
+        assert_eq!(porcelain_field("active (running)"), "active (running)");
+        assert_eq!(porcelain_field("a\tb\\c"), "a\\tb\\\\c");
+    }
+
+    #[test]
+    fn test_paint_diff() {
+        let diff = "--- a\n+++ b\n@@ -1 +1 @@\n-old\n+new\n same\n";
+        assert_eq!(paint_diff(diff, false), diff);
+        assert_eq!(
+            paint_diff(diff, true),
+            "\x1b[1m--- a\x1b[0m\n\x1b[1m+++ b\x1b[0m\n\x1b[36m@@ -1 +1 @@\x1b[0m\n\
+             \x1b[31m-old\x1b[0m\n\x1b[32m+new\x1b[0m\n same\n"
+        );
+    }
+}
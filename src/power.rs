@@ -0,0 +1,42 @@
+use crate::config::{PowerHook, ServiceConfig, ServiceLevel};
+use anyhow::{anyhow, Result};
+
+/// Turns `service`, a oneshot, into a hook run as the machine goes to
+/// sleep, or as it resumes. Only the system manager sees sleep.target.
+pub fn apply(service: &mut ServiceConfig, hook: PowerHook) -> Result<()> {
+    if service.level != ServiceLevel::System {
+        return Err(anyhow!(
+            "Sleep is the system manager's, a power hook must be a system service."
+        ));
+    }
+    if service.timer.is_some() || !service.listen.is_empty() {
+        return Err(anyhow!(
+            "A power hook is started by sleep, it can't have a schedule or sockets too."
+        ));
+    }
+    service.power_hook = Some(hook);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServiceType;
+
+    #[test]
+    fn test_apply() {
+        let mut service = ServiceConfig {
+            service_type: ServiceType::Oneshot,
+            ..Default::default()
+        };
+        apply(&mut service, PowerHook::Resume).unwrap();
+        assert_eq!(service.service_type, ServiceType::Oneshot);
+        assert_eq!(service.power_hook, Some(PowerHook::Resume));
+
+        let mut service = ServiceConfig {
+            level: ServiceLevel::User,
+            ..Default::default()
+        };
+        assert!(apply(&mut service, PowerHook::Sleep).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Named bundles of unit directives for common service shapes, so they don't
+//! need spelling out flag by flag.
+
+use crate::config::{ServiceConfig, ServiceType};
+use crate::settings;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+const BUILTIN: [(&str, &str); 3] = [
+    (
+        "webapp",
+        r#"
+[Unit]
+Wants = "network-online.target"
+After = "network-online.target"
+
+[Service]
+Restart = "always"
+RestartSec = "5s"
+LimitNOFILE = "65536"
+NoNewPrivileges = "yes"
+PrivateTmp = "yes"
+ProtectSystem = "full"
+ProtectKernelTunables = "yes"
+ProtectControlGroups = "yes"
+"#,
+    ),
+    (
+        "worker",
+        r#"
+[Service]
+Restart = "on-failure"
+RestartSec = "10s"
+# Give in-flight work a chance to finish on stop.
+TimeoutStopSec = "5min"
+Nice = "5"
+NoNewPrivileges = "yes"
+PrivateTmp = "yes"
+ProtectSystem = "full"
+"#,
+    ),
+    (
+        "cronjob",
+        r#"
+type = "oneshot"
+
+[Service]
+Nice = "10"
+IOSchedulingClass = "idle"
+NoNewPrivileges = "yes"
+PrivateTmp = "yes"
+ProtectSystem = "full"
+"#,
+    ),
+];
+
+const SECTIONS: [&str; 3] = ["Unit", "Service", "Install"];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Values {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    #[serde(rename = "type")]
+    service_type: Option<ServiceType>,
+    #[serde(rename = "Unit", default)]
+    unit: BTreeMap<String, Values>,
+    #[serde(rename = "Service", default)]
+    service: BTreeMap<String, Values>,
+    #[serde(rename = "Install", default)]
+    install: BTreeMap<String, Values>,
+}
+
+pub fn preset_dir() -> Result<PathBuf> {
+    Ok(settings::config_dir()?.join("presets"))
+}
+
+/// Loads the preset called `name`. A file of that name in `preset_dir`
+/// replaces the built-in one.
+pub fn load(name: &str) -> Result<Preset> {
+    let path = preset_dir()?.join(format!("{}.toml", name));
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return builtin(name).ok_or_else(|| {
+                let names: Vec<&str> = BUILTIN.iter().map(|(name, _)| *name).collect();
+                anyhow!(
+                    "No preset {:?}, built-in ones are {:?}, or add {:?}.",
+                    name,
+                    names,
+                    path
+                )
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
+    toml::from_str(&content).with_context(|| format!("Bad preset {:?}", name))
+}
+
+/// The built-in preset called `name`, if there is one.
+pub fn builtin(name: &str) -> Option<Preset> {
+    let (_, content) = BUILTIN.iter().find(|(builtin, _)| *builtin == name)?;
+    Some(toml::from_str(content).expect("built-in presets parse"))
+}
+
+impl Preset {
+    /// The type the preset asks for, which goes beneath --service-type. The
+    /// caller picks the service's type, checking it along with the flags'.
+    pub fn service_type(&self) -> Option<ServiceType> {
+        self.service_type
+    }
+}
+
+/// Adds the preset's directives to `service`, beneath what flags set.
+pub fn apply(service: &mut ServiceConfig, preset: Preset) {
+    for (section, options) in
+        SECTIONS
+            .into_iter()
+            .zip([preset.unit, preset.service, preset.install])
+    {
+        let section = service.unit_options.entry(section.into()).or_default();
+        for (key, values) in options {
+            let values = match values {
+                Values::One(value) => vec![value],
+                Values::Many(values) => values,
+            };
+            section.entry(key).or_insert(values);
+        }
+    }
+    service
+        .unit_options
+        .retain(|_, options| !options.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::{btreemap, convert_args};
+
+    #[test]
+    fn test_builtin_presets() {
+        for (name, content) in BUILTIN {
+            let preset: Preset = toml::from_str(content).expect(name);
+            let mut service = ServiceConfig::default();
+            apply(&mut service, preset);
+            assert!(service.unit_options.contains_key("Service"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_apply_beneath_existing() {
+        let preset: Preset =
+            toml::from_str("type = \"oneshot\"\n[Service]\nNice = \"10\"\nRestart = \"always\"\n")
+                .unwrap();
+        let mut service = ServiceConfig {
+            service_type: ServiceType::Dbus,
+            unit_options: convert_args!(btreemap!(
+                "Service" => convert_args!(btreemap!("Nice" => vec!["0".to_string()])),
+            )),
+            ..Default::default()
+        };
+        assert_eq!(preset.service_type(), Some(ServiceType::Oneshot));
+        apply(&mut service, preset);
+        assert_eq!(service.service_type, ServiceType::Dbus);
+        assert_eq!(service.unit_options["Service"]["Nice"], vec!["0"]);
+        assert_eq!(service.unit_options["Service"]["Restart"], vec!["always"]);
+    }
+}
@@ -0,0 +1,347 @@
+//! What each provider does with each feature a service can use, so users
+//! know what translates before relying on it, and installs fail up front on
+//! what doesn't.
+
+use crate::config::{ServiceConfig, ServiceLevel, ServiceType};
+use crate::provider::ProviderKind;
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Env,
+    EnvFiles,
+    EnvSecrets,
+    VaultSecrets,
+    CloudMetadata,
+    WorkingDirectory,
+    User,
+    UserLevel,
+    PassthroughEnv,
+    Timer,
+    Instances,
+    DependsOn,
+    RequiresMounts,
+    Devices,
+    Dependencies,
+    FirstBootOnly,
+    BootHooks,
+    Forking,
+    Notify,
+    FailureMail,
+    BusName,
+    Listen,
+    FdStore,
+    RestartPolicy,
+    ResourceLimits,
+    Supervision,
+    KeepAlivePath,
+    Tty,
+    Kiosk,
+    PowerHooks,
+    GraphicalSession,
+    UnitOptions,
+    Start,
+    Restart,
+    Status,
+}
+
+/// Every feature, in the order they're listed.
+pub const FEATURES: [Feature; 35] = [
+    Feature::Env,
+    Feature::EnvFiles,
+    Feature::EnvSecrets,
+    Feature::VaultSecrets,
+    Feature::CloudMetadata,
+    Feature::WorkingDirectory,
+    Feature::User,
+    Feature::UserLevel,
+    Feature::PassthroughEnv,
+    Feature::Timer,
+    Feature::Instances,
+    Feature::DependsOn,
+    Feature::RequiresMounts,
+    Feature::Devices,
+    Feature::Dependencies,
+    Feature::FirstBootOnly,
+    Feature::BootHooks,
+    Feature::Forking,
+    Feature::Notify,
+    Feature::FailureMail,
+    Feature::BusName,
+    Feature::Listen,
+    Feature::FdStore,
+    Feature::RestartPolicy,
+    Feature::ResourceLimits,
+    Feature::Supervision,
+    Feature::KeepAlivePath,
+    Feature::Tty,
+    Feature::Kiosk,
+    Feature::PowerHooks,
+    Feature::GraphicalSession,
+    Feature::UnitOptions,
+    Feature::Start,
+    Feature::Restart,
+    Feature::Status,
+];
+
+impl Feature {
+    /// How it's asked for: the flag, subcommand or imported setting.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Env => "--env",
+            Feature::EnvFiles => "--env-file",
+            Feature::EnvSecrets => "--env-secret",
+            Feature::VaultSecrets => "--vault-secret",
+            Feature::CloudMetadata => "--env-from-imds, --env-from-gce-metadata",
+            Feature::WorkingDirectory => "--workdir",
+            Feature::User => "--run-as-user, --run-as-group",
+            Feature::UserLevel => "--level user",
+            Feature::PassthroughEnv => "--passthrough-env",
+            Feature::Timer => "timers (job, imports)",
+            Feature::Instances => "instances (imports)",
+            Feature::DependsOn => "depends_on (imports)",
+            Feature::RequiresMounts => "--requires-mount",
+            Feature::Devices => "--bind-to-device, --after-device",
+            Feature::Dependencies => "--after, --before, --requires, --wants, --network",
+            Feature::FirstBootOnly => "--first-boot-only",
+            Feature::BootHooks => "--at-shutdown, --at-boot-once",
+            Feature::Forking => "--service-type forking",
+            Feature::Notify => "--service-type notify",
+            Feature::FailureMail => "failure mail (imports)",
+            Feature::BusName => "--dbus-name",
+            Feature::Listen => "--listen",
+            Feature::FdStore => "--fd-store-max",
+            Feature::RestartPolicy => "--restart, --restart-sec",
+            Feature::ResourceLimits => "--memory-max, --cpu-quota, --tasks-max",
+            Feature::Supervision => "--supervisor, --respawn-max, --pidfile",
+            Feature::KeepAlivePath => "--keepalive-path",
+            Feature::Tty => "--tty, --standard-input",
+            Feature::Kiosk => "kiosk",
+            Feature::PowerHooks => "on-sleep, on-resume",
+            Feature::GraphicalSession => "--graphical",
+            Feature::UnitOptions => "--unit-option, --harden, presets",
+            Feature::Start => "--start",
+            Feature::Restart => "restart",
+            Feature::Status => "status, list, report",
+        }
+    }
+
+    /// The features `service` relies on.
+    pub fn used_by(service: &ServiceConfig) -> Vec<Feature> {
+        let used = [
+            (Feature::Env, !service.env.is_empty()),
+            (Feature::EnvFiles, !service.env_files.is_empty()),
+            (Feature::EnvSecrets, !service.env_secrets.is_empty()),
+            (Feature::VaultSecrets, !service.vault_secrets.is_empty()),
+            (
+                Feature::CloudMetadata,
+                !service.imds_env.is_empty() || !service.gce_metadata_env.is_empty(),
+            ),
+            (
+                Feature::WorkingDirectory,
+                service.working_directory.is_some(),
+            ),
+            (
+                Feature::User,
+                service.user.is_some() || service.group.is_some(),
+            ),
+            (Feature::UserLevel, service.level == ServiceLevel::User),
+            (Feature::PassthroughEnv, service.passthrough_env.is_some()),
+            (Feature::Timer, service.timer.is_some()),
+            (Feature::Instances, service.instances.is_some()),
+            (Feature::DependsOn, !service.depends_on.is_empty()),
+            (Feature::RequiresMounts, !service.requires_mounts.is_empty()),
+            (
+                Feature::Devices,
+                !service.bind_to_devices.is_empty() || !service.after_devices.is_empty(),
+            ),
+            (
+                Feature::Dependencies,
+                !service.after.is_empty()
+                    || !service.before.is_empty()
+                    || !service.requires.is_empty()
+                    || !service.wants.is_empty(),
+            ),
+            (Feature::FirstBootOnly, service.first_boot_only),
+            (
+                Feature::BootHooks,
+                service.at_shutdown || service.at_boot_once,
+            ),
+            (
+                Feature::Forking,
+                service.service_type == ServiceType::Forking,
+            ),
+            (Feature::Notify, service.service_type == ServiceType::Notify),
+            (Feature::FailureMail, service.failure_mail.is_some()),
+            (Feature::BusName, service.bus_name.is_some()),
+            (Feature::Listen, !service.listen.is_empty()),
+            (Feature::FdStore, service.fd_store_max.is_some()),
+            (
+                Feature::RestartPolicy,
+                service.restart.is_some() || service.restart_sec.is_some(),
+            ),
+            (
+                Feature::ResourceLimits,
+                service.memory_max.is_some()
+                    || service.cpu_quota.is_some()
+                    || service.tasks_max.is_some(),
+            ),
+            (Feature::Supervision, !service.openrc.is_default()),
+            (Feature::KeepAlivePath, !service.keepalive_paths.is_empty()),
+            (
+                Feature::Tty,
+                service.tty.is_some() || service.standard_input.is_some(),
+            ),
+            (Feature::Kiosk, service.kiosk),
+            (Feature::PowerHooks, service.power_hook.is_some()),
+            (Feature::GraphicalSession, service.graphical),
+            (Feature::UnitOptions, !service.unit_options.is_empty()),
+        ];
+        used.into_iter()
+            .filter(|(_, used)| *used)
+            .map(|(feature, _)| feature)
+            .collect()
+    }
+}
+
+/// How a provider handles a feature. Emulated and unsupported features say
+/// how, or why not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Support {
+    Supported,
+    Emulated(&'static str),
+    Unsupported(&'static str),
+}
+
+impl Support {
+    fn label(&self) -> &'static str {
+        match self {
+            Support::Supported => "supported",
+            Support::Emulated(_) => "emulated",
+            Support::Unsupported(_) => "unsupported",
+        }
+    }
+
+    fn note(&self) -> &'static str {
+        match self {
+            Support::Supported => "",
+            Support::Emulated(note) | Support::Unsupported(note) => note,
+        }
+    }
+}
+
+/// Fails if `provider` can't do something `service` relies on.
+pub fn check(provider: ProviderKind, service: &ServiceConfig) -> Result<()> {
+    let mut unsupported = Vec::new();
+    for feature in Feature::used_by(service) {
+        match provider.support(feature) {
+            Support::Supported => {}
+            Support::Emulated(note) => log::debug!("{} is emulated: {}", feature.name(), note),
+            Support::Unsupported(note) => {
+                unsupported.push(format!("{} ({})", feature.name(), note))
+            }
+        }
+    }
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{} can't run {:?} as asked, it doesn't support: {}.",
+        provider.name(),
+        service.name,
+        unsupported.join(", ")
+    ))
+}
+
+/// A table of how each of `providers` handles each feature. With a single
+/// provider, the notes on emulated and unsupported features are included.
+pub fn render_table(providers: &[ProviderKind]) -> String {
+    let mut header = vec!["Feature".to_string()];
+    header.extend(providers.iter().map(|provider| provider.name().to_string()));
+    if providers.len() == 1 {
+        header.push("Notes".into());
+    }
+    let mut rows = vec![header];
+    for feature in FEATURES {
+        let mut row = vec![feature.name().to_string()];
+        row.extend(
+            providers
+                .iter()
+                .map(|provider| provider.support(feature).label().to_string()),
+        );
+        if let [provider] = providers {
+            row.push(provider.support(feature).note().to_string());
+        }
+        rows.push(row);
+    }
+    let columns = rows[0].len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .map(|row| row.get(i).map_or(0, |cell| cell.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut out = String::new();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        let _ = writeln!(out, "{}", cells.join("  ").trim_end());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::systemd;
+
+    #[test]
+    fn test_used_by() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            env: [("PORT".to_string(), "8080".to_string())].into(),
+            level: ServiceLevel::User,
+            ..Default::default()
+        };
+        assert_eq!(
+            Feature::used_by(&service),
+            vec![Feature::Env, Feature::UserLevel]
+        );
+        assert!(check(ProviderKind::Systemd, &service).is_ok());
+    }
+
+    #[test]
+    fn test_render_table() {
+        let table = render_table(&[ProviderKind::Systemd]);
+        let lines: Vec<&str> = table.lines().collect();
+        let columns = |line: &str| -> Vec<String> {
+            line.split("  ")
+                .filter(|cell| !cell.is_empty())
+                .map(|cell| cell.trim().to_string())
+                .collect()
+        };
+        assert_eq!(lines.len(), FEATURES.len() + 1);
+        assert_eq!(columns(lines[0]), ["Feature", "systemd", "Notes"]);
+        assert_eq!(columns(lines[1]), ["--env", "supported"]);
+        assert!(lines.iter().any(|line| columns(line)
+            == [
+                "failure mail (imports)",
+                "emulated",
+                "OnFailure= unit running mail(1)"
+            ]));
+    }
+
+    #[test]
+    fn test_systemd_support() {
+        let support = |version| systemd::support(Feature::FirstBootOnly, version);
+        assert_eq!(support(Some(252)), Support::Supported);
+        assert_eq!(support(None), Support::Supported);
+        assert!(matches!(support(Some(245)), Support::Unsupported(_)));
+    }
+}
@@ -0,0 +1,127 @@
+//! Golden-file tests of what each provider renders, over a matrix of
+//! services. Run with MKSERVICE_UPDATE_GOLDEN=1 to rewrite the files after
+//! an intended change, and review the diff.
+
+use super::{for_kind, ProviderKind};
+use crate::config::{RestartPolicy, ServiceConfig, ServiceType, TimerConfig};
+use clap::ValueEnum;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Compares `rendered` to the golden file at `path` under testdata/.
+pub fn assert_golden(path: &str, rendered: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/provider/testdata")
+        .join(path);
+    if env::var_os("MKSERVICE_UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().expect("golden files are in a directory")).unwrap();
+        fs::write(&path, rendered).unwrap();
+    }
+    let golden = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "{:?}: {}, set MKSERVICE_UPDATE_GOLDEN=1 to write it",
+            path, e
+        )
+    });
+    assert_eq!(
+        rendered, golden,
+        "{:?} differs, set MKSERVICE_UPDATE_GOLDEN=1 if that's intended",
+        path
+    );
+}
+
+/// The services each provider renders, by name.
+fn matrix() -> Vec<(&'static str, ServiceConfig)> {
+    let base = ServiceConfig {
+        name: "web".into(),
+        command: vec![
+            "/usr/bin/web".into(),
+            "--greeting".into(),
+            "it's \"$HOME\"".into(),
+        ],
+        ..Default::default()
+    };
+    vec![
+        ("simple", base.clone()),
+        (
+            "environment",
+            ServiceConfig {
+                env: [("PORT".to_string(), "8080".to_string())].into(),
+                env_files: vec!["/etc/web.env".into(), "-/etc/web.local.env".into()],
+                working_directory: Some("/srv/web".into()),
+                user: Some("www".into()),
+                group: Some("www-data".into()),
+                ..base.clone()
+            },
+        ),
+        (
+            "restart",
+            ServiceConfig {
+                restart: Some(RestartPolicy::Always),
+                restart_sec: Some(5),
+                ..base.clone()
+            },
+        ),
+        (
+            "dependencies",
+            ServiceConfig {
+                depends_on: vec!["db".into()],
+                requires_mounts: vec!["/srv".into()],
+                ..base.clone()
+            },
+        ),
+        (
+            "oneshot",
+            ServiceConfig {
+                name: "setup".into(),
+                command: vec!["/usr/bin/migrate".into()],
+                steps: vec![vec!["/usr/bin/seed".into(), "--all".into()]],
+                service_type: ServiceType::Oneshot,
+                ..Default::default()
+            },
+        ),
+        (
+            "timer",
+            ServiceConfig {
+                name: "backup".into(),
+                command: vec!["/usr/local/bin/backup.sh".into()],
+                service_type: ServiceType::Oneshot,
+                timer: Some(TimerConfig {
+                    on_calendar: vec!["daily".into()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ),
+        (
+            "socket",
+            ServiceConfig {
+                listen: vec!["tcp:8080".into()],
+                ..base.clone()
+            },
+        ),
+    ]
+}
+
+/// The files as `--dry-run` prints them, or the error rendering fails with.
+fn render(kind: ProviderKind, service: ServiceConfig) -> String {
+    match for_kind(kind, service).unit_files() {
+        Ok(files) => files
+            .iter()
+            .map(|(path, content)| format!("# {}\n{}", path.display(), content))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+#[test]
+fn test_render_golden() {
+    for kind in ProviderKind::value_variants() {
+        for (case, service) in matrix() {
+            let path = format!("{}/{}.golden", kind.name(), case);
+            assert_golden(&path, &render(*kind, service));
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! launchd, on macOS: a property list per service, in LaunchDaemons for
+//! system services and the user's LaunchAgents for user services.
+
+use crate::command::{shell_quote, timed};
+use crate::config::{RestartPolicy, ServiceConfig, ServiceLevel, ServiceType};
+use crate::error::{MkserviceError, Result};
+use crate::provider::capabilities::{Feature, Support};
+use crate::provider::{remove_files, ServiceStatus};
+use crate::ServiceOperator;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How launchd handles `feature`.
+pub fn support(feature: Feature) -> Support {
+    match feature {
+        Feature::Env
+        | Feature::WorkingDirectory
+        | Feature::User
+        | Feature::UserLevel
+        | Feature::Start
+        | Feature::Restart
+        | Feature::KeepAlivePath
+        | Feature::Status => Support::Supported,
+        Feature::PassthroughEnv => Support::Emulated("jobs start from a clean environment"),
+        Feature::ResourceLimits => Support::Unsupported("systemd cgroup directives"),
+        Feature::RestartPolicy => Support::Emulated("KeepAlive conditions and ThrottleInterval"),
+        Feature::EnvFiles => Support::Unsupported("no environment files"),
+        Feature::EnvSecrets => Support::Unsupported("systemd credentials"),
+        Feature::VaultSecrets => Support::Unsupported("systemd ExecStartPre="),
+        Feature::CloudMetadata => Support::Unsupported("systemd ExecStartPre="),
+        Feature::Timer => Support::Unsupported("no calendar timers yet"),
+        Feature::Instances => Support::Unsupported("no templates"),
+        Feature::DependsOn => Support::Unsupported("no dependencies between jobs"),
+        Feature::RequiresMounts => Support::Unsupported("no mount dependencies"),
+        Feature::Devices => Support::Unsupported("no device dependencies"),
+        Feature::Dependencies => Support::Unsupported("no dependencies between jobs"),
+        Feature::FirstBootOnly => Support::Unsupported("no first boot condition"),
+        Feature::FailureMail => Support::Unsupported("no failure hooks"),
+        Feature::BusName => Support::Unsupported("no D-Bus"),
+        Feature::Listen => Support::Unsupported("launchd sockets need launch_activate_socket"),
+        Feature::FdStore => Support::Unsupported("no file descriptor store"),
+        Feature::Supervision => Support::Unsupported("OpenRC options, launchd supervises itself"),
+        Feature::Tty => Support::Unsupported("no consoles"),
+        Feature::Kiosk | Feature::GraphicalSession => Support::Unsupported("no sessions"),
+        Feature::Forking => Support::Unsupported("jobs stay in the foreground"),
+        Feature::Notify => Support::Emulated("no readiness protocol, started like simple"),
+        Feature::BootHooks => Support::Unsupported("systemd oneshot units"),
+        Feature::PowerHooks => Support::Unsupported("systemd sleep.target"),
+        Feature::UnitOptions => Support::Unsupported("systemd directives, e.g. from presets"),
+    }
+}
+
+/// When launchd restarts the job: always, or on the conditions given.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum KeepAlive {
+    Always(bool),
+    #[serde(rename_all = "PascalCase")]
+    When {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        successful_exit: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        crashed: Option<bool>,
+        /// Paths to keep it running while they exist (true) or don't.
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        path_state: BTreeMap<String, bool>,
+    },
+}
+
+/// The job's property list, with launchd's keys.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Job {
+    label: String,
+    program_arguments: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    environment_variables: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    working_directory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_name: Option<String>,
+    run_at_load: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<KeepAlive>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    throttle_interval: Option<u32>,
+}
+
+pub struct Launchd {
+    pub service: ServiceConfig,
+}
+
+impl Launchd {
+    fn label(&self) -> String {
+        format!("mkservice.{}", self.service.name)
+    }
+
+    fn plist_path(&self) -> Result<PathBuf> {
+        let dir = match self.service.level {
+            ServiceLevel::System => PathBuf::from("/Library/LaunchDaemons"),
+            ServiceLevel::User => {
+                let home_dir =
+                    env::var("HOME").map_err(|_| anyhow!("HOME must be set for user services."))?;
+                PathBuf::from(home_dir).join("Library/LaunchAgents")
+            }
+        };
+        Ok(dir.join(format!("{}.plist", self.label())))
+    }
+
+    /// The launchd domain the job is loaded into: the system's, or the
+    /// logged in user's GUI session.
+    fn domain(&self) -> Result<String> {
+        match self.service.level {
+            ServiceLevel::System => Ok("system".into()),
+            ServiceLevel::User => {
+                let output = Command::new("id")
+                    .arg("-u")
+                    .output()
+                    .map_err(|e| MkserviceError::spawn("id", e))?;
+                let uid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                Ok(format!("gui/{}", uid))
+            }
+        }
+    }
+
+    /// The job in its domain, as launchctl names services.
+    fn target(&self) -> Result<String> {
+        Ok(format!("{}/{}", self.domain()?, self.label()))
+    }
+
+    /// When launchd restarts the job, from the restart policy and the paths
+    /// it's kept alive by.
+    fn keep_alive(&self) -> Result<Option<KeepAlive>> {
+        let path_state: BTreeMap<String, bool> = self
+            .service
+            .keepalive_paths
+            .iter()
+            .map(|path| match path.strip_prefix('!') {
+                Some(path) => (path.to_string(), false),
+                None => (path.to_string(), true),
+            })
+            .collect();
+        let (successful_exit, crashed) = match (self.service.restart, self.service.service_type) {
+            (Some(RestartPolicy::No), _) | (None, ServiceType::Oneshot) => (None, None),
+            (Some(RestartPolicy::Always), _) if !path_state.is_empty() => {
+                return Err(anyhow!(
+                    "--restart always keeps the job running whatever --keepalive-path says."
+                )
+                .into());
+            }
+            (Some(RestartPolicy::Always), _) => return Ok(Some(KeepAlive::Always(true))),
+            // Restarted when it fails, like Restart=on-failure.
+            (Some(RestartPolicy::OnFailure), _)
+            | (
+                None,
+                ServiceType::Simple
+                | ServiceType::Exec
+                | ServiceType::Forking
+                | ServiceType::Notify
+                | ServiceType::Dbus,
+            ) => (Some(false), None),
+            (Some(RestartPolicy::OnAbnormal), _) => (None, Some(true)),
+        };
+        if successful_exit.is_none() && crashed.is_none() && path_state.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(KeepAlive::When {
+            successful_exit,
+            crashed,
+            path_state,
+        }))
+    }
+
+    pub fn to_plist(&self) -> Result<String> {
+        let program_arguments = match self.service.steps.is_empty() {
+            true => self.service.command.clone(),
+            // One program per job, so steps run through the shell.
+            false => {
+                let script = std::iter::once(&self.service.command)
+                    .chain(&self.service.steps)
+                    .map(|command| {
+                        command
+                            .iter()
+                            .map(|arg| shell_quote(arg))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" && ");
+                vec!["/bin/sh".into(), "-c".into(), script]
+            }
+        };
+        let job = Job {
+            label: self.label(),
+            program_arguments,
+            environment_variables: self.service.env.clone(),
+            working_directory: self.service.working_directory.clone(),
+            user_name: match self.service.level {
+                ServiceLevel::System => self.service.user.clone(),
+                ServiceLevel::User => None,
+            },
+            group_name: match self.service.level {
+                ServiceLevel::System => self.service.group.clone(),
+                ServiceLevel::User => None,
+            },
+            run_at_load: true,
+            keep_alive: self.keep_alive()?,
+            throttle_interval: self.service.restart_sec,
+        };
+        let mut xml = Vec::new();
+        plist::to_writer_xml(&mut xml, &job).map_err(|e| anyhow!(e))?;
+        let mut xml = String::from_utf8_lossy(&xml).into_owned();
+        if !xml.ends_with('\n') {
+            xml.push('\n');
+        }
+        Ok(match &self.service.header {
+            Some(header) => {
+                // Comments can't hold "--".
+                let comment = format!("<!--\n{}\n-->\n", header.replace("--", "- -"));
+                match xml.find("<plist") {
+                    Some(i) => format!("{}{}{}", &xml[..i], comment, &xml[i..]),
+                    None => xml,
+                }
+            }
+            None => xml,
+        })
+    }
+
+    /// Runs launchctl with `args`, failing with what it printed if it exits
+    /// non-zero.
+    fn launchctl(&self, args: &[&str]) -> Result<String> {
+        let cmd = format!("launchctl {}", args.join(" "));
+        let output = timed(&cmd, || Command::new("launchctl").args(args).output())
+            .map_err(|e| MkserviceError::spawn("launchctl", e))?;
+        if !output.status.success() {
+            return Err(MkserviceError::CommandFailed {
+                cmd,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The job's state from `launchctl print`, None if it isn't loaded.
+    fn print(&self) -> Result<Option<String>> {
+        match self.launchctl(&["print", &self.target()?]) {
+            Ok(output) => Ok(Some(output)),
+            Err(MkserviceError::CommandFailed { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loads the job, which starts it: it's RunAtLoad.
+    fn bootstrap(&self) -> Result<()> {
+        let path = self.plist_path()?;
+        self.launchctl(&["bootstrap", &self.domain()?, &path.to_string_lossy()])?;
+        Ok(())
+    }
+}
+
+/// The status of a job from `launchctl print` output.
+fn parse_print(output: &str) -> ServiceStatus {
+    let properties: BTreeMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.trim().split_once(" = "))
+        .collect();
+    let last_exit_status = properties
+        .get("last exit code")
+        .and_then(|code| code.parse().ok());
+    let state = match properties.get("state").copied() {
+        Some("running") => "active (running)".to_string(),
+        Some(state) if last_exit_status.is_some_and(|code| code != 0) => {
+            format!("failed ({})", state)
+        }
+        Some(state) => format!("inactive ({})", state),
+        None => "unknown".into(),
+    };
+    ServiceStatus {
+        state,
+        enabled: "enabled".into(),
+        restarts: properties
+            .get("runs")
+            .and_then(|runs| runs.parse::<u32>().ok())
+            .map(|runs| runs.saturating_sub(1)),
+        last_exit_status,
+        ..Default::default()
+    }
+}
+
+impl ServiceOperator for Launchd {
+    fn install(&self) -> Result<()> {
+        let path = self.plist_path()?;
+        let plist = self.to_plist()?;
+        log::info!("Writing launchd job to {:?}", path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+        }
+        fs::write(&path, plist).map_err(|e| MkserviceError::io(&path, e))?;
+
+        // A loaded job keeps its old definition until it's reloaded.
+        if self.print()?.is_some() {
+            self.launchctl(&["bootout", &self.target()?])?;
+        }
+        log::info!("Loading service...");
+        self.launchctl(&["enable", &self.target()?])?;
+        self.bootstrap()
+    }
+
+    fn start(&self) -> Result<()> {
+        if self.print()?.is_none() {
+            return self.bootstrap();
+        }
+        self.launchctl(&["kickstart", &self.target()?])?;
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        // Unloading is the only way to keep KeepAlive from restarting it.
+        // The job's loaded again at boot, or by `start`.
+        self.launchctl(&["bootout", &self.target()?])?;
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        if self.print()?.is_some() {
+            self.stop()?;
+        }
+        remove_files([self.plist_path()?])
+    }
+
+    fn restart_with(&self, units: &[String]) -> Result<()> {
+        if !units.is_empty() {
+            log::warn!("launchd can't restart {} along with it.", units.join(", "));
+        }
+        match self.print()? {
+            Some(_) => self.launchctl(&["kickstart", "-k", &self.target()?])?,
+            None => return self.bootstrap(),
+        };
+        Ok(())
+    }
+
+    fn dependents(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    fn log_command(&self, lines: usize, follow: bool) -> Option<Command> {
+        // The unified log, by the program's name, as launchd keeps no
+        // output of its own.
+        let program = Path::new(self.service.command.first()?).file_name()?;
+        let predicate = format!("process == {:?}", program.to_string_lossy());
+        let command = match follow {
+            true => {
+                let mut command = Command::new("log");
+                command.args(["stream", "--style", "compact", "--predicate", &predicate]);
+                command
+            }
+            // log show has no line limit of its own.
+            false => {
+                let mut command = Command::new("/bin/sh");
+                command.args([
+                    "-c",
+                    "log show --style compact --last 1d --predicate \"$1\" | tail -n \"$2\"",
+                    "sh",
+                    &predicate,
+                    &lines.to_string(),
+                ]);
+                command
+            }
+        };
+        Some(command)
+    }
+
+    fn existing_units(&self) -> Result<Vec<PathBuf>> {
+        let path = self.plist_path()?;
+        Ok(match path.exists() {
+            true => vec![path],
+            false => vec![],
+        })
+    }
+
+    fn unit_files(&self) -> Result<Vec<(PathBuf, String)>> {
+        Ok(vec![(self.plist_path()?, self.to_plist()?)])
+    }
+
+    fn status(&self) -> Result<ServiceStatus> {
+        Ok(match self.print()? {
+            Some(output) => parse_print(&output),
+            None => ServiceStatus {
+                state: "inactive (not loaded)".into(),
+                enabled: match self.plist_path()?.exists() {
+                    true => "enabled".into(),
+                    false => "not-found".into(),
+                },
+                ..Default::default()
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::golden;
+
+    #[test]
+    fn test_plist_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: vec!["/usr/local/bin/web".into(), "--port=8080".into()],
+            env: [("LOG_LEVEL".to_string(), "debug".to_string())].into(),
+            user: Some("www".into()),
+            header: Some("Managed by mkservice".into()),
+            ..Default::default()
+        };
+        let plist = Launchd { service }.to_plist().unwrap();
+        let value = plist::Value::from_reader_xml(plist.as_bytes()).unwrap();
+        let job = value.as_dictionary().unwrap();
+        assert_eq!(
+            job.get("Label").and_then(|v| v.as_string()),
+            Some("mkservice.web")
+        );
+        assert_eq!(
+            job.get("ProgramArguments")
+                .and_then(|v| v.as_array())
+                .map(|a| a.len()),
+            Some(2)
+        );
+        assert_eq!(job.get("UserName").and_then(|v| v.as_string()), Some("www"));
+        let keep_alive = job
+            .get("KeepAlive")
+            .and_then(|v| v.as_dictionary())
+            .unwrap();
+        assert_eq!(
+            keep_alive
+                .get("SuccessfulExit")
+                .and_then(|v| v.as_boolean()),
+            Some(false)
+        );
+        assert!(plist.contains("<!--\nManaged by mkservice\n-->\n<plist"));
+    }
+
+    /// Renders `service` and compares it to testdata/launchd/`golden`.
+    fn assert_golden(service: ServiceConfig, golden: &str) {
+        let plist = Launchd { service }.to_plist().unwrap();
+        golden::assert_golden(&format!("launchd/{}", golden), &plist);
+    }
+
+    #[test]
+    fn test_keep_alive_golden() {
+        let service = |restart, keepalive_paths: &[&str]| ServiceConfig {
+            name: "web".into(),
+            command: vec!["/usr/local/bin/web".into()],
+            restart,
+            keepalive_paths: keepalive_paths.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        // Restarted on failure unless asked otherwise.
+        assert_golden(service(None, &[]), "on-failure.plist");
+        assert_golden(
+            service(Some(RestartPolicy::OnFailure), &[]),
+            "on-failure.plist",
+        );
+        assert_golden(service(Some(RestartPolicy::Always), &[]), "always.plist");
+        assert_golden(
+            service(Some(RestartPolicy::OnAbnormal), &[]),
+            "on-abnormal.plist",
+        );
+        assert_golden(service(Some(RestartPolicy::No), &[]), "no.plist");
+        assert_golden(
+            service(
+                Some(RestartPolicy::No),
+                &["/Volumes/Data", "!/var/run/maintenance"],
+            ),
+            "keepalive-path.plist",
+        );
+        let conflicting = service(Some(RestartPolicy::Always), &["/Volumes/Data"]);
+        assert!(Launchd {
+            service: conflicting
+        }
+        .to_plist()
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_print() {
+        let output = "system/mkservice.web = {\n\
+            \tactive count = 1\n\
+            \tpath = /Library/LaunchDaemons/mkservice.web.plist\n\
+            \tstate = running\n\
+            \truns = 3\n\
+            \tpid = 412\n\
+            \tlast exit code = 1\n\
+            }\n";
+        let status = parse_print(output);
+        assert_eq!(status.state, "active (running)");
+        assert_eq!(status.restarts, Some(2));
+        assert_eq!(status.last_exit_status, Some(1));
+        let status = parse_print("\tstate = not running\n\tlast exit code = 78\n");
+        assert_eq!(status.state, "failed (not running)");
+    }
+}
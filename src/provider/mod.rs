@@ -1,14 +1,240 @@
+use crate::command::timed;
 use crate::config::ServiceConfig;
+use crate::error::{MkserviceError, Result};
 use crate::ServiceOperator;
-use std::path::Path;
+use anyhow::anyhow;
+use capabilities::{Feature, Support};
+#[cfg(feature = "launchd")]
+use launchd::Launchd;
+#[cfg(feature = "openrc")]
+use openrc::OpenRc;
+#[cfg(feature = "runit")]
+use runit::Runit;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use systemd::Systemd;
 
+pub mod capabilities;
+#[cfg(test)]
+mod golden;
+#[cfg(feature = "launchd")]
+pub mod launchd;
+#[cfg(feature = "openrc")]
+pub mod openrc;
+#[cfg(feature = "runit")]
+pub mod runit;
 pub mod systemd;
 
-pub fn get_provider(service: ServiceConfig) -> Option<impl ServiceOperator> {
+/// Runtime state of an installed service, as far as the provider knows it.
+#[derive(Debug, Default, Serialize)]
+pub struct ServiceStatus {
+    /// e.g. "active (running)" or "failed (failed)".
+    pub state: String,
+    pub enabled: String,
+    pub restarts: Option<u32>,
+    /// Seconds since it last became active, while it is.
+    pub uptime: Option<u64>,
+    /// Outcome of the last run, e.g. "success" or "exit-code".
+    pub last_result: Option<String>,
+    pub last_exit_status: Option<i32>,
+    pub last_exit_time: Option<String>,
+    /// Resource limits in effect, keyed by the provider's own names.
+    pub limits: BTreeMap<String, String>,
+}
+
+/// The service managers mkservice writes services for.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProviderKind {
+    Systemd,
+    #[cfg(feature = "openrc")]
+    #[value(name = "openrc")]
+    OpenRc,
+    #[cfg(feature = "runit")]
+    Runit,
+    #[cfg(feature = "launchd")]
+    Launchd,
+}
+
+impl ProviderKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderKind::Systemd => "systemd",
+            #[cfg(feature = "openrc")]
+            #[cfg(feature = "openrc")]
+            ProviderKind::OpenRc => "openrc",
+            #[cfg(feature = "runit")]
+            ProviderKind::Runit => "runit",
+            #[cfg(feature = "launchd")]
+            #[cfg(feature = "launchd")]
+            ProviderKind::Launchd => "launchd",
+        }
+    }
+
+    pub fn support(&self, feature: Feature) -> Support {
+        match self {
+            ProviderKind::Systemd => systemd::support(feature, systemd::systemd_version()),
+            #[cfg(feature = "openrc")]
+            #[cfg(feature = "openrc")]
+            ProviderKind::OpenRc => openrc::support(feature),
+            #[cfg(feature = "runit")]
+            ProviderKind::Runit => runit::support(feature),
+            #[cfg(feature = "launchd")]
+            #[cfg(feature = "launchd")]
+            ProviderKind::Launchd => launchd::support(feature),
+        }
+    }
+}
+
+/// The service manager running this host. Detected once per run, since
+/// it can't change under a running mkservice.
+pub fn detect() -> Result<ProviderKind> {
+    static DETECTED: OnceLock<Option<ProviderKind>> = OnceLock::new();
+    DETECTED
+        .get_or_init(|| timed("Detecting the service manager", detect_uncached))
+        .ok_or_else(|| MkserviceError::ProviderUnavailable {
+            detected: "no /run/systemd/system, /run/openrc, runsvdir or /bin/launchctl".into(),
+        })
+}
+
+fn detect_uncached() -> Option<ProviderKind> {
     if Path::new("/run/systemd/system").exists() {
-        Some(Systemd { service })
-    } else {
-        None
+        return Some(ProviderKind::Systemd);
+    }
+    #[cfg(feature = "openrc")]
+    if Path::new("/run/openrc").exists() || crate::command::find_executable("rc-service").is_some()
+    {
+        return Some(ProviderKind::OpenRc);
+    }
+    #[cfg(feature = "runit")]
+    if runit::detect() {
+        return Some(ProviderKind::Runit);
+    }
+    #[cfg(feature = "launchd")]
+    if Path::new("/bin/launchctl").exists() {
+        return Some(ProviderKind::Launchd);
+    }
+    None
+}
+
+/// `kind`'s operator for `service`.
+pub fn for_kind(kind: ProviderKind, service: ServiceConfig) -> Box<dyn ServiceOperator> {
+    match kind {
+        ProviderKind::Systemd => Box::new(Systemd { service }),
+        #[cfg(feature = "openrc")]
+        ProviderKind::OpenRc => Box::new(OpenRc { service }),
+        #[cfg(feature = "runit")]
+        ProviderKind::Runit => Box::new(Runit { service }),
+        #[cfg(feature = "launchd")]
+        ProviderKind::Launchd => Box::new(Launchd { service }),
+    }
+}
+
+/// Removes the files at `paths`, skipping those already gone.
+pub fn remove_files(paths: impl IntoIterator<Item = PathBuf>) -> Result<()> {
+    for path in paths {
+        match fs::remove_file(&path) {
+            Ok(()) => log::info!("Removed {:?}", path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(MkserviceError::io(&path, e)),
+        }
+    }
+    Ok(())
+}
+
+/// Installs `services` on `kind`. systemd reloads and enables them all at
+/// once; other managers take them one at a time.
+pub fn install_all(kind: ProviderKind, services: &[ServiceConfig]) -> Result<()> {
+    if kind == ProviderKind::Systemd {
+        return systemd::install_all(services);
+    }
+    for service in services {
+        for_kind(kind, service.clone()).install()?;
+    }
+    Ok(())
+}
+
+/// Starts `services` on `kind`, in one go where it can.
+pub fn start_all(kind: ProviderKind, services: &[ServiceConfig]) -> Result<()> {
+    if kind == ProviderKind::Systemd {
+        return systemd::start_all(services);
+    }
+    for service in services {
+        for_kind(kind, service.clone()).start()?;
+    }
+    Ok(())
+}
+
+pub fn get_provider(service: ServiceConfig) -> Result<Box<dyn ServiceOperator>> {
+    Ok(for_kind(detect()?, service))
+}
+
+/// The last `lines` lines of `p`'s output, if its service manager keeps them.
+pub fn recent_logs(p: &dyn ServiceOperator, lines: usize) -> Result<Option<String>> {
+    let Some(mut command) = p.log_command(lines, false) else {
+        return Ok(None);
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| MkserviceError::spawn(&program, e))?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: program,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Shows `p`'s output on the terminal, the last `lines` lines and with
+/// `follow` new ones until interrupted. Returns the log command's exit code.
+pub fn show_logs(p: &dyn ServiceOperator, lines: usize, follow: bool) -> Result<i32> {
+    let mut command = p.log_command(lines, follow).ok_or_else(|| {
+        let kind = detect().map_or("The service manager", |kind| kind.name());
+        MkserviceError::Other(anyhow!("{} keeps no log of the service's output.", kind))
+    })?;
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .map_err(|e| MkserviceError::spawn(&program, e))?;
+    Ok(status.code().unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    #[test]
+    fn test_render_reproducible() {
+        let service = |env: &[(&str, &str)]| ServiceConfig {
+            name: "web".into(),
+            command: vec!["/usr/bin/web".into(), "--port".into(), "8080".into()],
+            env: env
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            working_directory: Some("/srv/web".into()),
+            user: Some("www".into()),
+            depends_on: vec!["db".into()],
+            header: Some("Managed by mkservice".into()),
+            ..Default::default()
+        };
+        // The same inputs, given in another order.
+        let first = service(&[("PORT", "8080"), ("LANG", "C"), ("HOME", "/srv/web")]);
+        let second = service(&[("HOME", "/srv/web"), ("PORT", "8080"), ("LANG", "C")]);
+        for kind in ProviderKind::value_variants() {
+            let files = for_kind(*kind, first.clone()).unit_files().unwrap();
+            assert_eq!(
+                files,
+                for_kind(*kind, second.clone()).unit_files().unwrap(),
+                "{}",
+                kind.name()
+            );
+        }
     }
 }
@@ -0,0 +1,623 @@
+//! OpenRC, as on Alpine and Gentoo: an init script per service, run under
+//! supervise-daemon so it's restarted when it dies, or start-stop-daemon
+//! if asked.
+
+use crate::command::{find_executable, shell_quote, timed};
+use crate::config::{
+    OpenRcConfig, RestartPolicy, ServiceConfig, ServiceLevel, ServiceType, Supervisor,
+};
+use crate::error::{MkserviceError, Result};
+use crate::provider::capabilities::{Feature, Support};
+use crate::provider::{remove_files, ServiceStatus};
+use crate::ServiceOperator;
+use anyhow::anyhow;
+use std::fmt::Write as _;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const INIT_DIR: &str = "/etc/init.d";
+/// The runlevel services are added to, as `rc-update add` does by default.
+const RUNLEVEL: &str = "default";
+
+/// The OpenRC services standing in for systemd units: services by name, and
+/// the targets OpenRC has a service for.
+fn openrc_services(units: &[String]) -> Result<Vec<String>> {
+    units
+        .iter()
+        .map(|unit| {
+            let service = match unit.as_str() {
+                "network.target" | "network-online.target" => "net",
+                "local-fs.target" => "localmount",
+                "remote-fs.target" => "netmount",
+                "time-sync.target" => "ntp-client",
+                "syslog.target" => "logger",
+                unit => unit
+                    .strip_suffix(".service")
+                    .ok_or_else(|| anyhow!("OpenRC has no service standing in for {}.", unit))?,
+            };
+            Ok(service.to_string())
+        })
+        .collect()
+}
+
+/// How OpenRC handles `feature`.
+pub fn support(feature: Feature) -> Support {
+    match feature {
+        Feature::Env
+        | Feature::WorkingDirectory
+        | Feature::User
+        | Feature::DependsOn
+        | Feature::Start
+        | Feature::Restart
+        | Feature::Supervision
+        | Feature::Status => Support::Supported,
+        Feature::EnvFiles => Support::Emulated("sourced by the init script before starting"),
+        Feature::ResourceLimits => Support::Unsupported("systemd cgroup directives"),
+        Feature::RestartPolicy => Support::Emulated("supervise-daemon respawns whatever exits"),
+        Feature::PassthroughEnv => Support::Emulated("init scripts start from a clean environment"),
+        Feature::RequiresMounts => Support::Emulated("needs localmount, uses netmount"),
+        Feature::Devices => Support::Unsupported("no device dependencies"),
+        Feature::Dependencies => Support::Emulated("services and targets OpenRC has, in depend()"),
+        Feature::UserLevel => Support::Unsupported("OpenRC runs system services only"),
+        Feature::EnvSecrets => Support::Unsupported("systemd credentials"),
+        Feature::VaultSecrets => Support::Unsupported("systemd ExecStartPre="),
+        Feature::CloudMetadata => Support::Unsupported("systemd ExecStartPre="),
+        Feature::Timer => Support::Unsupported("no timers, use cron"),
+        Feature::Instances => Support::Unsupported("no templates"),
+        Feature::FirstBootOnly => Support::Unsupported("no first boot condition"),
+        Feature::FailureMail => Support::Unsupported("no failure hooks"),
+        Feature::BusName => Support::Unsupported("no D-Bus activation"),
+        Feature::Listen => Support::Unsupported("no socket activation"),
+        Feature::FdStore => Support::Unsupported("no file descriptor store"),
+        Feature::KeepAlivePath => Support::Unsupported("launchd option"),
+        Feature::Tty => Support::Unsupported("supervise-daemon doesn't attach consoles"),
+        Feature::Kiosk | Feature::GraphicalSession => Support::Unsupported("no sessions"),
+        Feature::Forking => Support::Emulated("start-stop-daemon with the pid file"),
+        Feature::Notify => Support::Emulated("no readiness protocol, started like simple"),
+        Feature::BootHooks => Support::Unsupported("systemd oneshot units"),
+        Feature::PowerHooks => Support::Unsupported("systemd sleep.target"),
+        Feature::UnitOptions => Support::Unsupported("systemd directives, e.g. from presets"),
+    }
+}
+
+/// A double-quoted sh string of `s`.
+fn double_quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub struct OpenRc {
+    pub service: ServiceConfig,
+}
+
+impl OpenRc {
+    fn script_path(&self) -> PathBuf {
+        Path::new(INIT_DIR).join(&self.service.name)
+    }
+
+    /// The init script, run by openrc-run.
+    pub fn to_init_script(&self) -> Result<String> {
+        if self.service.level != ServiceLevel::System {
+            return Err(anyhow!("OpenRC runs system services only.").into());
+        }
+        let mut script = String::from("#!/sbin/openrc-run\n");
+        if let Some(header) = &self.service.header {
+            for line in header.lines() {
+                let _ = writeln!(script, "{}", format!("# {}", line).trim_end());
+            }
+        }
+        script += "\n";
+        let _ = writeln!(script, "description={}", double_quote(&self.service.name));
+        for (key, value) in &self.service.env {
+            let _ = writeln!(script, "export {}={}", key, shell_quote(value));
+        }
+        if let Some(dir) = &self.service.working_directory {
+            let _ = writeln!(script, "directory={}", double_quote(dir));
+        }
+        if let Some(user) = &self.service.user {
+            let user = match &self.service.group {
+                Some(group) => format!("{}:{}", user, group),
+                None => user.clone(),
+            };
+            let _ = writeln!(script, "command_user={}", double_quote(&user));
+        }
+
+        let commands: Vec<&Vec<String>> = std::iter::once(&self.service.command)
+            .chain(&self.service.steps)
+            .collect();
+        match self.service.service_type {
+            ServiceType::Oneshot => {
+                if !self.service.openrc.is_default() {
+                    return Err(anyhow!(
+                        "OpenRC oneshots run in the init script, without a supervisor."
+                    )
+                    .into());
+                }
+                if self.service.group.is_some() {
+                    return Err(anyhow!(
+                        "OpenRC oneshots run through su, which can't pick the group."
+                    )
+                    .into());
+                }
+                // Stays "started" once the commands succeed, like a systemd
+                // oneshot that remains after exit.
+                script += "\nstart() {\n\tebegin \"Running ${RC_SVCNAME}\"\n\t(\n";
+                if let Some(user) = &self.service.user {
+                    // command_user= only applies to supervised commands.
+                    let _ = writeln!(
+                        script,
+                        "\t\texec su -s /bin/sh {} -c {}",
+                        shell_quote(user),
+                        shell_quote(&self.oneshot_body(&commands))
+                    );
+                } else {
+                    let _ = writeln!(script, "\t\t{}", self.oneshot_body(&commands));
+                }
+                script += "\t)\n\teend $?\n}\n";
+            }
+            ServiceType::Simple
+            | ServiceType::Exec
+            | ServiceType::Forking
+            | ServiceType::Notify
+            | ServiceType::Dbus => {
+                let (program, args) = self
+                    .service
+                    .command
+                    .split_first()
+                    .ok_or_else(|| anyhow!("{:?} has no command.", self.service.name))?;
+                let args: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+                self.write_supervision(&self.supervision()?, &mut script)?;
+                let _ = writeln!(script, "command={}", double_quote(program));
+                if !args.is_empty() {
+                    // openrc-run evals command_args, so the quoting survives.
+                    let _ = writeln!(script, "command_args={}", double_quote(&args.join(" ")));
+                }
+            }
+        }
+
+        let mut need: Vec<String> = self.service.depends_on.clone();
+        let mut uses = Vec::new();
+        if !self.service.requires_mounts.is_empty() {
+            need.insert(0, "localmount".into());
+            uses.push("netmount".to_string());
+        }
+        need.extend(openrc_services(&self.service.requires)?);
+        uses.extend(openrc_services(&self.service.wants)?);
+        let depend = [
+            ("need", need),
+            ("use", uses),
+            ("after", openrc_services(&self.service.after)?),
+            ("before", openrc_services(&self.service.before)?),
+        ];
+        if depend.iter().any(|(_, services)| !services.is_empty()) {
+            script += "\ndepend() {\n";
+            for (keyword, services) in depend.iter().filter(|(_, s)| !s.is_empty()) {
+                let _ = writeln!(script, "\t{} {}", keyword, services.join(" "));
+            }
+            script += "}\n";
+        }
+
+        if !self.service.env_files.is_empty() {
+            script += "\nstart_pre() {\n\tset -a\n";
+            for file in &self.service.env_files {
+                match file.strip_prefix('-') {
+                    // Optional, as systemd's EnvironmentFile=-.
+                    Some(file) => {
+                        let file = shell_quote(file);
+                        let _ = writeln!(script, "\t[ ! -f {} ] || . {}", file, file);
+                    }
+                    None => {
+                        let _ = writeln!(script, "\t. {} || return 1", shell_quote(file));
+                    }
+                }
+            }
+            script += "\tset +a\n}\n";
+        }
+        Ok(script)
+    }
+
+    /// How the service is supervised: as asked, or by start-stop-daemon
+    /// with its pid file for a daemon that forks.
+    fn supervision(&self) -> Result<OpenRcConfig> {
+        let mut openrc = self.service.openrc.clone();
+        if self.service.service_type == ServiceType::Forking {
+            if openrc.supervisor == Some(Supervisor::SuperviseDaemon) {
+                return Err(anyhow!(
+                    "supervise-daemon can't follow a daemon that forks, leave --supervisor unset."
+                )
+                .into());
+            }
+            openrc.supervisor = Some(Supervisor::StartStopDaemon);
+            openrc.pidfile = self.service.pidfile.clone();
+        }
+        Ok(openrc)
+    }
+
+    /// The variables choosing how a long-running service is supervised.
+    fn write_supervision(&self, openrc: &OpenRcConfig, script: &mut String) -> Result<()> {
+        match openrc.supervisor.unwrap_or(Supervisor::SuperviseDaemon) {
+            Supervisor::SuperviseDaemon => {
+                if openrc.pidfile.is_some() || openrc.background {
+                    return Err(anyhow!(
+                        "--pidfile and --command-background are for --supervisor start-stop-daemon."
+                    )
+                    .into());
+                }
+                if self.service.restart == Some(RestartPolicy::No) {
+                    return Err(anyhow!(
+                        "supervise-daemon respawns whatever exits, it can't --restart no."
+                    )
+                    .into());
+                }
+                *script += "supervisor=supervise-daemon\n";
+                if let Some(secs) = self.service.restart_sec {
+                    let _ = writeln!(script, "respawn_delay={}", secs);
+                }
+                if let Some(max) = openrc.respawn_max {
+                    let _ = writeln!(script, "respawn_max={}", max);
+                }
+                if let Some(secs) = openrc.respawn_period {
+                    let _ = writeln!(script, "respawn_period={}", secs);
+                }
+            }
+            // openrc-run's default when no supervisor= is set.
+            Supervisor::StartStopDaemon => {
+                let restarts = self.service.restart.is_some_and(|r| r != RestartPolicy::No);
+                if restarts || self.service.restart_sec.is_some() {
+                    return Err(anyhow!(
+                        "start-stop-daemon doesn't restart services, use --supervisor supervise-daemon."
+                    )
+                    .into());
+                }
+                if openrc.respawn_max.is_some() || openrc.respawn_period.is_some() {
+                    return Err(anyhow!(
+                        "--respawn-max and --respawn-period are for --supervisor supervise-daemon."
+                    )
+                    .into());
+                }
+                match &openrc.pidfile {
+                    Some(pidfile) => {
+                        let _ = writeln!(script, "pidfile={}", double_quote(pidfile));
+                    }
+                    // start-stop-daemon writes it for a command it backgrounds.
+                    None if openrc.background => *script += "pidfile=\"/run/${RC_SVCNAME}.pid\"\n",
+                    None => {}
+                }
+                if openrc.background {
+                    *script += "command_background=true\n";
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The commands of a oneshot as one sh command line, stopping at the
+    /// first failure.
+    fn oneshot_body(&self, commands: &[&Vec<String>]) -> String {
+        let mut body: Vec<String> = Vec::new();
+        if let Some(dir) = &self.service.working_directory {
+            body.push(format!("cd {}", shell_quote(dir)));
+        }
+        body.extend(commands.iter().map(|command| {
+            command
+                .iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }));
+        body.join(" && ")
+    }
+
+    /// Runs `program` with `args`, failing with what it printed if it exits
+    /// non-zero.
+    fn run(&self, program: &str, args: &[&str]) -> Result<()> {
+        let cmd = format!("{} {}", program, args.join(" "));
+        let output = timed(&cmd, || Command::new(program).args(args).output())
+            .map_err(|e| MkserviceError::spawn(program, e))?;
+        if !output.status.success() {
+            let stderr = match output.stderr.is_empty() {
+                true => String::from_utf8_lossy(&output.stdout).into_owned(),
+                false => String::from_utf8_lossy(&output.stderr).into_owned(),
+            };
+            return Err(MkserviceError::CommandFailed { cmd, stderr });
+        }
+        Ok(())
+    }
+
+    fn rc_service(&self, action: &str) -> Result<()> {
+        self.run("rc-service", &[&self.service.name, action])
+    }
+
+    /// Whether the service is in the default runlevel.
+    fn enabled(&self) -> String {
+        let link = Path::new("/etc/runlevels")
+            .join(RUNLEVEL)
+            .join(&self.service.name);
+        match link.symlink_metadata() {
+            Ok(_) => "enabled".into(),
+            Err(_) => "disabled".into(),
+        }
+    }
+}
+
+/// The state `rc-service NAME status` reports, in the "active (sub-state)"
+/// form systemd's states take, so the rest of mkservice reads it alike.
+fn parse_status(output: &str) -> String {
+    let state = output
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .trim_start_matches("* ")
+                .strip_prefix("status: ")
+        })
+        .unwrap_or("unknown")
+        .trim();
+    let active = match state {
+        "started" => "active",
+        "starting" => "activating",
+        "stopping" => "deactivating",
+        "crashed" => "failed",
+        _ => "inactive",
+    };
+    format!("{} ({})", active, state)
+}
+
+impl ServiceOperator for OpenRc {
+    fn install(&self) -> Result<()> {
+        let path = self.script_path();
+        let script = self.to_init_script()?;
+        log::info!("Writing init script to {:?}", path);
+        fs::write(&path, script).map_err(|e| MkserviceError::io(&path, e))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| MkserviceError::io(&path, e))?;
+
+        if find_executable("rc-update").is_none() {
+            log::warn!("rc-update not found, enabling by symlink.");
+            let link = Path::new("/etc/runlevels")
+                .join(RUNLEVEL)
+                .join(&self.service.name);
+            if link.symlink_metadata().is_err() {
+                std::os::unix::fs::symlink(&path, &link)
+                    .map_err(|e| MkserviceError::io(&link, e))?;
+            }
+            return Ok(());
+        }
+        log::info!("Enabling service...");
+        self.run("rc-update", &["add", &self.service.name, RUNLEVEL])
+    }
+
+    fn start(&self) -> Result<()> {
+        self.rc_service("start")
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.rc_service("stop")
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        let path = self.script_path();
+        if !path.exists() {
+            return Ok(());
+        }
+        self.rc_service("stop")?;
+        let link = Path::new("/etc/runlevels")
+            .join(RUNLEVEL)
+            .join(&self.service.name);
+        if link.symlink_metadata().is_ok() {
+            log::info!("Disabling service...");
+            match find_executable("rc-update") {
+                Some(_) => self.run("rc-update", &["del", &self.service.name, RUNLEVEL])?,
+                None => remove_files([link])?,
+            }
+        }
+        remove_files([path])
+    }
+
+    fn restart_with(&self, units: &[String]) -> Result<()> {
+        self.rc_service("restart")?;
+        for unit in units {
+            self.run("rc-service", &[unit, "restart"])?;
+        }
+        Ok(())
+    }
+
+    fn dependents(&self) -> Result<Vec<String>> {
+        // OpenRC restarts services that need this one itself.
+        Ok(vec![])
+    }
+
+    fn existing_units(&self) -> Result<Vec<PathBuf>> {
+        let path = self.script_path();
+        Ok(match path.exists() {
+            true => vec![path],
+            false => vec![],
+        })
+    }
+
+    fn unit_files(&self) -> Result<Vec<(PathBuf, String)>> {
+        Ok(vec![(self.script_path(), self.to_init_script()?)])
+    }
+
+    fn status(&self) -> Result<ServiceStatus> {
+        // Exits non-zero for anything but started, so only a failure to run
+        // it is an error.
+        let output = Command::new("rc-service")
+            .args([&self.service.name, "status"])
+            .output()
+            .map_err(|e| MkserviceError::spawn("rc-service", e))?;
+        let mut out = String::from_utf8_lossy(&output.stdout).into_owned();
+        out += &String::from_utf8_lossy(&output.stderr);
+        Ok(ServiceStatus {
+            state: parse_status(&out),
+            enabled: self.enabled(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OpenRcConfig;
+
+    #[test]
+    fn test_init_script_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: vec![
+                "/usr/bin/web".into(),
+                "--greeting".into(),
+                "it's $HOME".into(),
+            ],
+            env: [("PORT".to_string(), "8080".to_string())].into(),
+            env_files: vec!["-/etc/web.env".into()],
+            user: Some("www".into()),
+            depends_on: vec!["db".into()],
+            header: Some("Managed by mkservice".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            OpenRc { service }.to_init_script().unwrap(),
+            "#!/sbin/openrc-run\n\
+            # Managed by mkservice\n\
+            \n\
+            description=\"web\"\n\
+            export PORT=8080\n\
+            command_user=\"www\"\n\
+            supervisor=supervise-daemon\n\
+            command=\"/usr/bin/web\"\n\
+            command_args=\"--greeting 'it'\\\\''s \\$HOME'\"\n\
+            \n\
+            depend() {\n\
+            \tneed db\n\
+            }\n\
+            \n\
+            start_pre() {\n\
+            \tset -a\n\
+            \t[ ! -f /etc/web.env ] || . /etc/web.env\n\
+            \tset +a\n\
+            }\n"
+        );
+    }
+
+    #[test]
+    fn test_dependencies_render() {
+        let mut service = ServiceConfig {
+            name: "web".into(),
+            command: vec!["/usr/bin/web".into()],
+            requires_mounts: vec!["/srv".into()],
+            depends_on: vec!["db".into()],
+            requires: vec!["redis.service".into()],
+            wants: vec!["network-online.target".into()],
+            after: vec!["network-online.target".into(), "syslog.target".into()],
+            before: vec!["nginx.service".into()],
+            ..Default::default()
+        };
+        let script = OpenRc {
+            service: service.clone(),
+        }
+        .to_init_script()
+        .unwrap();
+        assert!(script.ends_with(
+            "depend() {\n\
+            \tneed localmount db redis\n\
+            \tuse netmount net\n\
+            \tafter net logger\n\
+            \tbefore nginx\n\
+            }\n"
+        ));
+
+        service.after = vec!["dev-ttyUSB0.device".into()];
+        assert!(OpenRc { service }.to_init_script().is_err());
+    }
+
+    #[test]
+    fn test_supervision_render() {
+        let service = |openrc: OpenRcConfig| ServiceConfig {
+            name: "web".into(),
+            command: vec!["/usr/bin/web".into()],
+            openrc,
+            ..Default::default()
+        };
+        let script = OpenRc {
+            service: service(OpenRcConfig {
+                respawn_max: Some(5),
+                respawn_period: Some(60),
+                ..Default::default()
+            }),
+        }
+        .to_init_script()
+        .unwrap();
+        assert!(script.contains("supervisor=supervise-daemon\nrespawn_max=5\nrespawn_period=60\n"));
+
+        let script = OpenRc {
+            service: service(OpenRcConfig {
+                supervisor: Some(Supervisor::StartStopDaemon),
+                background: true,
+                ..Default::default()
+            }),
+        }
+        .to_init_script()
+        .unwrap();
+        assert!(!script.contains("supervisor="));
+        assert!(script.contains("pidfile=\"/run/${RC_SVCNAME}.pid\"\ncommand_background=true\n"));
+
+        let forking = OpenRc {
+            service: service(OpenRcConfig {
+                supervisor: Some(Supervisor::StartStopDaemon),
+                pidfile: Some("/run/web/web.pid".into()),
+                ..Default::default()
+            }),
+        };
+        assert!(forking
+            .to_init_script()
+            .unwrap()
+            .contains("pidfile=\"/run/web/web.pid\"\ncommand=\"/usr/bin/web\"\n"));
+
+        let mismatched = OpenRc {
+            service: service(OpenRcConfig {
+                pidfile: Some("/run/web.pid".into()),
+                ..Default::default()
+            }),
+        };
+        assert!(mismatched.to_init_script().is_err());
+
+        // --service-type forking picks start-stop-daemon itself.
+        let mut service = service(OpenRcConfig::default());
+        service.service_type = ServiceType::Forking;
+        service.pidfile = Some("/run/web/web.pid".into());
+        let script = OpenRc { service }.to_init_script().unwrap();
+        assert!(!script.contains("supervisor="));
+        assert!(script.contains("pidfile=\"/run/web/web.pid\"\ncommand=\"/usr/bin/web\"\n"));
+    }
+
+    #[test]
+    fn test_oneshot_render() {
+        let service = ServiceConfig {
+            name: "setup".into(),
+            command: vec!["/usr/bin/migrate".into()],
+            steps: vec![vec!["/usr/bin/seed".into(), "--all".into()]],
+            service_type: ServiceType::Oneshot,
+            ..Default::default()
+        };
+        let script = OpenRc { service }.to_init_script().unwrap();
+        assert!(script.contains("\t\t/usr/bin/migrate && /usr/bin/seed --all\n"));
+        assert!(!script.contains("supervisor"));
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(" * status: started\n"), "active (started)");
+        assert_eq!(parse_status(" * status: crashed\n"), "failed (crashed)");
+        assert_eq!(parse_status(" * status: stopped\n"), "inactive (stopped)");
+    }
+}
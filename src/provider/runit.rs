@@ -0,0 +1,446 @@
+//! runit, as on Void and Artix: a service directory per service with a run
+//! script, supervised by runsv once it's linked into runsvdir's directory.
+
+use crate::command::{shell_quote, timed};
+use crate::config::{RestartPolicy, ServiceConfig, ServiceLevel, ServiceType};
+use crate::error::{MkserviceError, Result};
+use crate::provider::capabilities::{Feature, Support};
+use crate::provider::{remove_files, ServiceStatus};
+use crate::ServiceOperator;
+use anyhow::anyhow;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SV_DIR: &str = "/etc/sv";
+/// The directories runsvdir watches, Void's first.
+const SERVICE_DIRS: [&str; 2] = ["/var/service", "/etc/service"];
+const LOG_DIR: &str = "/var/log";
+
+/// How runit handles `feature`.
+pub fn support(feature: Feature) -> Support {
+    match feature {
+        Feature::Env
+        | Feature::WorkingDirectory
+        | Feature::User
+        | Feature::Start
+        | Feature::Restart
+        | Feature::Status => Support::Supported,
+        Feature::EnvFiles => Support::Emulated("sourced by the run script"),
+        Feature::ResourceLimits => Support::Unsupported("systemd cgroup directives"),
+        Feature::RestartPolicy => Support::Emulated("a finish script takes the service down"),
+        Feature::PassthroughEnv => Support::Emulated("runsv starts from a clean environment"),
+        Feature::DependsOn => Support::Emulated("the run script waits on `sv check`"),
+        Feature::UserLevel => Support::Unsupported("mkservice writes system services only"),
+        Feature::EnvSecrets => Support::Unsupported("systemd credentials"),
+        Feature::VaultSecrets => Support::Unsupported("systemd ExecStartPre="),
+        Feature::CloudMetadata => Support::Unsupported("systemd ExecStartPre="),
+        Feature::Timer => Support::Unsupported("no timers, use cron"),
+        Feature::Instances => Support::Unsupported("no templates"),
+        Feature::RequiresMounts => Support::Unsupported("no mount dependencies"),
+        Feature::Devices => Support::Unsupported("no device dependencies"),
+        Feature::Dependencies => Support::Unsupported("no ordering between services"),
+        Feature::FirstBootOnly => Support::Unsupported("no first boot condition"),
+        Feature::FailureMail => Support::Unsupported("no failure hooks"),
+        Feature::BusName => Support::Unsupported("no D-Bus activation"),
+        Feature::Listen => Support::Unsupported("no socket activation"),
+        Feature::FdStore => Support::Unsupported("no file descriptor store"),
+        Feature::Supervision => Support::Unsupported("OpenRC options, runsv supervises itself"),
+        Feature::KeepAlivePath => Support::Unsupported("launchd option"),
+        Feature::Tty => Support::Unsupported("runsv doesn't attach consoles"),
+        Feature::Kiosk | Feature::GraphicalSession => Support::Unsupported("no sessions"),
+        Feature::Forking => Support::Unsupported("runsv supervises the foreground process"),
+        Feature::Notify => Support::Emulated("no readiness protocol, started like simple"),
+        Feature::BootHooks => Support::Unsupported("systemd oneshot units"),
+        Feature::PowerHooks => Support::Unsupported("systemd sleep.target"),
+        Feature::UnitOptions => Support::Unsupported("systemd directives, e.g. from presets"),
+    }
+}
+
+/// Whether runit supervises this host: runsvdir is running, or it's set up
+/// in /etc/runit.
+pub fn detect() -> bool {
+    if Path::new("/etc/runit").is_dir() {
+        return true;
+    }
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+    processes.flatten().any(|process| {
+        fs::read_to_string(process.path().join("comm"))
+            .is_ok_and(|comm| comm.trim_end() == "runsvdir")
+    })
+}
+
+pub struct Runit {
+    pub service: ServiceConfig,
+}
+
+impl Runit {
+    fn sv_path(&self) -> PathBuf {
+        Path::new(SV_DIR).join(&self.service.name)
+    }
+
+    /// Where runsvdir looks for services on this host.
+    fn service_dir() -> &'static Path {
+        SERVICE_DIRS
+            .iter()
+            .map(Path::new)
+            .find(|dir| dir.is_dir())
+            .unwrap_or(Path::new(SERVICE_DIRS[0]))
+    }
+
+    fn link_path(&self) -> PathBuf {
+        Self::service_dir().join(&self.service.name)
+    }
+
+    /// The run script, exec'ing the command so runsv supervises it directly.
+    pub fn to_run_script(&self) -> Result<String> {
+        if self.service.level != ServiceLevel::System {
+            return Err(anyhow!("mkservice writes runit system services only.").into());
+        }
+        if self.service.service_type == ServiceType::Oneshot {
+            return Err(
+                anyhow!("runit restarts whatever exits, it has no oneshot services.").into(),
+            );
+        }
+        let mut script = String::from("#!/bin/sh\n");
+        if let Some(header) = &self.service.header {
+            for line in header.lines() {
+                let _ = writeln!(script, "{}", format!("# {}", line).trim_end());
+            }
+        }
+        // Into the log service, with the output.
+        script += "exec 2>&1\n";
+        for dependency in &self.service.depends_on {
+            // runsv runs the script again a second later.
+            let _ = writeln!(
+                script,
+                "sv check {} >/dev/null || exit 1",
+                shell_quote(dependency)
+            );
+        }
+        for (key, value) in &self.service.env {
+            let _ = writeln!(script, "export {}={}", key, shell_quote(value));
+        }
+        if !self.service.env_files.is_empty() {
+            script += "set -a\n";
+            for file in &self.service.env_files {
+                match file.strip_prefix('-') {
+                    // Optional, as systemd's EnvironmentFile=-.
+                    Some(file) => {
+                        let file = shell_quote(file);
+                        let _ = writeln!(script, "[ ! -f {} ] || . {}", file, file);
+                    }
+                    None => {
+                        let _ = writeln!(script, ". {} || exit 1", shell_quote(file));
+                    }
+                }
+            }
+            script += "set +a\n";
+        }
+        if let Some(dir) = &self.service.working_directory {
+            let _ = writeln!(script, "cd {} || exit 1", shell_quote(dir));
+        }
+        if self.service.command.is_empty() {
+            return Err(anyhow!("{:?} has no command.", self.service.name).into());
+        }
+        let mut command: Vec<String> = Vec::new();
+        if let Some(user) = &self.service.user {
+            let account = match &self.service.group {
+                Some(group) => format!("{}:{}", user, group),
+                None => user.clone(),
+            };
+            command.extend(["chpst".into(), "-u".into(), shell_quote(&account)]);
+        }
+        command.extend(self.service.command.iter().map(|arg| shell_quote(arg)));
+        let _ = writeln!(script, "exec {}", command.join(" "));
+        Ok(script)
+    }
+
+    /// The log service's run script, keeping the output with svlogd.
+    pub fn to_log_script(&self) -> String {
+        let dir = shell_quote(
+            &Path::new(LOG_DIR)
+                .join(&self.service.name)
+                .to_string_lossy(),
+        );
+        format!("#!/bin/sh\nmkdir -p {}\nexec svlogd -tt {}\n", dir, dir)
+    }
+
+    /// The finish script runsv runs after the command exits, with its exit
+    /// code (-1 for a signal), for a restart policy other than runit's own
+    /// of always restarting. None without one.
+    pub fn to_finish_script(&self) -> Option<String> {
+        if self.service.restart.is_none() && self.service.restart_sec.is_none() {
+            return None;
+        }
+        let mut script = String::from("#!/bin/sh\n");
+        // Wanting the service down keeps runsv from starting it again.
+        match self.service.restart {
+            Some(RestartPolicy::No) => script += "exec sv down .\n",
+            Some(RestartPolicy::OnFailure) => script += "[ \"$1\" != 0 ] || exec sv down .\n",
+            Some(RestartPolicy::OnAbnormal) => script += "[ \"$1\" = -1 ] || exec sv down .\n",
+            Some(RestartPolicy::Always) | None => {}
+        }
+        if let Some(secs) = self.service.restart_sec {
+            let _ = writeln!(script, "sleep {}", secs);
+        }
+        Some(script)
+    }
+
+    fn sv(&self, action: &str, name: &str) -> Result<()> {
+        let cmd = format!("sv {} {}", action, name);
+        let output = timed(&cmd, || Command::new("sv").args([action, name]).output())
+            .map_err(|e| MkserviceError::spawn("sv", e))?;
+        if !output.status.success() {
+            // sv reports failures on stdout.
+            return Err(MkserviceError::CommandFailed {
+                cmd,
+                stderr: String::from_utf8_lossy(&output.stdout).into_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The path `sv` takes for a service, so it doesn't depend on SVDIR.
+    fn sv_target(&self, name: &str) -> String {
+        Self::service_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// The state and uptime `sv status` reports for the service itself, in the
+/// "active (sub-state)" form systemd's states take, e.g. from
+/// "run: /var/service/web: (pid 123) 45s; run: log: (pid 120) 45s".
+fn parse_status(output: &str) -> (String, Option<u64>) {
+    let first = output.split(';').next().unwrap_or_default().trim();
+    let (state, rest) = first.split_once(':').unwrap_or((first, ""));
+    let active = match state {
+        "run" => "active",
+        "finish" => "deactivating",
+        "down" => "inactive",
+        "fail" | "warning" => "failed",
+        _ => "unknown",
+    };
+    let uptime = match state {
+        "run" => rest
+            .split_whitespace()
+            .find_map(|word| word.strip_suffix('s')?.parse().ok()),
+        _ => None,
+    };
+    (format!("{} ({})", active, state), uptime)
+}
+
+fn write_script(path: &Path, script: &str) -> Result<()> {
+    log::info!("Writing run script to {:?}", path);
+    fs::write(path, script).map_err(|e| MkserviceError::io(path, e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .map_err(|e| MkserviceError::io(path, e))
+}
+
+impl ServiceOperator for Runit {
+    fn install(&self) -> Result<()> {
+        let sv_path = self.sv_path();
+        let log_dir = sv_path.join("log");
+        fs::create_dir_all(&log_dir).map_err(|e| MkserviceError::io(&log_dir, e))?;
+        write_script(&sv_path.join("run"), &self.to_run_script()?)?;
+        write_script(&log_dir.join("run"), &self.to_log_script())?;
+        let finish = sv_path.join("finish");
+        match self.to_finish_script() {
+            Some(script) => write_script(&finish, &script)?,
+            None if finish.exists() => {
+                fs::remove_file(&finish).map_err(|e| MkserviceError::io(&finish, e))?
+            }
+            None => {}
+        }
+
+        // runsvdir starts it within a few seconds of the link appearing.
+        let link = self.link_path();
+        if link.symlink_metadata().is_err() {
+            log::info!("Enabling service...");
+            std::os::unix::fs::symlink(&sv_path, &link)
+                .map_err(|e| MkserviceError::io(&link, e))?;
+        }
+        Ok(())
+    }
+
+    fn start(&self) -> Result<()> {
+        self.sv("up", &self.sv_target(&self.service.name))
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.sv("down", &self.sv_target(&self.service.name))
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        let link = self.link_path();
+        if link.symlink_metadata().is_ok() {
+            self.stop()?;
+            // runsvdir stops supervising it within a few seconds.
+            log::info!("Disabling service...");
+            remove_files([link])?;
+        }
+        let sv_path = self.sv_path();
+        match fs::remove_dir_all(&sv_path) {
+            Ok(()) => log::info!("Removed {:?}", sv_path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(MkserviceError::io(&sv_path, e)),
+        }
+        Ok(())
+    }
+
+    fn restart_with(&self, units: &[String]) -> Result<()> {
+        self.sv("restart", &self.sv_target(&self.service.name))?;
+        for unit in units {
+            self.sv("restart", &self.sv_target(unit))?;
+        }
+        Ok(())
+    }
+
+    fn log_command(&self, lines: usize, follow: bool) -> Option<Command> {
+        let mut command = Command::new("tail");
+        command.args(["-n", &lines.to_string()]);
+        if follow {
+            // svlogd rotates current, so follow the name.
+            command.arg("-F");
+        }
+        command.arg(Path::new(LOG_DIR).join(&self.service.name).join("current"));
+        Some(command)
+    }
+
+    fn dependents(&self) -> Result<Vec<String>> {
+        // Dependencies are only checked when a service starts.
+        Ok(vec![])
+    }
+
+    fn existing_units(&self) -> Result<Vec<PathBuf>> {
+        let path = self.sv_path().join("run");
+        Ok(match path.exists() {
+            true => vec![path],
+            false => vec![],
+        })
+    }
+
+    fn unit_files(&self) -> Result<Vec<(PathBuf, String)>> {
+        let sv_path = self.sv_path();
+        let mut files = vec![
+            (sv_path.join("run"), self.to_run_script()?),
+            (sv_path.join("log").join("run"), self.to_log_script()),
+        ];
+        files.extend(
+            self.to_finish_script()
+                .map(|script| (sv_path.join("finish"), script)),
+        );
+        Ok(files)
+    }
+
+    fn status(&self) -> Result<ServiceStatus> {
+        // Exits non-zero for a service runsv isn't supervising, which is
+        // still a status.
+        let output = Command::new("sv")
+            .args(["status", &self.sv_target(&self.service.name)])
+            .output()
+            .map_err(|e| MkserviceError::spawn("sv", e))?;
+        let (state, uptime) = parse_status(&String::from_utf8_lossy(&output.stdout));
+        Ok(ServiceStatus {
+            state,
+            enabled: match self.link_path().symlink_metadata() {
+                Ok(_) => "enabled".into(),
+                Err(_) => "disabled".into(),
+            },
+            uptime,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_script_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: vec!["/usr/bin/web".into(), "it's $HOME".into()],
+            env: [("PORT".to_string(), "8080".to_string())].into(),
+            env_files: vec!["/etc/web.env".into()],
+            working_directory: Some("/srv/web".into()),
+            user: Some("www".into()),
+            depends_on: vec!["db".into()],
+            header: Some("Managed by mkservice".into()),
+            ..Default::default()
+        };
+        let runit = Runit { service };
+        assert_eq!(
+            runit.to_run_script().unwrap(),
+            "#!/bin/sh\n\
+            # Managed by mkservice\n\
+            exec 2>&1\n\
+            sv check db >/dev/null || exit 1\n\
+            export PORT=8080\n\
+            set -a\n\
+            . /etc/web.env || exit 1\n\
+            set +a\n\
+            cd /srv/web || exit 1\n\
+            exec chpst -u www /usr/bin/web 'it'\\''s $HOME'\n"
+        );
+        assert_eq!(
+            runit.to_log_script(),
+            "#!/bin/sh\nmkdir -p /var/log/web\nexec svlogd -tt /var/log/web\n"
+        );
+        let tail = runit.log_command(20, true).unwrap();
+        assert_eq!(
+            tail.get_args().collect::<Vec<_>>(),
+            ["-n", "20", "-F", "/var/log/web/current"]
+        );
+    }
+
+    #[test]
+    fn test_finish_script_render() {
+        let runit = |restart, restart_sec| Runit {
+            service: ServiceConfig {
+                name: "web".into(),
+                command: vec!["/usr/bin/web".into()],
+                restart,
+                restart_sec,
+                ..Default::default()
+            },
+        };
+        assert_eq!(runit(None, None).to_finish_script(), None);
+        assert_eq!(
+            runit(Some(RestartPolicy::OnFailure), Some(5))
+                .to_finish_script()
+                .unwrap(),
+            "#!/bin/sh\n[ \"$1\" != 0 ] || exec sv down .\nsleep 5\n"
+        );
+        assert_eq!(
+            runit(Some(RestartPolicy::No), None)
+                .to_finish_script()
+                .unwrap(),
+            "#!/bin/sh\nexec sv down .\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            parse_status("run: /var/service/web: (pid 123) 45s; run: log: (pid 120) 45s\n"),
+            ("active (run)".into(), Some(45))
+        );
+        assert_eq!(
+            parse_status("down: /var/service/web: 3s, normally up; run: log: (pid 120) 9s\n"),
+            ("inactive (down)".into(), None)
+        );
+        assert_eq!(
+            parse_status("fail: /var/service/web: runsv not running\n"),
+            ("failed (fail)".into(), None)
+        );
+    }
+}
@@ -1,16 +1,28 @@
-use crate::config::{ServiceConfig, ServiceLevel};
-use crate::ServiceOperator;
-use anyhow::Result;
+use crate::command::{self, find_executable, timed};
+use crate::config::{
+    PassthroughEnv, PowerHook, ServiceConfig, ServiceLevel, ServiceType, StandardInput,
+};
+use crate::error::{MkserviceError, Result};
+use crate::metadata;
+use crate::provider::capabilities::{Feature, Support};
+use crate::provider::{remove_files, ServiceStatus};
+use crate::socket::Listen;
+use crate::vault;
+use crate::{str_partition, ServiceOperator};
+use anyhow::anyhow;
 use maplit::{btreemap, convert_args};
 use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 use std::collections::BTreeMap;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
 use std::fs::File;
 use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
+use std::thread;
 
 #[derive(Debug)]
 enum SystemdValue {
@@ -24,12 +36,42 @@ type SystemdSection = BTreeMap<String, SystemdValue>;
 struct SystemdServiceUnit {
     #[serde(serialize_with = "serialize_systemd_section", rename = "Unit")]
     unit: SystemdSection,
-    #[serde(serialize_with = "serialize_systemd_section", rename = "Install")]
+    #[serde(
+        serialize_with = "serialize_systemd_section",
+        rename = "Install",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
     install: SystemdSection,
     #[serde(serialize_with = "serialize_systemd_section", rename = "Service")]
     service: SystemdSection,
 }
 
+#[derive(Debug, Default, Serialize)]
+struct DbusServiceFile {
+    #[serde(serialize_with = "serialize_systemd_section", rename = "D-BUS Service")]
+    service: SystemdSection,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct SystemdTimerUnit {
+    #[serde(serialize_with = "serialize_systemd_section", rename = "Unit")]
+    unit: SystemdSection,
+    #[serde(serialize_with = "serialize_systemd_section", rename = "Timer")]
+    timer: SystemdSection,
+    #[serde(serialize_with = "serialize_systemd_section", rename = "Install")]
+    install: SystemdSection,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct SystemdSocketUnit {
+    #[serde(serialize_with = "serialize_systemd_section", rename = "Unit")]
+    unit: SystemdSection,
+    #[serde(serialize_with = "serialize_systemd_section", rename = "Socket")]
+    socket: SystemdSection,
+    #[serde(serialize_with = "serialize_systemd_section", rename = "Install")]
+    install: SystemdSection,
+}
+
 fn serialize_systemd_section<S>(section: &SystemdSection, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -68,40 +110,497 @@ impl From<&str> for SystemdValue {
     }
 }
 
+/// Adds `values` to the list under `key`, after any already there.
+fn add_values(section: &mut SystemdSection, key: &str, values: Vec<String>) {
+    let merged = match section.remove(key) {
+        Some(SystemdValue::List(mut existing)) => {
+            existing.extend(values);
+            existing
+        }
+        Some(SystemdValue::Str(existing)) => std::iter::once(existing).chain(values).collect(),
+        None => values,
+    };
+    section.insert(key.into(), merged.into());
+}
+
 /// serde_ini serializes with CRLF by default, this just enforces LF.
 fn serialize_to_string<T: Serialize>(t: &T) -> Result<String> {
     let mut buf: Vec<u8> = Vec::with_capacity(128);
     let writer = serde_ini::write::Writer::new(&mut buf, serde_ini::write::LineEnding::Linefeed);
     let mut ser = serde_ini::ser::Serializer::new(writer);
-    t.serialize(&mut ser).map(|_| &buf)?;
+    t.serialize(&mut ser)
+        .map_err(|e| MkserviceError::Other(e.into()))?;
     Ok(unsafe { String::from_utf8_unchecked(buf) })
 }
 
-fn systemd_escape(strings: Vec<String>, args: Vec<&str>) -> Result<String> {
-    let mut output = Command::new("systemd-escape")
-        .args(args)
-        .arg("--")
-        .args(strings)
-        .output()?;
-    // Trim the trailing newline.
-    output.stdout.truncate(output.stdout.len() - 1);
-    let escaped = String::from_utf8(output.stdout)?;
-    Ok(escaped)
+/// The user manager's target for the graphical session, which only starts
+/// once one is up.
+pub const GRAPHICAL_SESSION: &str = "graphical-session.target";
+
+/// first-boot-complete.target, which first boot services order before.
+const FIRST_BOOT_MIN_VERSION: u32 = 247;
+
+/// SetCredentialEncrypted=, which --env-secret uses.
+const ENCRYPTED_CREDENTIALS_MIN_VERSION: u32 = 250;
+
+/// How systemd `version` handles `feature`: natively, but for failure mail
+/// and secrets.
+/// An unknown version is assumed recent.
+pub fn support(feature: Feature, version: Option<u32>) -> Support {
+    match feature {
+        Feature::FailureMail => Support::Emulated("OnFailure= unit running mail(1)"),
+        Feature::Supervision => Support::Unsupported("OpenRC options, systemd supervises itself"),
+        Feature::KeepAlivePath => Support::Unsupported("launchd option, see path units"),
+        Feature::EnvSecrets if version.is_some_and(|v| v < ENCRYPTED_CREDENTIALS_MIN_VERSION) => {
+            Support::Unsupported("needs systemd 250 or newer")
+        }
+        Feature::EnvSecrets => Support::Emulated("encrypted credentials, exported by sh"),
+        Feature::VaultSecrets | Feature::CloudMetadata => {
+            Support::Emulated("fetched by mkservice in ExecStartPre=")
+        }
+        Feature::FirstBootOnly if version.is_some_and(|v| v < FIRST_BOOT_MIN_VERSION) => {
+            Support::Unsupported("needs systemd 247 or newer")
+        }
+        _ => Support::Supported,
+    }
 }
 
-fn systemd_quote(strings: Vec<String>) -> String {
-    strings
-        .into_iter()
-        // Naive...
-        .map(|s| format!("\"{}\"", s.replace('"', "\\\"")))
+/// Escapes a string for use in a unit name, the same way `systemd-escape`
+/// does: "/" becomes "-", and anything outside `[a-zA-Z0-9:_.]` (or a
+/// leading ".") becomes a `\xNN` escape of each byte.
+fn systemd_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for (i, byte) in s.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Escapes a path for a unit name the same way `systemd-escape --path`
+/// does, ignoring leading, trailing and doubled slashes.
+fn systemd_escape_path(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    match parts.is_empty() {
+        true => "-".into(),
+        false => systemd_escape(&parts.join("/")),
+    }
+}
+
+/// The device unit of a network interface by name, or of a device node by
+/// path.
+fn device_unit(device: &str) -> String {
+    let path = match device.starts_with('/') {
+        true => device.to_string(),
+        false => format!("/sys/subsystem/net/devices/{}", device),
+    };
+    format!("{}.device", systemd_escape_path(&path))
+}
+
+/// Makes the service's command its stop command, run as the unit stops
+/// rather than as it starts.
+fn run_on_stop(service: &mut SystemdSection) {
+    let exec_stop = service
+        .insert("ExecStart".into(), "/bin/true".into())
+        .expect("ExecStart is set");
+    service.insert("ExecStop".into(), exec_stop);
+}
+
+/// A word of a command line, taken either literally or with systemd's
+/// specifiers in it, e.g. "%h/bin/app" or "100%%".
+enum ExecWord {
+    Literal(String),
+    Specified(String),
+}
+
+fn systemd_quote(words: Vec<ExecWord>) -> String {
+    words
+        .iter()
+        .map(quote_exec_word)
         .collect::<Vec<String>>()
         .join(" ")
 }
 
+/// Quotes one word of a command line so systemd passes it through as is:
+/// C escapes for quotes, backslashes and control characters, and doubled
+/// `$` so variables aren't expanded, and `%` too unless the word has
+/// specifiers.
+fn quote_exec_word(word: &ExecWord) -> String {
+    let (word, specified) = match word {
+        ExecWord::Literal(word) => (word, false),
+        ExecWord::Specified(word) => (word, true),
+    };
+    // A lone ";" separates commands, even quoted.
+    if word == ";" {
+        return "\\;".into();
+    }
+    let mut quoted = String::with_capacity(word.len() + 2);
+    quoted.push('"');
+    for c in word.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '%' if !specified => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            c if c.is_control() && c.is_ascii() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits an `ExecStart=` command line into words, reversing
+/// `quote_exec_word`. Lines using specifiers, variables or several commands
+/// are an error, since a command mkservice writes can't.
+#[cfg(feature = "import")]
+pub fn split_exec(line: &str) -> Result<Vec<String>> {
+    if line.split_whitespace().any(|word| word == ";") {
+        return Err(anyhow!("{:?} runs several commands, which isn't supported.", line).into());
+    }
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            c if quote.is_none() && c.is_whitespace() => {
+                words.extend(word.take());
+                continue;
+            }
+            '"' | '\'' if quote.is_none() => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+                continue;
+            }
+            c if Some(c) == quote => {
+                quote = None;
+                continue;
+            }
+            '\\' => unescape_c(&mut chars).ok_or_else(|| anyhow!("Bad escape in {:?}", line))?,
+            '%' | '$' => {
+                if chars.next() != Some(c) {
+                    let what = if c == '%' { "specifiers" } else { "variables" };
+                    return Err(anyhow!("{:?} uses {}, which aren't supported.", line, what).into());
+                }
+                c
+            }
+            c => c,
+        };
+        word.get_or_insert_with(String::new).push(c);
+    }
+    if quote.is_some() {
+        return Err(anyhow!("Unterminated quote in {:?}", line).into());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// The character a C escape stands for, after its backslash.
+#[cfg(feature = "import")]
+fn unescape_c(chars: &mut std::str::Chars) -> Option<char> {
+    let c = chars.next()?;
+    let (radix, len) = match c {
+        'a' => return Some('\x07'),
+        'b' => return Some('\x08'),
+        'f' => return Some('\x0c'),
+        'n' => return Some('\n'),
+        'r' => return Some('\r'),
+        's' => return Some(' '),
+        't' => return Some('\t'),
+        'v' => return Some('\x0b'),
+        '\\' | '"' | '\'' | ';' => return Some(c),
+        'x' => (16, 2),
+        'u' => (16, 4),
+        'U' => (16, 8),
+        '0'..='7' => (8, 3),
+        _ => return None,
+    };
+    let mut digits = String::new();
+    if radix == 8 {
+        digits.push(c);
+    }
+    digits.extend(chars.by_ref().take(len - digits.len()));
+    if digits.len() != len {
+        return None;
+    }
+    u32::from_str_radix(&digits, radix)
+        .ok()
+        .and_then(char::from_u32)
+}
+
+/// Reverses `systemd_escape`.
+pub fn systemd_unescape(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let escaped = after
+            .strip_prefix(b"x")
+            .filter(|_| byte == b'\\')
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (byte, escaped) {
+            (_, Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &after[3..];
+                continue;
+            }
+            (b'-', None) => bytes.push(b'/'),
+            (byte, None) => bytes.push(byte),
+        }
+        rest = after;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Marks the service as using specifiers, escaping each `%` already in its
+/// command, working directory, environment and env files as `%%` so they
+/// stay literal.
+pub fn allow_specifiers(service: &mut ServiceConfig) {
+    if service.specifiers {
+        return;
+    }
+    service.specifiers = true;
+    let escape = |value: &mut String| *value = value.replace('%', "%%");
+    let commands = std::iter::once(&mut service.command).chain(&mut service.steps);
+    commands.flatten().for_each(escape);
+    service.working_directory.iter_mut().for_each(escape);
+    service.env_files.iter_mut().for_each(escape);
+    service.env.values_mut().for_each(escape);
+}
+
+/// Rewrites paths under the user's runtime and home directories with the %t
+/// and %h specifiers, so a user unit holds up on machines where those are
+/// elsewhere. Only values that are paths themselves are rewritten, not e.g.
+/// "--config=/home/me/app.toml".
+pub fn use_specifiers(service: &mut ServiceConfig, home: &Path, runtime_dir: Option<&Path>) {
+    allow_specifiers(service);
+    let dirs: Vec<(String, &str)> = runtime_dir
+        .map(|dir| (dir, "%t"))
+        .into_iter()
+        .chain([(home, "%h")])
+        .map(|(dir, specifier)| {
+            let dir = dir
+                .to_string_lossy()
+                .trim_end_matches('/')
+                .replace('%', "%%");
+            (dir, specifier)
+        })
+        .filter(|(dir, _)| !dir.is_empty())
+        .collect();
+    let rewrite = |value: &mut String| {
+        for (dir, specifier) in &dirs {
+            let rest = value
+                .strip_prefix(dir.as_str())
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'));
+            if let Some(rest) = rest {
+                *value = format!("{}{}", specifier, rest);
+                return;
+            }
+        }
+    };
+    let commands = std::iter::once(&mut service.command).chain(&mut service.steps);
+    commands.flatten().for_each(rewrite);
+    service.working_directory.iter_mut().for_each(rewrite);
+    // Past the "-" that makes an env file optional.
+    for file in service.env_files.iter_mut() {
+        let (optional, mut path) = match file.strip_prefix('-') {
+            Some(path) => ("-", path.to_string()),
+            None => ("", file.clone()),
+        };
+        rewrite(&mut path);
+        *file = format!("{}{}", optional, path);
+    }
+    service.env.values_mut().for_each(rewrite);
+}
+
+/// The running systemd's version, from `systemctl --version`.
+pub fn systemd_version() -> Option<u32> {
+    static VERSION: OnceLock<Option<u32>> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let output = timed("systemctl --version", || {
+            Command::new("systemctl").arg("--version").output()
+        })
+        .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    })
+}
+
+/// Variables a desktop or SSH session may push into the service manager,
+/// which daemons have no business inheriting.
+const SESSION_VARS: [&str; 15] = [
+    "DBUS_SESSION_BUS_ADDRESS",
+    "DESKTOP_SESSION",
+    "DISPLAY",
+    "GPG_AGENT_INFO",
+    "SSH_AGENT_PID",
+    "SSH_AUTH_SOCK",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_CURRENT_DESKTOP",
+    "XDG_SEAT",
+    "XDG_SESSION_CLASS",
+    "XDG_SESSION_DESKTOP",
+    "XDG_SESSION_ID",
+    "XDG_SESSION_TYPE",
+    "XDG_VTNR",
+];
+
+const LOCALE_VARS: [&str; 14] = [
+    "LANG",
+    "LANGUAGE",
+    "LC_ADDRESS",
+    "LC_ALL",
+    "LC_COLLATE",
+    "LC_CTYPE",
+    "LC_IDENTIFICATION",
+    "LC_MEASUREMENT",
+    "LC_MESSAGES",
+    "LC_MONETARY",
+    "LC_NAME",
+    "LC_NUMERIC",
+    "LC_PAPER",
+    "LC_TIME",
+];
+
+/// The PassEnvironment= and UnsetEnvironment= variables for `passthrough`.
+/// Variables the service is explicitly given or passed are kept.
+pub fn passthrough_directives(
+    passthrough: &PassthroughEnv,
+    env: &BTreeMap<String, String>,
+) -> (Vec<String>, Vec<String>) {
+    let (pass, scrub): (&[String], Vec<&str>) = match passthrough {
+        PassthroughEnv::None => (&[], SESSION_VARS.into_iter().chain(LOCALE_VARS).collect()),
+        PassthroughEnv::Minimal => (&[], SESSION_VARS.to_vec()),
+        PassthroughEnv::List(vars) => (vars, SESSION_VARS.to_vec()),
+    };
+    let unset = scrub
+        .into_iter()
+        .filter(|var| !env.contains_key(*var) && !pass.iter().any(|p| p == var))
+        .map(String::from)
+        .collect();
+    (pass.to_vec(), unset)
+}
+
+/// Where systemd looks for system units, highest priority first, for when
+/// `systemd-analyze unit-paths` can't tell.
+const SYSTEM_UNIT_PATH: [&str; 11] = [
+    "/etc/systemd/system.control",
+    "/run/systemd/system.control",
+    "/run/systemd/transient",
+    "/etc/systemd/system",
+    "/run/systemd/system",
+    "/run/systemd/generator",
+    "/usr/local/lib/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+    "/run/systemd/generator.late",
+    "/run/systemd/generator.early",
+];
+
+/// The same for user units, relative to the user's home where not absolute.
+const USER_UNIT_PATH: [&str; 6] = [
+    ".config/systemd/user",
+    "/etc/systemd/user",
+    "/run/systemd/user",
+    ".local/share/systemd/user",
+    "/usr/local/lib/systemd/user",
+    "/usr/lib/systemd/user",
+];
+
+/// The directories systemd loads `level` units from, highest priority first.
+/// Directories that are links to others, like /lib on merged-/usr systems,
+/// are left out.
+pub fn unit_search_path(level: &ServiceLevel) -> Vec<PathBuf> {
+    let mut command = Command::new("systemd-analyze");
+    if *level == ServiceLevel::User {
+        command.arg("--user");
+    }
+    let dirs: Vec<PathBuf> = match command.arg("unit-paths").output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            .collect(),
+        _ => match level {
+            ServiceLevel::System => SYSTEM_UNIT_PATH.iter().map(PathBuf::from).collect(),
+            ServiceLevel::User => {
+                let home = PathBuf::from(env::var_os("HOME").unwrap_or_default());
+                USER_UNIT_PATH.iter().map(|dir| home.join(dir)).collect()
+            }
+        },
+    };
+    let mut seen = Vec::new();
+    dirs.into_iter()
+        .filter(|dir| {
+            let real = dir.canonicalize().unwrap_or_else(|_| dir.clone());
+            let new = !seen.contains(&real);
+            seen.push(real);
+            new
+        })
+        .collect()
+}
+
+/// The unit files named `names` in `dirs`, including masks and links.
+fn find_units(dirs: &[PathBuf], names: &[String]) -> Vec<PathBuf> {
+    dirs.iter()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .filter(|path| path.symlink_metadata().is_ok())
+        .collect()
+}
+
+const STATUS_PROPERTIES: [&str; 11] = [
+    "ActiveState",
+    "SubState",
+    "ActiveEnterTimestampMonotonic",
+    "UnitFileState",
+    "NRestarts",
+    "Result",
+    "ExecMainStatus",
+    "ExecMainExitTimestamp",
+    "MemoryMax",
+    "TasksMax",
+    "CPUQuotaPerSecUSec",
+];
+
 pub struct Systemd {
     pub service: ServiceConfig,
 }
 
+/// Runs `command`, failing with what it printed if it exits non-zero, and
+/// otherwise logging that, e.g. the symlinks `systemctl enable` created.
+fn run_checked(mut command: Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let cmd = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let output =
+        timed(&cmd, || command.output()).map_err(|e| MkserviceError::spawn(&program, e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed { cmd, stderr });
+    }
+    for line in stderr.lines() {
+        log::info!("{}", line);
+    }
+    Ok(())
+}
+
 impl Systemd {
     fn systemctl_command(&self) -> Command {
         let mut command = Command::new("systemctl");
@@ -111,98 +610,1042 @@ impl Systemd {
         command
     }
 
+    /// `verb` on the activation units, then `units`.
+    fn systemctl_args(&self, verb: &str, units: &[String]) -> Vec<String> {
+        let mut args = vec![verb.to_string()];
+        args.extend(self.activation_units());
+        args.extend(units.iter().cloned());
+        args
+    }
+
+    /// Runs systemctl with `args`, failing with what it printed if it exits
+    /// non-zero.
+    fn systemctl(&self, args: &[String]) -> Result<()> {
+        let mut command = self.systemctl_command();
+        command.args(args);
+        run_checked(command)
+    }
+
     pub fn to_systemd_unit(&self) -> Result<String> {
-        let service_unit = SystemdServiceUnit {
+        let exec_start = std::iter::once(&self.service.command)
+            .chain(&self.service.steps)
+            .map(|command| systemd_quote(self.with_secrets(self.exec_words(command))))
+            .collect::<Vec<String>>();
+        let mut service_unit = SystemdServiceUnit {
             unit: convert_args!(btreemap!(
                 "Description" => self.service.name.clone(),
             )),
             service: convert_args!(btreemap!(
-                "Type" => "simple",
-                "ExecStart" => systemd_quote(self.service.command.clone()),
+                "Type" => self.service.service_type.name(),
+                "ExecStart" => exec_start,
                 "Environment" => self.service.env
                     .iter()
-                    .map(|(k, v)| format!("{}={}", k, v))
+                    .map(|(k, v)| self.environment_assignment(k, v))
                     .collect::<Vec<String>>(),
                 "Restart" => "on-failure",
             )),
             install: convert_args!(btreemap!(
-                "WantedBy" => "multi-user.target",
+                "WantedBy" => self.install_target(),
+            )),
+        };
+        if self.service.service_type == ServiceType::Oneshot {
+            // Restart= other than "no" is rejected for oneshot on older systemd.
+            service_unit.service.remove("Restart");
+        }
+        if let (ServiceType::Forking, Some(pidfile)) =
+            (self.service.service_type, &self.service.pidfile)
+        {
+            service_unit
+                .service
+                .insert("PIDFile".into(), pidfile.as_str().into());
+        }
+        if self.service.remain_after_exit {
+            service_unit
+                .service
+                .insert("RemainAfterExit".into(), "yes".into());
+        }
+        for (section, options) in &self.service.unit_options {
+            let section = match section.as_str() {
+                "Unit" => &mut service_unit.unit,
+                "Service" => &mut service_unit.service,
+                "Install" => &mut service_unit.install,
+                _ => return Err(anyhow!("Unknown unit section {:?}", section).into()),
+            };
+            for (key, values) in options {
+                let mut values = values.clone();
+                // Environment= from flags comes after, so it wins.
+                if let (true, Some(SystemdValue::List(env))) =
+                    (key == "Environment", section.get(key))
+                {
+                    values.extend(env.iter().cloned());
+                }
+                section.insert(key.clone(), values.into());
+            }
+        }
+        // Flags win over presets.
+        if let Some(restart) = self.service.restart {
+            service_unit
+                .service
+                .insert("Restart".into(), restart.name().into());
+        }
+        if let Some(secs) = self.service.restart_sec {
+            service_unit
+                .service
+                .insert("RestartSec".into(), secs.to_string().into());
+        }
+        for (key, limit) in [
+            ("MemoryMax", &self.service.memory_max),
+            ("CPUQuota", &self.service.cpu_quota),
+            ("TasksMax", &self.service.tasks_max),
+        ] {
+            if let Some(limit) = limit {
+                service_unit
+                    .service
+                    .insert(key.into(), limit.as_str().into());
+            }
+        }
+        if let Some(passthrough) = &self.service.passthrough_env {
+            let (pass, unset) = passthrough_directives(passthrough, &self.service.env);
+            if !pass.is_empty() {
+                service_unit
+                    .service
+                    .insert("PassEnvironment".into(), pass.join(" ").into());
+            }
+            if !unset.is_empty() {
+                service_unit
+                    .service
+                    .insert("UnsetEnvironment".into(), unset.join(" ").into());
+            }
+        }
+        if let Some(user) = &self.service.user {
+            service_unit
+                .service
+                .insert("User".into(), user.as_str().into());
+        }
+        if let Some(group) = &self.service.group {
+            service_unit
+                .service
+                .insert("Group".into(), group.as_str().into());
+        }
+        if let Some(max) = self.service.fd_store_max {
+            service_unit
+                .service
+                .insert("FileDescriptorStoreMax".into(), max.to_string().into());
+        }
+        if let Some(tty) = &self.service.tty {
+            service_unit
+                .service
+                .insert("TTYPath".into(), tty.as_str().into());
+        }
+        if let Some(input) = self.service.standard_input {
+            let input = match input {
+                StandardInput::Null => "null",
+                StandardInput::Tty => "tty",
+                StandardInput::TtyForce => "tty-force",
+                StandardInput::TtyFail => "tty-fail",
+            };
+            service_unit
+                .service
+                .insert("StandardInput".into(), input.into());
+        }
+        if self.service.standard_input.is_some_and(|i| i.is_tty()) {
+            // Leave the console clean for whoever gets it next.
+            service_unit.service.insert("TTYReset".into(), "yes".into());
+            service_unit
+                .service
+                .insert("TTYVHangup".into(), "yes".into());
+        }
+        if let Some(hook) = self.service.power_hook {
+            // Started on the way into sleep, and stopped as no longer needed
+            // once sleep.target goes on the way out, so a resume hook is the
+            // stop command.
+            add_values(
+                &mut service_unit.unit,
+                "Before",
+                vec!["sleep.target".into()],
+            );
+            service_unit
+                .unit
+                .insert("StopWhenUnneeded".into(), "yes".into());
+            service_unit
+                .service
+                .insert("RemainAfterExit".into(), "yes".into());
+            if hook == PowerHook::Resume {
+                run_on_stop(&mut service_unit.service);
+            }
+        }
+        if self.service.at_shutdown || self.service.at_boot_once {
+            // Active from boot until shutdown. The default dependencies
+            // order the stop, and so a shutdown hook, before shutdown.target
+            // and after whatever the unit itself is ordered after, such as
+            // its mounts and the network, which are still up.
+            service_unit
+                .service
+                .insert("RemainAfterExit".into(), "yes".into());
+            if self.service.at_shutdown {
+                run_on_stop(&mut service_unit.service);
+            }
+        }
+        if let (true, Some(tty)) = (self.service.kiosk, &self.service.tty) {
+            let vt = tty.trim_start_matches("/dev/");
+            let getty = format!("getty@{}.service", vt);
+            service_unit
+                .unit
+                .insert("Conflicts".into(), getty.as_str().into());
+            add_values(
+                &mut service_unit.unit,
+                "After",
+                vec![getty, "systemd-user-sessions.service".into()],
+            );
+            // A PAM session gets the user a logind seat, which X and
+            // Wayland compositors need to open the console's devices.
+            service_unit
+                .service
+                .insert("PAMName".into(), "login".into());
+            service_unit
+                .service
+                .insert("UtmpIdentifier".into(), vt.into());
+            service_unit
+                .service
+                .insert("UtmpMode".into(), "user".into());
+            service_unit
+                .service
+                .insert("Restart".into(), "always".into());
+            service_unit.service.insert("RestartSec".into(), "2".into());
+        }
+        if let Some(bus_name) = &self.service.bus_name {
+            service_unit
+                .service
+                .insert("BusName".into(), bus_name.as_str().into());
+            service_unit
+                .install
+                .insert("Alias".into(), format!("dbus-{}.service", bus_name).into());
+        }
+        if self.service.graphical {
+            service_unit
+                .unit
+                .insert("PartOf".into(), GRAPHICAL_SESSION.into());
+            add_values(
+                &mut service_unit.unit,
+                "After",
+                vec![GRAPHICAL_SESSION.into()],
+            );
+        }
+        if !self.service.depends_on.is_empty() {
+            let units: Vec<String> = self
+                .service
+                .depends_on
+                .iter()
+                .map(|name| format!("{}.service", systemd_escape(name)))
+                .collect();
+            add_values(&mut service_unit.unit, "Requires", units.clone());
+            add_values(&mut service_unit.unit, "After", units);
+        }
+        for (units, key) in [
+            (&self.service.after, "After"),
+            (&self.service.before, "Before"),
+            (&self.service.requires, "Requires"),
+            (&self.service.wants, "Wants"),
+        ] {
+            if !units.is_empty() {
+                add_values(&mut service_unit.unit, key, units.clone());
+            }
+        }
+        if self.service.failure_mail.is_some() {
+            service_unit
+                .unit
+                .insert("OnFailure".into(), self.failure_mail_file_name().into());
+        }
+        let mut env_files: Vec<String> = self
+            .service
+            .env_files
+            .iter()
+            .map(|file| self.setting(file))
+            .collect();
+        // mkservice itself fetches these, as the service's user, into its
+        // runtime directory. Missing until then, hence the "-".
+        let mut fetches = Vec::new();
+        if !self.service.vault_secrets.is_empty() {
+            let mut fetch = vec!["vault-env".to_string()];
+            fetch.extend(self.service.vault_secrets.iter().cloned());
+            fetches.push((fetch, vault::ENV_FILE));
+        }
+        if !self.service.imds_env.is_empty() || !self.service.gce_metadata_env.is_empty() {
+            let mut fetch = vec!["metadata-env".to_string()];
+            for value in &self.service.imds_env {
+                fetch.extend(["--imds".into(), value.name().into()]);
+            }
+            for (key, attr) in &self.service.gce_metadata_env {
+                fetch.extend(["--gce".into(), format!("{}={}", key, attr)]);
+            }
+            fetches.push((fetch, metadata::ENV_FILE));
+        }
+        if !fetches.is_empty() {
+            let exe = command::mkservice_program()?;
+            let pre = fetches
+                .iter()
+                .map(|(fetch, _)| {
+                    let command = std::iter::once(&exe)
+                        .chain(fetch)
+                        .map(|word| ExecWord::Literal(word.clone()));
+                    systemd_quote(self.with_secrets(command.collect()))
+                })
+                .collect();
+            add_values(&mut service_unit.service, "ExecStartPre", pre);
+            service_unit
+                .service
+                .insert("RuntimeDirectory".into(), self.service.name.as_str().into());
+            service_unit
+                .service
+                .insert("RuntimeDirectoryMode".into(), "0700".into());
+            env_files.extend(
+                fetches
+                    .iter()
+                    .map(|(_, file)| format!("-%t/{}/{}", self.service.name, file)),
+            );
+        }
+        if !env_files.is_empty() {
+            service_unit
+                .service
+                .insert("EnvironmentFile".into(), env_files.into());
+        }
+        if !self.service.env_secrets.is_empty() {
+            let credentials = self
+                .service
+                .env_secrets
+                .iter()
+                .map(|(key, encrypted)| format!("{}:{}", key, encrypted))
+                .collect::<Vec<String>>();
+            service_unit
+                .service
+                .insert("SetCredentialEncrypted".into(), credentials.into());
+        }
+        if let Some(dir) = &self.service.working_directory {
+            service_unit
+                .service
+                .insert("WorkingDirectory".into(), self.setting(dir).into());
+        }
+        for (devices, requirement) in [
+            (&self.service.bind_to_devices, "BindsTo"),
+            (&self.service.after_devices, "Requires"),
+        ] {
+            let units: Vec<String> = devices.iter().map(|device| device_unit(device)).collect();
+            if !units.is_empty() {
+                add_values(&mut service_unit.unit, requirement, units.clone());
+                add_values(&mut service_unit.unit, "After", units);
+            }
+        }
+        if !self.service.requires_mounts.is_empty() {
+            service_unit.unit.insert(
+                "RequiresMountsFor".into(),
+                self.service.requires_mounts.clone().into(),
+            );
+        }
+        if self.service.first_boot_only {
+            // Ordering before first-boot-complete.target keeps a failed run
+            // from marking the first boot as done, so it's retried next boot.
+            service_unit
+                .unit
+                .insert("ConditionFirstBoot".into(), "yes".into());
+            for key in ["Wants", "Before"] {
+                add_values(
+                    &mut service_unit.unit,
+                    key,
+                    vec!["first-boot-complete.target".into()],
+                );
+            }
+        }
+        if self.service.timer.is_some() {
+            // The timer is what gets enabled, the service only runs when triggered.
+            service_unit.install.clear();
+        } else if !self.service.listen.is_empty() {
+            // Likewise the socket, and enabling the service enables it too.
+            let socket = self.socket_file_name();
+            service_unit.install.clear();
+            service_unit
+                .install
+                .insert("Also".into(), socket.as_str().into());
+            if self.service.accept {
+                // Each instance serves one connection, over stdin and stdout,
+                // and restarting one would find it closed.
+                service_unit.service.remove("Restart");
+                service_unit
+                    .service
+                    .insert("StandardInput".into(), "socket".into());
+            } else {
+                add_values(&mut service_unit.unit, "Requires", vec![socket.clone()]);
+                add_values(&mut service_unit.unit, "After", vec![socket]);
+            }
+        }
+
+        serialize_to_string(&service_unit).map(|unit| self.with_header(unit))
+    }
+
+    /// Prefixes `unit` with the configured header, as comment lines. Empty
+    /// lines stay bare comments.
+    fn with_header(&self, unit: String) -> String {
+        match &self.service.header {
+            Some(header) => {
+                let mut out: String = header
+                    .lines()
+                    .map(|line| match line {
+                        "" => "#\n".to_string(),
+                        line => format!("# {}\n", line),
+                    })
+                    .collect();
+                out += &unit;
+                out
+            }
+            None => unit,
+        }
+    }
+
+    pub fn to_systemd_timer(&self) -> Result<Option<String>> {
+        let timer = match &self.service.timer {
+            Some(timer) => timer,
+            None => return Ok(None),
+        };
+        let mut timer_unit = SystemdTimerUnit {
+            unit: convert_args!(btreemap!(
+                "Description" => format!("Timer for {}", self.service.name),
+            )),
+            timer: convert_args!(btreemap!(
+                "OnCalendar" => timer.on_calendar.clone(),
+            )),
+            install: convert_args!(btreemap!(
+                "WantedBy" => self.install_target(),
             )),
         };
+        if let Some(span) = &timer.on_boot {
+            timer_unit
+                .timer
+                .insert("OnBootSec".into(), span.as_str().into());
+        }
+        if let Some(span) = &timer.on_unit_inactive {
+            timer_unit
+                .timer
+                .insert("OnUnitInactiveSec".into(), span.as_str().into());
+        }
+        if let Some(delay) = &timer.randomized_delay {
+            timer_unit
+                .timer
+                .insert("RandomizedDelaySec".into(), delay.as_str().into());
+        }
+        if let Some(persistent) = timer.persistent {
+            let value = if persistent { "true" } else { "false" };
+            timer_unit.timer.insert("Persistent".into(), value.into());
+        }
 
-        serialize_to_string(&service_unit)
+        serialize_to_string(&timer_unit).map(|unit| Some(self.with_header(unit)))
     }
-}
 
-impl ServiceOperator for Systemd {
-    fn install(&self) -> Result<()> {
-        let safe_unit_name = systemd_escape(vec![self.service.name.clone()], vec![])?;
-        let unit_file_name = format!("{}.service", safe_unit_name);
+    /// `command`'s words, with specifiers if the service uses them.
+    fn exec_words(&self, command: &[String]) -> Vec<ExecWord> {
+        let word = match self.service.specifiers {
+            true => ExecWord::Specified,
+            false => ExecWord::Literal,
+        };
+        command.iter().cloned().map(word).collect()
+    }
 
-        let unit_path = match self.service.level {
-            ServiceLevel::System => PathBuf::from(r"/etc/systemd/system"),
-            ServiceLevel::User => {
-                let home_dir = env::var("HOME")?;
-                let unit_dir = PathBuf::from(format!(r"{}/.config/systemd/user", home_dir));
-                fs::create_dir_all(&unit_dir)?;
-                unit_dir
+    /// `value` for a setting taking specifiers, with `%` escaped unless the
+    /// service uses them.
+    fn setting(&self, value: &str) -> String {
+        match self.service.specifiers {
+            true => value.to_string(),
+            false => value.replace('%', "%%"),
+        }
+    }
+
+    /// An `Environment=` assignment, quoted if the value has whitespace,
+    /// quotes or backslashes, which systemd would otherwise split it on or
+    /// take as quoting.
+    fn environment_assignment(&self, key: &str, value: &str) -> String {
+        let assignment = self.setting(&format!("{}={}", key, value));
+        if !assignment.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+            return assignment;
+        }
+        format!(
+            "\"{}\"",
+            assignment.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    }
+
+    /// `command`, run by a shell that first exports the decrypted secrets
+    /// from the service's credentials directory.
+    fn with_secrets(&self, command: Vec<ExecWord>) -> Vec<ExecWord> {
+        if self.service.env_secrets.is_empty() {
+            return command;
+        }
+        let mut script = String::new();
+        for key in self.service.env_secrets.keys() {
+            script.push_str(&format!(
+                "{}=$(cat \"$CREDENTIALS_DIRECTORY/{}\") && export {} && ",
+                key, key, key
+            ));
+        }
+        script.push_str("exec \"$@\"");
+        let mut wrapped: Vec<ExecWord> = ["/bin/sh", "-c", &script, &self.service.name]
+            .into_iter()
+            .map(|word| ExecWord::Literal(word.into()))
+            .collect();
+        wrapped.extend(command);
+        wrapped
+    }
+
+    fn socket_file_name(&self) -> String {
+        format!("{}.socket", self.unit_name())
+    }
+
+    /// The socket unit listening for the service, which systemd starts it
+    /// on the first connection to.
+    pub fn to_systemd_socket(&self) -> Result<Option<String>> {
+        if self.service.listen.is_empty() {
+            return Ok(None);
+        }
+        let mut socket_unit = SystemdSocketUnit {
+            unit: convert_args!(btreemap!(
+                "Description" => format!("Socket for {}", self.service.name),
+            )),
+            install: convert_args!(btreemap!(
+                "WantedBy" => self.install_target(),
+            )),
+            ..Default::default()
+        };
+        for listen in &self.service.listen {
+            let listen = Listen::parse(listen).map_err(|e| anyhow!(e))?;
+            if self.service.accept && !listen.is_stream() {
+                return Err(anyhow!("--accept only works with tcp and unix listeners.").into());
             }
+            let key = match listen {
+                Listen::Tcp(_) | Listen::Unix(_) | Listen::Abstract(_) => "ListenStream",
+                Listen::Udp(_) => "ListenDatagram",
+                Listen::Sctp(_) => "ListenSequentialPacket",
+            };
+            add_values(&mut socket_unit.socket, key, vec![listen.address()]);
+        }
+        if self.service.accept {
+            socket_unit.socket.insert("Accept".into(), "yes".into());
+        }
+        if !self.service.socket_allow.is_empty() {
+            socket_unit.socket.insert(
+                "IPAddressAllow".into(),
+                self.service.socket_allow.join(" ").into(),
+            );
+            // Allowing means nothing without denying the rest.
+            socket_unit
+                .socket
+                .insert("IPAddressDeny".into(), "any".into());
+        }
+        if let Some(name) = &self.service.socket_fd_name {
+            socket_unit
+                .socket
+                .insert("FileDescriptorName".into(), name.as_str().into());
+        }
+        if let Some(device) = &self.service.socket_bind_device {
+            socket_unit
+                .socket
+                .insert("BindToDevice".into(), device.as_str().into());
+        }
+        if let Some(user) = &self.service.socket_user {
+            socket_unit
+                .socket
+                .insert("SocketUser".into(), user.as_str().into());
+        }
+        // The default, 0666, would let anyone connect regardless of the owner.
+        let mode = match (&self.service.socket_mode, &self.service.socket_user) {
+            (Some(mode), _) => Some(mode.as_str()),
+            (None, Some(_)) => Some("0600"),
+            (None, None) => None,
+        };
+        if let Some(mode) = mode {
+            socket_unit.socket.insert("SocketMode".into(), mode.into());
         }
-        .join(unit_file_name);
+        serialize_to_string(&socket_unit).map(|unit| Some(self.with_header(unit)))
+    }
+
+    /// The D-Bus activation file, telling the bus to start the service when
+    /// its name is requested.
+    pub fn to_dbus_service(&self) -> Result<Option<String>> {
+        let bus_name = match &self.service.bus_name {
+            Some(bus_name) => bus_name,
+            None => return Ok(None),
+        };
+        let mut dbus_service = DbusServiceFile {
+            service: convert_args!(btreemap!(
+                "Name" => bus_name.clone(),
+                // Never used, the bus hands activation over to systemd.
+                "Exec" => "/bin/false",
+                "SystemdService" => format!("{}.service", self.unit_name()),
+            )),
+        };
+        if self.service.level == ServiceLevel::System {
+            dbus_service.service.insert("User".into(), "root".into());
+        }
+        serialize_to_string(&dbus_service).map(|file| Some(self.with_header(file)))
+    }
 
-        let content = self.to_systemd_unit()?;
-        let debug_prefix = "\n>  ";
-        log::info!(
-            "Writing systemd unit to {:?}:{}{}",
-            unit_path,
-            debug_prefix,
-            content.replace('\n', debug_prefix)
+    fn failure_mail_file_name(&self) -> String {
+        format!("{}-failure-mail.service", self.unit_name())
+    }
+
+    /// A oneshot mailing the status of the failed service, its OnFailure=.
+    pub fn to_failure_mail_unit(&self) -> Result<Option<String>> {
+        let address = match &self.service.failure_mail {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        let systemctl = match self.service.level {
+            ServiceLevel::System => "systemctl",
+            ServiceLevel::User => "systemctl --user",
+        };
+        let unit = self.status_unit().replace('%', "%%");
+        // Leaving %H for systemd to fill in the hostname.
+        let script = format!(
+            "{} status --full --no-pager '{}' | mail -s '{} failed on %H' '{}'",
+            systemctl,
+            unit,
+            unit,
+            address.replace('%', "%%")
         );
-        let mut file = File::create(&unit_path)?;
-        file.write_all(content.as_bytes())?;
+        let mail_unit = SystemdServiceUnit {
+            unit: convert_args!(btreemap!(
+                "Description" => format!("Mail failure of {}", self.service.name),
+            )),
+            service: convert_args!(btreemap!(
+                "Type" => "oneshot",
+                "ExecStart" => systemd_quote(vec![
+                    ExecWord::Literal("/bin/sh".into()),
+                    ExecWord::Literal("-c".into()),
+                    ExecWord::Specified(script),
+                ]),
+            )),
+            ..Default::default()
+        };
+        serialize_to_string(&mail_unit).map(|unit| Some(self.with_header(unit)))
+    }
 
-        let mut base_command = vec!["systemctl"];
-        if self.service.level == ServiceLevel::User {
-            base_command.push("--user");
+    fn dbus_service_dir(&self) -> Result<PathBuf> {
+        Ok(match self.service.level {
+            ServiceLevel::System => PathBuf::from("/usr/local/share/dbus-1/system-services"),
+            ServiceLevel::User => {
+                let home_dir =
+                    env::var("HOME").map_err(|_| anyhow!("HOME must be set for user services."))?;
+                PathBuf::from(home_dir).join(".local/share/dbus-1/services")
+            }
+        })
+    }
+
+    fn unit_dir(&self) -> Result<PathBuf> {
+        config_dir(&self.service.level)
+    }
+
+    /// The service's unit name, without the ".service" suffix.
+    fn unit_name(&self) -> String {
+        systemd_escape(&self.service.name)
+    }
+
+    /// The service unit's file name: a template if it runs as instances,
+    /// including one per connection.
+    pub fn service_file_name(&self) -> String {
+        match (self.service.instances, self.service.accept) {
+            (Some(_), _) | (None, true) => format!("{}@.service", self.unit_name()),
+            (None, false) => format!("{}.service", self.unit_name()),
+        }
+    }
+
+    /// The units that get enabled and started: the timer or socket if there
+    /// is one, or each instance of a template.
+    fn activation_units(&self) -> Vec<String> {
+        match (&self.service.timer, self.service.instances) {
+            (Some(_), _) => vec![format!("{}.timer", self.unit_name())],
+            (None, _) if !self.service.listen.is_empty() => vec![self.socket_file_name()],
+            (None, Some(instances)) => (0..instances)
+                .map(|i| format!("{}@{}.service", self.unit_name(), i))
+                .collect(),
+            (None, None) => vec![format!("{}.service", self.unit_name())],
         }
+    }
 
-        log::info!("Reloading systemd daemon...");
-        self.systemctl_command()
-            .arg("daemon-reload")
-            .spawn()?
-            .wait()?;
-
-        log::info!("Enabling service...");
-        self.systemctl_command()
-            .arg("enable")
-            .arg(self.service.name.clone())
-            .spawn()?
-            .wait()?;
+    /// The unit whose state stands for the service's: the first instance of
+    /// a template, or the socket if each connection gets its own instance.
+    fn status_unit(&self) -> String {
+        match (self.service.instances, self.service.accept) {
+            (_, true) => self.socket_file_name(),
+            (Some(_), false) => format!("{}@0.service", self.unit_name()),
+            (None, false) => format!("{}.service", self.unit_name()),
+        }
+    }
+
+    /// The target the activation unit is wanted by.
+    fn install_target(&self) -> &'static str {
+        match (&self.service.timer, self.service.graphical) {
+            (Some(_), _) => "timers.target",
+            (None, _) if !self.service.listen.is_empty() => "sockets.target",
+            (None, _) if self.service.power_hook.is_some() => "sleep.target",
+            // A kiosk replaces a console's getty, for a graphical boot.
+            (None, _) if self.service.kiosk => "graphical.target",
+            (None, true) => GRAPHICAL_SESSION,
+            // The user manager has no multi-user.target.
+            (None, false) if self.service.level == ServiceLevel::User => "default.target",
+            (None, false) => "multi-user.target",
+        }
+    }
+
+    /// The links `systemctl enable` makes, as (link, unit file name) with the
+    /// link relative to the unit directory: the activation units in their
+    /// target's .wants directory. Instances link to their template.
+    pub fn wants_links(&self) -> Vec<(PathBuf, String)> {
+        let wants_dir = PathBuf::from(format!("{}.wants", self.install_target()));
+        self.activation_units()
+            .into_iter()
+            .map(|unit| {
+                let file_name = match (&self.service.timer, self.service.instances) {
+                    (None, Some(_)) if self.service.listen.is_empty() => self.service_file_name(),
+                    _ => unit.clone(),
+                };
+                (wants_dir.join(unit), file_name)
+            })
+            .collect()
+    }
 
+    fn write_units(&self) -> Result<()> {
+        for (unit_path, content) in self.unit_files()? {
+            write_unit(&unit_path, &content)?;
+        }
         Ok(())
     }
 
-    fn start(&self) -> Result<()> {
-        self.systemctl_command()
-            .arg("start")
-            .arg(self.service.name.clone())
-            .spawn()?
-            .wait()?;
+    /// Enables the activation unit the way `systemctl enable` would, by
+    /// linking it into its target's .wants directory. For hosts without
+    /// systemctl, e.g. while building an image.
+    fn enable_with_symlink(&self) -> Result<()> {
+        let unit_dir = self.unit_dir()?;
+        for (link, file_name) in self.wants_links() {
+            let link = unit_dir.join(link);
+            if let Some(dir) = link.parent() {
+                fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+            }
+            if link.symlink_metadata().is_ok() {
+                fs::remove_file(&link).map_err(|e| MkserviceError::io(&link, e))?;
+            }
+            symlink(unit_dir.join(file_name), &link).map_err(|e| MkserviceError::io(&link, e))?;
+        }
         Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
-    use super::*;
+/// Where units are configured at `level`, as opposed to shipped by packages.
+pub fn config_dir(level: &ServiceLevel) -> Result<PathBuf> {
+    Ok(match level {
+        ServiceLevel::System => PathBuf::from(r"/etc/systemd/system"),
+        ServiceLevel::User => {
+            let home_dir =
+                env::var("HOME").map_err(|_| anyhow!("HOME must be set for user services."))?;
+            PathBuf::from(format!(r"{}/.config/systemd/user", home_dir))
+        }
+    })
+}
 
-    macro_rules! string_vec {
-        ($($x:expr),*) => (vec![$($x.to_string()), *]);
-    }
+/// Reloads the service manager at `level`, to pick up changed units.
+pub fn daemon_reload(level: &ServiceLevel) -> Result<()> {
+    let service = ServiceConfig {
+        level: level.clone(),
+        ..Default::default()
+    };
+    Systemd { service }.systemctl(&["daemon-reload".into()])
+}
 
-    #[test]
-    fn test_systemd_unit_render() {
+/// Microseconds since boot, to compare with systemd's monotonic timestamps.
+/// /proc/uptime also counts time suspended, which servers rarely are.
+fn monotonic_now() -> Option<u64> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    let seconds: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    Some((seconds * 1_000_000.0) as u64)
+}
+
+fn write_unit(unit_path: &PathBuf, content: &str) -> Result<()> {
+    let debug_prefix = "\n>  ";
+    log::info!(
+        "Writing systemd unit to {:?}:{}{}",
+        unit_path,
+        debug_prefix,
+        content.replace('\n', debug_prefix)
+    );
+    if let Some(dir) = unit_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| MkserviceError::io(dir, e))?;
+    }
+    let mut file = File::create(unit_path).map_err(|e| MkserviceError::io(unit_path, e))?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// `services` by level, as systemctl takes the units of one manager at a
+/// time.
+fn by_level(services: &[ServiceConfig]) -> Vec<Vec<Systemd>> {
+    let mut levels: BTreeMap<ServiceLevel, Vec<Systemd>> = BTreeMap::new();
+    for service in services {
+        levels
+            .entry(service.level.clone())
+            .or_default()
+            .push(Systemd {
+                service: service.clone(),
+            });
+    }
+    levels.into_values().collect()
+}
+
+/// Installs `services` together: their files are written in parallel, then
+/// systemd reloads once and enables them all in one go, rather than once per
+/// service.
+pub fn install_all(services: &[ServiceConfig]) -> Result<()> {
+    for systemds in by_level(services) {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = systemds.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let writers: Vec<_> = systemds
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(|| chunk.iter().try_for_each(Systemd::write_units)))
+                .collect();
+            writers
+                .into_iter()
+                .try_for_each(|writer| writer.join().expect("Writing units panicked"))
+        })?;
+
+        if find_executable("systemctl").is_none() {
+            log::warn!(
+                "systemctl not found, enabling by symlink. systemd must reload to see the units."
+            );
+            systemds.iter().try_for_each(Systemd::enable_with_symlink)?;
+            continue;
+        }
+        let units: Vec<String> = systemds
+            .iter()
+            .flat_map(Systemd::activation_units)
+            .collect();
+        log::info!("Reloading systemd daemon...");
+        systemds[0].systemctl(&["daemon-reload".into()])?;
+        match systemds.len() {
+            1 => log::info!("Enabling service..."),
+            n => log::info!("Enabling {} services...", n),
+        }
+        let args: Vec<String> = std::iter::once("enable".to_string()).chain(units).collect();
+        systemds[0].systemctl(&args)?;
+    }
+    Ok(())
+}
+
+/// Starts `services` with one systemctl call per level.
+pub fn start_all(services: &[ServiceConfig]) -> Result<()> {
+    for systemds in by_level(services) {
+        let units: Vec<String> = systemds
+            .iter()
+            .flat_map(Systemd::activation_units)
+            .collect();
+        let args: Vec<String> = std::iter::once("start".to_string()).chain(units).collect();
+        systemds[0].systemctl(&args)?;
+    }
+    Ok(())
+}
+
+impl ServiceOperator for Systemd {
+    fn install(&self) -> Result<()> {
+        install_all(std::slice::from_ref(&self.service))
+    }
+
+    fn start(&self) -> Result<()> {
+        self.systemctl(&self.systemctl_args("start", &[]))
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.systemctl(&self.systemctl_args("stop", &[]))
+    }
+
+    fn log_command(&self, lines: usize, follow: bool) -> Option<Command> {
+        let mut command = Command::new("journalctl");
+        if self.service.level == ServiceLevel::User {
+            command.arg("--user");
+        }
+        command.args(["--no-pager", "--lines", &lines.to_string()]);
+        if follow {
+            command.arg("--follow");
+        }
+        // Every instance's, for templates.
+        let unit = match (self.service.instances, self.service.accept) {
+            (Some(_), _) | (None, true) => format!("{}@*.service", self.unit_name()),
+            (None, false) => format!("{}.service", self.unit_name()),
+        };
+        command.args(["--unit", &unit]);
+        Some(command)
+    }
+
+    fn restart_with(&self, units: &[String]) -> Result<()> {
+        self.systemctl(&self.systemctl_args("restart", units))
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        let has_systemctl = find_executable("systemctl").is_some();
+        if has_systemctl {
+            log::info!("Disabling service...");
+            let mut args = self.systemctl_args("disable", &[]);
+            args.insert(1, "--now".into());
+            self.systemctl(&args)?;
+            // Stopping a timer or socket leaves the service it started running.
+            if self.service.timer.is_some() || !self.service.listen.is_empty() {
+                let service = self.service_file_name().replace("@.", "@*.");
+                self.systemctl(&["stop".into(), service])?;
+            }
+        } else {
+            let unit_dir = self.unit_dir()?;
+            let links = self
+                .wants_links()
+                .into_iter()
+                .map(|(link, _)| unit_dir.join(link));
+            remove_files(links)?;
+        }
+        remove_files(self.unit_files()?.into_iter().map(|(path, _)| path))?;
+        if has_systemctl {
+            self.systemctl(&["daemon-reload".into()])?;
+        }
+        Ok(())
+    }
+
+    fn dependents(&self) -> Result<Vec<String>> {
+        let own_units = self.activation_units();
+        let output = self
+            .systemctl_command()
+            .args(["show", "--property=ConsistsOf,BoundBy", "--value"])
+            .args(&own_units)
+            .output()
+            .map_err(|e| MkserviceError::spawn("systemctl", e))?;
+        if !output.status.success() {
+            return Err(MkserviceError::CommandFailed {
+                cmd: format!("systemctl show {}", own_units.join(" ")),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        let mut units: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .filter(|unit| !own_units.iter().any(|own| own == unit))
+            .map(String::from)
+            .collect();
+        units.sort();
+        units.dedup();
+        Ok(units)
+    }
+
+    fn existing_units(&self) -> Result<Vec<PathBuf>> {
+        let names = vec![
+            self.service_file_name(),
+            self.socket_file_name(),
+            format!("{}.timer", self.unit_name()),
+        ];
+        let mut dirs = unit_search_path(&self.service.level);
+        let unit_dir = self.unit_dir()?;
+        if !dirs.contains(&unit_dir) {
+            dirs.push(unit_dir);
+        }
+        Ok(find_units(&dirs, &names))
+    }
+
+    fn unit_files(&self) -> Result<Vec<(PathBuf, String)>> {
+        let safe_unit_name = self.unit_name();
+        let unit_dir = self.unit_dir()?;
+
+        let mut files = vec![(self.service_file_name(), self.to_systemd_unit()?)];
+        if let Some(content) = self.to_systemd_timer()? {
+            files.push((format!("{}.timer", safe_unit_name), content));
+        }
+        if let Some(content) = self.to_systemd_socket()? {
+            files.push((self.socket_file_name(), content));
+        }
+        if let Some(content) = self.to_failure_mail_unit()? {
+            files.push((self.failure_mail_file_name(), content));
+        }
+        let mut files: Vec<(PathBuf, String)> = files
+            .into_iter()
+            .map(|(file_name, content)| (unit_dir.join(file_name), content))
+            .collect();
+        if let (Some(bus_name), Some(content)) = (&self.service.bus_name, self.to_dbus_service()?) {
+            let dbus_dir = self.dbus_service_dir()?;
+            files.push((dbus_dir.join(format!("{}.service", bus_name)), content));
+        }
+        for (path, content) in files.iter_mut() {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(adopted) = self.service.adopted_units.get(&*file_name) {
+                *content = adopted.clone();
+            }
+        }
+        Ok(files)
+    }
+
+    fn status(&self) -> Result<ServiceStatus> {
+        let output = self
+            .systemctl_command()
+            .arg("show")
+            .arg(format!("--property={}", STATUS_PROPERTIES.join(",")))
+            .arg(self.status_unit())
+            .output()
+            .map_err(|e| MkserviceError::spawn("systemctl", e))?;
+        if !output.status.success() {
+            return Err(MkserviceError::CommandFailed {
+                cmd: format!("systemctl show {}", self.status_unit()),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        let mut properties: BTreeMap<String, String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| str_partition(line, "="))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        let mut property = |name: &str| properties.remove(name);
+
+        let state = format!(
+            "{} ({})",
+            property("ActiveState").unwrap_or_default(),
+            property("SubState").unwrap_or_default()
+        );
+        let active_since = property("ActiveEnterTimestampMonotonic").and_then(|v| v.parse().ok());
+        let uptime = match active_since {
+            Some(since @ 1..) if state.starts_with("active ") => {
+                monotonic_now().map(|now| now.saturating_sub(since) / 1_000_000)
+            }
+            _ => None,
+        };
+        let enabled = property("UnitFileState").unwrap_or_default();
+        let restarts = property("NRestarts").and_then(|v| v.parse().ok());
+        let last_result = property("Result");
+        let last_exit_status = property("ExecMainStatus").and_then(|v| v.parse().ok());
+        let last_exit_time = property("ExecMainExitTimestamp");
+        let limits = properties
+            .into_iter()
+            .filter(|(_, value)| value != "infinity")
+            .collect();
+        Ok(ServiceStatus {
+            state,
+            enabled,
+            restarts,
+            uptime,
+            last_result,
+            last_exit_status,
+            last_exit_time,
+            limits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::config::{ImdsValue, RestartPolicy, TimerConfig};
+
+    macro_rules! string_vec {
+        ($($x:expr),*) => (vec![$($x.to_string()), *]);
+    }
+
+    #[test]
+    fn test_systemd_unit_render() {
         let service = ServiceConfig {
             name: "hello".into(),
             command: string_vec!["/bin/sh", "-c", "echo hello"],
@@ -211,6 +1654,7 @@ mod tests {
                 "FOO" => "foo",
                 "BAR" => "bar",
             )),
+            ..Default::default()
         };
         let systemd = Systemd { service };
         let unit_cfg = systemd.to_systemd_unit().unwrap();
@@ -229,4 +1673,1036 @@ mod tests {
             ",
         )
     }
+
+    #[test]
+    fn test_systemd_account_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            user: Some("www".into()),
+            group: Some("web".into()),
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("User=www\n"));
+        assert!(unit.contains("Group=web\n"));
+    }
+
+    #[test]
+    fn test_systemd_secrets_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web", "--port", "80"],
+            env_secrets: convert_args!(btreemap!("API_KEY" => "k1", "DB_PASS" => "k2")),
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(
+            unit.contains("SetCredentialEncrypted=API_KEY:k1\nSetCredentialEncrypted=DB_PASS:k2\n")
+        );
+        assert!(unit.contains(concat!(
+            r#"ExecStart="/bin/sh" "-c" "API_KEY=$$(cat \"$$CREDENTIALS_DIRECTORY/API_KEY\") && "#,
+            r#"export API_KEY && DB_PASS=$$(cat \"$$CREDENTIALS_DIRECTORY/DB_PASS\") && "#,
+            r#"export DB_PASS && exec \"$$@\"" "web" "/usr/bin/web" "--port" "80""#,
+        )));
+    }
+
+    /// Re-runs `test` with a fake mkservice alone on PATH, so units running
+    /// it render the same everywhere. True in the parent, which should return.
+    fn rerun_with_mkservice(test: &str) -> bool {
+        if env::var_os("MKSERVICE_TEST_BIN").is_some() {
+            return false;
+        }
+        let bin = env::temp_dir().join(format!(
+            "mkservice-bin-{}-{}",
+            std::process::id(),
+            test.rsplit("::").next().unwrap()
+        ));
+        fs::create_dir_all(&bin).unwrap();
+        let mkservice = bin.join("mkservice");
+        fs::write(&mkservice, "#!/bin/sh\n").unwrap();
+        let executable = std::os::unix::fs::PermissionsExt::from_mode(0o755);
+        fs::set_permissions(&mkservice, executable).unwrap();
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", test])
+            .env("PATH", &bin)
+            .env("MKSERVICE_TEST_BIN", &bin)
+            .status()
+            .unwrap();
+        fs::remove_dir_all(&bin).ok();
+        assert!(status.success());
+        true
+    }
+
+    #[test]
+    fn test_systemd_vault_render() {
+        if rerun_with_mkservice("provider::systemd::tests::test_systemd_vault_render") {
+            return;
+        }
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            env_files: string_vec!["/etc/web.env"],
+            vault_secrets: string_vec!["secret/data/web#API_KEY"],
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("EnvironmentFile=/etc/web.env\nEnvironmentFile=-%t/web/vault.env\n"));
+        let mkservice = PathBuf::from(env::var("MKSERVICE_TEST_BIN").unwrap()).join("mkservice");
+        assert!(unit.contains(&format!(
+            "ExecStartPre=\"{}\" \"vault-env\" \"secret/data/web#API_KEY\"\n",
+            mkservice.display()
+        )));
+        assert!(unit.contains("RuntimeDirectory=web\nRuntimeDirectoryMode=0700\n"));
+    }
+
+    #[test]
+    fn test_systemd_metadata_render() {
+        if rerun_with_mkservice("provider::systemd::tests::test_systemd_metadata_render") {
+            return;
+        }
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            vault_secrets: string_vec!["secret/data/web#API_KEY"],
+            imds_env: vec![ImdsValue::RoleCredentials],
+            gce_metadata_env: [("DB_HOST".to_string(), "db-host".to_string())].into(),
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains(concat!(
+            "EnvironmentFile=-%t/web/vault.env\n",
+            "EnvironmentFile=-%t/web/metadata.env\n"
+        )));
+        assert!(unit
+            .contains(r#" "metadata-env" "--imds" "role-credentials" "--gce" "DB_HOST=db-host""#));
+        assert_eq!(unit.matches("ExecStartPre=").count(), 2);
+    }
+
+    #[test]
+    fn test_systemd_restart_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            restart: Some(RestartPolicy::Always),
+            restart_sec: Some(5),
+            unit_options: convert_args!(btreemap!(
+                "Service" => convert_args!(btreemap!(
+                    "Restart" => string_vec!["on-abnormal"],
+                )),
+            )),
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("Restart=always\nRestartSec=5\n"));
+    }
+
+    #[test]
+    fn test_systemd_oneshot_steps_render() {
+        let service = ServiceConfig {
+            name: "deploy".into(),
+            command: string_vec!["/srv/deploy.d/10-fetch"],
+            steps: vec![string_vec!["/srv/deploy.d/20-migrate"]],
+            service_type: ServiceType::Oneshot,
+            requires_mounts: string_vec!["/srv"],
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        assert_eq!(
+            unit_cfg,
+            "[Unit]\n\
+            Description=deploy\n\
+            RequiresMountsFor=/srv\n\
+            [Install]\n\
+            WantedBy=multi-user.target\n\
+            [Service]\n\
+            ExecStart=\"/srv/deploy.d/10-fetch\"\n\
+            ExecStart=\"/srv/deploy.d/20-migrate\"\n\
+            Type=oneshot\n\
+            ",
+        )
+    }
+
+    #[test]
+    fn test_systemd_timer_render() {
+        let service = ServiceConfig {
+            name: "backup".into(),
+            command: string_vec!["/usr/local/bin/backup.sh"],
+            service_type: ServiceType::Oneshot,
+            timer: Some(TimerConfig {
+                on_calendar: string_vec!["daily"],
+                randomized_delay: Some("5m".into()),
+                on_boot: Some("2m".into()),
+                persistent: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        assert!(!systemd.to_systemd_unit().unwrap().contains("[Install]"));
+        assert_eq!(
+            systemd.to_systemd_timer().unwrap().unwrap(),
+            "[Unit]\n\
+            Description=Timer for backup\n\
+            [Timer]\n\
+            OnBootSec=2m\n\
+            OnCalendar=daily\n\
+            Persistent=true\n\
+            RandomizedDelaySec=5m\n\
+            [Install]\n\
+            WantedBy=timers.target\n\
+            ",
+        )
+    }
+
+    #[test]
+    fn test_use_specifiers() {
+        let mut service = ServiceConfig {
+            name: "notes".into(),
+            command: string_vec![
+                "/home/me/bin/notes",
+                "--socket",
+                "/run/user/1000/notes.sock"
+            ],
+            env: convert_args!(btreemap!("DATA" => "/home/me/.local/share/notes")),
+            env_files: string_vec!["/home/me/notes.env"],
+            working_directory: Some("/home/me".into()),
+            ..Default::default()
+        };
+        service.steps = vec![string_vec![
+            "/home/mel/bin/sync",
+            "--config=/home/me/sync.toml"
+        ]];
+        use_specifiers(
+            &mut service,
+            Path::new("/home/me/"),
+            Some(Path::new("/run/user/1000")),
+        );
+        assert_eq!(
+            service.command,
+            string_vec!["%h/bin/notes", "--socket", "%t/notes.sock"]
+        );
+        assert_eq!(
+            service.steps,
+            vec![string_vec![
+                "/home/mel/bin/sync",
+                "--config=/home/me/sync.toml"
+            ]]
+        );
+        assert_eq!(service.env["DATA"], "%h/.local/share/notes");
+        assert_eq!(service.env_files, string_vec!["%h/notes.env"]);
+        assert_eq!(service.working_directory.as_deref(), Some("%h"));
+
+        let mut service = ServiceConfig {
+            name: "notes".into(),
+            command: string_vec![
+                "/home/me/bin/notes",
+                "--socket",
+                "/run/user/1000/notes.sock",
+                "--cache=100%"
+            ],
+            env_files: string_vec!["/home/me/notes.env", "-/run/user/1000/notes.env"],
+            working_directory: Some("/home/me/data".into()),
+            level: ServiceLevel::User,
+            ..Default::default()
+        };
+        use_specifiers(
+            &mut service,
+            Path::new("/home/me"),
+            Some(Path::new("/run/user/1000")),
+        );
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        for line in [
+            r#"ExecStart="%h/bin/notes" "--socket" "%t/notes.sock" "--cache=100%%""#,
+            "WorkingDirectory=%h/data",
+            "EnvironmentFile=%h/notes.env",
+            "EnvironmentFile=-%t/notes.env",
+        ] {
+            assert!(unit.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn test_systemd_escape() {
+        assert_eq!(systemd_escape("hello"), "hello");
+        assert_eq!(systemd_escape("my-app"), "my\\x2dapp");
+        assert_eq!(systemd_escape("/dev/sda1"), "-dev-sda1");
+        assert_eq!(systemd_escape(".hidden.x"), "\\x2ehidden.x");
+        assert_eq!(systemd_escape("a b\u{e9}"), "a\\x20b\\xc3\\xa9");
+        assert_eq!(systemd_escape(""), "");
+        for s in ["my-app", "/dev/sda1", ".hidden.x", "a b\u{e9}"] {
+            assert_eq!(systemd_unescape(&systemd_escape(s)), s);
+        }
+        assert_eq!(systemd_escape_path("//dev//ttyUSB0/"), "dev-ttyUSB0");
+        assert_eq!(systemd_escape_path("/"), "-");
+        assert_eq!(device_unit("eth1"), "sys-subsystem-net-devices-eth1.device");
+        assert_eq!(
+            device_unit("br-lan"),
+            "sys-subsystem-net-devices-br\\x2dlan.device"
+        );
+        assert_eq!(device_unit("/dev/ttyUSB0"), "dev-ttyUSB0.device");
+    }
+
+    #[test]
+    fn test_systemd_power_hook_render() {
+        let service = ServiceConfig {
+            name: "nas".into(),
+            command: string_vec!["/usr/local/bin/nas", "resume"],
+            service_type: ServiceType::Oneshot,
+            power_hook: Some(PowerHook::Resume),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit = systemd.to_systemd_unit().unwrap();
+        assert!(unit.contains("Before=sleep.target\nDescription=nas\nStopWhenUnneeded=yes\n"));
+        assert!(unit.contains("ExecStart=/bin/true\n"));
+        assert!(unit.contains("ExecStop=\"/usr/local/bin/nas\" \"resume\"\n"));
+        assert!(unit.contains("RemainAfterExit=yes\n"));
+        assert!(unit.contains("WantedBy=sleep.target\n"));
+        assert_eq!(
+            systemd.wants_links()[0].0,
+            PathBuf::from("sleep.target.wants/nas.service")
+        );
+    }
+
+    #[test]
+    fn test_systemd_service_type_render() {
+        let service = ServiceConfig {
+            name: "nginx".into(),
+            command: string_vec!["/usr/sbin/nginx"],
+            service_type: ServiceType::Forking,
+            pidfile: Some("/run/nginx.pid".into()),
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("PIDFile=/run/nginx.pid\n"));
+        assert!(unit.contains("Type=forking\n"));
+
+        let service = ServiceConfig {
+            name: "setup".into(),
+            command: string_vec!["/usr/bin/setup"],
+            service_type: ServiceType::Oneshot,
+            remain_after_exit: true,
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("RemainAfterExit=yes\n"));
+        assert!(!unit.contains("Restart="));
+    }
+
+    #[test]
+    fn test_systemd_boot_hooks_render() {
+        let service = ServiceConfig {
+            name: "backup".into(),
+            command: string_vec!["/usr/local/bin/backup", "--final"],
+            service_type: ServiceType::Oneshot,
+            at_shutdown: true,
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(!unit.contains("DefaultDependencies="));
+        assert!(unit.contains(concat!(
+            "ExecStart=/bin/true\n",
+            "ExecStop=\"/usr/local/bin/backup\" \"--final\"\n",
+            "RemainAfterExit=yes\n"
+        )));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+
+        let service = ServiceConfig {
+            name: "warm".into(),
+            command: string_vec!["/usr/local/bin/warm"],
+            service_type: ServiceType::Oneshot,
+            at_boot_once: true,
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("ExecStart=\"/usr/local/bin/warm\"\nRemainAfterExit=yes\n"));
+        assert!(!unit.contains("ExecStop="));
+    }
+
+    #[test]
+    fn test_systemd_devices_render() {
+        let service = ServiceConfig {
+            name: "modem".into(),
+            command: string_vec!["/usr/bin/modem"],
+            bind_to_devices: string_vec!["eth1"],
+            after_devices: string_vec!["/dev/ttyUSB0"],
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("BindsTo=sys-subsystem-net-devices-eth1.device\n"));
+        assert!(unit.contains("Requires=dev-ttyUSB0.device\n"));
+        assert!(unit.contains(concat!(
+            "After=sys-subsystem-net-devices-eth1.device\n",
+            "After=dev-ttyUSB0.device\n"
+        )));
+    }
+
+    #[test]
+    fn test_systemd_dependencies_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            depends_on: string_vec!["db"],
+            after: string_vec!["network-online.target"],
+            before: string_vec!["nginx.service"],
+            requires: string_vec!["redis.service"],
+            wants: string_vec!["network-online.target"],
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("After=db.service\nAfter=network-online.target\n"));
+        assert!(unit.contains("Before=nginx.service\n"));
+        assert!(unit.contains("Requires=db.service\nRequires=redis.service\n"));
+        assert!(unit.contains("Wants=network-online.target\n"));
+    }
+
+    #[test]
+    fn test_systemd_dependencies_kept_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            listen: string_vec!["tcp:8080"],
+            requires: string_vec!["postgresql.service"],
+            after_devices: string_vec!["eth1"],
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains(concat!(
+            "Requires=postgresql.service\n",
+            "Requires=sys-subsystem-net-devices-eth1.device\n",
+            "Requires=web.socket\n"
+        )));
+
+        let service = ServiceConfig {
+            name: "init".into(),
+            command: string_vec!["/usr/bin/init"],
+            first_boot_only: true,
+            before: string_vec!["bar.service"],
+            wants: string_vec!["foo.service"],
+            ..Default::default()
+        };
+        let unit = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit.contains("Before=bar.service\nBefore=first-boot-complete.target\n"));
+        assert!(unit.contains("Wants=foo.service\nWants=first-boot-complete.target\n"));
+    }
+
+    #[test]
+    fn test_systemd_quote() {
+        let quote = |words: &[&str]| {
+            systemd_quote(
+                words
+                    .iter()
+                    .map(|w| ExecWord::Literal(w.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            quote(&["/bin/echo", "hi there"]),
+            r#""/bin/echo" "hi there""#
+        );
+        assert_eq!(quote(&["say \"hi\""]), r#""say \"hi\"""#);
+        assert_eq!(quote(&["C:\\dir\\"]), r#""C:\\dir\\""#);
+        assert_eq!(quote(&["100%", "%h"]), r#""100%%" "%%h""#);
+        assert_eq!(
+            systemd_quote(vec![ExecWord::Specified("%h/bin/app$".into())]),
+            r#""%h/bin/app$$""#
+        );
+        assert_eq!(
+            quote(&["$HOME", "${PATH}", "$$"]),
+            r#""$$HOME" "$${PATH}" "$$$$""#
+        );
+        assert_eq!(quote(&["a\nb", "\tc\r"]), r#""a\nb" "\tc\r""#);
+        assert_eq!(quote(&["\x1b[0m", "\u{85}"]), r#""\x1b[0m" "\u0085""#);
+        assert_eq!(
+            quote(&["find", ".", "-exec", "rm", "{}", ";"]),
+            r#""find" "." "-exec" "rm" "{}" \;"#
+        );
+        assert_eq!(quote(&["", "'single'", "-"]), r#""" "'single'" "-""#);
+        assert_eq!(quote(&["caf\u{e9} \u{1f600}"]), "\"caf\u{e9} \u{1f600}\"");
+    }
+
+    #[cfg(feature = "import")]
+    #[test]
+    fn test_split_exec() {
+        let adversarial = [
+            "/bin/echo",
+            "",
+            " padded ",
+            "say \"hi\"",
+            "it's",
+            "back\\slash\\",
+            "100%",
+            "%h%%",
+            "$HOME ${PATH} $$",
+            "multi\nline\r\n",
+            "\ttab \x07bell \x1b[0m \x7f \u{85}",
+            ";",
+            "; not alone",
+            "\\;",
+            "caf\u{e9} \u{1f600}",
+        ];
+        let words: Vec<String> = adversarial.iter().map(|w| w.to_string()).collect();
+        let line = systemd_quote(words.iter().cloned().map(ExecWord::Literal).collect());
+        assert!(!line.contains(['\n', '\r']));
+        assert_eq!(split_exec(&line).unwrap(), words);
+        assert_eq!(
+            split_exec(r"/bin/app  --flag='a b' plain\sword \101 'it''s' x\;").unwrap(),
+            ["/bin/app", "--flag=a b", "plain word", "A", "its", "x;"]
+        );
+        for line in [
+            "/bin/app %h",
+            "/bin/app $HOME",
+            "/bin/app ${HOME}",
+            "/bin/a ; /bin/b",
+            "/bin/app \"open",
+            "/bin/app \\q",
+            "/bin/app \\x4",
+        ] {
+            assert!(split_exec(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_systemd_escape_arbitrary() {
+        // A fixed xorshift stream, so failures reproduce.
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet: Vec<char> = "aZ9:_.-/\\x%$\" \n\t\r\0\u{7f}\u{e9}\u{1f600}"
+            .chars()
+            .collect();
+        for _ in 0..2000 {
+            let len = next() % 12;
+            let s: String = (0..len)
+                .map(|_| alphabet[next() as usize % alphabet.len()])
+                .collect();
+            let escaped = systemd_escape(&s);
+            assert!(
+                escaped
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ":_.-\\".contains(c)),
+                "{:?} escaped to {:?}",
+                s,
+                escaped
+            );
+            assert_eq!(systemd_unescape(&escaped), s);
+            // Unescaping what isn't escape output mustn't panic either.
+            systemd_unescape(&s);
+
+            // Nor may it reach a unit as anything but one ExecStart= line.
+            let service = ServiceConfig {
+                name: "arbitrary".into(),
+                command: vec!["/bin/echo".into(), s.clone()],
+                ..Default::default()
+            };
+            let unit = Systemd { service }.to_systemd_unit().unwrap();
+            assert!(
+                !unit.contains(|c: char| c.is_control() && c != '\n'),
+                "{:?} rendered as {:?}",
+                s,
+                unit
+            );
+            let exec_start: Vec<&str> = unit
+                .lines()
+                .filter(|line| line.starts_with("ExecStart="))
+                .collect();
+            assert_eq!(exec_start.len(), 1, "{:?} rendered as {:?}", s, unit);
+        }
+    }
+
+    #[test]
+    fn test_find_units() {
+        let root = env::temp_dir().join(format!("mkservice-units-{}", std::process::id()));
+        let (etc, usr) = (root.join("etc"), root.join("usr"));
+        fs::create_dir_all(&etc).unwrap();
+        fs::create_dir_all(&usr).unwrap();
+        fs::write(usr.join("web.service"), "[Service]\n").unwrap();
+        // Masked: a link to /dev/null.
+        symlink("/dev/null", etc.join("web.socket")).unwrap();
+        let found = find_units(
+            &[etc.clone(), usr.clone()],
+            &["web.service", "web.socket", "web.timer"].map(String::from),
+        );
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(found, vec![etc.join("web.socket"), usr.join("web.service")]);
+    }
+
+    /// The default install flow must not depend on any external binary, so
+    /// this re-runs itself with an empty environment (no PATH at all).
+    #[test]
+    fn test_install_without_path() {
+        if env::var_os("PATH").is_some() {
+            let home = env::temp_dir().join(format!("mkservice-test-{}", std::process::id()));
+            let status = Command::new(env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "provider::systemd::tests::test_install_without_path",
+                ])
+                .env_clear()
+                .env("HOME", &home)
+                .status()
+                .unwrap();
+            fs::remove_dir_all(&home).ok();
+            assert!(status.success());
+            return;
+        }
+
+        let service = ServiceConfig {
+            name: "my-app".into(),
+            command: string_vec!["/usr/bin/my-app"],
+            level: ServiceLevel::User,
+            ..Default::default()
+        };
+        Systemd { service }.install().unwrap();
+        let unit_dir = PathBuf::from(env::var("HOME").unwrap()).join(".config/systemd/user");
+        assert!(unit_dir.join("my\\x2dapp.service").is_file());
+        assert!(unit_dir
+            .join("default.target.wants/my\\x2dapp.service")
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_install_all_batches() {
+        if env::var_os("MKSERVICE_TEST_HOME").is_none() {
+            let home = env::temp_dir().join(format!("mkservice-batch-{}", std::process::id()));
+            let bin = home.join("bin");
+            fs::create_dir_all(&bin).unwrap();
+            let systemctl = bin.join("systemctl");
+            fs::write(
+                &systemctl,
+                "#!/bin/sh\necho \"$@\" >> \"$HOME/systemctl.log\"\n",
+            )
+            .unwrap();
+            let executable = std::os::unix::fs::PermissionsExt::from_mode(0o755);
+            fs::set_permissions(&systemctl, executable).unwrap();
+            let status = Command::new(env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "provider::systemd::tests::test_install_all_batches",
+                ])
+                .env_clear()
+                .env("HOME", &home)
+                .env("PATH", &bin)
+                .env("MKSERVICE_TEST_HOME", &home)
+                .status()
+                .unwrap();
+            let log = fs::read_to_string(home.join("systemctl.log"));
+            fs::remove_dir_all(&home).ok();
+            assert!(status.success());
+            assert_eq!(
+                log.unwrap(),
+                "--user daemon-reload\n\
+                --user enable worker1.service worker2.service worker3.service\n"
+            );
+            return;
+        }
+
+        let services: Vec<ServiceConfig> = (1..=3)
+            .map(|i| ServiceConfig {
+                name: format!("worker{}", i),
+                command: string_vec!["/usr/bin/worker"],
+                level: ServiceLevel::User,
+                ..Default::default()
+            })
+            .collect();
+        install_all(&services).unwrap();
+        let home = PathBuf::from(env::var("HOME").unwrap());
+        assert!(home.join(".config/systemd/user/worker3.service").is_file());
+    }
+
+    #[test]
+    fn test_systemd_first_boot_render() {
+        let service = ServiceConfig {
+            name: "provision".into(),
+            command: string_vec!["/usr/local/bin/provision"],
+            service_type: ServiceType::Oneshot,
+            first_boot_only: true,
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        assert_eq!(
+            unit_cfg,
+            "[Unit]\n\
+            Before=first-boot-complete.target\n\
+            ConditionFirstBoot=yes\n\
+            Description=provision\n\
+            Wants=first-boot-complete.target\n\
+            [Install]\n\
+            WantedBy=multi-user.target\n\
+            [Service]\n\
+            ExecStart=\"/usr/local/bin/provision\"\n\
+            Type=oneshot\n\
+            ",
+        )
+    }
+
+    #[test]
+    fn test_systemd_dbus_render() {
+        let service = ServiceConfig {
+            name: "foo".into(),
+            command: string_vec!["/usr/bin/foo-daemon"],
+            service_type: ServiceType::Dbus,
+            bus_name: Some("org.example.Foo".into()),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("Alias=dbus-org.example.Foo.service\n"));
+        assert!(unit_cfg.contains("BusName=org.example.Foo\nExecStart="));
+        assert!(unit_cfg.contains("Type=dbus\n"));
+        assert_eq!(
+            systemd.to_dbus_service().unwrap().unwrap(),
+            "[D-BUS Service]\n\
+            Exec=/bin/false\n\
+            Name=org.example.Foo\n\
+            SystemdService=foo.service\n\
+            User=root\n\
+            ",
+        )
+    }
+
+    #[test]
+    fn test_systemd_passthrough_env_render() {
+        let service = ServiceConfig {
+            name: "agent".into(),
+            command: string_vec!["/usr/bin/agent"],
+            env: convert_args!(btreemap!("DISPLAY" => ":0")),
+            passthrough_env: Some(PassthroughEnv::List(string_vec!["SSH_AUTH_SOCK"])),
+            ..Default::default()
+        };
+        let unit_cfg = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("PassEnvironment=SSH_AUTH_SOCK\n"));
+        let unset = unit_cfg
+            .lines()
+            .find_map(|line| line.strip_prefix("UnsetEnvironment="))
+            .unwrap();
+        assert!(unset.contains("WAYLAND_DISPLAY"));
+        assert!(!unset.contains("SSH_AUTH_SOCK"));
+        assert!(!unset.split(' ').any(|var| var == "DISPLAY"));
+        assert!(!unset.contains("LANG"));
+    }
+
+    #[test]
+    fn test_systemd_tty_render() {
+        let service = ServiceConfig {
+            name: "kiosk".into(),
+            command: string_vec!["/usr/bin/kiosk"],
+            tty: Some("/dev/tty2".into()),
+            standard_input: Some(StandardInput::TtyForce),
+            ..Default::default()
+        };
+        let unit_cfg = Systemd { service }.to_systemd_unit().unwrap();
+        for line in [
+            "StandardInput=tty-force",
+            "TTYPath=/dev/tty2",
+            "TTYReset=yes",
+            "TTYVHangup=yes",
+        ] {
+            assert!(unit_cfg.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn test_systemd_kiosk_render() {
+        let service = ServiceConfig {
+            name: "kiosk".into(),
+            command: string_vec!["/usr/bin/startx"],
+            user: Some("kiosk".into()),
+            tty: Some("/dev/tty1".into()),
+            standard_input: Some(StandardInput::TtyForce),
+            kiosk: true,
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        for line in [
+            "Conflicts=getty@tty1.service",
+            "After=getty@tty1.service",
+            "After=systemd-user-sessions.service",
+            "User=kiosk",
+            "PAMName=login",
+            "UtmpIdentifier=tty1",
+            "Restart=always",
+            "WantedBy=graphical.target",
+        ] {
+            assert!(unit_cfg.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(!unit_cfg.contains("WantedBy=multi-user.target"));
+        assert_eq!(
+            systemd.wants_links(),
+            [(
+                PathBuf::from("graphical.target.wants/kiosk.service"),
+                "kiosk.service".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_systemd_socket_render() {
+        let service = ServiceConfig {
+            name: "echo".into(),
+            command: string_vec!["/usr/bin/echo-server"],
+            listen: string_vec!["tcp:7", "unix:/run/echo.sock", "udp:7", "sctp:7"],
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        for line in [
+            "Requires=echo.socket",
+            "After=echo.socket",
+            "Also=echo.socket",
+        ] {
+            assert!(unit_cfg.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(!unit_cfg.contains("WantedBy="));
+        assert_eq!(
+            systemd.to_systemd_socket().unwrap().unwrap(),
+            "[Unit]\n\
+            Description=Socket for echo\n\
+            [Socket]\n\
+            ListenDatagram=7\n\
+            ListenSequentialPacket=7\n\
+            ListenStream=7\n\
+            ListenStream=/run/echo.sock\n\
+            [Install]\n\
+            WantedBy=sockets.target\n\
+            ",
+        );
+        assert_eq!(systemd.activation_units(), vec!["echo.socket"]);
+    }
+
+    #[test]
+    fn test_systemd_accept_render() {
+        let service = ServiceConfig {
+            name: "echo".into(),
+            command: string_vec!["/usr/bin/cat"],
+            listen: string_vec!["tcp:2222"],
+            accept: true,
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let files: Vec<String> = systemd
+            .unit_files()
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(files, vec!["echo@.service", "echo.socket"]);
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("StandardInput=socket\n"));
+        assert!(!unit_cfg.contains("Restart=") && !unit_cfg.contains("Requires="));
+        assert!(systemd
+            .to_systemd_socket()
+            .unwrap()
+            .unwrap()
+            .contains("[Socket]\nAccept=yes\nListenStream=2222\n"));
+        assert_eq!(systemd.status_unit(), "echo.socket");
+        assert_eq!(
+            systemd.wants_links(),
+            vec![(
+                PathBuf::from("sockets.target.wants/echo.socket"),
+                "echo.socket".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_systemd_socket_access_render() {
+        let service = ServiceConfig {
+            name: "api".into(),
+            command: string_vec!["/usr/bin/api"],
+            listen: string_vec!["tcp:8080", "unix:/run/api.sock"],
+            socket_allow: string_vec!["10.0.0.0/8", "localhost"],
+            socket_bind_device: Some("eth1".into()),
+            socket_user: Some("app".into()),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let socket = systemd.to_systemd_socket().unwrap().unwrap();
+        for line in [
+            "IPAddressAllow=10.0.0.0/8 localhost",
+            "IPAddressDeny=any",
+            "BindToDevice=eth1",
+            "SocketUser=app",
+            "SocketMode=0600",
+        ] {
+            assert!(socket.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn test_systemd_fd_store_render() {
+        let service = ServiceConfig {
+            name: "proxy".into(),
+            command: string_vec!["/usr/bin/proxy"],
+            listen: string_vec!["abstract:proxy"],
+            socket_fd_name: Some("listener".into()),
+            fd_store_max: Some(64),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("FileDescriptorStoreMax=64\n"));
+        let socket = systemd.to_systemd_socket().unwrap().unwrap();
+        assert!(socket.contains("[Socket]\nFileDescriptorName=listener\nListenStream=@proxy\n"));
+    }
+
+    #[test]
+    fn test_systemd_graphical_render() {
+        let service = ServiceConfig {
+            name: "tray".into(),
+            command: string_vec!["/usr/bin/tray"],
+            level: ServiceLevel::User,
+            graphical: true,
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        for line in [
+            "PartOf=graphical-session.target",
+            "After=graphical-session.target",
+            "WantedBy=graphical-session.target",
+        ] {
+            assert!(unit_cfg.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert_eq!(
+            systemd.wants_links(),
+            vec![(
+                PathBuf::from("graphical-session.target.wants/tray.service"),
+                "tray.service".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_systemd_unit_options_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            env: convert_args!(btreemap!("TZ" => "Europe/Berlin")),
+            depends_on: string_vec!["db"],
+            unit_options: convert_args!(btreemap!(
+                "Unit" => convert_args!(btreemap!(
+                    "After" => string_vec!["network-online.target"],
+                )),
+                "Service" => convert_args!(btreemap!(
+                    "Restart" => string_vec!["always"],
+                    "Environment" => string_vec!["TZ=UTC"],
+                )),
+            )),
+            ..Default::default()
+        };
+        let unit_cfg = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("Environment=TZ=UTC\nEnvironment=TZ=Europe/Berlin\n"));
+        assert!(unit_cfg.contains("Restart=always\n"));
+        assert!(unit_cfg.contains("After=network-online.target\nAfter=db.service\n"));
+    }
+
+    #[test]
+    fn test_systemd_resource_limits_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            memory_max: Some("512M".into()),
+            cpu_quota: Some("50%".into()),
+            tasks_max: Some("64".into()),
+            unit_options: convert_args!(btreemap!(
+                "Service" => convert_args!(btreemap!("MemoryMax" => string_vec!["1G"])),
+            )),
+            ..Default::default()
+        };
+        let unit_cfg = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("CPUQuota=50%\n"));
+        assert!(unit_cfg.contains("MemoryMax=512M\n"));
+        assert!(unit_cfg.contains("TasksMax=64\n"));
+    }
+
+    #[test]
+    fn test_systemd_header_render() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            command: string_vec!["/usr/bin/web"],
+            header: Some("Managed by mkservice.\nOwner: ops".into()),
+            ..Default::default()
+        };
+        let unit_cfg = Systemd { service }.to_systemd_unit().unwrap();
+        assert!(unit_cfg.starts_with("# Managed by mkservice.\n# Owner: ops\n[Unit]\n"));
+    }
+
+    #[test]
+    fn test_systemd_instances() {
+        let service = ServiceConfig {
+            name: "api".into(),
+            command: string_vec!["/usr/bin/node", "/srv/api/server.js"],
+            instances: Some(2),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        assert_eq!(
+            systemd.activation_units(),
+            string_vec!["api@0.service", "api@1.service"]
+        );
+        assert_eq!(systemd.service_file_name(), "api@.service");
+        assert_eq!(systemd.status_unit(), "api@0.service");
+    }
+
+    #[test]
+    fn test_systemd_failure_mail_render() {
+        let service = ServiceConfig {
+            name: "backup".into(),
+            command: string_vec!["/usr/local/bin/backup"],
+            service_type: ServiceType::Oneshot,
+            failure_mail: Some("ops@example.com".into()),
+            ..Default::default()
+        };
+        let systemd = Systemd { service };
+        let unit_cfg = systemd.to_systemd_unit().unwrap();
+        assert!(unit_cfg.contains("OnFailure=backup-failure-mail.service\n"));
+        let mail_unit = systemd.to_failure_mail_unit().unwrap().unwrap();
+        assert!(mail_unit.contains("Type=oneshot\n"));
+        assert!(mail_unit.contains("ExecStart=\"/bin/sh\" \"-c\" \"systemctl status --full --no-pager 'backup.service' | mail -s 'backup.service failed on %H' 'ops@example.com'\"\n"));
+    }
+
+    #[test]
+    fn test_run_checked() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo 'Unit web.service not found.' >&2; exit 5"]);
+        match run_checked(command) {
+            Err(MkserviceError::CommandFailed { cmd, stderr }) => {
+                assert_eq!(cmd, "sh -c echo 'Unit web.service not found.' >&2; exit 5");
+                assert_eq!(stderr, "Unit web.service not found.\n");
+            }
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo 'Created symlink.' >&2"]);
+        assert!(run_checked(command).is_ok());
+        assert!(matches!(
+            run_checked(Command::new("/nonexistent/systemctl")),
+            Err(MkserviceError::CommandNotFound { cmd }) if cmd == "/nonexistent/systemctl"
+        ));
+
+        let timer = TimerConfig {
+            on_calendar: string_vec!["daily"],
+            ..Default::default()
+        };
+        let systemd = Systemd {
+            service: ServiceConfig {
+                name: "backup".into(),
+                timer: Some(timer),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            systemd.systemctl_args("restart", &["db.service".to_string()]),
+            ["restart", "backup.timer", "db.service"]
+        );
+    }
 }
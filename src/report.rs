@@ -0,0 +1,481 @@
+use crate::command;
+use crate::config::{ServiceConfig, ServiceLevel, ServiceType, TimerConfig};
+use crate::error::{self, MkserviceError};
+use crate::manifest;
+use crate::output::{self, Porcelain};
+use crate::provider::{self, ServiceStatus};
+use crate::schedule;
+use crate::stack;
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt::Write;
+use std::fs;
+use std::io::Write as _;
+use std::process::{Command, Stdio};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+    Json,
+}
+
+/// A managed service along with what the provider reports about it.
+pub struct ReportEntry {
+    pub service: ServiceConfig,
+    pub status: error::Result<ServiceStatus>,
+}
+
+impl ReportEntry {
+    fn findings(&self) -> Vec<String> {
+        let mut findings = Vec::new();
+        if !self
+            .service
+            .command
+            .first()
+            .is_some_and(|c| c.starts_with('/'))
+        {
+            findings.push("Command isn't an absolute path.".to_string());
+        }
+        match &self.status {
+            Err(e) => findings.push(format!("Status unavailable: {}", e)),
+            Ok(status) => {
+                if status.state.starts_with("failed") {
+                    findings.push("Service has failed.".into());
+                }
+                if self.service.timer.is_none() && status.enabled != "enabled" {
+                    findings.push(format!("Not enabled at boot ({}).", status.enabled));
+                }
+                if let Some(restarts @ 1..) = status.restarts {
+                    findings.push(format!("Restarted {} time(s).", restarts));
+                }
+            }
+        }
+        findings
+    }
+
+    fn facts(&self) -> Vec<(&'static str, String)> {
+        let service = &self.service;
+        let mut facts = vec![
+            ("Level", format!("{:?}", service.level).to_lowercase()),
+            ("Type", format!("{:?}", service.service_type).to_lowercase()),
+        ];
+        for command in std::iter::once(&service.command).chain(&service.steps) {
+            facts.push(("Command", command.join(" ")));
+        }
+        if let Some(timer) = &service.timer {
+            let mut schedule = timer.on_calendar.clone();
+            schedule.extend(timer.on_boot.iter().map(|s| format!("{} after boot", s)));
+            schedule.extend(
+                timer
+                    .on_unit_inactive
+                    .iter()
+                    .map(|s| format!("{} after last run", s)),
+            );
+            facts.push(("Schedule", schedule.join(", ")));
+        }
+        if !service.env.is_empty() {
+            // Only the names, values may well be secrets.
+            let names: Vec<&str> = service.env.keys().map(String::as_str).collect();
+            facts.push(("Environment", names.join(", ")));
+        }
+        if let Ok(status) = &self.status {
+            for (limit, value) in &status.limits {
+                facts.push(("Limit", format!("{}={}", limit, value)));
+            }
+            if let Some(result) = &status.last_result {
+                let mut last_run = result.clone();
+                if let Some(code) = status.last_exit_status {
+                    last_run += &format!(", exit status {}", code);
+                }
+                if let Some(time) = &status.last_exit_time {
+                    last_run += &format!(", at {}", time);
+                }
+                facts.push(("Last run", last_run));
+            }
+        }
+        facts
+    }
+
+    fn summary(&self) -> [String; 4] {
+        match &self.status {
+            Ok(status) => [
+                status.state.clone(),
+                status.enabled.clone(),
+                status.restarts.map(|r| r.to_string()).unwrap_or_default(),
+                status.last_result.clone().unwrap_or_default(),
+            ],
+            Err(_) => ["unknown".into(), "".into(), "".into(), "".into()],
+        }
+    }
+}
+
+/// Collects every managed service at the given levels along with its status.
+pub fn collect(levels: &[ServiceLevel]) -> Result<Vec<ReportEntry>> {
+    let mut entries = Vec::new();
+    for level in levels {
+        for service in manifest::load_all(level)? {
+            let status = provider::get_provider(service.clone()).and_then(|p| p.status());
+            entries.push(ReportEntry { service, status });
+        }
+    }
+    Ok(entries)
+}
+
+const SUMMARY_HEADERS: [&str; 5] = ["Service", "State", "Enabled", "Restarts", "Last result"];
+
+pub fn render_markdown(entries: &[ReportEntry]) -> Result<String> {
+    let mut out = String::from("# mkservice report\n\n");
+    out += &format!("| {} |\n", SUMMARY_HEADERS.join(" | "));
+    out += &format!("|{}\n", "---|".repeat(SUMMARY_HEADERS.len()));
+    for entry in entries {
+        let cells: Vec<String> = std::iter::once(&entry.service.name)
+            .chain(&entry.summary())
+            .map(|cell| table_cell(cell))
+            .collect();
+        writeln!(out, "| {} |", cells.join(" | "))?;
+    }
+    for entry in entries {
+        write!(out, "\n## {}\n\n", entry.service.name)?;
+        for (fact, value) in entry.facts() {
+            writeln!(out, "- {}: {}", fact, code_span(&value))?;
+        }
+        let findings = entry.findings();
+        if !findings.is_empty() {
+            out += "\nFindings:\n\n";
+            for finding in findings {
+                writeln!(out, "- {}", finding)?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// `value` as a Markdown table cell, where a `|` would end the cell.
+fn table_cell(value: &str) -> String {
+    value.replace('|', "\\|").replace('\n', " ")
+}
+
+/// `value` as Markdown inline code, fenced with more backticks than it
+/// contains in a row.
+fn code_span(value: &str) -> String {
+    let longest = value.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest + 1);
+    // A backtick next to the fence would join it, a space keeps them apart.
+    let pad = if value.starts_with('`') || value.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{}{}{}{}{}", fence, pad, value, pad, fence)
+}
+
+/// A service as listed in the JSON inventory.
+#[derive(Serialize)]
+struct InventoryEntry<'a> {
+    name: &'a str,
+    level: &'a ServiceLevel,
+    #[serde(rename = "type")]
+    service_type: ServiceType,
+    commands: Vec<&'a Vec<String>>,
+    timer: Option<&'a TimerConfig>,
+    /// Only the names, values may well be secrets.
+    env: Vec<&'a str>,
+    status: Option<&'a ServiceStatus>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Inventory<'a> {
+    host: String,
+    services: Vec<InventoryEntry<'a>>,
+}
+
+pub fn render_json(entries: &[ReportEntry]) -> Result<String> {
+    let host = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    let services = entries
+        .iter()
+        .map(|entry| InventoryEntry {
+            name: &entry.service.name,
+            level: &entry.service.level,
+            service_type: entry.service.service_type,
+            commands: std::iter::once(&entry.service.command)
+                .chain(&entry.service.steps)
+                .collect(),
+            timer: entry.service.timer.as_ref(),
+            env: entry.service.env.keys().map(String::as_str).collect(),
+            status: entry.status.as_ref().ok(),
+            error: entry.status.as_ref().err().map(|e| e.to_string()),
+        })
+        .collect();
+    let inventory = Inventory {
+        host: host.trim().to_string(),
+        services,
+    };
+    Ok(serde_json::to_string_pretty(&inventory)? + "\n")
+}
+
+/// POSTs the JSON inventory to `url`. Uses curl rather than bundling an
+/// HTTP client for something run once in a while.
+pub fn push(url: &str, inventory: &str) -> error::Result<()> {
+    let mut child = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--data-binary", "@-"])
+        .args(["--header", "Content-Type: application/json", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| MkserviceError::spawn("curl", e))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(inventory.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: format!("curl {}", url),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// A managed service that pushes the inventory to `url` on the `every`
+/// schedule, see `schedule::every_to_calendar`.
+pub fn push_service(url: &str, level: Option<&ServiceLevel>, every: &str) -> Result<ServiceConfig> {
+    let mut command = vec![
+        command::mkservice_program()?,
+        "report".into(),
+        "--push".into(),
+        url.into(),
+    ];
+    if let Some(level) = level {
+        command.push("--level".into());
+        command.push(format!("{:?}", level).to_lowercase());
+    }
+    Ok(ServiceConfig {
+        name: "mkservice-push".into(),
+        command,
+        service_type: ServiceType::Oneshot,
+        level: level.cloned().unwrap_or_default(),
+        timer: Some(TimerConfig {
+            on_calendar: vec![schedule::every_to_calendar(every)?],
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// One line per service, aligned for reading.
+/// One service's state and configuration, for `mkservice status`.
+pub fn render_status(entry: &ReportEntry, color: bool) -> Result<String> {
+    let mut out = String::new();
+    match &entry.status {
+        Ok(status) => {
+            let state = output::paint_state(&status.state, color);
+            writeln!(out, "{} - {}", entry.service.name, state)?;
+            let mut facts = vec![("Enabled", status.enabled.clone())];
+            facts.extend(status.uptime.map(|s| ("Uptime", stack::format_uptime(s))));
+            facts.extend(status.restarts.map(|r| ("Restarts", r.to_string())));
+            for (name, value) in facts {
+                writeln!(out, "  {}: {}", name, value)?;
+            }
+        }
+        Err(e) => writeln!(out, "{} - unknown ({})", entry.service.name, e)?,
+    }
+    for (name, value) in entry.facts() {
+        writeln!(out, "  {}: {}", name, value)?;
+    }
+    Ok(out)
+}
+
+pub fn render_list(entries: &[ReportEntry], color: bool) -> Result<String> {
+    let width = entries
+        .iter()
+        .map(|entry| entry.service.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+    let mut out = format!("{:width$}  {:6}  {:9}  STATE\n", "NAME", "LEVEL", "ENABLED");
+    for entry in entries {
+        let [state, enabled, ..] = entry.summary();
+        writeln!(
+            out,
+            "{:width$}  {:6}  {:9}  {}",
+            entry.service.name,
+            format!("{:?}", entry.service.level).to_lowercase(),
+            enabled,
+            output::paint_state(&state, color),
+        )?;
+    }
+    Ok(out)
+}
+
+/// Tab-separated lines for scripts, in a format that's stable per version.
+///
+/// v1: name, level, type, state, enabled, restarts, last result. Empty
+/// fields are "-".
+pub fn render_porcelain(entries: &[ReportEntry], version: Porcelain) -> Result<String> {
+    let mut out = String::new();
+    for entry in entries {
+        let fields = match version {
+            Porcelain::V1 => {
+                let [state, enabled, restarts, last_result] = entry.summary();
+                [
+                    entry.service.name.clone(),
+                    format!("{:?}", entry.service.level).to_lowercase(),
+                    format!("{:?}", entry.service.service_type).to_lowercase(),
+                    state,
+                    enabled,
+                    restarts,
+                    last_result,
+                ]
+            }
+        };
+        let fields: Vec<String> = fields.iter().map(|f| output::porcelain_field(f)).collect();
+        writeln!(out, "{}", fields.join("\t"))?;
+    }
+    Ok(out)
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render_html(entries: &[ReportEntry]) -> Result<String> {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>mkservice report</title></head>\n<body>\n<h1>mkservice report</h1>\n<table>\n<tr>",
+    );
+    for header in SUMMARY_HEADERS {
+        write!(out, "<th>{}</th>", header)?;
+    }
+    out += "</tr>\n";
+    for entry in entries {
+        write!(out, "<tr><td>{}</td>", html_escape(&entry.service.name))?;
+        for cell in entry.summary() {
+            write!(out, "<td>{}</td>", html_escape(&cell))?;
+        }
+        out += "</tr>\n";
+    }
+    out += "</table>\n";
+    for entry in entries {
+        writeln!(out, "<h2>{}</h2>\n<dl>", html_escape(&entry.service.name))?;
+        for (fact, value) in entry.facts() {
+            writeln!(
+                out,
+                "<dt>{}</dt><dd><code>{}</code></dd>",
+                fact,
+                html_escape(&value)
+            )?;
+        }
+        out += "</dl>\n";
+        let findings = entry.findings();
+        if !findings.is_empty() {
+            out += "<ul>\n";
+            for finding in findings {
+                writeln!(out, "<li>{}</li>", html_escape(&finding))?;
+            }
+            out += "</ul>\n";
+        }
+    }
+    out += "</body>\n</html>\n";
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_markdown_escapes() {
+        let entries = vec![ReportEntry {
+            service: ServiceConfig {
+                name: "web".into(),
+                command: vec!["/bin/sh".into(), "-c".into(), "echo `date` | logger".into()],
+                ..Default::default()
+            },
+            status: Ok(ServiceStatus {
+                state: "active (running)".into(),
+                enabled: "enabled".into(),
+                last_result: Some("exit-code|signal".into()),
+                ..Default::default()
+            }),
+        }];
+        let markdown = render_markdown(&entries).unwrap();
+        assert!(markdown.contains("| web | active (running) | enabled |  | exit-code\\|signal |\n"));
+        assert!(markdown.contains("- Command: ``/bin/sh -c echo `date` | logger``\n"));
+        assert_eq!(code_span("`x`"), "`` `x` ``");
+        assert_eq!(code_span("plain"), "`plain`");
+    }
+
+    #[test]
+    fn test_render_porcelain() {
+        let entries = vec![ReportEntry {
+            service: ServiceConfig {
+                name: "web".into(),
+                ..Default::default()
+            },
+            status: Ok(ServiceStatus {
+                state: "active (running)".into(),
+                enabled: "enabled".into(),
+                restarts: Some(0),
+                ..Default::default()
+            }),
+        }];
+        assert_eq!(
+            render_porcelain(&entries, Porcelain::V1).unwrap(),
+            "web\tsystem\tsimple\tactive (running)\tenabled\t0\t-\n"
+        );
+    }
+
+    #[test]
+    fn test_render_status() {
+        let entry = ReportEntry {
+            service: ServiceConfig {
+                name: "web".into(),
+                command: vec!["/usr/bin/web".into()],
+                ..Default::default()
+            },
+            status: Ok(ServiceStatus {
+                state: "active (running)".into(),
+                enabled: "enabled".into(),
+                restarts: Some(1),
+                uptime: Some(3720),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(
+            render_status(&entry, false).unwrap(),
+            "web - active (running)\n\
+            \x20 Enabled: enabled\n\
+            \x20 Uptime: 1h 2m\n\
+            \x20 Restarts: 1\n\
+            \x20 Level: system\n\
+            \x20 Type: simple\n\
+            \x20 Command: /usr/bin/web\n"
+        );
+    }
+
+    #[test]
+    fn test_render_json_hides_env_values() {
+        let entries = vec![ReportEntry {
+            service: ServiceConfig {
+                name: "web".into(),
+                command: vec!["/usr/bin/web".into()],
+                env: [("TOKEN".to_string(), "hunter2".to_string())].into(),
+                ..Default::default()
+            },
+            status: Ok(ServiceStatus::default()),
+        }];
+        let json = render_json(&entries).unwrap();
+        let inventory: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let service = &inventory["services"][0];
+        assert_eq!(service["name"], "web");
+        assert_eq!(service["env"], serde_json::json!(["TOKEN"]));
+        assert!(!json.contains("hunter2"));
+    }
+}
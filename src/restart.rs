@@ -0,0 +1,242 @@
+//! Restarting sets of managed services, all at once or one at a time with a
+//! health check after each, and checking services come up once started.
+
+use crate::config::{ServiceConfig, ServiceLevel, ServiceType};
+use crate::manifest;
+use crate::provider::{self, ServiceStatus};
+use crate::ServiceOperator;
+use anyhow::{anyhow, Context, Result};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long a started service must stay up to count as started.
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// How often to look at a starting service's state.
+const POLL: Duration = Duration::from_millis(250);
+
+/// The managed services called `names` plus those tagged with any of `tags`,
+/// by name. Timer-triggered services are left out, restarting them would
+/// run their job.
+pub fn select(
+    names: &[String],
+    tags: &[String],
+    level: Option<&ServiceLevel>,
+) -> Result<Vec<ServiceConfig>> {
+    let mut services = Vec::new();
+    for name in names {
+        services.push(manifest::find(name, level)?);
+    }
+    for level in manifest::levels(level) {
+        for service in manifest::load_all(&level)? {
+            if service.tags.iter().any(|tag| tags.contains(tag)) {
+                services.push(service);
+            }
+        }
+    }
+    services.sort_by(|a, b| a.name.cmp(&b.name).then(a.level.cmp(&b.level)));
+    services.dedup_by(|a, b| a.name == b.name && a.level == b.level);
+    services.retain(|service| {
+        let triggered = service.timer.is_some();
+        if triggered {
+            log::info!("Skipping {:?}, its timer starts it.", service.name);
+        }
+        !triggered
+    });
+    if services.is_empty() {
+        return Err(anyhow!("No managed services to restart."));
+    }
+    Ok(services)
+}
+
+/// Checks that `service` is healthy, given its status right after the restart
+/// and now. A service the manager had to restart since isn't.
+fn check_health(
+    service: &ServiceConfig,
+    restarted: &ServiceStatus,
+    now: &ServiceStatus,
+) -> Result<()> {
+    let ran_once = service.service_type == ServiceType::Oneshot
+        && now.last_result.as_deref() == Some("success");
+    if !now.state.starts_with("active ") && !ran_once {
+        return Err(anyhow!(
+            "{:?} is {} after restarting.",
+            service.name,
+            now.state
+        ));
+    }
+    if now.restarts.unwrap_or(0) > restarted.restarts.unwrap_or(0) {
+        return Err(anyhow!(
+            "{:?} crashed and was restarted {} time(s) since.",
+            service.name,
+            now.restarts.unwrap_or(0) - restarted.restarts.unwrap_or(0)
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `service`, started when its status was `started`, is up by `now`:
+/// active, or a oneshot that has run. A failed service, or one the manager
+/// restarted since, is an error.
+fn is_up(service: &ServiceConfig, started: &ServiceStatus, now: &ServiceStatus) -> Result<bool> {
+    if service.service_type == ServiceType::Oneshot && now.last_result.as_deref() == Some("success")
+    {
+        return Ok(true);
+    }
+    if now.state.starts_with("failed ") {
+        return Err(anyhow!(
+            "{:?} failed to start ({}).",
+            service.name,
+            now.last_result.as_deref().unwrap_or("no result")
+        ));
+    }
+    if now.restarts.unwrap_or(0) > started.restarts.unwrap_or(0)
+        || now.state.contains("auto-restart")
+    {
+        return Err(anyhow!(
+            "{:?} crashed on start and is being restarted.",
+            service.name
+        ));
+    }
+    Ok(now.state.starts_with("active "))
+}
+
+/// Waits up to `timeout` for `service`, just started, to come up and stay up
+/// for a moment, so a service crashing on launch isn't taken as started.
+pub fn wait_started(
+    service: &ServiceConfig,
+    p: &dyn ServiceOperator,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let started = p.status()?;
+    let mut up_since = None;
+    loop {
+        let now = p.status()?;
+        match is_up(service, &started, &now)? {
+            true if service.service_type == ServiceType::Oneshot => return Ok(()),
+            true => {
+                if up_since.get_or_insert_with(Instant::now).elapsed() >= SETTLE {
+                    return Ok(());
+                }
+            }
+            false => up_since = None,
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "{:?} is still {} after {:?}.",
+                service.name,
+                now.state,
+                timeout
+            ));
+        }
+        sleep(POLL);
+    }
+}
+
+/// Restarts `services`. Rolling, each is restarted and checked after
+/// `delay` before the next, stopping at the first unhealthy one. Otherwise
+/// all are restarted, then checked after `delay`.
+pub fn restart(services: &[ServiceConfig], rolling: bool, delay: Duration) -> Result<()> {
+    let mut restarted = Vec::new();
+    for (i, service) in services.iter().enumerate() {
+        let p = provider::get_provider(service.clone())?;
+        p.restart()
+            .with_context(|| format!("Failed restarting {:?}", service.name))?;
+        let status = p.status()?;
+        if !rolling {
+            restarted.push((service, p, status));
+            continue;
+        }
+        sleep(delay);
+        check_health(service, &status, &p.status()?).with_context(|| {
+            format!(
+                "Stopped the rolling restart, {} service(s) not restarted",
+                services.len() - i - 1
+            )
+        })?;
+        log::info!(
+            "Restarted {:?} ({}/{}).",
+            service.name,
+            i + 1,
+            services.len()
+        );
+    }
+    if rolling {
+        return Ok(());
+    }
+    sleep(delay);
+    let mut unhealthy = 0;
+    for (service, p, status) in restarted {
+        match check_health(service, &status, &p.status()?) {
+            Ok(()) => log::info!("Restarted {:?}.", service.name),
+            Err(e) => {
+                log::error!("{}", e);
+                unhealthy += 1;
+            }
+        }
+    }
+    match unhealthy {
+        0 => Ok(()),
+        n => Err(anyhow!("{} service(s) unhealthy after restarting.", n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_health() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            ..Default::default()
+        };
+        let status = |state: &str, restarts| ServiceStatus {
+            state: state.into(),
+            restarts: Some(restarts),
+            ..Default::default()
+        };
+        let restarted = status("active (running)", 2);
+        assert!(check_health(&service, &restarted, &status("active (running)", 2)).is_ok());
+        assert!(check_health(&service, &restarted, &status("active (running)", 3)).is_err());
+        assert!(check_health(&service, &restarted, &status("failed (failed)", 2)).is_err());
+        let job = ServiceConfig {
+            service_type: ServiceType::Oneshot,
+            ..service
+        };
+        let done = ServiceStatus {
+            last_result: Some("success".into()),
+            ..status("inactive (dead)", 0)
+        };
+        assert!(check_health(&job, &status("activating (start)", 0), &done).is_ok());
+    }
+
+    #[test]
+    fn test_is_up() {
+        let service = ServiceConfig {
+            name: "web".into(),
+            ..Default::default()
+        };
+        let status = |state: &str, restarts| ServiceStatus {
+            state: state.into(),
+            restarts: Some(restarts),
+            ..Default::default()
+        };
+        let started = status("activating (start)", 0);
+        assert!(!is_up(&service, &started, &started).unwrap());
+        assert!(is_up(&service, &started, &status("active (running)", 0)).unwrap());
+        assert!(is_up(&service, &started, &status("active (running)", 1)).is_err());
+        assert!(is_up(&service, &started, &status("activating (auto-restart)", 0)).is_err());
+        assert!(is_up(&service, &started, &status("failed (failed)", 0)).is_err());
+        let job = ServiceConfig {
+            service_type: ServiceType::Oneshot,
+            ..service
+        };
+        let done = ServiceStatus {
+            last_result: Some("success".into()),
+            ..status("inactive (dead)", 0)
+        };
+        assert!(is_up(&job, &started, &done).unwrap());
+    }
+}
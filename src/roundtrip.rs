@@ -0,0 +1,236 @@
+//! Round trips between the importers and the systemd provider. Every format
+//! converts through `ServiceConfig`, so a service rendered to units and
+//! imported back must be the same service, rendering to the same units.
+
+use crate::config::{PassthroughEnv, ServiceConfig, ServiceType, StandardInput, TimerConfig};
+use crate::import;
+use crate::provider::systemd::Systemd;
+use crate::ServiceOperator;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+type Files = Vec<(String, String)>;
+
+/// The unit files for `service`, by file name.
+fn render(service: &ServiceConfig) -> Files {
+    let systemd = Systemd {
+        service: service.clone(),
+    };
+    systemd
+        .unit_files()
+        .unwrap()
+        .into_iter()
+        .map(|(path, content)| {
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (file_name, content)
+        })
+        .collect()
+}
+
+/// Imports rendered units, the service unit being the first.
+fn reimport(files: &Files) -> ServiceConfig {
+    let find = |suffix: &str| {
+        files
+            .iter()
+            .find(|(name, _)| name.ends_with(suffix))
+            .map(|(_, content)| content.as_str())
+    };
+    let (name, content) = &files[0];
+    import::systemd::convert(name, content, find(".timer"), find("-failure-mail.service")).unwrap()
+}
+
+/// systemd -> IR -> systemd gives the same units.
+fn assert_units_round_trip(service: &ServiceConfig) -> ServiceConfig {
+    let files = render(service);
+    let imported = reimport(&files);
+    assert_eq!(render(&imported), files, "{}", service.name);
+    imported
+}
+
+/// IR -> systemd -> IR gives the same service, and the same units again.
+fn assert_round_trip(service: ServiceConfig) {
+    let imported = assert_units_round_trip(&service);
+    assert_eq!(imported, service);
+}
+
+/// An importer's service may spell out systemd's defaults, but once through
+/// systemd it must round-trip exactly.
+fn assert_stable(service: ServiceConfig) {
+    assert_round_trip(assert_units_round_trip(&service));
+}
+
+#[test]
+fn test_round_trip_services() {
+    assert_round_trip(ServiceConfig {
+        name: "nightly-backup".into(),
+        command: vec!["/usr/bin/backup".into(), "--label".into(), "\"a b\"".into()],
+        steps: vec![vec!["/usr/bin/prune".into(), "--keep=7".into()]],
+        service_type: ServiceType::Oneshot,
+        env: BTreeMap::from([("TARGET".into(), "s3://backups".into())]),
+        env_files: vec!["/etc/backup.env".into()],
+        working_directory: Some("/var/lib/backup".into()),
+        timer: Some(TimerConfig {
+            on_calendar: vec!["*-*-* 02:30:00".into(), "Sun *-*-* 12:00:00".into()],
+            on_boot: Some("15min".into()),
+            randomized_delay: Some("5m".into()),
+            persistent: Some(true),
+            ..Default::default()
+        }),
+        passthrough_env: Some(PassthroughEnv::None),
+        user: Some("backup".into()),
+        depends_on: vec!["db".into(), "object-store".into()],
+        requires_mounts: vec!["/srv/data".into()],
+        failure_mail: Some("ops@example.com".into()),
+        header: Some("Managed by ops\nTicket: OPS-1".into()),
+        unit_options: BTreeMap::from([(
+            "Service".into(),
+            BTreeMap::from([("Nice".into(), vec!["10".into()])]),
+        )]),
+        ..Default::default()
+    });
+    assert_round_trip(ServiceConfig {
+        name: "notifier".into(),
+        command: vec!["/usr/bin/notifier".into()],
+        service_type: ServiceType::Dbus,
+        bus_name: Some("org.example.Notifier".into()),
+        passthrough_env: Some(PassthroughEnv::List(vec!["LANG".into()])),
+        tty: Some("/dev/tty3".into()),
+        standard_input: Some(StandardInput::TtyFail),
+        first_boot_only: true,
+        ..Default::default()
+    });
+    assert_round_trip(ServiceConfig {
+        name: "tray".into(),
+        command: vec!["/usr/bin/tray".into()],
+        graphical: true,
+        depends_on: vec!["agent".into()],
+        ..Default::default()
+    });
+    // Kiosks are kept as their directives, which render the same.
+    let mut kiosk = ServiceConfig {
+        name: "signage".into(),
+        command: vec![
+            "/usr/bin/cage".into(),
+            "--".into(),
+            "/usr/bin/browser".into(),
+        ],
+        ..Default::default()
+    };
+    crate::kiosk::apply(&mut kiosk, "signage").unwrap();
+    assert_units_round_trip(&kiosk);
+}
+
+#[test]
+fn test_round_trip_hand_written_unit() {
+    let service = import::systemd::convert(
+        "api.service",
+        "[Unit]\n\
+        Description=API server\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=notify\n\
+        ExecStart=/opt/api/bin/api --config /etc/api.toml\n\
+        Restart=always\n\
+        LimitNOFILE=65536\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n",
+        None,
+        None,
+    )
+    .unwrap();
+    assert_round_trip(service);
+}
+
+#[test]
+fn test_round_trip_compose() {
+    let services = import::compose::parse(
+        r#"
+services:
+  web:
+    image: nginx:1.25
+    command: nginx -g 'daemon off;'
+    environment:
+      TZ: UTC
+    ports: ["8080:80"]
+    volumes: ["./html:/usr/share/nginx/html:ro"]
+    restart: unless-stopped
+    depends_on: [db]
+  db:
+    image: postgres:16
+    env_file: ./db.env
+    restart: on-failure
+"#,
+        "/usr/bin/podman",
+        Path::new("/srv/app"),
+    )
+    .unwrap();
+    for service in services {
+        assert_stable(service);
+    }
+}
+
+#[test]
+fn test_round_trip_supervisor() {
+    let sections = import::supervisor::parse(
+        "[program:worker]\n\
+        command=/usr/bin/python3 worker.py --queue 'high priority'\n\
+        directory=/srv/app\n\
+        user=app\n\
+        environment=QUEUE=\"default\"\n\
+        autorestart=false\n\
+        stopsignal=INT\n\
+        stdout_logfile=/var/log/worker.log\n",
+    )
+    .unwrap();
+    for (section, program) in &sections {
+        let name = section.trim_start_matches("program:");
+        let service =
+            import::supervisor::convert(name, program, Path::new("/etc/supervisor")).unwrap();
+        assert_stable(service);
+    }
+}
+
+#[test]
+fn test_round_trip_crontab() {
+    let jobs = import::crontab::parse(
+        "MAILTO=ops@example.com\n\
+        SHELL=/bin/bash\n\
+        30 2 * * * /usr/local/bin/backup.sh --full > /dev/null\n\
+        */15 9-17 * * mon-fri /usr/local/bin/poll\n\
+        @reboot /usr/local/bin/warm-cache\n",
+        false,
+    )
+    .unwrap();
+    for service in import::crontab::import(jobs).unwrap() {
+        assert_stable(service);
+    }
+}
+
+#[test]
+fn test_round_trip_launchd() {
+    let plist = plist::from_bytes(
+        br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>com.example.sync</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>/usr/local/bin/sync</string>
+        <string>--all</string>
+    </array>
+    <key>StartInterval</key>
+    <integer>300</integer>
+    <key>StandardOutPath</key>
+    <string>/var/log/sync.log</string>
+    <key>Nice</key>
+    <integer>5</integer>
+</dict>
+</plist>"#,
+    )
+    .unwrap();
+    assert_stable(import::launchd::convert(plist).unwrap());
+}
@@ -0,0 +1,590 @@
+use crate::provider::systemd::systemd_version;
+use crate::timespan;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+/// How many upcoming trigger times to show when checking a schedule.
+const NEXT_RUNS: u32 = 3;
+
+/// First systemd release accepting a timezone in calendar expressions.
+const TIMEZONE_MIN_VERSION: u32 = 235;
+
+const DAYS: [(&str, &str); 7] = [
+    ("mon", "Mon"),
+    ("tue", "Tue"),
+    ("wed", "Wed"),
+    ("thu", "Thu"),
+    ("fri", "Fri"),
+    ("sat", "Sat"),
+    ("sun", "Sun"),
+];
+
+/// The calendar for `--every` with a time span, e.g. "15m". Spans only fit
+/// a calendar if they evenly divide the minute, hour or day they repeat in.
+fn parse_interval(spec: &str) -> Option<Result<String>> {
+    let secs = timespan::parse(spec).ok()?.as_secs_f64();
+    let calendar = match secs as u64 {
+        _ if secs.fract() != 0.0 => None,
+        86400 => Some("daily".into()),
+        n @ 1..=59 => Some(format!("*-*-* *:*:00/{}", n)),
+        n if n % 3600 == 0 && n / 3600 <= 23 => Some(format!("*-*-* 00/{}:00:00", n / 3600)),
+        n if n % 60 == 0 && n / 60 <= 59 => Some(format!("*-*-* *:00/{}:00", n / 60)),
+        _ => None,
+    };
+    Some(calendar.ok_or_else(|| {
+        anyhow!(
+            "Interval {:?} doesn't fit a calendar, use --on-unit-inactive instead.",
+            spec
+        )
+    }))
+}
+
+fn parse_days(token: &str) -> Option<String> {
+    match token {
+        "day" | "days" | "daily" | "everyday" => return Some("".into()),
+        "weekday" | "weekdays" => return Some("Mon..Fri ".into()),
+        "weekend" | "weekends" => return Some("Sat,Sun ".into()),
+        _ => (),
+    }
+    let days = token
+        .split(',')
+        .map(|day| {
+            DAYS.iter()
+                .find(|(prefix, _)| day.len() >= 3 && day.starts_with(prefix))
+                .map(|(_, name)| *name)
+        })
+        .collect::<Option<Vec<&str>>>()?;
+    Some(format!("{} ", days.join(",")))
+}
+
+fn parse_time(token: &str) -> Option<(u32, u32)> {
+    match token {
+        "midnight" => return Some((0, 0)),
+        "noon" => return Some((12, 0)),
+        _ => (),
+    }
+    let re_time = Regex::new(r"^(\d{1,2})(?::(\d{2}))?(am|pm)?$").expect("Bad regex");
+    let captures = re_time.captures(token)?;
+    let mut hour: u32 = captures[1].parse().ok()?;
+    let minute: u32 = captures
+        .get(2)
+        .map_or(Some(0), |m| m.as_str().parse().ok())?;
+    match captures.get(3).map(|m| m.as_str()) {
+        Some(_) if hour == 0 || hour > 12 => return None,
+        Some("am") if hour == 12 => hour = 0,
+        Some("pm") if hour != 12 => hour += 12,
+        _ => (),
+    }
+    (hour < 24 && minute < 60).then_some((hour, minute))
+}
+
+/// Translates a human-friendly schedule such as "15m", "hourly",
+/// "monday 9am" or "weekdays 17:30" into an OnCalendar= expression.
+pub fn every_to_calendar(spec: &str) -> Result<String> {
+    let spec = spec.trim().to_lowercase();
+    if let Some(calendar) = parse_interval(&spec) {
+        return calendar;
+    }
+    match spec.as_str() {
+        "minute" | "minutely" => return Ok("minutely".into()),
+        "hour" | "hourly" => return Ok("hourly".into()),
+        "week" | "weekly" => return Ok("weekly".into()),
+        "month" | "monthly" => return Ok("monthly".into()),
+        "year" | "yearly" => return Ok("yearly".into()),
+        _ => (),
+    }
+
+    let invalid = || {
+        anyhow!(
+            "Can't make sense of schedule {:?}, try e.g. \"monday 9am\".",
+            spec
+        )
+    };
+    let mut tokens = spec.split_whitespace();
+    let days = tokens.next().and_then(parse_days).ok_or_else(invalid)?;
+    let (hour, minute) = match tokens.next() {
+        Some(token) => parse_time(token).ok_or_else(invalid)?,
+        None => (0, 0),
+    };
+    if tokens.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(format!("{}*-*-* {:02}:{:02}:00", days, hour, minute))
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Parses a number or, where `names` are given, a name counted from `first`.
+fn cron_value(value: &str, first: u32, names: &[&str]) -> Option<u32> {
+    value.parse().ok().or_else(|| {
+        let value = value.to_lowercase();
+        names
+            .iter()
+            .position(|name| *name == value)
+            .map(|i| i as u32 + first)
+    })
+}
+
+/// Expands one cron field into the values it matches, within `min..=max`.
+fn cron_values(field: &str, min: u32, max: u32, names: &[&str]) -> Option<Vec<u32>> {
+    let mut values = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|&s| s > 0)?),
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (cron_value(start, min, names)?, cron_value(end, min, names)?),
+            // "5/10" means from 5 to the end.
+            None if step > 1 => (cron_value(range, min, names)?, max),
+            None => {
+                let value = cron_value(range, min, names)?;
+                (value, value)
+            }
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        values.extend((start..=end).step_by(step));
+    }
+    values.sort();
+    values.dedup();
+    Some(values)
+}
+
+/// Formats a time or date component as systemd does: `*`, `A/step` or a
+/// comma-separated list, with two-digit numbers.
+fn calendar_component(field: &str, min: u32, max: u32, names: &[&str]) -> Option<String> {
+    if field == "*" {
+        return Some("*".into());
+    }
+    let values = cron_values(field, min, max, names)?;
+    if let [first, second, ..] = values[..] {
+        let step = second - first;
+        let repeats = (first..=max).step_by(step as usize).collect::<Vec<u32>>();
+        if values.len() > 2 && values == repeats {
+            return Some(format!("{:02}/{}", first, step));
+        }
+    }
+    Some(
+        values
+            .iter()
+            .map(|v| format!("{:02}", v))
+            .collect::<Vec<String>>()
+            .join(","),
+    )
+}
+
+/// Translates a crontab schedule, either five fields or a shorthand like
+/// "@daily", into an OnCalendar= expression. "@reboot" isn't a calendar
+/// schedule and is an error.
+pub fn cron_to_calendar(schedule: &str) -> Result<String> {
+    match schedule {
+        "@hourly" => return Ok("hourly".into()),
+        "@daily" | "@midnight" => return Ok("daily".into()),
+        "@weekly" => return Ok("weekly".into()),
+        "@monthly" => return Ok("monthly".into()),
+        "@yearly" | "@annually" => return Ok("yearly".into()),
+        _ => (),
+    }
+    let invalid = || anyhow!("Can't translate cron schedule {:?}.", schedule);
+    let fields: Vec<&str> = schedule.split_whitespace().collect();
+    let [minute, hour, day, month, weekday] = fields[..] else {
+        return Err(invalid());
+    };
+    let minute = calendar_component(minute, 0, 59, &[]).ok_or_else(invalid)?;
+    let hour = calendar_component(hour, 0, 23, &[]).ok_or_else(invalid)?;
+    let day_of_month = calendar_component(day, 1, 31, &[]).ok_or_else(invalid)?;
+    let month = calendar_component(month, 1, 12, &MONTHS).ok_or_else(invalid)?;
+    let cron_days = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+    let weekdays = match weekday {
+        "*" => String::new(),
+        _ => {
+            let mut days = cron_values(weekday, 0, 7, &cron_days).ok_or_else(invalid)?;
+            // Both 0 and 7 are Sunday, which systemd puts last.
+            for day in days.iter_mut() {
+                if *day == 0 {
+                    *day = 7;
+                }
+            }
+            days.sort();
+            days.dedup();
+            let names: Vec<&str> = days.iter().map(|&d| DAYS[d as usize - 1].1).collect();
+            format!("{} ", names.join(","))
+        }
+    };
+    if day != "*" && weekday != "*" {
+        log::warn!(
+            "{:?} runs on matching days of the month OR of the week in cron, but on days matching both in systemd.",
+            schedule
+        );
+    }
+    Ok(format!(
+        "{}*-{}-{} {}:{}:00",
+        weekdays, month, day_of_month, hour, minute
+    ))
+}
+
+/// Pins a calendar expression to `timezone`, e.g. "Europe/Berlin".
+pub fn with_timezone(expression: &str, timezone: &str) -> Result<String> {
+    check_timezone(timezone)?;
+    with_timezone_for(expression, timezone, systemd_version())
+}
+
+fn with_timezone_for(expression: &str, timezone: &str, version: Option<u32>) -> Result<String> {
+    match version {
+        Some(version) if version < TIMEZONE_MIN_VERSION => {
+            // Older systemd only knows UTC, so a zone with a fixed offset can
+            // be converted to it. Zones with daylight saving time can't.
+            let offset = fixed_offset(timezone).ok_or_else(|| {
+                anyhow!(
+                    "systemd {} can't schedule in {} (needs {}), only in zones with a fixed offset like Etc/GMT-2. Convert {:?} to local time instead.",
+                    version,
+                    timezone,
+                    TIMEZONE_MIN_VERSION,
+                    expression
+                )
+            })?;
+            let converted = format!("{} UTC", shift_hours(expression, -offset)?);
+            log::info!(
+                "systemd {} doesn't know timezones, scheduling {:?} in {} as {:?}.",
+                version,
+                expression,
+                timezone,
+                converted
+            );
+            return Ok(converted);
+        }
+        Some(_) => (),
+        None => log::warn!(
+            "Couldn't determine the systemd version, timezones need systemd {} or newer.",
+            TIMEZONE_MIN_VERSION
+        ),
+    }
+    Ok(format!("{} {}", expression, timezone))
+}
+
+/// Checks `timezone` names a zone: a plain relative name and, where the
+/// zoneinfo database is installed, one of its files.
+fn check_timezone(timezone: &str) -> Result<()> {
+    let unknown = || anyhow!("Unknown timezone {:?}.", timezone);
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/_+-".contains(c);
+    if timezone.is_empty()
+        || !timezone.chars().all(plain)
+        || timezone.starts_with('/')
+        || timezone
+            .split('/')
+            .any(|part| part.is_empty() || part == "..")
+    {
+        return Err(unknown());
+    }
+    let zoneinfo = Path::new("/usr/share/zoneinfo");
+    if zoneinfo.is_dir() && !zoneinfo.join(timezone).is_file() {
+        return Err(unknown());
+    }
+    Ok(())
+}
+
+/// Hours ahead of UTC for zones that never change their offset.
+fn fixed_offset(timezone: &str) -> Option<i32> {
+    let name = timezone.strip_prefix("Etc/").unwrap_or(timezone);
+    match name {
+        "UTC" | "UCT" | "GMT" | "GMT0" | "GMT+0" | "GMT-0" | "Greenwich" | "Universal" | "Zulu" => {
+            Some(0)
+        }
+        _ if timezone.starts_with("Etc/GMT") => {
+            // POSIX-style: Etc/GMT+5 is five hours behind UTC.
+            let hours: i32 = name.strip_prefix("GMT")?.parse().ok()?;
+            (-14..=12).contains(&hours).then_some(-hours)
+        }
+        _ => None,
+    }
+}
+
+/// Moves a calendar expression `hours` later, for the simple expressions
+/// mkservice writes: any day or given weekdays, at listed hours, ranges or
+/// steps. Runs moved to another day move their weekdays along.
+fn shift_hours(expression: &str, hours: i32) -> Result<String> {
+    let unsupported = || {
+        anyhow!(
+            "Can't convert {:?} to another timezone, write it in local time instead.",
+            expression
+        )
+    };
+    let expression = match expression {
+        "minutely" => "*-*-* *:*:00",
+        "hourly" => "*-*-* *:00:00",
+        "daily" => "*-*-* 00:00:00",
+        "weekly" => "Mon *-*-* 00:00:00",
+        expression => expression,
+    };
+    if hours == 0 {
+        return Ok(expression.to_string());
+    }
+    let (mut weekdays, mut date, mut time) = (None, "*-*-*", None);
+    for token in expression.split_whitespace() {
+        match token {
+            _ if token.contains(':') && time.is_none() => time = Some(token),
+            _ if token.starts_with(|c: char| c.is_ascii_alphabetic()) && weekdays.is_none() => {
+                weekdays = Some(token)
+            }
+            _ if token.contains('-') && time.is_none() => date = token,
+            _ => return Err(unsupported()),
+        }
+    }
+    let (hour, rest) = time
+        .and_then(|time| time.split_once(':'))
+        .ok_or_else(unsupported)?;
+    if hour == "*" {
+        // Every hour is still every hour, on every day.
+        return match (weekdays, date) {
+            (None, "*-*-*") => Ok(expression.to_string()),
+            _ => Err(unsupported()),
+        };
+    }
+    let mut shifted = Vec::new();
+    for hour in expand(hour, 23).ok_or_else(unsupported)? {
+        let hour = hour as i32 + hours;
+        shifted.push((hour.rem_euclid(24) as u32, hour.div_euclid(24)));
+    }
+    let days = shifted[0].1;
+    let (weekdays, date) = match (weekdays, date) {
+        (weekdays, "*-*-*") if shifted.iter().all(|(_, d)| *d == days) => {
+            let weekdays = match weekdays {
+                Some(weekdays) => {
+                    let mut moved: Vec<usize> = expand_weekdays(weekdays)
+                        .ok_or_else(unsupported)?
+                        .into_iter()
+                        .map(|day| (day as i32 + days).rem_euclid(7) as usize)
+                        .collect();
+                    moved.sort();
+                    let names: Vec<&str> = moved.iter().map(|&day| DAYS[day].1).collect();
+                    format!("{} ", names.join(","))
+                }
+                None => String::new(),
+            };
+            (weekdays, "*-*-*")
+        }
+        (None, "*-*-*") => (String::new(), "*-*-*"),
+        (weekdays, date) if days == 0 && shifted.iter().all(|(_, d)| *d == 0) => (
+            weekdays.map(|w| format!("{} ", w)).unwrap_or_default(),
+            date,
+        ),
+        _ => return Err(unsupported()),
+    };
+    let mut hours: Vec<u32> = shifted.into_iter().map(|(hour, _)| hour).collect();
+    hours.sort();
+    hours.dedup();
+    let hours: Vec<String> = hours.iter().map(|hour| format!("{:02}", hour)).collect();
+    Ok(format!("{}{} {}:{}", weekdays, date, hours.join(","), rest))
+}
+
+/// The values of a calendar component of numbers, `a..b` ranges and
+/// `a/step` repetitions, up to `max`.
+fn expand(component: &str, max: u32) -> Option<Vec<u32>> {
+    let mut values = Vec::new();
+    for item in component.split(',') {
+        if let Some((start, step)) = item.split_once('/') {
+            let (start, step): (u32, u32) = (start.parse().ok()?, step.parse().ok()?);
+            if step == 0 {
+                return None;
+            }
+            values.extend((start..=max).step_by(step as usize));
+        } else if let Some((first, last)) = item.split_once("..") {
+            let (first, last): (u32, u32) = (first.parse().ok()?, last.parse().ok()?);
+            values.extend(first..=last);
+        } else {
+            values.push(item.parse().ok()?);
+        }
+    }
+    values.iter().all(|&value| value <= max).then_some(values)
+}
+
+/// Indices into DAYS of weekdays like "Mon,Fri" or "Mon..Fri".
+fn expand_weekdays(weekdays: &str) -> Option<Vec<usize>> {
+    let index = |name: &str| DAYS.iter().position(|(_, day)| *day == name);
+    let mut days = Vec::new();
+    for item in weekdays.split(',') {
+        match item.split_once("..") {
+            Some((first, last)) => days.extend(index(first)?..=index(last)?),
+            None => days.push(index(item)?),
+        }
+    }
+    Some(days)
+}
+
+/// Validates a calendar expression with `systemd-analyze`, logging the next
+/// few trigger times so they can be eyeballed. Skipped when systemd-analyze
+/// isn't installed.
+pub fn check_calendar(expression: &str) -> Result<()> {
+    let output = match Command::new("systemd-analyze")
+        .arg("calendar")
+        .arg(format!("--iterations={}", NEXT_RUNS))
+        .arg(expression)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::debug!("systemd-analyze not found, not checking {:?}.", expression);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Invalid calendar expression {:?}: {}",
+            expression,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    log::info!(
+        "Schedule {:?}:\n{}",
+        expression,
+        String::from_utf8_lossy(&output.stdout).trim_end()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_to_calendar() {
+        for (schedule, calendar) in [
+            ("@daily", "daily"),
+            ("*/15 * * * *", "*-*-* *:00/15:00"),
+            ("30 2 * * *", "*-*-* 02:30:00"),
+            ("0 9 * * 1-5", "Mon,Tue,Wed,Thu,Fri *-*-* 09:00:00"),
+            ("0 0 1 jan,jul *", "*-01,07-01 00:00:00"),
+            ("5 4 * * sun,0,7", "Sun *-*-* 04:05:00"),
+            ("0 */6 * * *", "*-*-* 00/6:00:00"),
+            ("0 8-12/2 * * *", "*-*-* 08,10,12:00:00"),
+        ] {
+            assert_eq!(
+                cron_to_calendar(schedule).unwrap(),
+                calendar,
+                "{}",
+                schedule
+            );
+        }
+        for schedule in [
+            "@reboot",
+            "* * * *",
+            "60 * * * *",
+            "0 0 * * mon-",
+            "*/0 * * * *",
+        ] {
+            assert!(cron_to_calendar(schedule).is_err(), "{}", schedule);
+        }
+    }
+
+    #[test]
+    fn test_with_timezone() {
+        assert_eq!(
+            with_timezone_for("*-*-* 09:00:00", "Europe/Berlin", Some(250)).unwrap(),
+            "*-*-* 09:00:00 Europe/Berlin"
+        );
+        for (expression, timezone, converted) in [
+            ("*-*-* 09:00:00", "Etc/GMT-2", "*-*-* 07:00:00 UTC"),
+            ("*-*-* 09:00:00", "UTC", "*-*-* 09:00:00 UTC"),
+            ("daily", "Etc/GMT+5", "*-*-* 05:00:00 UTC"),
+            ("hourly", "Etc/GMT-3", "*-*-* *:00:00 UTC"),
+            (
+                "*-*-* 00/6:30:00",
+                "Etc/GMT-1",
+                "*-*-* 05,11,17,23:30:00 UTC",
+            ),
+            (
+                "Mon..Fri *-*-* 08:30:00",
+                "Etc/GMT-10",
+                "Mon,Tue,Wed,Thu,Sun *-*-* 22:30:00 UTC",
+            ),
+            (
+                "Sat,Sun *-*-* 20:00:00",
+                "Etc/GMT+6",
+                "Mon,Sun *-*-* 02:00:00 UTC",
+            ),
+            ("*-01-01 12:00:00", "Etc/GMT-2", "*-01-01 10:00:00 UTC"),
+        ] {
+            assert_eq!(
+                with_timezone_for(expression, timezone, Some(219)).unwrap(),
+                converted,
+                "{} {}",
+                expression,
+                timezone
+            );
+        }
+        for (expression, timezone) in [
+            ("*-*-* 09:00:00", "Europe/Berlin"),
+            ("*-01-01 01:00:00", "Etc/GMT-2"),
+            ("Mon *-*-* 00..03:00:00", "Etc/GMT-2"),
+            ("Mon *-*-* *:00:00", "Etc/GMT-2"),
+            ("monthly", "Etc/GMT-2"),
+        ] {
+            assert!(
+                with_timezone_for(expression, timezone, Some(219)).is_err(),
+                "{} {}",
+                expression,
+                timezone
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_timezone() {
+        for timezone in [
+            "../../etc/passwd",
+            "/etc/passwd",
+            "Europe/ Berlin",
+            "UTC\n",
+            "",
+        ] {
+            assert!(check_timezone(timezone).is_err(), "{:?}", timezone);
+        }
+        assert_eq!(fixed_offset("Etc/GMT-14"), Some(14));
+        assert_eq!(fixed_offset("Etc/GMT+12"), Some(-12));
+        assert_eq!(fixed_offset("Etc/GMT+13"), None);
+        assert_eq!(fixed_offset("GMT+5"), None);
+    }
+
+    #[test]
+    fn test_every_to_calendar() {
+        for (spec, calendar) in [
+            ("15m", "*-*-* *:00/15:00"),
+            ("2 hours", "*-*-* 00/2:00:00"),
+            ("30secs", "*-*-* *:*:00/30"),
+            ("5 mins", "*-*-* *:00/5:00"),
+            ("60m", "*-*-* 00/1:00:00"),
+            ("24h", "daily"),
+            ("1d", "daily"),
+            ("hourly", "hourly"),
+            ("monday 9am", "Mon *-*-* 09:00:00"),
+            ("Mon,Fri 12pm", "Mon,Fri *-*-* 12:00:00"),
+            ("weekdays 17:30", "Mon..Fri *-*-* 17:30:00"),
+            ("day 12am", "*-*-* 00:00:00"),
+            ("sunday", "Sun *-*-* 00:00:00"),
+        ] {
+            assert_eq!(every_to_calendar(spec).unwrap(), calendar, "{}", spec);
+        }
+        for spec in [
+            "90m",
+            "3d",
+            "monday 25:00",
+            "someday 9am",
+            "monday 9am sharp",
+            "15ms",
+            "90s",
+            "15mss",
+            "2hs",
+        ] {
+            assert!(every_to_calendar(spec).is_err(), "{}", spec);
+        }
+    }
+}
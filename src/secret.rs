@@ -0,0 +1,224 @@
+//! Environment variables kept encrypted at rest, with systemd's credentials.
+//! `systemd-creds` encrypts each value with the host's key, the unit carries
+//! the ciphertext in SetCredentialEncrypted=, and systemd decrypts it at
+//! start into a directory only the service can read.
+
+use crate::config::{ServiceConfig, ServiceLevel};
+use crate::error::{self, MkserviceError};
+use crate::manifest;
+use crate::provider;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The host key systemd-creds encrypts with, when not using a TPM.
+const HOST_KEY: &str = "/var/lib/systemd/credential.secret";
+
+/// Runs systemd-creds with `input` on its stdin, returning its stdout.
+fn systemd_creds(args: &[&str], input: &[u8]) -> error::Result<Vec<u8>> {
+    let mut child = Command::new("systemd-creds")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| MkserviceError::spawn("systemd-creds", e))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: format!("systemd-creds {}", args.join(" ")),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// Encrypts `value` as the credential `name`, in base64 on one line. The
+/// host key is used even with a TPM, so `rotate_key` covers every secret.
+fn encrypt(name: &str, value: &str) -> Result<String> {
+    let name = format!("--name={}", name);
+    let encrypted = systemd_creds(
+        &["encrypt", "--with-key=host", &name, "-", "-"],
+        value.as_bytes(),
+    )?;
+    Ok(String::from_utf8_lossy(&encrypted)
+        .split_whitespace()
+        .collect())
+}
+
+fn decrypt(name: &str, encrypted: &str) -> Result<String> {
+    let name = format!("--name={}", name);
+    let value = systemd_creds(&["decrypt", &name, "-", "-"], encrypted.as_bytes())?;
+    Ok(String::from_utf8(value)?)
+}
+
+/// Encrypts the `--env-secret` values, given as KEY=VALUE, or as KEY to take
+/// the value from our environment. Values that `previous` already has keep
+/// their ciphertext, so reinstalling doesn't change the unit.
+pub fn seal(
+    secrets: &[String],
+    previous: Option<&ServiceConfig>,
+) -> Result<BTreeMap<String, String>> {
+    let mut sealed = BTreeMap::new();
+    for secret in secrets {
+        let (key, value) = match secret.split_once('=') {
+            Some((key, value)) => (key, value.to_string()),
+            None => (
+                secret.as_str(),
+                env::var(secret).map_err(|_| {
+                    anyhow!(
+                        "--env-secret {} takes the value from your environment, but {} isn't set.",
+                        secret,
+                        secret
+                    )
+                })?,
+            ),
+        };
+        let unchanged = previous
+            .and_then(|previous| previous.env_secrets.get(key))
+            .filter(|encrypted| decrypt(key, encrypted).is_ok_and(|old| old == value));
+        let encrypted = match unchanged {
+            Some(encrypted) => encrypted.clone(),
+            None => encrypt(key, &value)?,
+        };
+        sealed.insert(key.to_string(), encrypted);
+    }
+    Ok(sealed)
+}
+
+/// Moves `key` aside and runs `setup` to make a new one, then `reencrypt`.
+/// The old key is only removed once both succeed, and put back otherwise.
+fn replace_key(
+    key: &Path,
+    setup: impl FnOnce() -> Result<()>,
+    reencrypt: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let mut old_key = key.as_os_str().to_owned();
+    old_key.push(".old");
+    let old_key = PathBuf::from(old_key);
+    fs::rename(key, &old_key)
+        .map_err(|e| anyhow!("Can't move the host key {:?} aside: {}", key, e))?;
+    if let Err(e) = setup().and_then(|_| reencrypt()) {
+        fs::rename(&old_key, key).map_err(|e| MkserviceError::io(&old_key, e))?;
+        return Err(e);
+    }
+    fs::remove_file(&old_key).map_err(|e| MkserviceError::io(&old_key, e))?;
+    Ok(())
+}
+
+/// Adopted unit files are installed as they are, with the ciphertext of
+/// the old key, so rotating would leave the service unable to decrypt.
+fn check_not_adopted(service: &ServiceConfig) -> Result<()> {
+    if service.adopted_units.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{} runs unit files adopted with drift --accept, which would keep secrets encrypted with the old key. Reinstall it with mkservice first, then rotate the key.",
+        service.name
+    ))
+}
+
+/// Replaces the host key and re-encrypts every managed secret with the new
+/// one, in every namespace, reinstalling the services that have any. If
+/// that fails, the old key and the services as they were are put back.
+/// Returns how many services that was.
+pub fn rotate_key() -> Result<usize> {
+    let mut services = Vec::new();
+    for (path, service) in manifest::load_everywhere(&ServiceLevel::System)? {
+        if service.env_secrets.is_empty() {
+            continue;
+        }
+        check_not_adopted(&service)?;
+        let mut values = BTreeMap::new();
+        for (key, encrypted) in &service.env_secrets {
+            values.insert(key.clone(), decrypt(key, encrypted)?);
+        }
+        services.push((path, service, values));
+    }
+    let mut rewritten = Vec::new();
+    let rotated = replace_key(
+        Path::new(HOST_KEY),
+        || Ok(systemd_creds(&["setup"], b"").map(|_| ())?),
+        || {
+            for (path, original, values) in &services {
+                let mut service = original.clone();
+                for (key, value) in values {
+                    service
+                        .env_secrets
+                        .insert(key.clone(), encrypt(key, value)?);
+                }
+                rewritten.push((path, original));
+                manifest::write(path, &service)?;
+                provider::get_provider(service.clone())?.install()?;
+                log::info!("Re-encrypted the secrets of {}.", service.name);
+            }
+            Ok(())
+        },
+    );
+    if let Err(e) = rotated {
+        for (path, service) in rewritten {
+            manifest::write(path, service)?;
+            provider::get_provider(service.clone())?.install()?;
+        }
+        return Err(e.context("Kept the old host key"));
+    }
+    Ok(services.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_not_adopted() {
+        let mut service = ServiceConfig {
+            name: "web".into(),
+            env_secrets: [("TOKEN".to_string(), "ciphertext".to_string())].into(),
+            ..Default::default()
+        };
+        assert!(check_not_adopted(&service).is_ok());
+        service.adopted_units.insert(
+            "web.service".into(),
+            "[Service]\nSetCredentialEncrypted=TOKEN:ciphertext\n".into(),
+        );
+        assert!(check_not_adopted(&service).is_err());
+    }
+
+    #[test]
+    fn test_replace_key() {
+        let dir = env::temp_dir().join(format!("mkservice-secret-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("credential.secret");
+        let old_key = dir.join("credential.secret.old");
+        fs::write(&key, "old").unwrap();
+
+        // The old key is still there while the services are rewritten.
+        let reencrypt = || {
+            assert_eq!(fs::read_to_string(&old_key)?, "old");
+            Err(anyhow!("Can't reinstall"))
+        };
+        let setup = || Ok(fs::write(&key, "new")?);
+        assert!(replace_key(&key, setup, reencrypt).is_err());
+        assert_eq!(fs::read_to_string(&key).unwrap(), "old");
+        assert!(!old_key.exists());
+
+        let setup = || Ok(fs::write(&key, "new")?);
+        replace_key(&key, setup, || {
+            assert!(old_key.exists());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs::read_to_string(&key).unwrap(), "new");
+        assert!(!old_key.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
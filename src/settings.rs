@@ -0,0 +1,297 @@
+//! Defaults from config files, so an organization can standardize what
+//! mkservice does across hosts. Flags always win over these.
+
+use crate::config::{Hardening, ServiceConfig, ServiceLevel, ServiceType};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SYSTEM_CONFIG: &str = "/etc/mkservice/config.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub level: Option<ServiceLevel>,
+    /// Restart= policy, e.g. "always".
+    pub restart: Option<String>,
+    /// Apply baseline hardening directives.
+    pub hardening: Option<bool>,
+    /// Comment written at the top of each unit. "{name}" and "{level}" are
+    /// replaced with the service's, here and in the annotations below.
+    pub header: Option<String>,
+    /// File whose text heads each unit, e.g. a license notice.
+    pub license_header: Option<PathBuf>,
+    /// Note when each unit was generated in its header. Off by default, so
+    /// the same inputs give byte-identical units.
+    pub header_timestamp: Option<bool>,
+    /// Team owning the services.
+    pub owner: Option<String>,
+    /// Ticket or runbook URL.
+    pub ticket: Option<String>,
+    /// Who to contact about the services.
+    pub contact: Option<String>,
+}
+
+/// Flags overriding the annotations written into each unit's header.
+#[derive(clap::Args, Debug, Default)]
+pub struct Annotations {
+    /// File whose text heads each unit, e.g. a license notice.
+    #[clap(long)]
+    pub license_header: Option<PathBuf>,
+    /// Note when the units were generated in their header, as of
+    /// SOURCE_DATE_EPOCH if set.
+    #[clap(long)]
+    pub header_timestamp: bool,
+    /// Team owning the service, noted in its units.
+    #[clap(long)]
+    pub owner: Option<String>,
+    /// Ticket or runbook URL, noted in the service's units.
+    #[clap(long)]
+    pub ticket: Option<String>,
+    /// Who to contact about the service, noted in its units.
+    #[clap(long)]
+    pub contact: Option<String>,
+}
+
+impl Settings {
+    /// Fills in whatever `self` leaves unset from `other`.
+    fn or(self, other: Settings) -> Settings {
+        Settings {
+            level: self.level.or(other.level),
+            restart: self.restart.or(other.restart),
+            hardening: self.hardening.or(other.hardening),
+            header: self.header.or(other.header),
+            license_header: self.license_header.or(other.license_header),
+            header_timestamp: self.header_timestamp.or(other.header_timestamp),
+            owner: self.owner.or(other.owner),
+            ticket: self.ticket.or(other.ticket),
+            contact: self.contact.or(other.contact),
+        }
+    }
+
+    /// Overrides the annotations with those given as flags.
+    pub fn annotate(&mut self, annotations: &Annotations) {
+        let Annotations {
+            license_header,
+            header_timestamp,
+            owner,
+            ticket,
+            contact,
+        } = annotations;
+        self.license_header = license_header.clone().or(self.license_header.take());
+        if *header_timestamp {
+            self.header_timestamp = Some(true);
+        }
+        self.owner = owner.clone().or(self.owner.take());
+        self.ticket = ticket.clone().or(self.ticket.take());
+        self.contact = contact.clone().or(self.contact.take());
+    }
+
+    /// The unit header for `service`: the license text, the header and the
+    /// annotations, or None if none are set.
+    fn header_for(&self, service: &ServiceConfig) -> Result<Option<String>> {
+        let mut lines = Vec::new();
+        if let Some(path) = &self.license_header {
+            let license = fs::read_to_string(path)
+                .with_context(|| format!("Can't read license header {:?}", path))?;
+            // The file may already be written as comments.
+            lines.extend(license.trim_end().lines().map(|line| {
+                line.strip_prefix("# ")
+                    .or_else(|| line.strip_prefix('#'))
+                    .unwrap_or(line)
+                    .to_string()
+            }));
+        }
+        lines.extend(self.header.as_ref().or(service.header.as_ref()).cloned());
+        for (label, value) in [
+            ("Owner", &self.owner),
+            ("Ticket", &self.ticket),
+            ("Contact", &self.contact),
+        ] {
+            if let Some(value) = value {
+                lines.push(format!("{}: {}", label, value));
+            }
+        }
+        if self.header_timestamp == Some(true) {
+            lines.push(format!("Generated: {}", utc_timestamp(generated_at()?)));
+        }
+        if lines.is_empty() {
+            return Ok(None);
+        }
+        let level = format!("{:?}", service.level).to_lowercase();
+        Ok(Some(
+            lines
+                .join("\n")
+                .replace("{name}", &service.name)
+                .replace("{level}", &level),
+        ))
+    }
+
+    /// Adds the defaults to `service`, beneath anything already set.
+    pub fn apply(&self, service: &mut ServiceConfig) -> Result<()> {
+        let options = service.unit_options.entry("Service".into()).or_default();
+        // Oneshot services are triggered again, not restarted.
+        let oneshot = service.service_type == ServiceType::Oneshot;
+        if let (Some(restart), false, None) = (&self.restart, oneshot, service.restart) {
+            options
+                .entry("Restart".into())
+                .or_insert_with(|| vec![restart.clone()]);
+        }
+        if self.hardening == Some(true) {
+            Hardening::Moderate.apply(service);
+        }
+        service
+            .unit_options
+            .retain(|_, options| !options.is_empty());
+        service.header = self.header_for(service)?;
+        Ok(())
+    }
+}
+
+/// Seconds since the epoch to date generated units with: SOURCE_DATE_EPOCH
+/// for reproducible builds, or now.
+fn generated_at() -> Result<u64> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse()
+            .with_context(|| format!("SOURCE_DATE_EPOCH {:?} isn't a number of seconds", epoch)),
+        Err(_) => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+    }
+}
+
+/// `secs` since the epoch as an RFC 3339 UTC time, whatever the locale.
+pub fn utc_timestamp(secs: u64) -> String {
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days, counting in 400-year eras from
+    // 0000-03-01.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// The user's mkservice config directory.
+pub fn config_dir() -> Result<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var("HOME")?).join(".config"),
+    };
+    Ok(config_dir.join("mkservice"))
+}
+
+fn load_file(path: &Path) -> Result<Settings> {
+    match fs::read_to_string(path) {
+        Ok(content) => toml::from_str(&content).with_context(|| format!("Bad config {:?}", path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Settings::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Loads the user's config over the system-wide one.
+pub fn load() -> Result<Settings> {
+    let system = load_file(Path::new(SYSTEM_CONFIG))?;
+    let user = match config_dir() {
+        Ok(dir) => load_file(&dir.join("config.toml"))?,
+        // No home to look in, e.g. under a bare service manager.
+        Err(_) => Settings::default(),
+    };
+    Ok(user.or(system))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_beneath_existing() {
+        let settings: Settings = toml::from_str(
+            "restart = \"always\"\nhardening = true\nheader = \"Managed by ops: {name} ({level})\"\n",
+        )
+        .unwrap();
+        let mut service = ServiceConfig {
+            name: "web".into(),
+            ..Default::default()
+        };
+        service
+            .unit_options
+            .entry("Service".into())
+            .or_default()
+            .insert("ProtectSystem".into(), vec!["strict".into()]);
+        settings.apply(&mut service).unwrap();
+        let options = &service.unit_options["Service"];
+        assert_eq!(options["Restart"], vec!["always"]);
+        assert_eq!(options["ProtectSystem"], vec!["strict"]);
+        assert_eq!(options["NoNewPrivileges"], vec!["yes"]);
+        assert_eq!(
+            service.header.as_deref(),
+            Some("Managed by ops: web (system)")
+        );
+        let mut service = ServiceConfig {
+            working_directory: Some("/srv/web".into()),
+            ..Default::default()
+        };
+        Hardening::Strict.apply(&mut service);
+        let options = &service.unit_options["Service"];
+        assert_eq!(options["ProtectSystem"], vec!["strict"]);
+        assert_eq!(options["ReadWritePaths"], vec!["/srv/web"]);
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_annotations() {
+        let license = env::temp_dir().join(format!("mkservice-license-{}", std::process::id()));
+        fs::write(&license, "# SPDX-License-Identifier: MIT\n#\n").unwrap();
+        let mut settings: Settings = toml::from_str(
+            "owner = \"platform\"\nticket = \"https://tickets.example.com/{name}\"\n",
+        )
+        .unwrap();
+        settings.annotate(&Annotations {
+            license_header: Some(license.clone()),
+            contact: Some("#platform-oncall".into()),
+            ..Default::default()
+        });
+        let mut service = ServiceConfig {
+            name: "web".into(),
+            header: Some("Imported from web.service".into()),
+            ..Default::default()
+        };
+        settings.apply(&mut service).unwrap();
+        fs::remove_file(&license).unwrap();
+        assert_eq!(
+            service.header.as_deref(),
+            Some(
+                "SPDX-License-Identifier: MIT\n\nImported from web.service\nOwner: platform\n\
+                Ticket: https://tickets.example.com/web\nContact: #platform-oncall"
+            )
+        );
+    }
+}
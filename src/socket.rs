@@ -0,0 +1,280 @@
+//! Socket activation: the addresses `--listen` takes, and checking that a
+//! service really takes its connections from the socket systemd passes it
+//! rather than binding its own.
+
+use crate::config::ServiceConfig;
+use crate::provider;
+use crate::ServiceOperator;
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the service gets to start and take a connection.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long it must stay up with nothing left to accept, since a program
+/// that binds its own socket fails a moment after starting.
+const SETTLE: Duration = Duration::from_secs(1);
+const POLL: Duration = Duration::from_millis(200);
+
+/// An address a socket unit listens on. Network addresses are a port, or an
+/// address and port, e.g. "8080" or "127.0.0.1:8080".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(String),
+    Udp(String),
+    Sctp(String),
+    /// A filesystem path.
+    Unix(String),
+    /// A name in Linux's abstract socket namespace, with no file.
+    Abstract(String),
+}
+
+impl Listen {
+    /// Parses "tcp:PORT", "udp:ADDRESS:PORT", "unix:/PATH" and the like.
+    pub fn parse(v: &str) -> Result<Listen, String> {
+        let invalid = || {
+            format!(
+                "{:?} must be \"tcp:PORT\", \"tcp:ADDRESS:PORT\", the same with udp or sctp, \
+                 \"unix:/PATH\" or \"abstract:NAME\".",
+                v
+            )
+        };
+        let (scheme, address) = v.split_once(':').ok_or_else(invalid)?;
+        let network = port(address).map(|_| address.to_string());
+        match (scheme, network) {
+            ("tcp", Some(address)) => Ok(Listen::Tcp(address)),
+            ("udp", Some(address)) => Ok(Listen::Udp(address)),
+            ("sctp", Some(address)) => Ok(Listen::Sctp(address)),
+            ("unix", _) if address.starts_with('/') => Ok(Listen::Unix(address.into())),
+            ("abstract", _) if !address.is_empty() => Ok(Listen::Abstract(address.into())),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The address as systemd's Listen*= directives take it.
+    pub fn address(&self) -> String {
+        match self {
+            Listen::Tcp(address)
+            | Listen::Udp(address)
+            | Listen::Sctp(address)
+            | Listen::Unix(address) => address.clone(),
+            Listen::Abstract(name) => format!("@{}", name),
+        }
+    }
+
+    /// Whether it's a stream of connections, rather than of datagrams or
+    /// packets.
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Listen::Tcp(_) | Listen::Unix(_) | Listen::Abstract(_))
+    }
+
+    /// Connects to the address, sending an empty datagram for UDP.
+    fn connect(&self) -> Result<Box<dyn Write>> {
+        match self {
+            Listen::Tcp(address) => Ok(Box::new(TcpStream::connect_timeout(
+                &local_addr(address)?,
+                ACCEPT_TIMEOUT,
+            )?)),
+            Listen::Udp(address) => {
+                let addr = local_addr(address)?;
+                let any: SocketAddr = match addr {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(any)?;
+                socket.send_to(&[], addr)?;
+                Ok(Box::new(io::sink()))
+            }
+            Listen::Sctp(_) => Err(anyhow!("There's no SCTP client to connect with.")),
+            Listen::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+            Listen::Abstract(name) => connect_abstract(name),
+        }
+    }
+
+    /// Whether connections are waiting to be accepted, if that can be told.
+    fn pending(&self) -> Option<bool> {
+        let Listen::Tcp(address) = self else {
+            return None;
+        };
+        let port = port(address)?;
+        let queued: Vec<u32> = ["/proc/net/tcp", "/proc/net/tcp6"]
+            .iter()
+            .filter_map(|table| accept_queue(&fs::read_to_string(table).ok()?, port))
+            .collect();
+        (!queued.is_empty()).then(|| queued.iter().sum::<u32>() > 0)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> Result<Box<dyn Write>> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    Ok(Box::new(UnixStream::connect_addr(&addr)?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_: &str) -> Result<Box<dyn Write>> {
+    Err(anyhow!("Abstract sockets are Linux only."))
+}
+
+/// The address to reach `address` at from this host, for a port alone or a
+/// wildcard address too.
+fn local_addr(address: &str) -> Result<SocketAddr> {
+    let port = port(address).unwrap_or_default();
+    let host = match address.rsplit_once(':') {
+        None | Some(("0.0.0.0" | "[::]", _)) => "localhost",
+        Some((host, _)) => host,
+    };
+    (host.trim_matches(['[', ']']), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("{} doesn't resolve.", host))
+}
+
+fn port(address: &str) -> Option<u16> {
+    let port = address.rsplit(':').next()?;
+    port.parse().ok().filter(|port| *port != 0)
+}
+
+/// The connections waiting in the accept queues of sockets listening on
+/// `port`, from a /proc/net/tcp table. For a listening socket, the receive
+/// queue column is its accept queue. None if nothing listens on the port.
+fn accept_queue(table: &str, port: u16) -> Option<u32> {
+    const LISTEN: &str = "0A";
+    let mut found = None;
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [_, local, _, state, queues, ..] = fields[..] else {
+            continue;
+        };
+        let local_port = local
+            .rsplit_once(':')
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+        if state != LISTEN || local_port != Some(port) {
+            continue;
+        }
+        let queued = queues
+            .split_once(':')
+            .and_then(|(_, rx)| u32::from_str_radix(rx, 16).ok())
+            .unwrap_or_default();
+        *found.get_or_insert(0) += queued;
+    }
+    found
+}
+
+/// Starts the service's socket, connects to each address it listens on,
+/// and fails unless the service starts and accepts the connection. A program
+/// that doesn't support socket activation (sd_listen_fds) typically fails
+/// binding an address the socket holds, or listens elsewhere and never takes
+/// the connection. The service is stopped if it fails.
+pub fn verify_activation(service: &ServiceConfig) -> Result<()> {
+    let p = provider::get_provider(service.clone())?;
+    p.start()?;
+    for listen in &service.listen {
+        let listen = Listen::parse(listen).map_err(|e| anyhow!(e))?;
+        if let Listen::Sctp(address) = &listen {
+            log::warn!(
+                "Not checking SCTP address {}, there's no client for it.",
+                address
+            );
+            continue;
+        }
+        let verified = verify_listen(p.as_ref(), &listen);
+        if verified.is_err() {
+            p.stop()?;
+        }
+        verified?;
+        log::info!(
+            "{:?} accepted a connection on {}.",
+            service.name,
+            listen.address()
+        );
+    }
+    Ok(())
+}
+
+fn verify_listen(p: &dyn ServiceOperator, listen: &Listen) -> Result<()> {
+    // Held open until the service has had its chance to accept it.
+    let _connection = listen
+        .connect()
+        .map_err(|e| anyhow!("Couldn't connect to {}: {}", listen.address(), e))?;
+    let deadline = Instant::now() + ACCEPT_TIMEOUT;
+    let mut settled_since = None;
+    while Instant::now() < deadline {
+        let status = p.status()?;
+        // With Restart=, a failed start shows as a pending restart first.
+        if status.state.starts_with("failed") || status.state.ends_with("(auto-restart)") {
+            return Err(anyhow!(
+                "The service failed after a connection to {} ({}). It may not support socket \
+                 activation and tried to bind the address itself.",
+                listen.address(),
+                status.last_result.unwrap_or(status.state)
+            ));
+        }
+        let settled = status.state.starts_with("active") && listen.pending() != Some(true);
+        match (settled, settled_since) {
+            (false, _) => settled_since = None,
+            (true, None) => settled_since = Some(Instant::now()),
+            (true, Some(since)) if since.elapsed() >= SETTLE => return Ok(()),
+            (true, Some(_)) => {}
+        }
+        thread::sleep(POLL);
+    }
+    Err(anyhow!(
+        "The service didn't accept a connection to {} within {}s. It may not support socket \
+         activation and listen somewhere else.",
+        listen.address(),
+        ACCEPT_TIMEOUT.as_secs()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Listen::parse("tcp:8080"), Ok(Listen::Tcp("8080".into())));
+        assert_eq!(
+            Listen::parse("tcp:[::1]:8080"),
+            Ok(Listen::Tcp("[::1]:8080".into()))
+        );
+        assert_eq!(
+            Listen::parse("unix:/run/app.sock"),
+            Ok(Listen::Unix("/run/app.sock".into()))
+        );
+        assert_eq!(Listen::parse("udp:53"), Ok(Listen::Udp("53".into())));
+        assert_eq!(
+            Listen::parse("sctp:0.0.0.0:3868"),
+            Ok(Listen::Sctp("0.0.0.0:3868".into()))
+        );
+        assert_eq!(
+            Listen::parse("abstract:appsock").map(|listen| listen.address()),
+            Ok("@appsock".into())
+        );
+        for invalid in [
+            "8080",
+            "tcp:http",
+            "tcp:0",
+            "unix:app.sock",
+            "udp:/run/app.sock",
+        ] {
+            assert!(Listen::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_accept_queue() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+            \x20  0: 00000000:1F90 00000000:0000 0A 00000000:00000002 00:00000000 00000000  1000        0 1\n\
+            \x20  1: 0100007F:1F90 0100007F:9C40 01 00000000:00000000 00:00000000 00000000  1000        0 2\n\
+            \x20  2: 0100007F:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 3\n";
+        assert_eq!(accept_queue(table, 8080), Some(2));
+        assert_eq!(accept_queue(table, 22), Some(0));
+        assert_eq!(accept_queue(table, 443), None);
+    }
+}
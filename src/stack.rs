@@ -0,0 +1,232 @@
+//! Stacks are the services imported together from one multi-service file,
+//! e.g. a compose file, looked at as a whole.
+
+use crate::config::{ServiceLevel, ServiceType};
+use crate::manifest;
+use crate::output;
+use crate::provider;
+use crate::report::ReportEntry;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// The services in stack `name`, along with their status.
+pub fn collect(name: &str, level: Option<&ServiceLevel>) -> Result<Vec<ReportEntry>> {
+    let mut entries = Vec::new();
+    for level in manifest::levels(level) {
+        for service in manifest::load_all(&level)? {
+            if service.stack.as_deref() == Some(name) {
+                let status = provider::get_provider(service.clone()).and_then(|p| p.status());
+                entries.push(ReportEntry { service, status });
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Err(anyhow!("No managed services in stack {:?}.", name));
+    }
+    Ok(entries)
+}
+
+/// Whether the service isn't doing its job: failed, or not running when it
+/// should be. Jobs only have to have succeeded last time.
+pub fn is_down(entry: &ReportEntry) -> bool {
+    let Ok(status) = &entry.status else {
+        return true;
+    };
+    if status.state.starts_with("failed") {
+        return true;
+    }
+    let job = entry.service.timer.is_some() || entry.service.service_type == ServiceType::Oneshot;
+    if job {
+        status
+            .last_result
+            .as_deref()
+            .is_some_and(|r| r != "success")
+    } else {
+        !status.state.starts_with("active ")
+    }
+}
+
+/// e.g. "3d 4h" or "5m 12s", the two largest units.
+pub fn format_uptime(seconds: u64) -> String {
+    let parts = [
+        (seconds / 86400, "d"),
+        (seconds / 3600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let parts: Vec<String> = parts
+        .iter()
+        .skip_while(|(n, _)| *n == 0)
+        .take(2)
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect();
+    if parts.is_empty() {
+        "0s".into()
+    } else {
+        parts.join(" ")
+    }
+}
+
+struct Tree<'a> {
+    entries: BTreeMap<&'a str, &'a ReportEntry>,
+    /// Services that are down while everything they depend on is up, where
+    /// an outage starts.
+    failing: BTreeSet<&'a str>,
+    color: bool,
+}
+
+impl<'a> Tree<'a> {
+    fn new(entries: &'a [ReportEntry], color: bool) -> Self {
+        let entries: BTreeMap<&str, &ReportEntry> = entries
+            .iter()
+            .map(|entry| (entry.service.name.as_str(), entry))
+            .collect();
+        let failing = entries
+            .iter()
+            .filter(|(_, entry)| is_down(entry))
+            .filter(|(_, entry)| {
+                !entry
+                    .service
+                    .depends_on
+                    .iter()
+                    .filter_map(|dependency| entries.get(dependency.as_str()))
+                    .any(|dependency| is_down(dependency))
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        Tree {
+            entries,
+            failing,
+            color,
+        }
+    }
+
+    fn line(&self, name: &str) -> String {
+        let Some(entry) = self.entries.get(name) else {
+            return format!("{} (not in the stack)", name);
+        };
+        let mut fields = vec![name.to_string()];
+        match &entry.status {
+            Err(e) => fields.push(format!("unknown ({})", e)),
+            Ok(status) => {
+                fields.push(output::paint_state(&status.state, self.color));
+                if let Some(uptime) = status.uptime {
+                    fields.push(format!("up {}", format_uptime(uptime)));
+                }
+                if let Some(restarts) = status.restarts {
+                    fields.push(format!("{} restart(s)", restarts));
+                }
+            }
+        }
+        if self.failing.contains(name) {
+            fields.push(if self.color {
+                "\x1b[1;31m<- failing\x1b[0m".into()
+            } else {
+                "<- failing".into()
+            });
+        }
+        fields.join("  ")
+    }
+
+    /// Writes `name` and, below it, what it depends on. A service already
+    /// written, e.g. shared by two others, is only referred to again.
+    fn write(&self, out: &mut String, name: &'a str, prefix: &str, seen: &mut BTreeSet<&'a str>) {
+        if !seen.insert(name) {
+            let _ = writeln!(out, "{} (see above)", name);
+            return;
+        }
+        let _ = writeln!(out, "{}", self.line(name));
+        let Some(entry) = self.entries.get(name) else {
+            return;
+        };
+        let dependencies = &entry.service.depends_on;
+        for (i, dependency) in dependencies.iter().enumerate() {
+            let (branch, indent) = if i + 1 == dependencies.len() {
+                ("└─ ", "   ")
+            } else {
+                ("├─ ", "│  ")
+            };
+            let _ = write!(out, "{}{}", prefix, branch);
+            self.write(out, dependency, &format!("{}{}", prefix, indent), seen);
+        }
+    }
+}
+
+/// Renders stack `name` as trees of services over what they depend on,
+/// marking where any outage starts.
+pub fn render_status(name: &str, entries: &[ReportEntry], color: bool) -> String {
+    let tree = Tree::new(entries, color);
+    let depended_on: BTreeSet<&str> = entries
+        .iter()
+        .flat_map(|entry| &entry.service.depends_on)
+        .map(String::as_str)
+        .collect();
+    let mut out = format!("{}\n", name);
+    let mut seen = BTreeSet::new();
+    // Services nothing depends on go at the top, then any left over from a
+    // dependency cycle.
+    let (roots, rest): (Vec<&str>, Vec<&str>) = tree
+        .entries
+        .keys()
+        .partition(|name| !depended_on.contains(*name));
+    for root in roots.into_iter().chain(rest) {
+        if !seen.contains(root) {
+            tree.write(&mut out, root, "", &mut seen);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServiceConfig;
+    use crate::provider::ServiceStatus;
+
+    fn entry(name: &str, depends_on: &[&str], state: &str) -> ReportEntry {
+        ReportEntry {
+            service: ServiceConfig {
+                name: name.into(),
+                depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+                ..Default::default()
+            },
+            status: Ok(ServiceStatus {
+                state: state.into(),
+                restarts: Some(0),
+                uptime: state.starts_with("active ").then_some(7380),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(0), "0s");
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(3600), "1h");
+        assert_eq!(format_uptime(7380), "2h 3m");
+        assert_eq!(format_uptime(90061), "1d 1h");
+    }
+
+    #[test]
+    fn test_render_status() {
+        let entries = vec![
+            entry("api", &["db", "cache"], "activating (auto-restart)"),
+            entry("cache", &[], "active (running)"),
+            entry("db", &["storage"], "failed (failed)"),
+            entry("web", &["api", "db"], "active (running)"),
+        ];
+        assert_eq!(
+            render_status("shop", &entries, false),
+            "shop\n\
+            web  active (running)  up 2h 3m  0 restart(s)\n\
+            ├─ api  activating (auto-restart)  0 restart(s)\n\
+            │  ├─ db  failed (failed)  0 restart(s)  <- failing\n\
+            │  │  └─ storage (not in the stack)\n\
+            │  └─ cache  active (running)  up 2h 3m  0 restart(s)\n\
+            └─ db (see above)\n"
+        );
+    }
+}
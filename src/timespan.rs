@@ -0,0 +1,75 @@
+//! Time spans as systemd writes them, e.g. "30", "500ms" or "1h 30min", for
+//! every flag taking one.
+
+use std::time::Duration;
+
+/// The units of a time span, as systemd spells them, with their length in
+/// microseconds. A bare number is seconds.
+const UNITS: [(&[&str], u64); 9] = [
+    (&["us", "usec"], 1),
+    (&["ms", "msec"], 1_000),
+    (&["", "s", "sec", "secs", "second", "seconds"], 1_000_000),
+    (&["m", "min", "mins", "minute", "minutes"], 60_000_000),
+    (&["h", "hr", "hrs", "hour", "hours"], 3_600_000_000),
+    (&["d", "day", "days"], 86_400_000_000),
+    (&["w", "week", "weeks"], 604_800_000_000),
+    (&["M", "month", "months"], 2_629_800_000_000),
+    (&["y", "year", "years"], 31_557_600_000_000),
+];
+
+/// Parses a time span: numbers, each with an optional unit, added up.
+pub fn parse(v: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "{:?} is not a valid time span, try e.g. \"10s\" or \"1h 30min\".",
+            v
+        )
+    };
+    let mut rest = v.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut micros = 0.0;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..split].parse().map_err(|_| invalid())?;
+        rest = rest[split..].trim_start();
+        let split = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let (_, length) = UNITS
+            .iter()
+            .find(|(names, _)| names.contains(&&rest[..split]))
+            .ok_or_else(invalid)?;
+        micros += number * *length as f64;
+        rest = rest[split..].trim_start();
+    }
+    Ok(Duration::from_micros(micros.round() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for (span, expected) in [
+            ("10s", Duration::from_secs(10)),
+            ("500ms", Duration::from_millis(500)),
+            ("2m", Duration::from_secs(120)),
+            ("3", Duration::from_secs(3)),
+            ("30d", Duration::from_secs(30 * 86400)),
+            ("1h 30min", Duration::from_secs(5400)),
+            ("1.5 hours", Duration::from_secs(5400)),
+            ("2 weeks", Duration::from_secs(14 * 86400)),
+            ("1M", Duration::from_secs(2_629_800)),
+        ] {
+            assert_eq!(parse(span), Ok(expected), "{}", span);
+        }
+        for span in ["", "soon", "10 fortnights", "15mss", "1.2.3s", "-5s", "m"] {
+            assert!(parse(span).is_err(), "{}", span);
+        }
+    }
+}
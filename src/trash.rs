@@ -0,0 +1,238 @@
+//! Uninstalled services go to a trash area next to the manifests, with their
+//! manifest and files as they were, so they can be restored.
+
+use crate::config::{ServiceConfig, ServiceLevel};
+use crate::manifest;
+use crate::provider;
+use crate::settings::utc_timestamp;
+use anyhow::{anyhow, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A trashed service, in a directory named after it and when it was trashed.
+pub struct Entry {
+    pub path: PathBuf,
+    pub name: String,
+    pub level: ServiceLevel,
+    /// Seconds since the epoch.
+    pub trashed_at: u64,
+}
+
+impl Entry {
+    fn files_dir(&self) -> PathBuf {
+        self.path.join("files")
+    }
+
+    fn service(&self) -> Result<ServiceConfig> {
+        Ok(serde_json::from_slice(&fs::read(
+            self.path.join("manifest.json"),
+        )?)?)
+    }
+}
+
+fn trash_dir(level: &ServiceLevel) -> Result<PathBuf> {
+    Ok(manifest::state_dir(level)?.join("trash"))
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Every file under `dir`.
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.is_dir() {
+            true => files.extend(files_in(&path)?),
+            false => files.push(path),
+        }
+    }
+    Ok(files)
+}
+
+/// Uninstalls a managed service, keeping its manifest and files in the
+/// trash. Returns where they are.
+pub fn uninstall(service: &ServiceConfig) -> Result<PathBuf> {
+    let p = provider::get_provider(service.clone())?;
+    let dir = trash_dir(&service.level)?.join(format!("{}-{}", service.name, now()?));
+    let files_dir = dir.join("files");
+    fs::create_dir_all(&files_dir)?;
+    for (path, _) in p.unit_files()? {
+        if !path.exists() {
+            continue;
+        }
+        let trashed = files_dir.join(path.strip_prefix("/")?);
+        if let Some(parent) = trashed.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Copying keeps the mode, which runit's scripts need.
+        fs::copy(&path, &trashed)?;
+    }
+    fs::write(
+        dir.join("manifest.json"),
+        serde_json::to_string_pretty(service)?,
+    )?;
+    p.uninstall()?;
+    manifest::remove(service)?;
+    Ok(dir)
+}
+
+/// The trashed services at `level`, by name and oldest first.
+pub fn list(level: &ServiceLevel) -> Result<Vec<Entry>> {
+    entries_in(&trash_dir(level)?, level)
+}
+
+fn entries_in(trash_dir: &Path, level: &ServiceLevel) -> Result<Vec<Entry>> {
+    let dirs = match fs::read_dir(trash_dir) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for dir in dirs {
+        let path = dir?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Some((name, trashed_at)) = file_name.rsplit_once('-') else {
+            continue;
+        };
+        let Ok(trashed_at) = trashed_at.parse() else {
+            continue;
+        };
+        entries.push(Entry {
+            name: name.to_string(),
+            level: level.clone(),
+            trashed_at,
+            path,
+        });
+    }
+    entries.sort_by(|a, b| (&a.name, a.trashed_at).cmp(&(&b.name, b.trashed_at)));
+    Ok(entries)
+}
+
+/// The latest trashed `name`. Without a level, a name trashed at both levels
+/// is ambiguous.
+fn find(name: &str, level: Option<&ServiceLevel>) -> Result<Entry> {
+    let mut found = Vec::new();
+    for level in manifest::levels(level) {
+        let latest = list(&level)?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.name == name);
+        found.extend(latest);
+    }
+    match found.len() {
+        0 => Err(anyhow!("{:?} isn't in the trash.", name)),
+        1 => Ok(found.remove(0)),
+        _ => Err(anyhow!(
+            "{:?} was trashed as both a system and a user service, pick one with --level system or --level user.",
+            name
+        )),
+    }
+}
+
+/// Reinstalls the latest trashed `name` from its manifest, then puts back
+/// any file that had been edited since mkservice wrote it.
+pub fn restore(name: &str, level: Option<&ServiceLevel>, start: bool) -> Result<()> {
+    let name = &manifest::qualify(name);
+    let entry = find(name, level)?;
+    let service = entry.service()?;
+    if manifest::load(&service.level, name)?.is_some() {
+        return Err(anyhow!(
+            "{:?} is installed again, uninstall it before restoring.",
+            name
+        ));
+    }
+    let p = provider::get_provider(service.clone())?;
+    p.install()?;
+    manifest::save(&service)?;
+    let files_dir = entry.files_dir();
+    for trashed in files_in(&files_dir)? {
+        let path = Path::new("/").join(trashed.strip_prefix(&files_dir)?);
+        if fs::read(&path).ok() != Some(fs::read(&trashed)?) {
+            log::warn!(
+                "{:?} had been edited outside mkservice, restoring the edited copy. `mkservice drift` shows the edits.",
+                path
+            );
+            fs::copy(&trashed, &path)?;
+        }
+    }
+    fs::remove_dir_all(&entry.path)?;
+    if start {
+        p.start()?;
+    }
+    Ok(())
+}
+
+/// Deletes the trashed services at `levels` trashed at least `older_than`
+/// ago, returning how many.
+pub fn empty(levels: &[ServiceLevel], older_than: Duration) -> Result<usize> {
+    let cutoff = now()?.saturating_sub(older_than.as_secs());
+    let mut removed = 0;
+    for level in levels {
+        for entry in list(level)? {
+            if entry.trashed_at <= cutoff {
+                log::debug!("Deleting {:?}", entry.path);
+                fs::remove_dir_all(&entry.path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+pub fn render_list(entries: &[Entry]) -> Result<String> {
+    let width = entries
+        .iter()
+        .map(|entry| entry.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+    let mut out = format!("{:width$}  {:6}  TRASHED\n", "NAME", "LEVEL");
+    for entry in entries {
+        writeln!(
+            out,
+            "{:width$}  {:6}  {}",
+            entry.name,
+            format!("{:?}", entry.level).to_lowercase(),
+            utc_timestamp(entry.trashed_at),
+        )?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_entries_in() {
+        let dir = env::temp_dir().join(format!("mkservice-trash-{}", std::process::id()));
+        for name in [
+            "web-api-1700000000",
+            "web-api-1600000000",
+            "db-1650000000",
+            "notes",
+        ] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        let entries = entries_in(&dir, &ServiceLevel::System).unwrap();
+        let found: Vec<(&str, u64)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.trashed_at))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("db", 1650000000),
+                ("web-api", 1600000000),
+                ("web-api", 1700000000)
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Unit files as systemd reads them, for the ones mkservice didn't write:
+//! units being imported and drop-ins edited by hand.
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+type Section = BTreeMap<String, Vec<String>>;
+
+/// A parsed unit file.
+#[derive(Debug, Default)]
+pub struct Unit {
+    /// The comment lines at the top, as mkservice writes its header.
+    pub header: Option<String>,
+    pub sections: BTreeMap<String, Section>,
+}
+
+/// Parses a unit file. An empty assignment resets the values before it.
+pub fn parse(content: &str) -> Result<Unit> {
+    let mut unit = Unit::default();
+    let mut header: Vec<&str> = Vec::new();
+    let mut section: Option<String> = None;
+    let mut lines = content.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            if section.is_none() {
+                header.push(line.strip_prefix("# ").unwrap_or(&line[1..]));
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            unit.sections.entry(name.into()).or_default();
+            section = Some(name.into());
+            continue;
+        }
+        let options = section
+            .as_ref()
+            .and_then(|name| unit.sections.get_mut(name))
+            .ok_or_else(|| anyhow!("Line {}: setting outside a section.", number + 1))?;
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Line {}: expected \"Key=value\".", number + 1))?;
+        let mut value = value.trim().to_string();
+        while let Some(continued) = value.strip_suffix('\\') {
+            value = continued.to_string();
+            if let Some((_, next)) = lines.next() {
+                value.push(' ');
+                value.push_str(next.trim());
+            }
+        }
+        let values = options.entry(key.trim().into()).or_default();
+        match value.is_empty() {
+            true => values.clear(),
+            false => values.push(value),
+        }
+    }
+    unit.header = (!header.is_empty()).then(|| header.join("\n"));
+    Ok(unit)
+}
@@ -0,0 +1,138 @@
+//! Secrets fetched from HashiCorp Vault at each start. The unit runs
+//! `mkservice vault-env` before the service, which reads the secrets with
+//! the vault CLI and writes them to an environment file in the service's
+//! runtime directory.
+
+use crate::error::MkserviceError;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Command;
+
+/// The environment file the secrets are written to, in the service's
+/// RuntimeDirectory=.
+pub const ENV_FILE: &str = "vault.env";
+
+/// Splits a `--vault-secret` into the secret's path and the field, which
+/// names the environment variable too.
+pub fn split(secret: &str) -> Option<(&str, &str)> {
+    secret
+        .split_once('#')
+        .filter(|(path, field)| !path.is_empty() && !field.is_empty())
+}
+
+/// The fields of the secret at `path`, from `vault read -format=json`. KV
+/// version 2 nests them under a second "data", next to "metadata".
+fn fields(read: &Value) -> Option<&serde_json::Map<String, Value>> {
+    let data = read.get("data")?;
+    match (data.get("data"), data.get("metadata")) {
+        (Some(Value::Object(fields)), Some(_)) => Some(fields),
+        _ => data.as_object(),
+    }
+}
+
+fn read(path: &str) -> Result<Value> {
+    let output = Command::new("vault")
+        .args(["read", "-format=json", path])
+        .output()
+        .map_err(|e| MkserviceError::spawn("vault", e))?;
+    if !output.status.success() {
+        return Err(MkserviceError::CommandFailed {
+            cmd: format!("vault read {}", path),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .into());
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// A double-quoted EnvironmentFile= value.
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The environment file lines for `secrets`, given the secrets read by path.
+fn render(secrets: &[String], reads: &BTreeMap<&str, Value>) -> Result<String> {
+    let mut lines = String::new();
+    for secret in secrets {
+        let (path, field) = split(secret).ok_or_else(|| anyhow!("Bad secret {:?}", secret))?;
+        let value = fields(&reads[path])
+            .and_then(|fields| fields.get(field))
+            .ok_or_else(|| anyhow!("{} has no field {:?}.", path, field))?;
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        lines.push_str(&format!("{}={}\n", field, quote(&value)));
+    }
+    Ok(lines)
+}
+
+/// Reads `secrets` from Vault, once per path, and writes them to the
+/// environment file.
+pub fn write_env(secrets: &[String]) -> Result<()> {
+    let mut reads = BTreeMap::new();
+    for secret in secrets {
+        let (path, _) = split(secret).ok_or_else(|| anyhow!("Bad secret {:?}", secret))?;
+        if !reads.contains_key(path) {
+            reads.insert(path, read(path)?);
+        }
+    }
+    write_env_file(ENV_FILE, &render(secrets, &reads)?)
+}
+
+/// Writes `content` to the environment file `name` in the service's runtime
+/// directory, readable only by the service's user.
+pub fn write_env_file(name: &str, content: &str) -> Result<()> {
+    let dir = env::var_os("RUNTIME_DIRECTORY")
+        .ok_or_else(|| anyhow!("RUNTIME_DIRECTORY isn't set, this runs from a unit."))?;
+    let output = Path::new(&dir).join(name);
+    let _ = fs::remove_file(&output);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&output)?
+        .write_all(content.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let reads = BTreeMap::from([
+            (
+                "secret/data/app",
+                json!({"data": {"data": {"API_KEY": "k\"1$"}, "metadata": {"version": 3}}}),
+            ),
+            ("kv/db", json!({"data": {"PORT": 5432}})),
+        ]);
+        let secrets = vec!["secret/data/app#API_KEY".to_string(), "kv/db#PORT".into()];
+        assert_eq!(
+            render(&secrets, &reads).unwrap(),
+            "API_KEY=\"k\\\"1\\$\"\nPORT=\"5432\"\n"
+        );
+        let missing = vec!["kv/db#USER".to_string()];
+        assert!(render(&missing, &reads).is_err());
+        assert_eq!(split("kv/db#PORT"), Some(("kv/db", "PORT")));
+        assert_eq!(split("kv/db"), None);
+    }
+}
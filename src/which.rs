@@ -0,0 +1,296 @@
+//! Where a unit comes from: the files defining it across the unit search
+//! path, the drop-ins applied to it, and whether mkservice manages it.
+
+use crate::config::ServiceLevel;
+use crate::manifest;
+use crate::provider::systemd::{systemd_unescape, unit_search_path, Systemd};
+use crate::ServiceOperator;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A file defining the unit.
+#[derive(Debug, PartialEq)]
+pub struct UnitFile {
+    pub path: PathBuf,
+    /// Where the file links to, for aliases and masks.
+    pub target: Option<PathBuf>,
+}
+
+impl UnitFile {
+    fn is_masked(&self) -> bool {
+        self.target.as_deref() == Some(Path::new("/dev/null"))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Provenance {
+    pub unit: String,
+    /// Files defining the unit, the one in effect first.
+    pub files: Vec<UnitFile>,
+    /// Drop-ins in the order they apply.
+    pub drop_ins: Vec<PathBuf>,
+    /// The file the service manager has loaded, if it's running.
+    pub loaded: Option<PathBuf>,
+    /// The managed service the unit belongs to.
+    pub managed_by: Option<String>,
+}
+
+/// The full unit name, adding ".service" if `name` has no type.
+pub fn unit_name(name: &str) -> String {
+    const TYPES: [&str; 11] = [
+        "service",
+        "socket",
+        "timer",
+        "target",
+        "path",
+        "mount",
+        "automount",
+        "swap",
+        "slice",
+        "scope",
+        "device",
+    ];
+    match name.rsplit_once('.') {
+        Some((_, suffix)) if TYPES.contains(&suffix) => name.into(),
+        _ => format!("{}.service", name),
+    }
+}
+
+/// The template a unit instance is made from, e.g. "getty@.service" for
+/// "getty@tty1.service".
+fn template_name(unit: &str) -> Option<String> {
+    let (prefix, rest) = unit.split_once('@')?;
+    let (_, suffix) = rest.rsplit_once('.')?;
+    Some(format!("{}@.{}", prefix, suffix))
+}
+
+/// The drop-in directory names for `unit`, most general first: the unit
+/// type's, each dash-separated prefix's, the template's, then the unit's.
+fn drop_in_dirs(unit: &str) -> Vec<String> {
+    let Some((stem, suffix)) = unit.rsplit_once('.') else {
+        return vec![];
+    };
+    let mut dirs = vec![format!("{}.d", suffix)];
+    let base = stem.split('@').next().unwrap_or(stem);
+    for (i, _) in base.match_indices('-') {
+        dirs.push(format!("{}-.{}.d", &base[..i], suffix));
+    }
+    dirs.extend(template_name(unit).map(|template| format!("{}.d", template)));
+    dirs.push(format!("{}.d", unit));
+    dirs.dedup();
+    dirs
+}
+
+/// Finds the files for `unit` in `search_path`, highest priority first.
+pub fn locate(search_path: &[PathBuf], unit: &str) -> Provenance {
+    let mut names = vec![unit.to_string()];
+    names.extend(template_name(unit));
+    let mut files = Vec::new();
+    for name in &names {
+        for dir in search_path {
+            let path = dir.join(name);
+            if path.symlink_metadata().is_ok() {
+                files.push(UnitFile {
+                    target: fs::read_link(&path).ok(),
+                    path,
+                });
+            }
+        }
+        // An instance's own file wins over its template.
+        if !files.is_empty() {
+            break;
+        }
+    }
+
+    // Drop-ins apply sorted by file name, whatever their directory. Of two
+    // with the same name, the more specific, then the earlier in the search
+    // path, wins.
+    let mut drop_ins: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for dir_name in drop_in_dirs(unit).iter().rev() {
+        for dir in search_path {
+            let Ok(entries) = fs::read_dir(dir.join(dir_name)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if file_name.ends_with(".conf") {
+                    drop_ins.entry(file_name).or_insert_with(|| entry.path());
+                }
+            }
+        }
+    }
+
+    Provenance {
+        unit: unit.into(),
+        files,
+        drop_ins: drop_ins.into_values().collect(),
+        ..Default::default()
+    }
+}
+
+/// The managed service `unit` belongs to, if any.
+fn managed_by(level: &ServiceLevel, unit: &str) -> Result<Option<String>> {
+    let stem = unit.rsplit_once('.').map_or(unit, |(stem, _)| stem);
+    let stem = stem.split('@').next().unwrap_or(stem);
+    let stem = stem.strip_suffix("-failure-mail").unwrap_or(stem);
+    let Some(service) = manifest::load(level, &systemd_unescape(stem))? else {
+        return Ok(None);
+    };
+    let name = service.name.clone();
+    let unit_files = Systemd { service }.unit_files()?;
+    let template = template_name(unit);
+    let owns = unit_files.iter().any(|(path, _)| {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        file_name == unit || Some(&*file_name) == template.as_deref()
+    });
+    Ok(owns.then_some(name))
+}
+
+/// The fragment the service manager has loaded for `unit`.
+fn loaded_fragment(level: &ServiceLevel, unit: &str) -> Option<PathBuf> {
+    let mut command = Command::new("systemctl");
+    if *level == ServiceLevel::User {
+        command.arg("--user");
+    }
+    let output = command
+        .args(["show", "--property=FragmentPath", "--value", unit])
+        .output()
+        .ok()?;
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !path.is_empty()).then(|| PathBuf::from(path))
+}
+
+/// Where `name`'s unit comes from at `level`.
+pub fn which(level: &ServiceLevel, name: &str) -> Result<Provenance> {
+    let unit = unit_name(name);
+    let mut provenance = locate(&unit_search_path(level), &unit);
+    provenance.loaded = loaded_fragment(level, &unit);
+    provenance.managed_by = managed_by(level, &unit)?;
+    Ok(provenance)
+}
+
+impl Provenance {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if self.files.is_empty() {
+            let _ = writeln!(out, "{}: no unit file", self.unit);
+            return out;
+        }
+        let _ = writeln!(out, "{}:", self.unit);
+        for (i, file) in self.files.iter().enumerate() {
+            let mut notes = vec![match i {
+                0 => "in effect",
+                _ => "shadowed",
+            }];
+            if file.is_masked() {
+                notes.push("masked");
+            }
+            let link = match (&file.target, file.is_masked()) {
+                (Some(target), false) => format!(" -> {}", target.to_string_lossy()),
+                _ => String::new(),
+            };
+            let _ = writeln!(
+                out,
+                "  {}{} ({})",
+                file.path.to_string_lossy(),
+                link,
+                notes.join(", ")
+            );
+        }
+        if !self.drop_ins.is_empty() {
+            let _ = writeln!(out, "Drop-ins:");
+            for drop_in in &self.drop_ins {
+                let _ = writeln!(out, "  {}", drop_in.to_string_lossy());
+            }
+        }
+        // Aliases load the file they link to.
+        let in_effect = &self.files[0];
+        if let Some(loaded) = self.loaded.as_ref().filter(|loaded| {
+            **loaded != in_effect.path && Some(*loaded) != in_effect.target.as_ref()
+        }) {
+            let _ = writeln!(
+                out,
+                "Loaded from {}, run `systemctl daemon-reload` to pick up changes.",
+                loaded.to_string_lossy()
+            );
+        }
+        let _ = match &self.managed_by {
+            Some(name) => writeln!(out, "Managed by mkservice as {:?}.", name),
+            None => writeln!(out, "Not managed by mkservice."),
+        };
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_drop_in_dirs() {
+        assert_eq!(unit_name("web"), "web.service");
+        assert_eq!(unit_name("web.timer"), "web.timer");
+        assert_eq!(unit_name("org.example.app"), "org.example.app.service");
+        assert_eq!(
+            drop_in_dirs("my-app@2.service"),
+            vec![
+                "service.d",
+                "my-.service.d",
+                "my-app@.service.d",
+                "my-app@2.service.d"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let root = env::temp_dir().join(format!("mkservice-which-{}", std::process::id()));
+        let (etc, usr) = (root.join("etc"), root.join("usr"));
+        for dir in ["etc/web.service.d", "usr/web.service.d", "usr/service.d"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(usr.join("web.service"), "[Service]\n").unwrap();
+        symlink("/dev/null", etc.join("web.service")).unwrap();
+        for drop_in in [
+            "etc/web.service.d/10-limits.conf",
+            "usr/web.service.d/10-limits.conf",
+            "usr/web.service.d/20-env.conf",
+            "usr/service.d/50-all.conf",
+        ] {
+            fs::write(root.join(drop_in), "").unwrap();
+        }
+        let provenance = locate(&[etc.clone(), usr.clone()], "web.service");
+        let worker = locate(&[etc.clone(), usr.clone()], "worker.service");
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            provenance.files,
+            vec![
+                UnitFile {
+                    path: etc.join("web.service"),
+                    target: Some("/dev/null".into())
+                },
+                UnitFile {
+                    path: usr.join("web.service"),
+                    target: None
+                },
+            ]
+        );
+        assert_eq!(
+            provenance.drop_ins,
+            vec![
+                etc.join("web.service.d/10-limits.conf"),
+                usr.join("web.service.d/20-env.conf"),
+                usr.join("service.d/50-all.conf"),
+            ]
+        );
+        assert!(provenance.render().contains("(in effect, masked)"));
+        assert!(worker.files.is_empty());
+    }
+}